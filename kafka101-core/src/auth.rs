@@ -0,0 +1,533 @@
+//! Typed authentication profiles for the producer and consumer clients.
+//!
+//! An [`Auth`] is chosen in the `[auth]` section of the config file (or of a
+//! `[profiles.<name>.auth]` section) and applied to both client configs by
+//! [`Settings`](crate::Settings):
+//!
+//! ```toml
+//! [profiles.staging.auth]
+//! mechanism = "scram-sha-512"
+//! username = "app"
+//! password = { env = "STAGING_SASL_PASSWORD" }
+//!
+//! [profiles.prod.auth]
+//! mechanism = "mtls"
+//! ca_location = "/etc/kafka/ca.pem"
+//! certificate_location = "/etc/kafka/client.pem"
+//! key_location = "/etc/kafka/client.key"
+//! key_password = { file = "/run/secrets/kafka-key-password" }
+//! ```
+//!
+//! Secrets can be written inline, or read from `{ env = "VAR" }` or
+//! `{ file = "path" }`. Keep the variables out of the `KAFKA_` namespace,
+//! which the loader reads client properties from. They are wrapped in [`Secret`], whose `Debug` and
+//! `Display` output is redacted.
+
+use std::{
+    env,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rdkafka::{
+    client::OAuthToken,
+    consumer::ConsumerContext,
+    producer::{DeliveryResult, ProducerContext},
+    ClientConfig, ClientContext,
+};
+use serde::Deserialize;
+
+/// Token lifetime reported to librdkafka when the profile does not set `lifetime_secs`.
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Client properties owned by an auth profile. Setting them directly as well is a config error.
+pub(crate) const MANAGED_PROPERTIES: &[&str] = &[
+    "security.protocol",
+    "sasl.mechanism",
+    "sasl.mechanisms",
+    "sasl.username",
+    "sasl.password",
+    "ssl.ca.location",
+    "ssl.certificate.location",
+    "ssl.key.location",
+    "ssl.key.password",
+];
+
+/// A password, key passphrase or token. Never shows up in `Debug` or `Display` output.
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new<S: Into<String>>(value: S) -> Secret {
+        Secret(value.into())
+    }
+
+    /// The secret value, for handing to librdkafka.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// Where a secret is read from.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum SecretSource {
+    Env { env: String },
+    File { file: PathBuf },
+    Value(Secret),
+}
+
+impl SecretSource {
+    /// Reads the secret. File contents are trimmed of surrounding whitespace.
+    pub fn resolve(&self) -> Result<Secret, String> {
+        match self {
+            SecretSource::Value(secret) => Ok(secret.clone()),
+            SecretSource::Env { env: var } => env::var(var)
+                .map(Secret)
+                .map_err(|_| format!("environment variable {} is not set", var)),
+            SecretSource::File { file } => fs::read_to_string(file)
+                .map(|contents| Secret(contents.trim().to_string()))
+                .map_err(|err| format!("could not read secret file {} - {}", file.display(), err)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaslMechanism {
+    Plain,
+    ScramSha256,
+    ScramSha512,
+}
+
+impl SaslMechanism {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+        }
+    }
+}
+
+/// Username and password authentication (PLAIN or SCRAM).
+#[derive(Debug, Clone)]
+pub struct SaslCredentials {
+    pub mechanism: SaslMechanism,
+    pub username: String,
+    pub password: Secret,
+    /// Use `SASL_SSL` rather than `SASL_PLAINTEXT`.
+    pub tls: bool,
+    pub ca_location: Option<PathBuf>,
+}
+
+/// `OAUTHBEARER` authentication. Tokens are fetched from the provider whenever librdkafka asks.
+#[derive(Clone)]
+pub struct OAuthBearer {
+    pub provider: Arc<dyn TokenProvider>,
+    pub tls: bool,
+    pub ca_location: Option<PathBuf>,
+}
+
+impl fmt::Debug for OAuthBearer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OAuthBearer")
+            .field("tls", &self.tls)
+            .field("ca_location", &self.ca_location)
+            .finish()
+    }
+}
+
+/// TLS client certificate authentication.
+#[derive(Debug, Clone)]
+pub struct MutualTls {
+    pub ca_location: Option<PathBuf>,
+    pub certificate_location: PathBuf,
+    pub key_location: PathBuf,
+    pub key_password: Option<Secret>,
+}
+
+/// How the clients authenticate to the brokers.
+#[derive(Debug, Clone, Default)]
+pub enum Auth {
+    #[default]
+    None,
+    Sasl(SaslCredentials),
+    OAuthBearer(OAuthBearer),
+    MutualTls(MutualTls),
+}
+
+impl Auth {
+    /// Sets the security properties for this profile on a producer or consumer config.
+    pub fn apply(&self, config: &mut ClientConfig) {
+        match self {
+            Auth::None => {}
+            Auth::Sasl(sasl) => {
+                config
+                    .set("security.protocol", security_protocol(sasl.tls))
+                    .set("sasl.mechanisms", sasl.mechanism.as_str())
+                    .set("sasl.username", &sasl.username)
+                    .set("sasl.password", sasl.password.expose());
+                set_path(config, "ssl.ca.location", sasl.ca_location.as_deref());
+            }
+            Auth::OAuthBearer(oauth) => {
+                config
+                    .set("security.protocol", security_protocol(oauth.tls))
+                    .set("sasl.mechanisms", "OAUTHBEARER");
+                set_path(config, "ssl.ca.location", oauth.ca_location.as_deref());
+            }
+            Auth::MutualTls(tls) => {
+                config.set("security.protocol", "SSL");
+                set_path(config, "ssl.ca.location", tls.ca_location.as_deref());
+                set_path(
+                    config,
+                    "ssl.certificate.location",
+                    Some(&tls.certificate_location),
+                );
+                set_path(config, "ssl.key.location", Some(&tls.key_location));
+                if let Some(password) = &tls.key_password {
+                    config.set("ssl.key.password", password.expose());
+                }
+            }
+        }
+    }
+
+    /// The token provider, for `OAUTHBEARER` profiles.
+    pub fn token_provider(&self) -> Option<Arc<dyn TokenProvider>> {
+        match self {
+            Auth::OAuthBearer(oauth) => Some(oauth.provider.clone()),
+            _ => None,
+        }
+    }
+}
+
+fn security_protocol(tls: bool) -> &'static str {
+    if tls {
+        "SASL_SSL"
+    } else {
+        "SASL_PLAINTEXT"
+    }
+}
+
+fn set_path(config: &mut ClientConfig, name: &str, path: Option<&Path>) {
+    if let Some(path) = path {
+        config.set(name, path.to_string_lossy().into_owned());
+    }
+}
+
+/// Supplies `OAUTHBEARER` tokens on demand.
+pub trait TokenProvider: Send + Sync {
+    fn token(&self) -> Result<OAuthToken, Box<dyn Error>>;
+}
+
+/// Reads the token from a [`SecretSource`] on every refresh, so a token file
+/// rotated by e.g. a Kubernetes projected volume is picked up automatically.
+#[derive(Debug, Clone)]
+pub struct SecretTokenProvider {
+    pub principal: String,
+    pub source: SecretSource,
+    pub lifetime: Duration,
+}
+
+impl TokenProvider for SecretTokenProvider {
+    fn token(&self) -> Result<OAuthToken, Box<dyn Error>> {
+        let token = self.source.resolve()?;
+        let expires_at = SystemTime::now().duration_since(UNIX_EPOCH)? + self.lifetime;
+
+        Ok(OAuthToken {
+            token: token.expose().to_string(),
+            principal_name: self.principal.clone(),
+            lifetime_ms: expires_at.as_millis() as i64,
+        })
+    }
+}
+
+/// Answers librdkafka's token refresh callback for the contexts in this crate.
+pub(crate) fn generate_token(
+    provider: Option<&Arc<dyn TokenProvider>>,
+) -> Result<OAuthToken, Box<dyn Error>> {
+    match provider {
+        Some(provider) => provider.token(),
+        None => Err("sasl.mechanisms is OAUTHBEARER but no token provider was configured".into()),
+    }
+}
+
+/// A context that does nothing but authenticate, for clients that do not want
+/// the logging contexts but may still need `OAUTHBEARER` token refreshes.
+#[derive(Clone, Default)]
+pub struct AuthContext {
+    token_provider: Option<Arc<dyn TokenProvider>>,
+}
+
+impl AuthContext {
+    pub fn new(auth: &Auth) -> AuthContext {
+        AuthContext {
+            token_provider: auth.token_provider(),
+        }
+    }
+}
+
+impl fmt::Debug for AuthContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthContext")
+            .field("oauth", &self.token_provider.is_some())
+            .finish()
+    }
+}
+
+impl ClientContext for AuthContext {
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn Error>> {
+        generate_token(self.token_provider.as_ref())
+    }
+}
+
+impl ProducerContext for AuthContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(
+        &self,
+        _delivery_result: &DeliveryResult<'_>,
+        _delivery_opaque: Self::DeliveryOpaque,
+    ) {
+    }
+}
+
+impl ConsumerContext for AuthContext {}
+
+/// The `[auth]` section of a config file, before secrets are read.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(tag = "mechanism", deny_unknown_fields)]
+pub(crate) enum AuthConfig {
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "plain", alias = "PLAIN")]
+    Plain(SaslConfig),
+    #[serde(rename = "scram-sha-256", alias = "SCRAM-SHA-256")]
+    ScramSha256(SaslConfig),
+    #[serde(rename = "scram-sha-512", alias = "SCRAM-SHA-512")]
+    ScramSha512(SaslConfig),
+    #[serde(rename = "oauthbearer", alias = "OAUTHBEARER")]
+    OAuthBearer {
+        principal: String,
+        token: SecretSource,
+        lifetime_secs: Option<u64>,
+        #[serde(default = "enabled")]
+        tls: bool,
+        ca_location: Option<PathBuf>,
+    },
+    #[serde(rename = "mtls")]
+    MutualTls {
+        ca_location: Option<PathBuf>,
+        certificate_location: PathBuf,
+        key_location: PathBuf,
+        key_password: Option<SecretSource>,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SaslConfig {
+    username: String,
+    password: SecretSource,
+    #[serde(default = "enabled")]
+    tls: bool,
+    ca_location: Option<PathBuf>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl AuthConfig {
+    /// Reads the secrets this profile refers to, reporting every one that is missing.
+    pub fn resolve(self, problems: &mut Vec<String>) -> Auth {
+        let mut secret = |source: &SecretSource| match source.resolve() {
+            Ok(secret) => secret,
+            Err(problem) => {
+                problems.push(format!("[auth] {}", problem));
+                Secret::new("")
+            }
+        };
+
+        match self {
+            AuthConfig::None => Auth::None,
+            AuthConfig::Plain(sasl) => Auth::Sasl(sasl.resolve(SaslMechanism::Plain, secret)),
+            AuthConfig::ScramSha256(sasl) => {
+                Auth::Sasl(sasl.resolve(SaslMechanism::ScramSha256, secret))
+            }
+            AuthConfig::ScramSha512(sasl) => {
+                Auth::Sasl(sasl.resolve(SaslMechanism::ScramSha512, secret))
+            }
+            AuthConfig::OAuthBearer {
+                principal,
+                token,
+                lifetime_secs,
+                tls,
+                ca_location,
+            } => {
+                // read once up front so a missing token is reported with everything else
+                secret(&token);
+                let provider = SecretTokenProvider {
+                    principal,
+                    source: token,
+                    lifetime: lifetime_secs
+                        .map(Duration::from_secs)
+                        .unwrap_or(DEFAULT_TOKEN_LIFETIME),
+                };
+                Auth::OAuthBearer(OAuthBearer {
+                    provider: Arc::new(provider),
+                    tls,
+                    ca_location,
+                })
+            }
+            AuthConfig::MutualTls {
+                ca_location,
+                certificate_location,
+                key_location,
+                key_password,
+            } => Auth::MutualTls(MutualTls {
+                ca_location,
+                certificate_location,
+                key_location,
+                key_password: key_password.as_ref().map(secret),
+            }),
+        }
+    }
+}
+
+impl SaslConfig {
+    fn resolve<F: FnMut(&SecretSource) -> Secret>(
+        self,
+        mechanism: SaslMechanism,
+        mut secret: F,
+    ) -> SaslCredentials {
+        SaslCredentials {
+            mechanism,
+            password: secret(&self.password),
+            username: self.username,
+            tls: self.tls,
+            ca_location: self.ca_location,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(toml: &str) -> SecretSource {
+        #[derive(Deserialize)]
+        struct Holder {
+            secret: SecretSource,
+        }
+        toml::from_str::<Holder>(toml).unwrap().secret
+    }
+
+    #[test]
+    fn sources_are_inline_env_or_file() {
+        assert_eq!(
+            source(r#"secret = "hunter2""#),
+            SecretSource::Value(Secret::new("hunter2"))
+        );
+        assert_eq!(
+            source(r#"secret = { env = "PASSWORD" }"#),
+            SecretSource::Env {
+                env: "PASSWORD".to_string()
+            }
+        );
+        assert_eq!(
+            source(r#"secret = { file = "/run/secrets/password" }"#),
+            SecretSource::File {
+                file: PathBuf::from("/run/secrets/password")
+            }
+        );
+    }
+
+    #[test]
+    fn secrets_resolve_from_env_and_trimmed_files() {
+        let var = format!("KAFKA101_AUTH_TEST_{}", std::process::id());
+        env::set_var(&var, "from-env");
+        let from_env = SecretSource::Env { env: var.clone() }.resolve().unwrap();
+        assert_eq!(from_env.expose(), "from-env");
+        env::remove_var(&var);
+        assert_eq!(
+            SecretSource::Env { env: var.clone() }
+                .resolve()
+                .unwrap_err(),
+            format!("environment variable {} is not set", var)
+        );
+
+        let file = env::temp_dir().join(format!("kafka101-secret-{}", std::process::id()));
+        fs::write(&file, "  from-file\n").unwrap();
+        let from_file = SecretSource::File { file: file.clone() }.resolve().unwrap();
+        assert_eq!(from_file.expose(), "from-file");
+        fs::remove_file(&file).unwrap();
+        assert!(SecretSource::File { file: file.clone() }
+            .resolve()
+            .unwrap_err()
+            .starts_with(&format!("could not read secret file {}", file.display())));
+    }
+
+    #[test]
+    fn secrets_are_redacted_when_printed() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(secret.to_string(), "<redacted>");
+        assert_eq!(format!("{:?}", secret), "Secret(<redacted>)");
+
+        let auth = Auth::Sasl(SaslCredentials {
+            mechanism: SaslMechanism::ScramSha512,
+            username: "app".to_string(),
+            password: secret.clone(),
+            tls: true,
+            ca_location: None,
+        });
+        let debug = format!("{:?}", auth);
+        assert!(debug.contains("app"));
+        assert!(!debug.contains("hunter2"), "{}", debug);
+
+        let source = format!("{:?}", SecretSource::Value(secret));
+        assert!(!source.contains("hunter2"), "{}", source);
+    }
+
+    #[test]
+    fn every_missing_secret_is_reported() {
+        let config: AuthConfig = toml::from_str(
+            r#"
+            mechanism = "mtls"
+            certificate_location = "client.pem"
+            key_location = "client.key"
+            key_password = { env = "KAFKA101_AUTH_TEST_UNSET" }
+            "#,
+        )
+        .unwrap();
+        let mut problems = Vec::new();
+        match config.resolve(&mut problems) {
+            Auth::MutualTls(tls) => assert_eq!(tls.key_password.unwrap().expose(), ""),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(
+            problems,
+            vec!["[auth] environment variable KAFKA101_AUTH_TEST_UNSET is not set"]
+        );
+    }
+}
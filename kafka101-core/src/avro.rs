@@ -0,0 +1,218 @@
+//! Avro payloads in the Confluent wire format.
+//!
+//! Records describe themselves through [`AvroRecord`]: the schema they are
+//! read and written with, and serde for mapping their fields onto Avro values.
+//! [`AvroSerde`] adds the schema registry on top: the schema is registered (or
+//! looked up) under the subject chosen by the [`SubjectNameStrategy`], and
+//! every payload starts with the magic byte and the 4-byte schema id.
+//!
+//! Payloads are decoded with Avro schema resolution. The schema a payload was
+//! written with is fetched by its id and resolved against the reader's own, so
+//! records written before or after a compatible change, such as a new field
+//! with a default, still decode. A writer schema the reader cannot read is
+//! reported as [`CodecError::SchemaMismatch`].
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use apache_avro::{schema_compatibility::SchemaCompatibility, Schema};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    codec::{CodecError, Deserializer, Serializer},
+    schema_registry::{self, SchemaRegistryClient, SchemaType, SubjectNameStrategy},
+    user::User,
+};
+
+/// A record with a fixed Avro schema, mapped onto Avro values by serde.
+pub trait AvroRecord: Serialize + DeserializeOwned {
+    /// The schema as Avro JSON.
+    const SCHEMA: &'static str;
+    /// `namespace.name` of the record, used by the record name subject strategies.
+    const FULL_NAME: &'static str;
+}
+
+impl AvroRecord for User {
+    const SCHEMA: &'static str = r#"{"type":"record","name":"User","namespace":"io.kafka101","fields":[{"name":"id","type":"int"},{"name":"email","type":"string"}]}"#;
+    const FULL_NAME: &'static str = "io.kafka101.User";
+}
+
+/// Malformed Avro data, or a record that does not fit its schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AvroError(String);
+
+impl AvroError {
+    pub fn new<S: Into<String>>(message: S) -> Self {
+        AvroError(message.into())
+    }
+}
+
+impl fmt::Display for AvroError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for AvroError {}
+
+impl From<apache_avro::Error> for AvroError {
+    fn from(err: apache_avro::Error) -> Self {
+        AvroError(err.to_string())
+    }
+}
+
+/// A writer schema id and the record it is read as.
+type WriterKey = (u32, &'static str);
+
+/// Serializer and deserializer for [`AvroRecord`]s backed by a schema registry.
+pub struct AvroSerde {
+    registry: Arc<SchemaRegistryClient>,
+    strategy: SubjectNameStrategy,
+    auto_register: bool,
+    check_compatibility: bool,
+    is_key: bool,
+    /// Parsed reader schemas, by record name.
+    readers: Mutex<HashMap<&'static str, Arc<Schema>>>,
+    /// Writer schemas already checked against a reader schema, `None` when
+    /// the reader cannot read them.
+    known_writers: Mutex<HashMap<WriterKey, Option<Arc<Schema>>>>,
+}
+
+impl AvroSerde {
+    /// A value serde with the topic name strategy that registers schemas on first use.
+    pub fn new(registry: Arc<SchemaRegistryClient>) -> Self {
+        AvroSerde {
+            registry,
+            strategy: SubjectNameStrategy::default(),
+            auto_register: true,
+            check_compatibility: true,
+            is_key: false,
+            readers: Mutex::new(HashMap::new()),
+            known_writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subject_name_strategy(mut self, strategy: SubjectNameStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// When off, the schema must already be registered under the subject.
+    pub fn auto_register(mut self, auto_register: bool) -> Self {
+        self.auto_register = auto_register;
+        self
+    }
+
+    /// Ask the registry whether the schema is compatible before registering it.
+    pub fn check_compatibility(mut self, check: bool) -> Self {
+        self.check_compatibility = check;
+        self
+    }
+
+    /// Use `-key` subjects, for serializing message keys.
+    pub fn for_keys(mut self) -> Self {
+        self.is_key = true;
+        self
+    }
+
+    fn reader<T: AvroRecord>(&self) -> Result<Arc<Schema>, AvroError> {
+        let mut readers = self.readers.lock().unwrap();
+        if let Some(schema) = readers.get(T::FULL_NAME) {
+            return Ok(schema.clone());
+        }
+        let schema = Arc::new(Schema::parse_str(T::SCHEMA)?);
+        readers.insert(T::FULL_NAME, schema.clone());
+        Ok(schema)
+    }
+
+    /// The writer schema `schema_id`, if records written with it can be read as `T`.
+    fn writer<T: AvroRecord>(
+        &self,
+        schema_id: u32,
+        reader: &Schema,
+    ) -> Result<Option<Arc<Schema>>, CodecError> {
+        let cache_key = (schema_id, T::FULL_NAME);
+        if let Some(writer) = self.known_writers.lock().unwrap().get(&cache_key) {
+            return Ok(writer.clone());
+        }
+
+        let registered = self.registry.schema_by_id(schema_id)?;
+        let writer = match registered.schema_type {
+            SchemaType::Avro => Schema::parse_str(&registered.schema)
+                .ok()
+                .filter(|writer| SchemaCompatibility::can_read(writer, reader))
+                .map(Arc::new),
+            _ => None,
+        };
+        self.known_writers
+            .lock()
+            .unwrap()
+            .insert(cache_key, writer.clone());
+        Ok(writer)
+    }
+}
+
+impl<T: AvroRecord> Serializer<T> for AvroSerde {
+    fn serialize(&self, topic: &str, value: &T) -> Result<Vec<u8>, CodecError> {
+        let subject = self.strategy.subject(topic, T::FULL_NAME, self.is_key);
+        let schema_id = self.registry.schema_id(
+            &subject,
+            T::SCHEMA,
+            SchemaType::Avro,
+            self.auto_register,
+            self.check_compatibility,
+        )?;
+
+        let reader = self.reader::<T>()?;
+        let record = apache_avro::to_value(value)
+            .and_then(|record| record.resolve(&reader))
+            .and_then(|record| apache_avro::to_avro_datum(&reader, record))
+            .map_err(AvroError::from)?;
+
+        let mut out = Vec::with_capacity(5 + record.len());
+        schema_registry::write_header(&mut out, schema_id);
+        out.extend_from_slice(&record);
+        Ok(out)
+    }
+
+    fn content_type(&self) -> Option<&'static str> {
+        Some("application/vnd.apache.avro+binary")
+    }
+
+    fn schema_id(&self, payload: &[u8]) -> Option<u32> {
+        schema_registry::read_header(payload)
+            .ok()
+            .map(|(schema_id, _)| schema_id)
+    }
+}
+
+impl<T: AvroRecord> Deserializer<T> for AvroSerde {
+    fn deserialize(&self, _topic: &str, payload: &[u8]) -> Result<T, CodecError> {
+        let (schema_id, mut body) = schema_registry::read_header(payload)?;
+        let reader = self.reader::<T>()?;
+        let writer = match self.writer::<T>(schema_id, &reader)? {
+            Some(writer) => writer,
+            None => {
+                return Err(CodecError::SchemaMismatch {
+                    schema_id,
+                    expected: T::FULL_NAME.to_string(),
+                })
+            }
+        };
+
+        let record = apache_avro::from_avro_datum(&writer, &mut body, Some(&reader))
+            .map_err(AvroError::from)?;
+        if !body.is_empty() {
+            return Err(AvroError::new(format!(
+                "{} bytes left over after reading {}",
+                body.len(),
+                T::FULL_NAME
+            ))
+            .into());
+        }
+        Ok(apache_avro::from_value(&record).map_err(AvroError::from)?)
+    }
+}
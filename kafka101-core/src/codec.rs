@@ -0,0 +1,238 @@
+//! Turning records into message payloads and back.
+//!
+//! rdkafka's `ToBytes` hands out a borrowed slice, so it cannot be implemented
+//! for a type like `User` that has to be encoded first. A [`Serializer`]
+//! returns an owned buffer instead, which [`TypedProducer`](crate::producer::TypedProducer)
+//! keeps alive until the record has been handed to librdkafka.
+
+use std::{fmt, str::FromStr};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    avro::{AvroError, AvroRecord, AvroSerde},
+    protobuf::{ProtobufRecord, ProtobufSerde},
+    schema_registry::RegistryError,
+};
+
+/// Encodes values of `T` into message payloads.
+pub trait Serializer<T: ?Sized> {
+    /// `topic` is the destination topic, for formats that look up schemas by topic.
+    fn serialize(&self, topic: &str, value: &T) -> Result<Vec<u8>, CodecError>;
+
+    /// The media type of the payloads, for the `content-type` header.
+    fn content_type(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// The schema id a payload was written with, for the `schema-id` header.
+    fn schema_id(&self, _payload: &[u8]) -> Option<u32> {
+        None
+    }
+}
+
+/// Decodes message payloads into values of `T`.
+pub trait Deserializer<T> {
+    fn deserialize(&self, topic: &str, payload: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Why a value could not be encoded or decoded.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum CodecError {
+    Json(serde_json::Error),
+    Avro(AvroError),
+    Protobuf(prost::DecodeError),
+    Registry(RegistryError),
+    /// The payload does not start with the schema registry magic byte and schema id.
+    InvalidFraming(String),
+    /// The payload was written with a schema other than the one it is read with.
+    SchemaMismatch {
+        schema_id: u32,
+        expected: String,
+    },
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Json(err) => write!(f, "JSON error - {}", err),
+            CodecError::Avro(err) => write!(f, "Avro error - {}", err),
+            CodecError::Protobuf(err) => write!(f, "Protobuf error - {}", err),
+            CodecError::Registry(err) => write!(f, "{}", err),
+            CodecError::InvalidFraming(err) => write!(f, "invalid payload framing - {}", err),
+            CodecError::SchemaMismatch {
+                schema_id,
+                expected,
+            } => write!(
+                f,
+                "payload was written with schema {}, which is not the schema of {}",
+                schema_id, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodecError::Json(err) => Some(err),
+            CodecError::Avro(err) => Some(err),
+            CodecError::Protobuf(err) => Some(err),
+            CodecError::Registry(err) => Some(err),
+            CodecError::InvalidFraming(_) | CodecError::SchemaMismatch { .. } => None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for CodecError {
+    fn from(err: serde_json::Error) -> Self {
+        CodecError::Json(err)
+    }
+}
+
+impl From<AvroError> for CodecError {
+    fn from(err: AvroError) -> Self {
+        CodecError::Avro(err)
+    }
+}
+
+impl From<prost::DecodeError> for CodecError {
+    fn from(err: prost::DecodeError) -> Self {
+        CodecError::Protobuf(err)
+    }
+}
+
+impl From<RegistryError> for CodecError {
+    fn from(err: RegistryError) -> Self {
+        CodecError::Registry(err)
+    }
+}
+
+/// JSON via serde_json, for any `Serialize`/`Deserialize` type.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonSerde {
+    pretty: bool,
+}
+
+impl JsonSerde {
+    pub fn new() -> Self {
+        JsonSerde { pretty: false }
+    }
+
+    /// Pretty-printed output, as the original JSON examples produced.
+    pub fn pretty() -> Self {
+        JsonSerde { pretty: true }
+    }
+}
+
+impl<T: Serialize + ?Sized> Serializer<T> for JsonSerde {
+    fn serialize(&self, _topic: &str, value: &T) -> Result<Vec<u8>, CodecError> {
+        let bytes = if self.pretty {
+            serde_json::to_vec_pretty(value)?
+        } else {
+            serde_json::to_vec(value)?
+        };
+        Ok(bytes)
+    }
+
+    fn content_type(&self) -> Option<&'static str> {
+        Some("application/json")
+    }
+}
+
+impl<T: DeserializeOwned> Deserializer<T> for JsonSerde {
+    fn deserialize(&self, _topic: &str, payload: &[u8]) -> Result<T, CodecError> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+/// The payload format selected in the configuration.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Avro,
+    Protobuf,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Json => write!(f, "json"),
+            Format::Avro => write!(f, "avro"),
+            Format::Protobuf => write!(f, "protobuf"),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "avro" => Ok(Format::Avro),
+            "protobuf" => Ok(Format::Protobuf),
+            other => Err(format!(
+                "unknown format `{}`, expected json, avro or protobuf",
+                other
+            )),
+        }
+    }
+}
+
+/// One of the serdes above, picked at runtime so a binary can switch formats
+/// through configuration. Works for records that support all of them, like `User`.
+pub enum FormatSerde {
+    Json(JsonSerde),
+    Avro(AvroSerde),
+    Protobuf(ProtobufSerde),
+}
+
+impl FormatSerde {
+    pub fn format(&self) -> Format {
+        match self {
+            FormatSerde::Json(_) => Format::Json,
+            FormatSerde::Avro(_) => Format::Avro,
+            FormatSerde::Protobuf(_) => Format::Protobuf,
+        }
+    }
+}
+
+impl<T: Serialize + AvroRecord + ProtobufRecord> Serializer<T> for FormatSerde {
+    fn serialize(&self, topic: &str, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            FormatSerde::Json(serde) => serde.serialize(topic, value),
+            FormatSerde::Avro(serde) => serde.serialize(topic, value),
+            FormatSerde::Protobuf(serde) => serde.serialize(topic, value),
+        }
+    }
+
+    fn content_type(&self) -> Option<&'static str> {
+        match self {
+            FormatSerde::Json(serde) => Serializer::<T>::content_type(serde),
+            FormatSerde::Avro(serde) => Serializer::<T>::content_type(serde),
+            FormatSerde::Protobuf(serde) => Serializer::<T>::content_type(serde),
+        }
+    }
+
+    fn schema_id(&self, payload: &[u8]) -> Option<u32> {
+        match self {
+            FormatSerde::Json(serde) => Serializer::<T>::schema_id(serde, payload),
+            FormatSerde::Avro(serde) => Serializer::<T>::schema_id(serde, payload),
+            FormatSerde::Protobuf(serde) => Serializer::<T>::schema_id(serde, payload),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + AvroRecord + ProtobufRecord> Deserializer<T> for FormatSerde {
+    fn deserialize(&self, topic: &str, payload: &[u8]) -> Result<T, CodecError> {
+        match self {
+            FormatSerde::Json(serde) => serde.deserialize(topic, payload),
+            FormatSerde::Avro(serde) => serde.deserialize(topic, payload),
+            FormatSerde::Protobuf(serde) => serde.deserialize(topic, payload),
+        }
+    }
+}
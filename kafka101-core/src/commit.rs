@@ -0,0 +1,930 @@
+//! Committing consumer offsets without ignoring the result.
+//!
+//! A [`CommitManager`] commits the offset after a message and looks at what
+//! came back:
+//!
+//! - a transient error (coordinator moving or loading, request timed out,
+//!   broker connection lost) is retried with the `[errors]` backoff
+//! - a rebalance error (rebalance in progress, stale generation, unknown
+//!   member, assignment lost) is not retried: the partition may belong to
+//!   another consumer by now, which resumes from the last committed offset, so
+//!   the message is processed again rather than lost
+//! - a fenced static member (another consumer started with the same
+//!   `group.instance.id`) must stop consuming
+//!
+//! It also keeps [`CommitStats`] on how many commits succeeded and failed and
+//! how long they took. The consumer context still logs every committed offset
+//! in its `commit_callback`.
+//!
+//! An [`OffsetCommitter`] decides when to commit, following the
+//! [`CommitStrategy`] of the `[commit]` section:
+//!
+//! ```toml
+//! [commit]
+//! strategy = "every-n"   # every-message, every-n, interval, async or contiguous
+//! every = 100
+//! interval_ms = 5000
+//! ```
+//!
+//! Whatever the strategy, a consumer context built
+//! [`with_committer`](crate::ConsumerCallbackLogger::with_committer) commits
+//! the processed offsets of partitions synchronously before they are revoked,
+//! and [`OffsetCommitter::commit_sync`] does the same on shutdown.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    consumer::{CommitMode, Consumer, ConsumerContext},
+    error::{KafkaError, KafkaResult},
+    types::RDKafkaErrorCode,
+    Message, Offset, TopicPartitionList,
+};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::dlq::RetryPolicy;
+
+/// Commits offsets, retrying what can be retried, and keeps statistics.
+#[derive(Debug, Default)]
+pub struct CommitManager {
+    retry: RetryPolicy,
+    stats: Mutex<CommitStats>,
+}
+
+impl CommitManager {
+    pub fn new(retry: RetryPolicy) -> CommitManager {
+        CommitManager {
+            retry,
+            stats: Mutex::new(CommitStats::default()),
+        }
+    }
+
+    /// Commits the offset after `msg`. With [`CommitMode::Async`] only enqueueing
+    /// the commit is checked, and timed; the outcome goes to the consumer context.
+    pub fn commit_message<C, K, M>(
+        &self,
+        consumer: &K,
+        msg: &M,
+        mode: CommitMode,
+    ) -> Result<(), CommitError>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+        M: Message,
+    {
+        let mut offsets = TopicPartitionList::new();
+        offsets
+            .add_partition_offset(
+                msg.topic(),
+                msg.partition(),
+                Offset::Offset(msg.offset() + 1),
+            )
+            .map_err(|error| CommitError::Failed { error, attempts: 0 })?;
+        self.commit_offsets(consumer, &offsets, mode)
+    }
+
+    /// Commits `offsets`, which are the offsets of the next messages to consume.
+    pub fn commit_offsets<C, K>(
+        &self,
+        consumer: &K,
+        offsets: &TopicPartitionList,
+        mode: CommitMode,
+    ) -> Result<(), CommitError>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        let started = Instant::now();
+        let mut retry = 0;
+        loop {
+            let err = match consumer.commit(offsets, mode) {
+                Ok(()) => {
+                    let latency = started.elapsed();
+                    debug!(
+                        offsets = %describe(offsets),
+                        latency_ms = latency.as_millis() as u64,
+                        "committed"
+                    );
+                    self.stats.lock().unwrap().succeeded(latency);
+                    return Ok(());
+                }
+                // nothing new to commit, e.g. after a rebalance reset the position
+                Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => {
+                    return Ok(());
+                }
+                Err(err) => err,
+            };
+
+            let failure = match CommitFailure::of(&err) {
+                CommitFailure::Retriable if retry < self.retry.max_retries => {
+                    retry += 1;
+                    self.stats.lock().unwrap().retries += 1;
+                    let backoff = self.retry.backoff(retry);
+                    warn!(
+                        offsets = %describe(offsets),
+                        error = %err,
+                        backoff_ms = backoff.as_millis() as u64,
+                        "commit failed, retrying"
+                    );
+                    thread::sleep(backoff);
+                    continue;
+                }
+                CommitFailure::Retriable | CommitFailure::Other => CommitError::Failed {
+                    error: err,
+                    attempts: retry + 1,
+                },
+                CommitFailure::Rebalance => CommitError::Rebalance(err),
+                CommitFailure::Fenced => CommitError::Fenced(err),
+            };
+            self.stats
+                .lock()
+                .unwrap()
+                .failed(&failure, started.elapsed());
+            return Err(failure);
+        }
+    }
+
+    /// Counts an async commit that the consumer context reported as failed.
+    pub(crate) fn async_failed(&self, err: &KafkaError) {
+        let failure = match CommitFailure::of(err) {
+            CommitFailure::Rebalance => CommitError::Rebalance(err.clone()),
+            _ => CommitError::Failed {
+                error: err.clone(),
+                attempts: 1,
+            },
+        };
+        self.stats.lock().unwrap().failed(&failure, Duration::ZERO);
+    }
+
+    /// A copy of the statistics so far.
+    pub fn stats(&self) -> CommitStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommitFailure {
+    Retriable,
+    Rebalance,
+    Fenced,
+    Other,
+}
+
+impl CommitFailure {
+    fn of(err: &KafkaError) -> CommitFailure {
+        match err.rdkafka_error_code() {
+            Some(RDKafkaErrorCode::CoordinatorLoadInProgress)
+            | Some(RDKafkaErrorCode::CoordinatorNotAvailable)
+            | Some(RDKafkaErrorCode::NotCoordinator)
+            | Some(RDKafkaErrorCode::RequestTimedOut)
+            | Some(RDKafkaErrorCode::NetworkException)
+            | Some(RDKafkaErrorCode::BrokerTransportFailure)
+            | Some(RDKafkaErrorCode::AllBrokersDown)
+            | Some(RDKafkaErrorCode::OperationTimedOut) => CommitFailure::Retriable,
+            Some(RDKafkaErrorCode::RebalanceInProgress)
+            | Some(RDKafkaErrorCode::IllegalGeneration)
+            | Some(RDKafkaErrorCode::UnknownMemberId)
+            | Some(RDKafkaErrorCode::AssignmentLost) => CommitFailure::Rebalance,
+            Some(RDKafkaErrorCode::FencedInstanceId) => CommitFailure::Fenced,
+            _ => CommitFailure::Other,
+        }
+    }
+}
+
+/// Why an offset was not committed.
+#[derive(Debug)]
+pub enum CommitError {
+    /// The group is rebalancing or has moved on. The message will be consumed
+    /// again by whoever owns the partition next; keep consuming.
+    Rebalance(KafkaError),
+    /// Another consumer took over this one's `group.instance.id`. Stop consuming.
+    Fenced(KafkaError),
+    /// The commit failed on every attempt, or with an error that is not retried.
+    Failed { error: KafkaError, attempts: u32 },
+}
+
+impl CommitError {
+    /// Whether the consumer can carry on. Only a fenced consumer cannot.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, CommitError::Fenced(_))
+    }
+}
+
+impl fmt::Display for CommitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommitError::Rebalance(err) => {
+                write!(f, "offset not committed because of a rebalance - {}", err)
+            }
+            CommitError::Fenced(err) => write!(
+                f,
+                "consumer fenced by another member with the same group.instance.id - {}",
+                err
+            ),
+            CommitError::Failed { error, attempts } => write!(
+                f,
+                "failed to commit offset after {} attempts - {}",
+                attempts, error
+            ),
+        }
+    }
+}
+
+impl Error for CommitError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CommitError::Rebalance(err) | CommitError::Fenced(err) => Some(err),
+            CommitError::Failed { error, .. } => Some(error),
+        }
+    }
+}
+
+/// Counters and latencies of the commits made through a [`CommitManager`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommitStats {
+    pub committed: u64,
+    /// Commits given up on, including rebalance and fencing errors.
+    pub failed: u64,
+    pub rebalance_failures: u64,
+    /// Attempts repeated after a transient error.
+    pub retries: u64,
+    /// Time from the first attempt until success, summed over all successful commits.
+    pub total_latency: Duration,
+    pub max_latency: Duration,
+}
+
+impl CommitStats {
+    /// Average time of a successful commit, retries included.
+    pub fn mean_latency(&self) -> Duration {
+        if self.committed == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total_latency.as_nanos() / self.committed as u128) as u64)
+    }
+
+    fn succeeded(&mut self, latency: Duration) {
+        self.committed += 1;
+        self.total_latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
+
+    fn failed(&mut self, err: &CommitError, latency: Duration) {
+        self.failed += 1;
+        if let CommitError::Rebalance(_) = err {
+            self.rebalance_failures += 1;
+        }
+        self.max_latency = self.max_latency.max(latency);
+    }
+}
+
+impl fmt::Display for CommitStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} committed, {} failed ({} during rebalances), {} retries, latency mean {:?} max {:?}",
+            self.committed,
+            self.failed,
+            self.rebalance_failures,
+            self.retries,
+            self.mean_latency(),
+            self.max_latency
+        )
+    }
+}
+
+/// Renders the offsets in `tpl` for log lines, e.g. `rust/0@42, rust/1@7`.
+pub(crate) fn describe(tpl: &TopicPartitionList) -> String {
+    tpl.elements()
+        .iter()
+        .map(|e| match e.offset() {
+            Offset::Offset(offset) => format!("{}/{}@{}", e.topic(), e.partition(), offset),
+            other => format!("{}/{}@{:?}", e.topic(), e.partition(), other),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// When an [`OffsetCommitter`] commits.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommitStrategy {
+    /// Synchronously after every message.
+    #[default]
+    EveryMessage,
+    /// Synchronously after every `every` messages.
+    EveryN,
+    /// Synchronously once `interval` has passed since the last commit.
+    Interval,
+    /// Asynchronously after every message.
+    Async,
+    /// Asynchronously, up to the first message that is not processed yet, for
+    /// messages that are processed out of order. Messages must be announced
+    /// with [`OffsetCommitter::received`] before processing starts.
+    Contiguous,
+}
+
+impl fmt::Display for CommitStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CommitStrategy::EveryMessage => "every-message",
+            CommitStrategy::EveryN => "every-n",
+            CommitStrategy::Interval => "interval",
+            CommitStrategy::Async => "async",
+            CommitStrategy::Contiguous => "contiguous",
+        })
+    }
+}
+
+/// The offsets that may be committed for each partition, given which messages
+/// are still being processed.
+#[derive(Debug, Clone, Default)]
+pub struct OffsetTracker {
+    partitions: BTreeMap<(String, i32), PartitionOffsets>,
+    /// Removed partitions, whose messages may still finish processing.
+    revoked: BTreeSet<(String, i32)>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct PartitionOffsets {
+    /// Received but not processed yet.
+    pending: BTreeSet<i64>,
+    /// One past the highest processed offset.
+    next: Option<i64>,
+    committed: Option<i64>,
+}
+
+impl PartitionOffsets {
+    /// The offset to commit: the first pending message, or the one after the
+    /// highest processed message when nothing is pending.
+    fn position(&self) -> Option<i64> {
+        let next = self.next?;
+        Some(
+            self.pending
+                .iter()
+                .next()
+                .map_or(next, |first| next.min(*first)),
+        )
+    }
+}
+
+impl OffsetTracker {
+    pub fn new() -> OffsetTracker {
+        OffsetTracker::default()
+    }
+
+    /// Records that processing of a message has started. Until it is
+    /// [`processed`](Self::processed), nothing from its offset on is committable.
+    pub fn received(&mut self, topic: &str, partition: i32, offset: i64) {
+        self.partition(topic, partition).pending.insert(offset);
+    }
+
+    /// Records that a message is done. Ignored for [`remove`](Self::remove)d
+    /// partitions, which another consumer may be committing by now.
+    pub fn processed(&mut self, topic: &str, partition: i32, offset: i64) {
+        if self.revoked.contains(&(topic.to_string(), partition)) {
+            return;
+        }
+        let offsets = self.partition(topic, partition);
+        offsets.pending.remove(&offset);
+        offsets.next = Some(offsets.next.map_or(offset + 1, |next| next.max(offset + 1)));
+    }
+
+    /// Messages received and not yet processed, over all partitions.
+    pub fn pending(&self) -> usize {
+        self.partitions.values().map(|p| p.pending.len()).sum()
+    }
+
+    /// The offsets that moved since they were last committed.
+    pub fn committable(&self) -> KafkaResult<TopicPartitionList> {
+        let mut tpl = TopicPartitionList::new();
+        for ((topic, partition), offsets) in &self.partitions {
+            match (offsets.position(), offsets.committed) {
+                (Some(position), Some(committed)) if position <= committed => {}
+                (Some(position), _) => {
+                    tpl.add_partition_offset(topic, *partition, Offset::Offset(position))?
+                }
+                (None, _) => {}
+            }
+        }
+        Ok(tpl)
+    }
+
+    /// Records that `tpl` was committed.
+    pub fn committed(&mut self, tpl: &TopicPartitionList) {
+        for e in tpl.elements() {
+            if let (Offset::Offset(offset), Some(offsets)) = (
+                e.offset(),
+                self.partitions
+                    .get_mut(&(e.topic().to_string(), e.partition())),
+            ) {
+                offsets.committed = Some(offsets.committed.map_or(offset, |c| c.max(offset)));
+            }
+        }
+    }
+
+    /// Forgets that `tpl` was committed, so it is committed again.
+    pub fn uncommitted(&mut self, tpl: &TopicPartitionList) {
+        for e in tpl.elements() {
+            if let Some(offsets) = self
+                .partitions
+                .get_mut(&(e.topic().to_string(), e.partition()))
+            {
+                offsets.committed = None;
+            }
+        }
+    }
+
+    /// Forgets the partitions in `tpl`, e.g. because they were revoked, until
+    /// they are [`assign`](Self::assign)ed again.
+    pub fn remove(&mut self, tpl: &TopicPartitionList) {
+        for e in tpl.elements() {
+            let partition = (e.topic().to_string(), e.partition());
+            self.partitions.remove(&partition);
+            self.revoked.insert(partition);
+        }
+    }
+
+    /// Starts tracking the partitions in `tpl` again.
+    pub fn assign(&mut self, tpl: &TopicPartitionList) {
+        for e in tpl.elements() {
+            self.revoked.remove(&(e.topic().to_string(), e.partition()));
+        }
+    }
+
+    fn partition(&mut self, topic: &str, partition: i32) -> &mut PartitionOffsets {
+        self.partitions
+            .entry((topic.to_string(), partition))
+            .or_default()
+    }
+}
+
+/// Commits processed offsets according to a [`CommitStrategy`]. Clones share
+/// the same state, so the consumer context can commit on revoke and learn the
+/// outcome of async commits, see [`ConsumerCallbackLogger::with_committer`](crate::ConsumerCallbackLogger::with_committer).
+#[derive(Debug, Clone)]
+pub struct OffsetCommitter {
+    settings: CommitSettings,
+    manager: Arc<CommitManager>,
+    state: Arc<Mutex<CommitterState>>,
+}
+
+#[derive(Debug)]
+struct CommitterState {
+    tracker: OffsetTracker,
+    since_commit: usize,
+    last_commit: Instant,
+    /// Async commits waiting for their result, by [`commit_key`].
+    in_flight: Vec<Vec<(String, i32, i64)>>,
+}
+
+impl OffsetCommitter {
+    pub fn new(settings: CommitSettings, manager: CommitManager) -> OffsetCommitter {
+        OffsetCommitter {
+            settings,
+            manager: Arc::new(manager),
+            state: Arc::new(Mutex::new(CommitterState {
+                tracker: OffsetTracker::new(),
+                since_commit: 0,
+                last_commit: Instant::now(),
+                in_flight: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn strategy(&self) -> CommitStrategy {
+        self.settings.strategy
+    }
+
+    /// Records that processing of `msg` has started. Needed for
+    /// [`CommitStrategy::Contiguous`], harmless otherwise.
+    pub fn received<M: Message>(&self, msg: &M) {
+        self.state
+            .lock()
+            .unwrap()
+            .tracker
+            .received(msg.topic(), msg.partition(), msg.offset());
+    }
+
+    /// Records that `msg` is done and commits if the strategy says so.
+    pub fn processed<C, K, M>(&self, consumer: &K, msg: &M) -> Result<(), CommitError>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+        M: Message,
+    {
+        let due = {
+            let mut state = self.state.lock().unwrap();
+            state
+                .tracker
+                .processed(msg.topic(), msg.partition(), msg.offset());
+            state.since_commit += 1;
+            match self.settings.strategy {
+                CommitStrategy::EveryMessage => Some(CommitMode::Sync),
+                CommitStrategy::EveryN if state.since_commit >= self.settings.every => {
+                    Some(CommitMode::Sync)
+                }
+                CommitStrategy::Interval
+                    if state.last_commit.elapsed() >= self.settings.interval =>
+                {
+                    Some(CommitMode::Sync)
+                }
+                CommitStrategy::Async | CommitStrategy::Contiguous => Some(CommitMode::Async),
+                CommitStrategy::EveryN | CommitStrategy::Interval => None,
+            }
+        };
+        match due {
+            Some(mode) => self.commit(consumer, mode),
+            None => Ok(()),
+        }
+    }
+
+    /// Records that `msg` is done without committing, e.g. from a
+    /// [`RebalanceListener`](crate::consumer::RebalanceListener), which has no
+    /// consumer to commit with. The next commit, or the commit on revoke,
+    /// covers it.
+    pub fn mark_processed<M: Message>(&self, msg: &M) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .tracker
+            .processed(msg.topic(), msg.partition(), msg.offset());
+        state.since_commit += 1;
+    }
+
+    /// Commits if the interval has passed without new messages. Call it when a
+    /// poll returns nothing.
+    pub fn tick<C, K>(&self, consumer: &K) -> Result<(), CommitError>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        let due = {
+            let state = self.state.lock().unwrap();
+            self.settings.strategy == CommitStrategy::Interval
+                && state.since_commit > 0
+                && state.last_commit.elapsed() >= self.settings.interval
+        };
+        if due {
+            self.commit(consumer, CommitMode::Sync)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Synchronously commits everything processed so far, e.g. on shutdown.
+    pub fn commit_sync<C, K>(&self, consumer: &K) -> Result<(), CommitError>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        self.commit(consumer, CommitMode::Sync)
+    }
+
+    /// A copy of the commit statistics so far.
+    pub fn stats(&self) -> CommitStats {
+        self.manager.stats()
+    }
+
+    fn commit<C, K>(&self, consumer: &K, mode: CommitMode) -> Result<(), CommitError>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        // not held while committing, the consumer context locks it too
+        let offsets = {
+            let state = self.state.lock().unwrap();
+            state
+                .tracker
+                .committable()
+                .map_err(|error| CommitError::Failed { error, attempts: 0 })?
+        };
+        if offsets.count() == 0 {
+            return Ok(());
+        }
+
+        if matches!(mode, CommitMode::Async) {
+            self.state
+                .lock()
+                .unwrap()
+                .in_flight
+                .push(commit_key(&offsets));
+        }
+        let committed = self.manager.commit_offsets(consumer, &offsets, mode);
+        let mut state = self.state.lock().unwrap();
+        match committed {
+            // for async commits, once the request has been enqueued
+            Ok(()) => {
+                state.since_commit = 0;
+                state.last_commit = Instant::now();
+                state.tracker.committed(&offsets)
+            }
+            // never enqueued, so no result will come back
+            Err(_) if matches!(mode, CommitMode::Async) => {
+                let key = commit_key(&offsets);
+                state.in_flight.retain(|pending| *pending != key);
+            }
+            Err(_) => {}
+        }
+        committed
+    }
+
+    /// The committable offsets of the revoked partitions in `tpl`, which are
+    /// forgotten afterwards.
+    pub(crate) fn revoking(&self, tpl: &TopicPartitionList) -> KafkaResult<TopicPartitionList> {
+        let mut state = self.state.lock().unwrap();
+        let committable = state.tracker.committable()?;
+        let mut revoked = TopicPartitionList::new();
+        for e in committable.elements() {
+            if tpl.find_partition(e.topic(), e.partition()).is_some() {
+                revoked.add_partition_offset(e.topic(), e.partition(), e.offset())?;
+            }
+        }
+        state.tracker.remove(tpl);
+        Ok(revoked)
+    }
+
+    /// Forgets the partitions in `tpl` without committing them, because they
+    /// already belong to another consumer.
+    pub(crate) fn lost(&self, tpl: &TopicPartitionList) {
+        self.state.lock().unwrap().tracker.remove(tpl);
+    }
+
+    /// Accepts processed messages of the assigned partitions in `tpl` again.
+    pub(crate) fn assigned(&self, tpl: &TopicPartitionList) {
+        self.state.lock().unwrap().tracker.assign(tpl);
+    }
+
+    /// The outcome of a commit, as reported to the consumer context. librdkafka
+    /// reports synchronous commits there too, including every attempt the
+    /// [`CommitManager`] retries, so only the outcome of async commits counts.
+    pub(crate) fn commit_completed(&self, result: &KafkaResult<()>, offsets: &TopicPartitionList) {
+        let mut state = self.state.lock().unwrap();
+        let key = commit_key(offsets);
+        match state.in_flight.iter().position(|pending| *pending == key) {
+            Some(i) => state.in_flight.remove(i),
+            None => return,
+        };
+        match result {
+            Ok(()) => state.tracker.committed(offsets),
+            // commit again next time
+            Err(err) => {
+                state.tracker.uncommitted(offsets);
+                self.manager.async_failed(err);
+            }
+        }
+    }
+}
+
+/// The partitions and offsets of a commit, in a canonical order, to tell its
+/// result apart from those of other commits.
+fn commit_key(tpl: &TopicPartitionList) -> Vec<(String, i32, i64)> {
+    let mut key: Vec<(String, i32, i64)> = tpl
+        .elements()
+        .iter()
+        .map(|e| {
+            (
+                e.topic().to_string(),
+                e.partition(),
+                e.offset().to_raw().unwrap_or(-1),
+            )
+        })
+        .collect();
+    key.sort();
+    key
+}
+
+/// The resolved `[commit]` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommitSettings {
+    pub strategy: CommitStrategy,
+    /// Messages per commit for [`CommitStrategy::EveryN`].
+    pub every: usize,
+    /// Time between commits for [`CommitStrategy::Interval`].
+    pub interval: Duration,
+}
+
+impl Default for CommitSettings {
+    fn default() -> Self {
+        CommitSettings {
+            strategy: CommitStrategy::default(),
+            every: 100,
+            interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The `[commit]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct CommitConfig {
+    strategy: Option<CommitStrategy>,
+    every: Option<usize>,
+    interval_ms: Option<u64>,
+}
+
+impl CommitConfig {
+    pub fn apply(self, settings: &mut CommitSettings) {
+        if let Some(strategy) = self.strategy {
+            settings.strategy = strategy;
+        }
+        if let Some(every) = self.every {
+            settings.every = every;
+        }
+        if let Some(interval_ms) = self.interval_ms {
+            settings.interval = Duration::from_millis(interval_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit_error(code: RDKafkaErrorCode) -> KafkaError {
+        KafkaError::ConsumerCommit(code)
+    }
+
+    #[test]
+    fn commit_errors_are_classified() {
+        let cases = [
+            (
+                RDKafkaErrorCode::CoordinatorLoadInProgress,
+                CommitFailure::Retriable,
+            ),
+            (
+                RDKafkaErrorCode::CoordinatorNotAvailable,
+                CommitFailure::Retriable,
+            ),
+            (RDKafkaErrorCode::NotCoordinator, CommitFailure::Retriable),
+            (RDKafkaErrorCode::RequestTimedOut, CommitFailure::Retriable),
+            (RDKafkaErrorCode::NetworkException, CommitFailure::Retriable),
+            (
+                RDKafkaErrorCode::BrokerTransportFailure,
+                CommitFailure::Retriable,
+            ),
+            (RDKafkaErrorCode::AllBrokersDown, CommitFailure::Retriable),
+            (
+                RDKafkaErrorCode::OperationTimedOut,
+                CommitFailure::Retriable,
+            ),
+            (
+                RDKafkaErrorCode::RebalanceInProgress,
+                CommitFailure::Rebalance,
+            ),
+            (
+                RDKafkaErrorCode::IllegalGeneration,
+                CommitFailure::Rebalance,
+            ),
+            (RDKafkaErrorCode::UnknownMemberId, CommitFailure::Rebalance),
+            (RDKafkaErrorCode::AssignmentLost, CommitFailure::Rebalance),
+            (RDKafkaErrorCode::FencedInstanceId, CommitFailure::Fenced),
+            (
+                RDKafkaErrorCode::GroupAuthorizationFailed,
+                CommitFailure::Other,
+            ),
+            (
+                RDKafkaErrorCode::OffsetMetadataTooLarge,
+                CommitFailure::Other,
+            ),
+            (
+                RDKafkaErrorCode::UnknownTopicOrPartition,
+                CommitFailure::Other,
+            ),
+        ];
+        for (code, expected) in &cases {
+            assert_eq!(
+                CommitFailure::of(&commit_error(*code)),
+                *expected,
+                "{:?}",
+                code
+            );
+        }
+        // errors without a librdkafka code are not retried
+        assert_eq!(
+            CommitFailure::of(&KafkaError::Canceled),
+            CommitFailure::Other
+        );
+    }
+
+    #[test]
+    fn only_fenced_consumers_must_stop() {
+        assert!(CommitError::Fenced(commit_error(RDKafkaErrorCode::FencedInstanceId)).is_fatal());
+        assert!(
+            !CommitError::Rebalance(commit_error(RDKafkaErrorCode::RebalanceInProgress)).is_fatal()
+        );
+        assert!(!CommitError::Failed {
+            error: commit_error(RDKafkaErrorCode::RequestTimedOut),
+            attempts: 4,
+        }
+        .is_fatal());
+    }
+
+    #[test]
+    fn failed_async_commits_are_counted() {
+        let manager = CommitManager::new(RetryPolicy::default());
+        manager.async_failed(&commit_error(RDKafkaErrorCode::RebalanceInProgress));
+        manager.async_failed(&commit_error(RDKafkaErrorCode::RequestTimedOut));
+        // async commits are not retried, whatever the error
+        manager.async_failed(&commit_error(RDKafkaErrorCode::FencedInstanceId));
+
+        let stats = manager.stats();
+        assert_eq!(stats.failed, 3);
+        assert_eq!(stats.rebalance_failures, 1);
+        assert_eq!(stats.retries, 0);
+        assert_eq!(stats.committed, 0);
+        assert_eq!(stats.mean_latency(), Duration::ZERO);
+    }
+
+    /// The committable positions, by partition.
+    fn positions(tracker: &OffsetTracker) -> Vec<(i32, i64)> {
+        tracker
+            .committable()
+            .unwrap()
+            .elements()
+            .iter()
+            .map(|e| (e.partition(), e.offset().to_raw().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn position_stops_at_the_first_pending_offset() {
+        let mut tracker = OffsetTracker::new();
+        for offset in 10..15 {
+            tracker.received("t", 0, offset);
+        }
+        assert!(positions(&tracker).is_empty());
+
+        // finished out of order: 10 is still being processed
+        tracker.processed("t", 0, 12);
+        tracker.processed("t", 0, 11);
+        assert_eq!(positions(&tracker), vec![(0, 10)]);
+        assert_eq!(tracker.pending(), 3);
+
+        tracker.processed("t", 0, 10);
+        assert_eq!(positions(&tracker), vec![(0, 13)]);
+
+        tracker.processed("t", 0, 14);
+        assert_eq!(positions(&tracker), vec![(0, 13)]);
+        tracker.processed("t", 0, 13);
+        assert_eq!(positions(&tracker), vec![(0, 15)]);
+        assert_eq!(tracker.pending(), 0);
+    }
+
+    #[test]
+    fn partitions_move_independently() {
+        let mut tracker = OffsetTracker::new();
+        tracker.received("t", 0, 0);
+        tracker.received("t", 0, 1);
+        tracker.received("t", 1, 7);
+        tracker.processed("t", 0, 1);
+        tracker.processed("t", 1, 7);
+        assert_eq!(positions(&tracker), vec![(0, 0), (1, 8)]);
+    }
+
+    #[test]
+    fn committed_positions_are_not_committed_again() {
+        let mut tracker = OffsetTracker::new();
+        tracker.received("t", 0, 0);
+        tracker.processed("t", 0, 0);
+        let tpl = tracker.committable().unwrap();
+        tracker.committed(&tpl);
+        assert!(positions(&tracker).is_empty());
+
+        tracker.uncommitted(&tpl);
+        assert_eq!(positions(&tracker), vec![(0, 1)]);
+        tracker.committed(&tpl);
+
+        tracker.received("t", 0, 1);
+        tracker.processed("t", 0, 1);
+        assert_eq!(positions(&tracker), vec![(0, 2)]);
+    }
+
+    #[test]
+    fn revoked_partitions_are_forgotten_until_assigned_again() {
+        let mut tracker = OffsetTracker::new();
+        tracker.received("t", 0, 5);
+        tracker.received("t", 0, 6);
+        tracker.processed("t", 0, 5);
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("t", 0);
+        tracker.remove(&revoked);
+        assert!(positions(&tracker).is_empty());
+        assert_eq!(tracker.pending(), 0);
+
+        // a message of the revoked partition finishing late is ignored
+        tracker.processed("t", 0, 6);
+        assert!(positions(&tracker).is_empty());
+
+        tracker.assign(&revoked);
+        tracker.received("t", 0, 6);
+        tracker.processed("t", 0, 6);
+        assert_eq!(positions(&tracker), vec![(0, 7)]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde::Deserialize;
+
+use crate::{
+    auth::AuthConfig, codec::Format, commit::CommitConfig, dlq::ErrorsConfig, lag::LagConfig,
+    logging::LoggingConfig, metrics::MetricsConfig, parallel::ParallelConfig,
+    partition::PartitionerConfig, replay::ReplayConfig, schema_registry::SchemaRegistryConfig,
+    transaction::TransactionsConfig,
+};
+
+/// The contents of a `kafka101.toml` / `kafka101.yaml` file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FileConfig {
+    pub profile: Option<String>,
+    pub topic: Option<String>,
+    pub group_id: Option<String>,
+    pub format: Option<Format>,
+    pub idempotent: Option<bool>,
+    #[serde(default)]
+    pub kafka: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub producer: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub consumer: BTreeMap<String, Value>,
+    pub auth: Option<AuthConfig>,
+    pub schema_registry: Option<SchemaRegistryConfig>,
+    pub errors: Option<ErrorsConfig>,
+    pub commit: Option<CommitConfig>,
+    pub parallel: Option<ParallelConfig>,
+    pub partitioner: Option<PartitionerConfig>,
+    pub replay: Option<ReplayConfig>,
+    pub lag: Option<LagConfig>,
+    pub logging: Option<LoggingConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub transactions: Option<TransactionsConfig>,
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Layer>,
+}
+
+/// A `[profiles.<name>]` section. Same shape as the top level, minus the profile selection.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Layer {
+    pub topic: Option<String>,
+    pub group_id: Option<String>,
+    pub format: Option<Format>,
+    pub idempotent: Option<bool>,
+    #[serde(default)]
+    pub kafka: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub producer: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub consumer: BTreeMap<String, Value>,
+    pub auth: Option<AuthConfig>,
+    pub schema_registry: Option<SchemaRegistryConfig>,
+    pub errors: Option<ErrorsConfig>,
+    pub commit: Option<CommitConfig>,
+    pub parallel: Option<ParallelConfig>,
+    pub partitioner: Option<PartitionerConfig>,
+    pub replay: Option<ReplayConfig>,
+    pub lag: Option<LagConfig>,
+    pub logging: Option<LoggingConfig>,
+    pub metrics: Option<MetricsConfig>,
+    pub transactions: Option<TransactionsConfig>,
+}
+
+/// librdkafka wants strings, but `acks = 1` or `enable.auto.commit: false` should not need quotes.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub(crate) enum Value {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl Value {
+    pub fn into_string(self) -> String {
+        match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::String(s) => s,
+        }
+    }
+}
+
+impl FileConfig {
+    pub fn top_level(&mut self) -> Layer {
+        Layer {
+            topic: self.topic.take(),
+            group_id: self.group_id.take(),
+            format: self.format.take(),
+            idempotent: self.idempotent.take(),
+            kafka: std::mem::take(&mut self.kafka),
+            producer: std::mem::take(&mut self.producer),
+            consumer: std::mem::take(&mut self.consumer),
+            auth: self.auth.take(),
+            schema_registry: self.schema_registry.take(),
+            errors: self.errors.take(),
+            commit: self.commit.take(),
+            parallel: self.parallel.take(),
+            partitioner: self.partitioner.take(),
+            replay: self.replay.take(),
+            lag: self.lag.take(),
+            logging: self.logging.take(),
+            metrics: self.metrics.take(),
+            transactions: self.transactions.take(),
+        }
+    }
+}
+
+pub(crate) fn parse(path: &Path) -> Result<FileConfig, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| format!("could not read config file {} - {}", path.display(), err))?;
+
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    match extension {
+        "toml" => toml::from_str(&contents).map_err(|err| err.to_string()),
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(|err| err.to_string()),
+        other => Err(format!(
+            "unsupported config file extension {:?}, expected .toml, .yaml or .yml",
+            other
+        )),
+    }
+    .map_err(|err| format!("invalid config file {} - {}", path.display(), err))
+}
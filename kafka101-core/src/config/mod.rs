@@ -0,0 +1,1146 @@
+//! Layered configuration for the example clients.
+//!
+//! Values are merged in this order, later layers winning:
+//!
+//! 1. built-in defaults (`localhost:9092`, topic `rust`, group `my_consumer_group`)
+//! 2. the top level of the config file (TOML or YAML, picked by extension)
+//! 3. the `[profiles.<name>]` section of that file for the selected profile
+//! 4. `KAFKA_*` environment variables
+//! 5. command line flags
+//!
+//! A config file looks like this:
+//!
+//! ```toml
+//! profile = "local"
+//! topic = "rust"
+//! group_id = "my_consumer_group"
+//! format = "json"
+//!
+//! [kafka]
+//! "bootstrap.servers" = "localhost:9092"
+//!
+//! [consumer]
+//! "auto.offset.reset" = "earliest"
+//!
+//! [profiles.prod.kafka]
+//! "bootstrap.servers" = "broker-1.prod:9092,broker-2.prod:9092"
+//! ```
+//!
+//! `[kafka]` properties go to both clients, `[producer]` and `[consumer]` only
+//! to one of them. In the environment, `KAFKA_BOOTSTRAP_SERVERS` sets
+//! `bootstrap.servers` for both clients, `KAFKA_PRODUCER_LINGER_MS` sets
+//! `linger.ms` for the producer only, and `KAFKA_TOPIC`, `KAFKA_GROUP_ID`,
+//! `KAFKA_PROFILE` and `KAFKA_CONFIG` mirror the flags below.
+//! `KAFKA_SCHEMA_REGISTRY_URL` sets the schema registry url, `KAFKA_DLQ_TOPIC`
+//! the dead letter topic, `KAFKA_TRANSACTIONAL_ID` the transactional id,
+//! `KAFKA_FROM` / `KAFKA_UNTIL` mirror `--from` / `--until`, and
+//! `KAFKA_LOG_FORMAT` / `KAFKA_LOG_LEVEL` set the `[logging]` format and level,
+//! and `KAFKA_METRICS_LISTEN` the address of the metrics endpoint.
+//! Other `KAFKA_*` variables that do not name a librdkafka property are
+//! ignored with a warning, as other tools use the prefix too.
+//!
+//! `format` picks the payload format of the typed clients: `json` (the
+//! default), `avro` or `protobuf`. The last two need a schema registry.
+//! `idempotent = true` (or `KAFKA_IDEMPOTENT=true`) turns on the idempotent
+//! producer properties, see [`crate::delivery`].
+//!
+//! Command line flags: `--config <file>`, `--profile <name>`, `--topic <name>`,
+//! `--group <id>`, `--format <name>`, `--bootstrap-servers <list>`,
+//! `--from <position>`, `--until <position>`, and `-X key=value`,
+//! `--producer-property key=value`, `--consumer-property key=value`.
+//!
+//! Authentication is configured in an `[auth]` section, see [`crate::auth`],
+//! the schema registry in a `[schema_registry]` section, see
+//! [`crate::schema_registry`], retries and the dead letter topic in an
+//! `[errors]` section, see [`crate::dlq`], when offsets are committed in a
+//! `[commit]` section, see [`crate::commit`], the worker pool in a `[parallel]`
+//! section, see [`crate::parallel`], the partitioner of the typed producers in
+//! a `[partitioner]` section, see [`crate::partition`], start positions and replay windows in a
+//! `[replay]` section, see [`crate::replay`], log output in a `[logging]`
+//! section, see [`crate::logging`], the metrics endpoint in a `[metrics]`
+//! section, see [`crate::metrics`], how often consumer lag is measured in a
+//! `[lag]` section, see [`crate::lag`], and the transactional pipeline in a
+//! `[transactions]` section, see [`crate::transaction`].
+//!
+//! Loading does not stop at the first mistake. Every problem (unreadable file, unknown
+//! librdkafka property or invalid value, property set for the wrong client, bad
+//! flag) is collected into a single [`ConfigError`].
+
+mod file;
+pub mod properties;
+
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    path::{Path, PathBuf},
+};
+
+use rdkafka::ClientConfig;
+
+use crate::{
+    auth::{self, Auth, AuthConfig},
+    codec::{Format, FormatSerde, JsonSerde},
+    commit::CommitSettings,
+    delivery::{self, IDEMPOTENT_PROPERTIES},
+    dlq::{ErrorSettings, DEFAULT_DLQ_SUFFIX},
+    lag::LagSettings,
+    logging::{self, LoggingSettings},
+    metrics::MetricsSettings,
+    parallel::ParallelSettings,
+    partition::PartitionerSettings,
+    replay::{EndPosition, ReplaySettings, StartPosition},
+    schema_registry::{SchemaRegistryConfig, SchemaRegistrySettings, SubjectNameStrategy},
+    transaction::{TransactionSettings, DEFAULT_OUTPUT_SUFFIX},
+};
+
+use self::{
+    file::{Layer, Value},
+    properties::Scope,
+};
+
+pub const DEFAULT_BOOTSTRAP_SERVERS: &str = "localhost:9092";
+pub const DEFAULT_TOPIC: &str = "rust";
+pub const DEFAULT_GROUP_ID: &str = "my_consumer_group";
+pub const DEFAULT_PROFILE: &str = "local";
+
+/// Files looked up in the working directory when no config file is given explicitly.
+const DEFAULT_FILES: &[&str] = &["kafka101.toml", "kafka101.yaml", "kafka101.yml"];
+
+const ENV_PREFIX: &str = "KAFKA_";
+
+/// `KAFKA_*` variables used by the Kafka distribution scripts, not by us.
+const FOREIGN_ENV_VARS: &[&str] = &[
+    "KAFKA_HOME",
+    "KAFKA_OPTS",
+    "KAFKA_HEAP_OPTS",
+    "KAFKA_JVM_PERFORMANCE_OPTS",
+    "KAFKA_LOG4J_OPTS",
+    "KAFKA_JMX_OPTS",
+    "KAFKA_GC_LOG_OPTS",
+];
+
+/// The group of librdkafka properties a value belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Section {
+    /// Shared by producer and consumer.
+    Kafka,
+    Producer,
+    Consumer,
+}
+
+impl fmt::Display for Section {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Kafka => write!(f, "kafka"),
+            Section::Producer => write!(f, "producer"),
+            Section::Consumer => write!(f, "consumer"),
+        }
+    }
+}
+
+/// Where a value came from, so problems can point at the right place.
+#[derive(Debug, Clone)]
+enum Origin {
+    Default,
+    File(PathBuf),
+    Profile(String, PathBuf),
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "built-in default"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Profile(name, path) => write!(f, "profile `{}` in {}", name, path.display()),
+            Origin::Env(var) => write!(f, "environment variable {}", var),
+            Origin::Flag(flag) => write!(f, "command line flag {}", flag),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Entry {
+    value: String,
+    origin: Origin,
+    sensitive: bool,
+}
+
+impl fmt::Debug for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = if self.sensitive {
+            "<redacted>"
+        } else {
+            self.value.as_str()
+        };
+        f.debug_struct("Entry")
+            .field("value", &value)
+            .field("origin", &self.origin)
+            .finish()
+    }
+}
+
+/// Every problem found while loading the configuration.
+#[derive(Debug)]
+pub struct ConfigError {
+    problems: Vec<String>,
+}
+
+impl ConfigError {
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// The merged configuration for one run of an example.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub profile: String,
+    pub topic: String,
+    pub group_id: String,
+    pub format: Format,
+    /// Whether producers are idempotent.
+    pub idempotent: bool,
+    pub auth: Auth,
+    pub schema_registry: Option<SchemaRegistrySettings>,
+    pub errors: ErrorSettings,
+    pub commit: CommitSettings,
+    pub parallel: ParallelSettings,
+    pub partitioner: PartitionerSettings,
+    pub replay: ReplaySettings,
+    pub lag: LagSettings,
+    pub logging: LoggingSettings,
+    pub metrics: MetricsSettings,
+    pub transactions: TransactionSettings,
+    kafka: BTreeMap<String, Entry>,
+    producer: BTreeMap<String, Entry>,
+    consumer: BTreeMap<String, Entry>,
+}
+
+impl Settings {
+    /// Loads the configuration from the process environment and command line.
+    pub fn load() -> Result<Settings, ConfigError> {
+        Loader::new()
+            .env(env::vars())
+            .args(env::args().skip(1))
+            .load()
+    }
+
+    /// Properties for a producer: the `[kafka]` section overlaid with `[producer]`, plus
+    /// the idempotence properties when `idempotent` is on, plus auth. Statistics are
+    /// turned on when metrics are served.
+    pub fn producer_config(&self) -> ClientConfig {
+        let mut config = self.base_config();
+        for (name, entry) in self.kafka.iter().chain(self.producer.iter()) {
+            config.set(name, &entry.value);
+        }
+        if self.idempotent {
+            for (name, value) in IDEMPOTENT_PROPERTIES {
+                config.set(*name, *value);
+            }
+        }
+        self.auth.apply(&mut config);
+        config
+    }
+
+    /// Properties for the producer of a transactional pipeline: the producer
+    /// properties plus the `transactional.id`. Only this producer gets the id, as
+    /// two producers sharing one would fence each other.
+    pub fn transactional_producer_config(&self) -> ClientConfig {
+        let mut config = self.producer_config();
+        config.set("transactional.id", self.transactional_id());
+        config
+    }
+
+    /// Properties for a consumer: the `[kafka]` section overlaid with `[consumer]`, plus
+    /// `group.id` and auth. Statistics are turned on when metrics are served.
+    pub fn consumer_config(&self) -> ClientConfig {
+        let mut config = self.base_config();
+        for (name, entry) in self.kafka.iter().chain(self.consumer.iter()) {
+            config.set(name, &entry.value);
+        }
+        config.set("group.id", &self.group_id);
+        self.auth.apply(&mut config);
+        config
+    }
+
+    /// The serde for the configured payload format. JSON is pretty-printed, as
+    /// the original examples did.
+    pub fn serde(&self) -> Result<FormatSerde, ConfigError> {
+        match (self.format, &self.schema_registry) {
+            (Format::Json, _) => Ok(FormatSerde::Json(JsonSerde::pretty())),
+            (Format::Avro, Some(registry)) => Ok(FormatSerde::Avro(registry.avro_serde())),
+            (Format::Protobuf, Some(registry)) => {
+                Ok(FormatSerde::Protobuf(registry.protobuf_serde()))
+            }
+            (format, None) => Err(ConfigError {
+                problems: vec![missing_registry(format)],
+            }),
+        }
+    }
+
+    /// The topic records that keep failing are sent to: the configured one, or
+    /// the topic with a `-dlq` suffix.
+    pub fn dlq_topic(&self) -> String {
+        self.errors
+            .dlq_topic
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", self.topic, DEFAULT_DLQ_SUFFIX))
+    }
+
+    /// The topic the transactional pipeline writes to: the configured one, or
+    /// the topic with an `-out` suffix.
+    pub fn output_topic(&self) -> String {
+        self.transactions
+            .output_topic
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", self.topic, DEFAULT_OUTPUT_SUFFIX))
+    }
+
+    /// The `transactional.id` of the pipeline producer: the configured one, or
+    /// `<group_id>-<topic>`.
+    pub fn transactional_id(&self) -> String {
+        self.transactions
+            .transactional_id
+            .clone()
+            .unwrap_or_else(|| format!("{}-{}", self.group_id, self.topic))
+    }
+
+    /// The effective value of a property for the given client.
+    pub fn get(&self, section: Section, name: &str) -> Option<&str> {
+        self.section(section)
+            .get(name)
+            .or_else(|| self.kafka.get(name))
+            .map(|entry| entry.value.as_str())
+    }
+
+    fn defaults(profile: String) -> Settings {
+        let mut settings = Settings {
+            profile,
+            topic: DEFAULT_TOPIC.to_string(),
+            group_id: DEFAULT_GROUP_ID.to_string(),
+            format: Format::default(),
+            idempotent: false,
+            auth: Auth::None,
+            schema_registry: None,
+            errors: ErrorSettings::default(),
+            commit: CommitSettings::default(),
+            parallel: ParallelSettings::default(),
+            partitioner: PartitionerSettings::default(),
+            replay: ReplaySettings::default(),
+            lag: LagSettings::default(),
+            logging: LoggingSettings::default(),
+            metrics: MetricsSettings::default(),
+            transactions: TransactionSettings::default(),
+            kafka: BTreeMap::new(),
+            producer: BTreeMap::new(),
+            consumer: BTreeMap::new(),
+        };
+        settings.set(
+            Section::Kafka,
+            "bootstrap.servers",
+            DEFAULT_BOOTSTRAP_SERVERS.to_string(),
+            Origin::Default,
+        );
+        settings
+    }
+
+    /// `statistics.interval.ms` when metrics are served, unless a section sets it.
+    fn base_config(&self) -> ClientConfig {
+        let mut config = ClientConfig::new();
+        if self.metrics.listen.is_some() {
+            let interval = self.metrics.statistics_interval.as_millis();
+            config.set("statistics.interval.ms", interval.to_string());
+        }
+        config
+    }
+
+    fn section(&self, section: Section) -> &BTreeMap<String, Entry> {
+        match section {
+            Section::Kafka => &self.kafka,
+            Section::Producer => &self.producer,
+            Section::Consumer => &self.consumer,
+        }
+    }
+
+    fn set(&mut self, section: Section, name: &str, value: String, origin: Origin) {
+        // group.id has a dedicated setting so that it cannot disagree with a property
+        if name == "group.id" && section != Section::Producer {
+            self.group_id = value;
+            return;
+        }
+
+        let entries = match section {
+            Section::Kafka => &mut self.kafka,
+            Section::Producer => &mut self.producer,
+            Section::Consumer => &mut self.consumer,
+        };
+        let sensitive = properties::is_sensitive(name);
+        entries.insert(
+            name.to_string(),
+            Entry {
+                value,
+                origin,
+                sensitive,
+            },
+        );
+    }
+
+    fn apply_layer(&mut self, layer: Layer, origin: Origin) {
+        if let Some(topic) = layer.topic {
+            self.topic = topic;
+        }
+        if let Some(group_id) = layer.group_id {
+            self.group_id = group_id;
+        }
+        if let Some(format) = layer.format {
+            self.format = format;
+        }
+        if let Some(idempotent) = layer.idempotent {
+            self.idempotent = idempotent;
+        }
+        if let Some(errors) = layer.errors {
+            errors.apply(&mut self.errors);
+        }
+        if let Some(commit) = layer.commit {
+            commit.apply(&mut self.commit);
+        }
+        if let Some(parallel) = layer.parallel {
+            parallel.apply(&mut self.parallel);
+        }
+        if let Some(partitioner) = layer.partitioner {
+            partitioner.apply(&mut self.partitioner);
+        }
+        if let Some(replay) = layer.replay {
+            replay.apply(&mut self.replay);
+        }
+        if let Some(lag) = layer.lag {
+            lag.apply(&mut self.lag);
+        }
+        if let Some(logging) = layer.logging {
+            logging.apply(&mut self.logging);
+        }
+        if let Some(metrics) = layer.metrics {
+            metrics.apply(&mut self.metrics);
+        }
+        if let Some(transactions) = layer.transactions {
+            transactions.apply(&mut self.transactions);
+        }
+
+        let sections = vec![
+            (Section::Kafka, layer.kafka),
+            (Section::Producer, layer.producer),
+            (Section::Consumer, layer.consumer),
+        ];
+        for (section, values) in sections {
+            for (name, value) in values {
+                self.set(section, &name, Value::into_string(value), origin.clone());
+            }
+        }
+    }
+
+    fn validate(&self, problems: &mut Vec<String>) {
+        let sections = [
+            (Section::Kafka, &self.kafka),
+            (Section::Producer, &self.producer),
+            (Section::Consumer, &self.consumer),
+        ];
+        for (section, entries) in sections.iter() {
+            for (name, entry) in entries.iter() {
+                match (properties::scope_of(name), section) {
+                    (Scope::Producer, Section::Consumer) => problems.push(format!(
+                        "`{}` is a producer property but is set in [consumer] (from {})",
+                        name, entry.origin
+                    )),
+                    (Scope::Consumer, Section::Producer) => problems.push(format!(
+                        "`{}` is a consumer property but is set in [producer] (from {})",
+                        name, entry.origin
+                    )),
+                    _ => {}
+                }
+
+                if !entry.value.trim().is_empty() {
+                    if let Err(description) = properties::check(name, &entry.value) {
+                        // librdkafka's description may quote the value
+                        let description = if entry.sensitive {
+                            "invalid value".to_string()
+                        } else {
+                            description
+                        };
+                        problems.push(format!(
+                            "`{}` in [{}]: {} (from {})",
+                            name, section, description, entry.origin
+                        ));
+                    }
+                }
+
+                if !matches!(self.auth, Auth::None)
+                    && auth::MANAGED_PROPERTIES.contains(&name.as_str())
+                {
+                    problems.push(format!(
+                        "`{}` in [{}] conflicts with the [auth] section (from {})",
+                        name, section, entry.origin
+                    ));
+                }
+
+                if self.idempotent && *section != Section::Consumer {
+                    if let Some(conflict) = delivery::idempotence_conflict(name, &entry.value) {
+                        problems.push(format!("{} (from {})", conflict, entry.origin));
+                    }
+                }
+
+                if entry.value.trim().is_empty() {
+                    problems.push(format!(
+                        "`{}` in [{}] is empty (from {})",
+                        name, section, entry.origin
+                    ));
+                }
+            }
+        }
+
+        if self.topic.trim().is_empty() {
+            problems.push("topic is empty".to_string());
+        }
+        if self.group_id.trim().is_empty() {
+            problems.push("group id is empty".to_string());
+        }
+        if self.dlq_topic().trim().is_empty() {
+            problems.push("[errors] dlq_topic is empty".to_string());
+        } else if self.dlq_topic() == self.topic {
+            problems.push(format!(
+                "[errors] dlq_topic `{}` is the topic being consumed",
+                self.topic
+            ));
+        }
+        if self.commit.every == 0 {
+            problems.push("[commit] every must be at least 1".to_string());
+        }
+        if self.commit.interval.is_zero() {
+            problems.push("[commit] interval_ms must be at least 1".to_string());
+        }
+        if let Err(problem) = logging::check_level(&self.logging.level) {
+            problems.push(problem);
+        }
+        if self.lag.interval.is_zero() {
+            problems.push("[lag] interval_ms must be at least 1".to_string());
+        }
+        if self.metrics.statistics_interval.is_zero() {
+            problems.push("[metrics] statistics_interval_ms must be at least 1".to_string());
+        }
+        if self.parallel.workers == 0 {
+            problems.push("[parallel] workers must be at least 1".to_string());
+        }
+        if self.parallel.max_in_flight == 0 {
+            problems.push("[parallel] max_in_flight must be at least 1".to_string());
+        }
+        if self.partitioner.sticky_linger.is_zero() {
+            problems.push("[partitioner] sticky_linger_ms must be at least 1".to_string());
+        }
+        if self.output_topic().trim().is_empty() {
+            problems.push("[transactions] output_topic is empty".to_string());
+        } else if self.output_topic() == self.topic {
+            problems.push(format!(
+                "[transactions] output_topic `{}` is the topic being consumed",
+                self.topic
+            ));
+        }
+        if self.transactional_id().trim().is_empty() {
+            problems.push("[transactions] transactional_id is empty".to_string());
+        }
+        if self.errors.retry.max_backoff < self.errors.retry.initial_backoff {
+            problems.push("[errors] max_backoff_ms is smaller than backoff_ms".to_string());
+        }
+        if self.format != Format::Json && self.schema_registry.is_none() {
+            problems.push(missing_registry(self.format));
+        }
+        if let Some(registry) = &self.schema_registry {
+            if !registry.url.starts_with("http://") && !registry.url.starts_with("https://") {
+                problems.push(format!(
+                    "[schema_registry] url `{}` is not an http(s) URL",
+                    registry.url
+                ));
+            }
+        }
+    }
+}
+
+/// Builds [`Settings`] from explicit sources. [`Settings::load`] is the usual entry point;
+/// the loader is there for callers that want to choose the environment and arguments.
+#[derive(Debug, Default)]
+pub struct Loader {
+    file: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    args: Vec<String>,
+}
+
+impl Loader {
+    pub fn new() -> Loader {
+        Loader::default()
+    }
+
+    /// Config file to use when neither `--config` nor `KAFKA_CONFIG` names one.
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Loader {
+        self.file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn env<I: IntoIterator<Item = (String, String)>>(mut self, vars: I) -> Loader {
+        self.env = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        self
+    }
+
+    /// Command line arguments, without the program name.
+    pub fn args<I: IntoIterator<Item = String>>(mut self, args: I) -> Loader {
+        self.args = args.into_iter().collect();
+        self
+    }
+
+    pub fn load(self) -> Result<Settings, ConfigError> {
+        let mut problems = Vec::new();
+        let flags = Flags::parse(&self.args, &mut problems);
+        let env: BTreeMap<String, String> = self.env.into_iter().collect();
+
+        let path = flags
+            .config
+            .clone()
+            .or_else(|| env.get("KAFKA_CONFIG").map(PathBuf::from))
+            .or(self.file)
+            .or_else(|| {
+                DEFAULT_FILES
+                    .iter()
+                    .map(PathBuf::from)
+                    .find(|path| path.is_file())
+            });
+        let mut file = match &path {
+            Some(path) => match file::parse(path) {
+                Ok(file) => Some(file),
+                Err(problem) => {
+                    problems.push(problem);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let profile = flags
+            .profile
+            .clone()
+            .or_else(|| env.get("KAFKA_PROFILE").cloned())
+            .or_else(|| file.as_ref().and_then(|file| file.profile.clone()))
+            .unwrap_or_else(|| DEFAULT_PROFILE.to_string());
+
+        let mut settings = Settings::defaults(profile);
+        let mut auth_config: Option<AuthConfig> = None;
+        let mut registry_config: Option<SchemaRegistryConfig> = None;
+
+        match (file.as_mut(), &path) {
+            (Some(file), Some(path)) => {
+                let mut top_level = file.top_level();
+                auth_config = top_level.auth.take();
+                registry_config = top_level.schema_registry.take();
+                settings.apply_layer(top_level, Origin::File(path.clone()));
+                match file.profiles.remove(&settings.profile) {
+                    Some(mut layer) => {
+                        // a profile's [auth] replaces the top level one as a whole
+                        if let Some(auth) = layer.auth.take() {
+                            auth_config = Some(auth);
+                        }
+                        if let Some(registry) = layer.schema_registry.take() {
+                            registry_config = Some(registry);
+                        }
+                        let origin = Origin::Profile(settings.profile.clone(), path.clone());
+                        settings.apply_layer(layer, origin);
+                    }
+                    None if settings.profile != DEFAULT_PROFILE => problems.push(format!(
+                        "profile `{}` is not defined in {}",
+                        settings.profile,
+                        path.display()
+                    )),
+                    None => {}
+                }
+            }
+            (None, None) if settings.profile != DEFAULT_PROFILE => problems.push(format!(
+                "profile `{}` was selected but there is no config file",
+                settings.profile
+            )),
+            _ => {}
+        }
+
+        if let Some(auth_config) = auth_config {
+            settings.auth = auth_config.resolve(&mut problems);
+        }
+        if let Some(registry_config) = registry_config {
+            settings.schema_registry = Some(registry_config.resolve(&mut problems));
+        }
+
+        apply_env(&mut settings, &env, &mut problems);
+        flags.apply(&mut settings);
+
+        settings.validate(&mut problems);
+
+        if problems.is_empty() {
+            Ok(settings)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+}
+
+fn missing_registry(format: Format) -> String {
+    format!(
+        "format `{}` needs a [schema_registry] section or KAFKA_SCHEMA_REGISTRY_URL",
+        format
+    )
+}
+
+fn apply_env(settings: &mut Settings, env: &BTreeMap<String, String>, problems: &mut Vec<String>) {
+    for (var, value) in env {
+        if FOREIGN_ENV_VARS.contains(&var.as_str()) {
+            continue;
+        }
+
+        let name = &var[ENV_PREFIX.len()..];
+        let origin = Origin::Env(var.clone());
+        match name {
+            "CONFIG" | "PROFILE" => {}
+            "TOPIC" => settings.topic = value.clone(),
+            "GROUP_ID" => settings.group_id = value.clone(),
+            "DLQ_TOPIC" => settings.errors.dlq_topic = Some(value.clone()),
+            "TRANSACTIONAL_ID" => settings.transactions.transactional_id = Some(value.clone()),
+            "IDEMPOTENT" => match value.parse() {
+                Ok(idempotent) => settings.idempotent = idempotent,
+                Err(_) => problems.push(format!(
+                    "expected true or false, got `{}` (from {})",
+                    value, origin
+                )),
+            },
+            "FORMAT" => match value.parse() {
+                Ok(format) => settings.format = format,
+                Err(problem) => problems.push(format!("{} (from {})", problem, origin)),
+            },
+            "FROM" => match value.parse() {
+                Ok(from) => settings.replay.from = from,
+                Err(problem) => problems.push(format!("{} (from {})", problem, origin)),
+            },
+            "UNTIL" => match value.parse() {
+                Ok(until) => settings.replay.until = Some(until),
+                Err(problem) => problems.push(format!("{} (from {})", problem, origin)),
+            },
+            "LOG_FORMAT" => match value.parse() {
+                Ok(format) => settings.logging.format = format,
+                Err(problem) => problems.push(format!("{} (from {})", problem, origin)),
+            },
+            "LOG_LEVEL" => settings.logging.level = value.clone(),
+            "METRICS_LISTEN" => match value.parse() {
+                Ok(listen) => settings.metrics.listen = Some(listen),
+                Err(_) => problems.push(format!(
+                    "expected an address like 127.0.0.1:9091, got `{}` (from {})",
+                    value, origin
+                )),
+            },
+            "SCHEMA_REGISTRY_URL" => match &mut settings.schema_registry {
+                Some(registry) => registry.url = value.clone(),
+                None => {
+                    settings.schema_registry = Some(SchemaRegistrySettings {
+                        url: value.clone(),
+                        username: None,
+                        password: None,
+                        subject_name_strategy: SubjectNameStrategy::default(),
+                        auto_register: true,
+                        check_compatibility: true,
+                    })
+                }
+            },
+            _ => {
+                let (section, property) = if let Some(rest) = name.strip_prefix("PRODUCER_") {
+                    (Section::Producer, rest)
+                } else if let Some(rest) = name.strip_prefix("CONSUMER_") {
+                    (Section::Consumer, rest)
+                } else {
+                    (Section::Kafka, name)
+                };
+                let property = property.to_ascii_lowercase().replace('_', ".");
+                // other tools use the prefix too, e.g. KAFKA_VERSION in Kafka images
+                if !properties::is_known(&property) {
+                    settings.logging.warnings.push(format!(
+                        "ignoring {} - `{}` is not a librdkafka property",
+                        var, property
+                    ));
+                    continue;
+                }
+                settings.set(section, &property, value.clone(), origin);
+            }
+        }
+    }
+}
+
+/// Command line flags, parsed before anything else so `--config` and `--profile` can take effect.
+#[derive(Debug, Default)]
+struct Flags {
+    config: Option<PathBuf>,
+    profile: Option<String>,
+    topic: Option<String>,
+    group_id: Option<String>,
+    format: Option<Format>,
+    from: Option<StartPosition>,
+    until: Option<EndPosition>,
+    properties: Vec<(Section, String, String, String)>,
+}
+
+impl Flags {
+    fn parse(args: &[String], problems: &mut Vec<String>) -> Flags {
+        let mut flags = Flags::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if arg.starts_with("--") => (flag, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+
+            let known = matches!(
+                flag,
+                "--config"
+                    | "--profile"
+                    | "--topic"
+                    | "--group"
+                    | "--format"
+                    | "--from"
+                    | "--until"
+                    | "--bootstrap-servers"
+                    | "-X"
+                    | "--property"
+                    | "--producer-property"
+                    | "--consumer-property"
+            );
+            if !known {
+                problems.push(format!("unknown command line argument `{}`", arg));
+                continue;
+            }
+
+            let value = match inline_value.or_else(|| args.next().cloned()) {
+                Some(value) => value,
+                None => {
+                    problems.push(format!("missing value for command line flag {}", flag));
+                    continue;
+                }
+            };
+
+            let section =
+                match flag {
+                    "--config" => {
+                        flags.config = Some(PathBuf::from(value));
+                        continue;
+                    }
+                    "--profile" => {
+                        flags.profile = Some(value);
+                        continue;
+                    }
+                    "--topic" => {
+                        flags.topic = Some(value);
+                        continue;
+                    }
+                    "--group" => {
+                        flags.group_id = Some(value);
+                        continue;
+                    }
+                    "--format" => {
+                        match value.parse() {
+                            Ok(format) => flags.format = Some(format),
+                            Err(problem) => problems
+                                .push(format!("{} (from command line flag {})", problem, flag)),
+                        }
+                        continue;
+                    }
+                    "--from" => {
+                        match value.parse() {
+                            Ok(from) => flags.from = Some(from),
+                            Err(problem) => problems
+                                .push(format!("{} (from command line flag {})", problem, flag)),
+                        }
+                        continue;
+                    }
+                    "--until" => {
+                        match value.parse() {
+                            Ok(until) => flags.until = Some(until),
+                            Err(problem) => problems
+                                .push(format!("{} (from command line flag {})", problem, flag)),
+                        }
+                        continue;
+                    }
+                    "--bootstrap-servers" => {
+                        flags.properties.push((
+                            Section::Kafka,
+                            "bootstrap.servers".to_string(),
+                            value,
+                            flag.to_string(),
+                        ));
+                        continue;
+                    }
+                    "--producer-property" => Section::Producer,
+                    "--consumer-property" => Section::Consumer,
+                    _ => Section::Kafka,
+                };
+
+            match value.split_once('=') {
+                Some((name, property_value)) => flags.properties.push((
+                    section,
+                    name.to_string(),
+                    property_value.to_string(),
+                    flag.to_string(),
+                )),
+                None => problems.push(format!(
+                    "expected key=value after {}, got `{}`",
+                    flag, value
+                )),
+            }
+        }
+
+        flags
+    }
+
+    fn apply(self, settings: &mut Settings) {
+        if let Some(topic) = self.topic {
+            settings.topic = topic;
+        }
+        if let Some(group_id) = self.group_id {
+            settings.group_id = group_id;
+        }
+        if let Some(format) = self.format {
+            settings.format = format;
+        }
+        if let Some(from) = self.from {
+            settings.replay.from = from;
+        }
+        if let Some(until) = self.until {
+            settings.replay.until = Some(until);
+        }
+        for (section, name, value, flag) in self.properties {
+            settings.set(section, &name, value, Origin::Flag(flag));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    const FILE: &str = r#"
+profile = "dev"
+topic = "from-file"
+
+[kafka]
+"bootstrap.servers" = "file:9092"
+"client.id" = "file"
+
+[producer]
+"linger.ms" = "5"
+
+[profiles.dev]
+topic = "from-dev"
+
+[profiles.dev.kafka]
+"bootstrap.servers" = "dev:9092"
+
+[profiles.prod]
+topic = "from-prod"
+"#;
+
+    /// Writes `contents` to a file of its own in the temp directory.
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("kafka101-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn load_problems(loader: Loader) -> Vec<String> {
+        loader.load().unwrap_err().problems().to_vec()
+    }
+
+    #[test]
+    fn defaults_apply_without_any_source() {
+        let settings = Loader::new().load().unwrap();
+        assert_eq!(settings.profile, DEFAULT_PROFILE);
+        assert_eq!(settings.topic, DEFAULT_TOPIC);
+        assert_eq!(settings.group_id, DEFAULT_GROUP_ID);
+        assert_eq!(
+            settings.get(Section::Producer, "bootstrap.servers"),
+            Some(DEFAULT_BOOTSTRAP_SERVERS)
+        );
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let path = config_file("precedence", FILE);
+        let settings = Loader::new()
+            .file(&path)
+            .env(vars(&[
+                ("KAFKA_BOOTSTRAP_SERVERS", "env:9092"),
+                ("KAFKA_GROUP_ID", "env-group"),
+                ("KAFKA_PRODUCER_LINGER_MS", "10"),
+                ("KAFKA_HOME", "/opt/kafka"),
+                ("KAFKA_VERSION", "3.7.0"),
+                ("PATH", "/usr/bin"),
+            ]))
+            .args(args(&["--group", "flag-group", "-X", "linger.ms=20"]))
+            .load()
+            .unwrap();
+
+        // file, then profile
+        assert_eq!(settings.topic, "from-dev");
+        assert_eq!(settings.get(Section::Kafka, "client.id"), Some("file"));
+        // profile, then environment
+        assert_eq!(
+            settings.get(Section::Consumer, "bootstrap.servers"),
+            Some("env:9092")
+        );
+        // environment, then flags
+        assert_eq!(settings.group_id, "flag-group");
+        // a section of its own is more specific than [kafka], whatever its layer
+        assert_eq!(settings.get(Section::Producer, "linger.ms"), Some("10"));
+        assert_eq!(settings.get(Section::Consumer, "linger.ms"), Some("20"));
+        assert!(settings.get(Section::Kafka, "home").is_none());
+        assert!(settings.get(Section::Kafka, "version").is_none());
+        assert_eq!(
+            settings.logging.warnings,
+            vec!["ignoring KAFKA_VERSION - `version` is not a librdkafka property"]
+        );
+
+        assert_eq!(
+            settings.kafka["bootstrap.servers"].origin.to_string(),
+            "environment variable KAFKA_BOOTSTRAP_SERVERS"
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn profile_is_picked_by_flag_then_environment_then_file() {
+        let path = config_file("profiles", FILE);
+        let load = |env: &[(&str, &str)], flags: &[&str]| {
+            Loader::new()
+                .file(&path)
+                .env(vars(env))
+                .args(args(flags))
+                .load()
+                .unwrap()
+        };
+
+        let from_file = load(&[], &[]);
+        assert_eq!(from_file.profile, "dev");
+        assert_eq!(from_file.topic, "from-dev");
+
+        let from_env = load(&[("KAFKA_PROFILE", "prod")], &[]);
+        assert_eq!(from_env.profile, "prod");
+        assert_eq!(from_env.topic, "from-prod");
+        // prod has no [kafka] of its own
+        assert_eq!(
+            from_env.get(Section::Kafka, "bootstrap.servers"),
+            Some("file:9092")
+        );
+
+        let from_flag = load(&[("KAFKA_PROFILE", "prod")], &["--profile", "dev"]);
+        assert_eq!(from_flag.profile, "dev");
+
+        // the default profile does not have to be defined
+        let local = load(&[], &["--profile=local"]);
+        assert_eq!(local.topic, "from-file");
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn undefined_profiles_are_reported() {
+        let path = config_file("undefined-profile", FILE);
+        let problems = load_problems(Loader::new().file(&path).args(args(&["--profile", "qa"])));
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("profile `qa` is not defined in"));
+        fs::remove_file(path).unwrap();
+
+        let problems = load_problems(Loader::new().env(vars(&[("KAFKA_PROFILE", "qa")])));
+        assert_eq!(
+            problems,
+            vec!["profile `qa` was selected but there is no config file"]
+        );
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let path = config_file(
+            "problems",
+            r#"
+[kafka]
+"no.such.property" = "1"
+
+[consumer]
+"linger.ms" = "5"
+"sasl.password" = ""
+"#,
+        );
+        let problems = load_problems(
+            Loader::new()
+                .file(&path)
+                .env(vars(&[("KAFKA_IDEMPOTENT", "maybe")]))
+                .args(args(&[
+                    "--format",
+                    "xml",
+                    "--verbose",
+                    "--producer-property",
+                    "acks",
+                    "--producer-property",
+                    "session.timeout.ms=3000",
+                    "-X",
+                    "message.max.bytes=lots",
+                ])),
+        );
+        fs::remove_file(&path).unwrap();
+
+        let expected = [
+            "unknown format `xml`",
+            "unknown command line argument `--verbose`",
+            "expected key=value after --producer-property, got `acks`",
+            "expected true or false, got `maybe`",
+            "`no.such.property` in [kafka]: No such configuration property",
+            "`linger.ms` is a producer property but is set in [consumer]",
+            "`session.timeout.ms` is a consumer property but is set in [producer]",
+            "`message.max.bytes` in [kafka]: Invalid value",
+            "`sasl.password` in [consumer] is empty",
+        ];
+        for expected in &expected {
+            assert!(
+                problems.iter().any(|problem| problem.contains(expected)),
+                "no `{}` in {:#?}",
+                expected,
+                problems
+            );
+        }
+        assert_eq!(problems.len(), expected.len(), "{:#?}", problems);
+    }
+}
@@ -0,0 +1,123 @@
+//! The client type of the librdkafka properties that only apply to producers
+//! or to consumers, taken from librdkafka's `CONFIGURATION.md`. Whether a
+//! property exists at all, and whether its value is valid, is left to
+//! librdkafka itself (see `check`), so that properties added by later
+//! librdkafka versions are accepted without updating this list.
+
+use rdkafka::{error::KafkaError, types::RDKafkaConfRes, ClientConfig};
+
+/// Which client type a property applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Both,
+    Producer,
+    Consumer,
+}
+
+use Scope::*;
+
+const SCOPES: &[(&str, Scope)] = &[
+    // consumer
+    ("group.id", Consumer),
+    ("group.instance.id", Consumer),
+    ("partition.assignment.strategy", Consumer),
+    ("session.timeout.ms", Consumer),
+    ("heartbeat.interval.ms", Consumer),
+    ("group.protocol.type", Consumer),
+    ("coordinator.query.interval.ms", Consumer),
+    ("max.poll.interval.ms", Consumer),
+    ("enable.auto.commit", Consumer),
+    ("auto.commit.enable", Consumer),
+    ("auto.commit.interval.ms", Consumer),
+    ("enable.auto.offset.store", Consumer),
+    ("queued.min.messages", Consumer),
+    ("queued.max.messages.kbytes", Consumer),
+    ("fetch.wait.max.ms", Consumer),
+    ("fetch.message.max.bytes", Consumer),
+    ("max.partition.fetch.bytes", Consumer),
+    ("fetch.max.bytes", Consumer),
+    ("fetch.min.bytes", Consumer),
+    ("fetch.error.backoff.ms", Consumer),
+    ("offset.store.method", Consumer),
+    ("offset.store.path", Consumer),
+    ("offset.store.sync.interval.ms", Consumer),
+    ("isolation.level", Consumer),
+    ("enable.partition.eof", Consumer),
+    ("check.crcs", Consumer),
+    ("allow.auto.create.topics", Consumer),
+    ("auto.offset.reset", Consumer),
+    ("consume.callback.max.messages", Consumer),
+    // producer
+    ("transactional.id", Producer),
+    ("transaction.timeout.ms", Producer),
+    ("enable.idempotence", Producer),
+    ("enable.gapless.guarantee", Producer),
+    ("queue.buffering.max.messages", Producer),
+    ("queue.buffering.max.kbytes", Producer),
+    ("queue.buffering.max.ms", Producer),
+    ("linger.ms", Producer),
+    ("message.send.max.retries", Producer),
+    ("retries", Producer),
+    ("retry.backoff.ms", Producer),
+    ("queue.buffering.backpressure.threshold", Producer),
+    ("compression.codec", Producer),
+    ("compression.type", Producer),
+    ("compression.level", Producer),
+    ("batch.num.messages", Producer),
+    ("batch.size", Producer),
+    ("delivery.report.only.error", Producer),
+    ("sticky.partitioning.linger.ms", Producer),
+    ("request.required.acks", Producer),
+    ("acks", Producer),
+    ("request.timeout.ms", Producer),
+    ("message.timeout.ms", Producer),
+    ("delivery.timeout.ms", Producer),
+    ("queuing.strategy", Producer),
+    ("produce.offset.report", Producer),
+    ("partitioner", Producer),
+];
+
+/// Looks up the scope of a librdkafka property. Properties that are not known
+/// to be producer or consumer only apply to both.
+pub fn scope_of(name: &str) -> Scope {
+    SCOPES
+        .iter()
+        .find(|(property, _)| *property == name)
+        .map_or(Both, |(_, scope)| *scope)
+}
+
+/// Sets the property on an empty librdkafka configuration, which rejects
+/// unknown properties and invalid values. The error is librdkafka's own
+/// description, such as `No such configuration property: "foo"`.
+pub fn check(name: &str, value: &str) -> Result<(), String> {
+    match ClientConfig::new().set(name, value).create_native_config() {
+        Ok(_) => Ok(()),
+        Err(KafkaError::ClientConfig(_, description, _, _)) => Err(description),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Whether librdkafka has a property called `name`, whatever values it accepts.
+pub fn is_known(name: &str) -> bool {
+    !matches!(
+        ClientConfig::new().set(name, "").create_native_config(),
+        Err(KafkaError::ClientConfig(
+            RDKafkaConfRes::RD_KAFKA_CONF_UNKNOWN,
+            ..
+        ))
+    )
+}
+
+/// Whether a property holds a password, a secret or key material that must not
+/// be printed.
+pub fn is_sensitive(name: &str) -> bool {
+    matches!(
+        name,
+        "sasl.password"
+            | "sasl.oauthbearer.config"
+            | "sasl.oauthbearer.client.secret"
+            | "ssl.key.pem"
+    ) || [".password", ".secret", ".key"]
+        .iter()
+        .any(|suffix| name.ends_with(suffix))
+}
@@ -0,0 +1,831 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    ffi::CStr,
+    fmt,
+    marker::PhantomData,
+    ptr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures::{Stream, StreamExt};
+use rdkafka::{
+    bindings as rdsys,
+    client::{NativeClient, OAuthToken},
+    consumer::{
+        BaseConsumer, CommitMode, Consumer, ConsumerContext, Rebalance, RebalanceProtocol,
+        StreamConsumer,
+    },
+    error::{KafkaError, KafkaResult},
+    message::{BorrowedMessage, OwnedMessage},
+    statistics::Statistics,
+    types::{RDKafkaErrorCode, RDKafkaRespErr},
+    ClientContext, Message, Offset, TopicPartitionList,
+};
+use tracing::{debug, info, info_span, warn, Span};
+
+use crate::{
+    auth::{self, Auth, TokenProvider},
+    codec::{CodecError, Deserializer},
+    commit::{self, OffsetCommitter},
+    headers::RecordHeaders,
+    metrics::Metrics,
+    poison::{PoisonKind, PoisonPill},
+    producer,
+};
+
+/// How long the consumer may take to rewind to a message it could not decode yet.
+const REWIND_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait before receiving a message again that could not be decoded yet.
+pub const UNAVAILABLE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Application code that follows the partitions assigned to a consumer, e.g.
+/// to keep per-partition state. Called on the thread that polls the consumer,
+/// from inside the poll.
+///
+/// With the cooperative-sticky assignor
+/// (`partition.assignment.strategy = cooperative-sticky`) the lists only hold
+/// the partitions that move, and the others keep being consumed. With the
+/// eager assignors every rebalance revokes all partitions and assigns them again.
+pub trait RebalanceListener: Send + Sync {
+    /// The partitions in `assigned` were added to the assignment.
+    fn on_assign(&self, _assigned: &TopicPartitionList) {}
+
+    /// The partitions in `revoked` are about to be taken away. Work still in
+    /// flight for them should be finished and marked processed here: the
+    /// committer of the context commits it right after this returns.
+    fn on_revoke(&self, _revoked: &TopicPartitionList) {}
+
+    /// The partitions in `lost` already belong to another consumer, e.g. after
+    /// missing `max.poll.interval.ms`. Their offsets can no longer be committed,
+    /// so work in flight for them will be done again by the new owner.
+    fn on_lost(&self, _lost: &TopicPartitionList) {}
+}
+
+/// Consumer context that prints rebalance and offset commit events.
+#[derive(Default)]
+#[non_exhaustive]
+pub struct ConsumerCallbackLogger {
+    token_provider: Option<Arc<dyn TokenProvider>>,
+    committer: Option<OffsetCommitter>,
+    listener: Option<Arc<dyn RebalanceListener>>,
+    metrics: Option<Metrics>,
+    /// Offsets to start from, by topic and partition, for partitions not assigned yet.
+    start: Mutex<BTreeMap<(String, i32), Offset>>,
+}
+
+impl ConsumerCallbackLogger {
+    pub fn new() -> Self {
+        ConsumerCallbackLogger::default()
+    }
+
+    /// Answers `OAUTHBEARER` token refreshes when `auth` is an OAuth profile.
+    pub fn with_auth(mut self, auth: &Auth) -> Self {
+        self.token_provider = auth.token_provider();
+        self
+    }
+
+    /// Commits what `committer` has processed before partitions are revoked, and
+    /// tells it the outcome of async commits.
+    pub fn with_committer(mut self, committer: OffsetCommitter) -> Self {
+        self.committer = Some(committer);
+        self
+    }
+
+    /// Tells `listener` about partitions being assigned, revoked and lost.
+    pub fn with_listener(mut self, listener: Arc<dyn RebalanceListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Counts commits in `metrics` and keeps the client's statistics there.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Starts the partitions in `tpl` from its offsets instead of the committed
+    /// ones, the first time each of them is assigned. Later assignments resume
+    /// from the committed offsets as usual.
+    pub fn start_from(&self, tpl: &TopicPartitionList) {
+        let mut start = self.start.lock().unwrap();
+        for e in tpl.elements() {
+            start.insert((e.topic().to_string(), e.partition()), e.offset());
+        }
+    }
+
+    /// Moves newly assigned partitions to their [`start_from`](Self::start_from) offsets.
+    fn seek_assigned(&self, assigned: &mut TopicPartitionList) {
+        let mut start = self.start.lock().unwrap();
+        if start.is_empty() {
+            return;
+        }
+        for e in assigned.clone().elements() {
+            if let Some(offset) = start.remove(&(e.topic().to_string(), e.partition())) {
+                match assigned.set_partition_offset(e.topic(), e.partition(), offset) {
+                    Ok(()) => info!(
+                        topic = e.topic(),
+                        partition = e.partition(),
+                        offset = ?offset,
+                        "starting partition"
+                    ),
+                    Err(err) => warn!(
+                        topic = e.topic(),
+                        partition = e.partition(),
+                        offset = ?offset,
+                        error = %err,
+                        "failed to start partition"
+                    ),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ConsumerCallbackLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConsumerCallbackLogger")
+            .field("oauth", &self.token_provider.is_some())
+            .field("committer", &self.committer.as_ref().map(|c| c.strategy()))
+            .field("listener", &self.listener.is_some())
+            .field("metrics", &self.metrics.is_some())
+            .field("start", &self.start.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl ClientContext for ConsumerCallbackLogger {
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn Error>> {
+        auth::generate_token(self.token_provider.as_ref())
+    }
+
+    fn stats(&self, statistics: Statistics) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_stats(statistics);
+        }
+    }
+}
+
+impl ConsumerContext for ConsumerCallbackLogger {
+    // The same as the default, plus the commit on revoke, which needs the
+    // client handle that only this callback gets.
+    fn rebalance(
+        &self,
+        native_client: &NativeClient,
+        err: RDKafkaRespErr,
+        tpl: &mut TopicPartitionList,
+    ) {
+        if err == RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS {
+            self.seek_assigned(tpl);
+        }
+        let rebalance = match err {
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__ASSIGN_PARTITIONS => Rebalance::Assign(tpl),
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR__REVOKE_PARTITIONS => Rebalance::Revoke(tpl),
+            _ => Rebalance::Error(KafkaError::Rebalance(err.into())),
+        };
+        self.pre_rebalance(&rebalance);
+
+        match &rebalance {
+            Rebalance::Assign(assigned) => {
+                if let Some(committer) = &self.committer {
+                    committer.assigned(assigned);
+                }
+            }
+            Rebalance::Revoke(lost) if rebalance::assignment_lost(native_client) => {
+                // the group has moved on, a commit would be refused
+                if let Some(listener) = &self.listener {
+                    listener.on_lost(lost);
+                }
+                if let Some(committer) = &self.committer {
+                    committer.lost(lost);
+                }
+            }
+            Rebalance::Revoke(revoked) => {
+                if let Some(listener) = &self.listener {
+                    listener.on_revoke(revoked);
+                }
+                if let Some(committer) = &self.committer {
+                    self.commit_revoked(native_client, committer, revoked);
+                }
+            }
+            Rebalance::Error(_) => {}
+        }
+
+        match &rebalance {
+            Rebalance::Assign(assigned) => rebalance::assign(native_client, assigned),
+            Rebalance::Revoke(revoked) => rebalance::unassign(native_client, revoked),
+            Rebalance::Error(_) => rebalance::unassign_all(native_client),
+        }
+
+        if let (Rebalance::Assign(assigned), Some(listener)) = (&rebalance, &self.listener) {
+            listener.on_assign(assigned);
+        }
+        self.post_rebalance(&rebalance);
+    }
+
+    fn pre_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        match rebalance {
+            Rebalance::Assign(tpl) => {
+                debug!(partitions = %partitions(tpl), "assigning partitions")
+            }
+            Rebalance::Revoke(tpl) => {
+                debug!(partitions = %partitions(tpl), "revoking partitions")
+            }
+            Rebalance::Error(err) => warn!(error = %err, "rebalance failed"),
+        }
+    }
+
+    fn post_rebalance<'a>(&self, rebalance: &Rebalance<'a>) {
+        match rebalance {
+            Rebalance::Assign(tpl) => {
+                for e in tpl.elements() {
+                    info!(
+                        topic = e.topic(),
+                        partition = e.partition(),
+                        "assigned partition"
+                    )
+                }
+            }
+            Rebalance::Revoke(tpl) => {
+                for e in tpl.elements() {
+                    info!(
+                        topic = e.topic(),
+                        partition = e.partition(),
+                        "revoked partition"
+                    )
+                }
+            }
+            Rebalance::Error(_) => {}
+        }
+    }
+
+    fn commit_callback(&self, result: KafkaResult<()>, offsets: &TopicPartitionList) {
+        if let Some(committer) = &self.committer {
+            committer.commit_completed(&result, offsets);
+        }
+        // librdkafka reports synchronous commits here as well
+        if let Some(metrics) = &self.metrics {
+            match result {
+                Ok(_) => metrics.committed(),
+                Err(_) => metrics.commit_failed(),
+            }
+        }
+        match result {
+            Ok(_) => {
+                for e in offsets.elements() {
+                    if let Offset::Offset(offset) = e.offset() {
+                        info!(
+                            topic = e.topic(),
+                            partition = e.partition(),
+                            offset,
+                            "committed offset"
+                        )
+                    }
+                }
+            }
+            Err(err) => warn!(
+                offsets = %commit::describe(offsets),
+                error_code = ?err.rdkafka_error_code(),
+                error = %err,
+                "failed to commit offsets"
+            ),
+        }
+    }
+}
+
+impl ConsumerCallbackLogger {
+    fn commit_revoked(
+        &self,
+        native_client: &NativeClient,
+        committer: &OffsetCommitter,
+        revoked: &TopicPartitionList,
+    ) {
+        match committer.revoking(revoked) {
+            Ok(offsets) if offsets.count() > 0 => {
+                match rebalance::commit_sync(native_client, &offsets) {
+                    Ok(()) => info!(
+                        offsets = %commit::describe(&offsets),
+                        "committed offsets before revoke"
+                    ),
+                    Err(err) => warn!(
+                        offsets = %commit::describe(&offsets),
+                        error_code = ?err.rdkafka_error_code(),
+                        error = %err,
+                        "failed to commit offsets before revoke"
+                    ),
+                }
+            }
+            Ok(_) => {}
+            Err(err) => warn!(error = %err, "failed to collect offsets before revoke"),
+        }
+    }
+}
+
+/// `topic/partition` for every partition in `tpl`.
+fn partitions(tpl: &TopicPartitionList) -> String {
+    tpl.elements()
+        .iter()
+        .map(|e| format!("{}/{}", e.topic(), e.partition()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The librdkafka calls behind [`ConsumerCallbackLogger::rebalance`]. Only valid
+/// inside the rebalance callback of the client.
+mod rebalance {
+    use super::*;
+
+    pub fn commit_sync(client: &NativeClient, offsets: &TopicPartitionList) -> KafkaResult<()> {
+        let err = unsafe { rdsys::rd_kafka_commit(client.ptr(), offsets.ptr(), 0) };
+        match RDKafkaErrorCode::from(err) {
+            RDKafkaErrorCode::NoError => Ok(()),
+            code => Err(KafkaError::ConsumerCommit(code)),
+        }
+    }
+
+    pub fn assign(client: &NativeClient, assigned: &TopicPartitionList) {
+        unsafe {
+            match rebalance_protocol(client) {
+                RebalanceProtocol::Cooperative => check(rdsys::rd_kafka_incremental_assign(
+                    client.ptr(),
+                    assigned.ptr(),
+                )),
+                _ => check_code(rdsys::rd_kafka_assign(client.ptr(), assigned.ptr())),
+            }
+        }
+    }
+
+    pub fn unassign(client: &NativeClient, revoked: &TopicPartitionList) {
+        unsafe {
+            match rebalance_protocol(client) {
+                RebalanceProtocol::Cooperative => check(rdsys::rd_kafka_incremental_unassign(
+                    client.ptr(),
+                    revoked.ptr(),
+                )),
+                _ => check_code(rdsys::rd_kafka_assign(client.ptr(), ptr::null())),
+            }
+        }
+    }
+
+    /// Drops every partition, after a rebalance failed part way.
+    pub fn unassign_all(client: &NativeClient) {
+        unsafe {
+            match rebalance_protocol(client) {
+                RebalanceProtocol::Cooperative => {
+                    // an incremental assignment cannot be cleared with `rd_kafka_assign`
+                    let mut current = ptr::null_mut();
+                    check_code(rdsys::rd_kafka_assignment(client.ptr(), &mut current));
+                    if !current.is_null() {
+                        check(rdsys::rd_kafka_incremental_unassign(client.ptr(), current));
+                        rdsys::rd_kafka_topic_partition_list_destroy(current);
+                    }
+                }
+                _ => check_code(rdsys::rd_kafka_assign(client.ptr(), ptr::null())),
+            }
+        }
+    }
+
+    /// Whether the partitions being revoked already belong to another consumer.
+    pub fn assignment_lost(client: &NativeClient) -> bool {
+        unsafe { rdsys::rd_kafka_assignment_lost(client.ptr()) != 0 }
+    }
+
+    /// The protocol of the group, as `Consumer::rebalance_protocol` reports it.
+    ///
+    /// `NativeClient::rebalance_protocol` is private to rdkafka, and the rebalance callback
+    /// only has the native client to hand.
+    fn rebalance_protocol(client: &NativeClient) -> RebalanceProtocol {
+        let protocol = unsafe { rdsys::rd_kafka_rebalance_protocol(client.ptr()) };
+        if protocol.is_null() {
+            return RebalanceProtocol::None;
+        }
+        match unsafe { CStr::from_ptr(protocol) }.to_bytes() {
+            b"EAGER" => RebalanceProtocol::Eager,
+            b"COOPERATIVE" => RebalanceProtocol::Cooperative,
+            _ => RebalanceProtocol::None,
+        }
+    }
+
+    unsafe fn check(error: *mut rdsys::rd_kafka_error_t) {
+        if !error.is_null() {
+            let message = CStr::from_ptr(rdsys::rd_kafka_error_string(error));
+            warn!(error = %message.to_string_lossy(), "incremental rebalance failed");
+            rdsys::rd_kafka_error_destroy(error);
+        }
+    }
+
+    fn check_code(err: RDKafkaRespErr) {
+        match RDKafkaErrorCode::from(err) {
+            RDKafkaErrorCode::NoError => {}
+            code => warn!(error = %code, "rebalance failed"),
+        }
+    }
+}
+
+/// A consumer that hands out values of `T` decoded by its deserializer.
+pub struct TypedConsumer<T, D, C = ConsumerCallbackLogger>
+where
+    C: ConsumerContext + 'static,
+{
+    consumer: BaseConsumer<C>,
+    deserializer: D,
+    require_key: bool,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T, D, C> TypedConsumer<T, D, C>
+where
+    D: Deserializer<T>,
+    C: ConsumerContext + 'static,
+{
+    pub fn new(consumer: BaseConsumer<C>, deserializer: D) -> Self {
+        TypedConsumer {
+            consumer,
+            deserializer,
+            require_key: false,
+            _value: PhantomData,
+        }
+    }
+
+    /// Report messages without a UTF-8 key as poison pills instead of decoding them.
+    pub fn require_key(mut self, require_key: bool) -> Self {
+        self.require_key = require_key;
+        self
+    }
+
+    /// Waits up to `timeout` for the next message. `None` means nothing arrived in time.
+    pub fn poll(&self, timeout: Duration) -> Option<Result<Received<'_, T>, ReceiveError>> {
+        self.consumer
+            .poll(timeout)
+            .map(|result| decode(&self.consumer, &self.deserializer, self.require_key, result))
+    }
+
+    /// Blocks for messages forever, like `BaseConsumer::iter`.
+    pub fn iter(&self) -> impl Iterator<Item = Result<Received<'_, T>, ReceiveError>> + '_ {
+        self.consumer
+            .iter()
+            .map(move |result| decode(&self.consumer, &self.deserializer, self.require_key, result))
+    }
+
+    /// The underlying consumer, e.g. to subscribe or commit.
+    pub fn inner(&self) -> &BaseConsumer<C> {
+        &self.consumer
+    }
+}
+
+/// Receives values of `T` and commits them, whether from Kafka through a
+/// [`TypedConsumer`] or from an [`InMemoryKafka`](crate::memory::InMemoryKafka),
+/// so processing code written against it can be tested without a broker.
+pub trait ValueConsumer<T> {
+    /// Waits up to `timeout` for the next value. `None` means nothing arrived in time.
+    fn poll(&self, timeout: Duration) -> Option<Result<OwnedReceived<T>, ReceiveError>>;
+
+    /// Commits the offset after `msg` synchronously, see [`commit_next`].
+    fn commit(&self, msg: &OwnedMessage) -> KafkaResult<()>;
+}
+
+impl<T, D, C> ValueConsumer<T> for TypedConsumer<T, D, C>
+where
+    D: Deserializer<T>,
+    C: ConsumerContext + 'static,
+{
+    fn poll(&self, timeout: Duration) -> Option<Result<OwnedReceived<T>, ReceiveError>> {
+        TypedConsumer::poll(self, timeout).map(|result| result.map(Received::detach))
+    }
+
+    fn commit(&self, msg: &OwnedMessage) -> KafkaResult<()> {
+        commit_next(&self.consumer, msg, CommitMode::Sync)
+    }
+}
+
+/// The async counterpart of [`TypedConsumer`], built on a `StreamConsumer`.
+///
+/// Messages are only fetched from librdkafka's queue when the stream is polled,
+/// so a caller that awaits its processing before asking for the next message
+/// applies backpressure all the way to the broker.
+pub struct StreamTypedConsumer<T, D, C = ConsumerCallbackLogger>
+where
+    C: ConsumerContext + 'static,
+{
+    consumer: StreamConsumer<C>,
+    deserializer: D,
+    require_key: bool,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T, D, C> StreamTypedConsumer<T, D, C>
+where
+    D: Deserializer<T>,
+    C: ConsumerContext + 'static,
+{
+    pub fn new(consumer: StreamConsumer<C>, deserializer: D) -> Self {
+        StreamTypedConsumer {
+            consumer,
+            deserializer,
+            require_key: false,
+            _value: PhantomData,
+        }
+    }
+
+    /// Report messages without a UTF-8 key as poison pills instead of decoding them.
+    pub fn require_key(mut self, require_key: bool) -> Self {
+        self.require_key = require_key;
+        self
+    }
+
+    /// Waits for the next message.
+    pub async fn recv(&self) -> Result<Received<'_, T>, ReceiveError> {
+        decode(
+            &self.consumer,
+            &self.deserializer,
+            self.require_key,
+            self.consumer.recv().await,
+        )
+    }
+
+    /// The messages as a never ending stream.
+    pub fn stream(&self) -> impl Stream<Item = Result<Received<'_, T>, ReceiveError>> + '_ {
+        self.consumer
+            .stream()
+            .map(move |result| decode(&self.consumer, &self.deserializer, self.require_key, result))
+    }
+
+    /// The underlying consumer, e.g. to subscribe or commit.
+    pub fn inner(&self) -> &StreamConsumer<C> {
+        &self.consumer
+    }
+}
+
+fn decode<'a, T, D, C, K>(
+    consumer: &K,
+    deserializer: &D,
+    require_key: bool,
+    result: KafkaResult<BorrowedMessage<'a>>,
+) -> Result<Received<'a, T>, ReceiveError>
+where
+    D: Deserializer<T>,
+    C: ConsumerContext,
+    K: Consumer<C>,
+{
+    let message = result.map_err(ReceiveError::Kafka)?;
+    match decode_value(deserializer, require_key, &message) {
+        Ok(value) => Ok(Received {
+            span: consume_span(&message),
+            message,
+            value,
+        }),
+        Err(Undecoded::Poison(kind, error)) => Err(ReceiveError::Poison(Box::new(PoisonPill {
+            kind,
+            message: message.detach(),
+            error,
+        }))),
+        Err(Undecoded::Unavailable(error)) => {
+            // received again by a later poll instead of being skipped
+            let rewound = consumer.seek(
+                message.topic(),
+                message.partition(),
+                Offset::Offset(message.offset()),
+                REWIND_TIMEOUT,
+            );
+            if let Err(err) = &rewound {
+                warn!(
+                    topic = message.topic(),
+                    partition = message.partition(),
+                    offset = message.offset(),
+                    error = %err,
+                    "failed to rewind to undecoded message"
+                );
+            }
+            Err(ReceiveError::Unavailable {
+                error,
+                rewound: rewound.is_ok(),
+            })
+        }
+    }
+}
+
+/// Why [`decode_value`] has no value for a message.
+pub(crate) enum Undecoded {
+    Poison(PoisonKind, Option<CodecError>),
+    /// See [`ReceiveError::Unavailable`].
+    Unavailable(CodecError),
+}
+
+/// Decodes the payload of `message`, or tells why it is a poison pill.
+pub(crate) fn decode_value<T, D: Deserializer<T>, M: Message>(
+    deserializer: &D,
+    require_key: bool,
+    message: &M,
+) -> Result<T, Undecoded> {
+    let payload = match message.payload() {
+        Some(payload) => payload,
+        None => return Err(Undecoded::Poison(PoisonKind::MissingPayload, None)),
+    };
+    if require_key {
+        match message.key_view::<str>() {
+            None => return Err(Undecoded::Poison(PoisonKind::MissingKey, None)),
+            Some(Err(_)) => return Err(Undecoded::Poison(PoisonKind::NonUtf8Key, None)),
+            Some(Ok(_)) => {}
+        }
+    }
+
+    deserializer
+        .deserialize(message.topic(), payload)
+        .map_err(|err| match PoisonKind::of(&err) {
+            Some(kind) => Undecoded::Poison(kind, Some(err)),
+            None => Undecoded::Unavailable(err),
+        })
+}
+
+/// Commits the offset after `msg`, so that the group resumes with the next
+/// message. Also works for messages that were never decoded, such as the one
+/// inside a [`PoisonPill`].
+pub fn commit_next<C, K, M>(consumer: &K, msg: &M, mode: CommitMode) -> KafkaResult<()>
+where
+    C: ConsumerContext,
+    K: Consumer<C>,
+    M: Message,
+{
+    let mut tpl = TopicPartitionList::new();
+    tpl.add_partition_offset(
+        msg.topic(),
+        msg.partition(),
+        Offset::Offset(msg.offset() + 1),
+    )?;
+    consumer.commit(&tpl, mode)
+}
+
+/// The `consume` span of a message, see [`crate::logging`].
+fn consume_span<M: Message>(msg: &M) -> Span {
+    info_span!(
+        "consume",
+        topic = msg.topic(),
+        partition = msg.partition(),
+        offset = msg.offset(),
+        key = producer::key_of(msg)
+    )
+}
+
+/// A decoded value together with the message it came from.
+pub struct Received<'a, T> {
+    message: BorrowedMessage<'a>,
+    value: T,
+    span: Span,
+}
+
+impl<'a, T> Received<'a, T> {
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_value(self) -> T {
+        self.value
+    }
+
+    /// The raw message, for its offset, headers, or to commit it.
+    pub fn message(&self) -> &BorrowedMessage<'a> {
+        &self.message
+    }
+
+    /// The key as text, if it is present and valid UTF-8.
+    pub fn key(&self) -> Option<&str> {
+        self.message.key_view::<str>().and_then(Result::ok)
+    }
+
+    /// A copy of the headers of the message, see [`crate::headers`].
+    pub fn headers(&self) -> RecordHeaders {
+        RecordHeaders::from_message(&self.message)
+    }
+
+    /// The `consume` span of the message. It closes once the last handle to
+    /// it, including this one, is dropped.
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Copies the message out of the consumer's buffers, so it can outlive the
+    /// next poll or be handed to another task.
+    pub fn detach(self) -> OwnedReceived<T> {
+        OwnedReceived {
+            message: self.message.detach(),
+            value: self.value,
+            span: self.span,
+        }
+    }
+}
+
+/// A [`Received`] that no longer borrows from the consumer.
+pub struct OwnedReceived<T> {
+    message: OwnedMessage,
+    value: T,
+    span: Span,
+}
+
+impl<T> OwnedReceived<T> {
+    pub(crate) fn new(message: OwnedMessage, value: T) -> Self {
+        OwnedReceived {
+            span: consume_span(&message),
+            message,
+            value,
+        }
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_value(self) -> T {
+        self.value
+    }
+
+    pub fn message(&self) -> &OwnedMessage {
+        &self.message
+    }
+
+    /// The key as text, if it is present and valid UTF-8.
+    pub fn key(&self) -> Option<&str> {
+        self.message.key_view::<str>().and_then(Result::ok)
+    }
+
+    /// See [`Received::headers`].
+    pub fn headers(&self) -> RecordHeaders {
+        RecordHeaders::from_message(&self.message)
+    }
+
+    /// See [`Received::span`].
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+}
+
+/// Why a message could not be received as a `T`.
+#[derive(Debug)]
+pub enum ReceiveError {
+    Kafka(KafkaError),
+    /// The message can never be decoded. It is not fatal to the consumer.
+    Poison(Box<PoisonPill>),
+    /// The message could not be decoded for now, e.g. the schema registry did
+    /// not answer. It is not a poison pill and must not be committed.
+    Unavailable {
+        error: CodecError,
+        /// Whether the consumer was rewound to the message, so that a later
+        /// poll receives it again. If not, see [`is_fatal`](Self::is_fatal).
+        rewound: bool,
+    },
+}
+
+impl ReceiveError {
+    /// Whether the consumer must stop without committing anything more: an
+    /// [`Unavailable`](Self::Unavailable) message it could not be rewound to
+    /// would otherwise be skipped.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, ReceiveError::Unavailable { rewound: false, .. })
+    }
+
+    /// How long to wait before polling again, when the same message is about
+    /// to be received again.
+    pub fn backoff(&self) -> Option<Duration> {
+        match self {
+            ReceiveError::Unavailable { rewound: true, .. } => Some(UNAVAILABLE_BACKOFF),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReceiveError::Kafka(err) => write!(f, "failed to consume message - {}", err),
+            ReceiveError::Poison(pill) => write!(f, "received poison pill - {}", pill),
+            ReceiveError::Unavailable {
+                error,
+                rewound: true,
+            } => write!(f, "could not decode message yet - {}", error),
+            ReceiveError::Unavailable {
+                error,
+                rewound: false,
+            } => write!(
+                f,
+                "could not decode message yet, nor rewind to it - {}",
+                error
+            ),
+        }
+    }
+}
+
+impl Error for ReceiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReceiveError::Kafka(err) => Some(err),
+            ReceiveError::Poison(pill) => pill.error.as_ref().map(|err| err as _),
+            ReceiveError::Unavailable { error, .. } => Some(error),
+        }
+    }
+}
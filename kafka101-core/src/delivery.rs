@@ -0,0 +1,193 @@
+//! Idempotent producing, and checking what the delivery reports promised.
+//!
+//! `idempotent = true` at the top level or in a profile (or `KAFKA_IDEMPOTENT=true`)
+//! makes [`Settings::producer_config`](crate::Settings::producer_config) add the
+//! [`IDEMPOTENT_PROPERTIES`]. The broker then drops the duplicates that retries
+//! would otherwise write, and keeps the records of a partition in send order
+//! even with several requests in flight. Setting one of those properties to a
+//! weaker value at the same time is a configuration error.
+//!
+//! A [`DeliveryTracker`] shared by a [`TypedProducer`](crate::TypedProducer) and
+//! its [`ProduceCallbackLogger`](crate::ProduceCallbackLogger) records every key
+//! sent and every delivery report, and summarizes them in a [`DeliveryReport`]:
+//! whether each record was acknowledged, and at an offset that follows the
+//! records of its partition sent before it.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use rdkafka::{producer::DeliveryResult, Message};
+
+/// What an idempotent producer needs. librdkafka allows at most five requests
+/// in flight per connection with idempotence enabled.
+pub const IDEMPOTENT_PROPERTIES: &[(&str, &str)] = &[
+    ("enable.idempotence", "true"),
+    ("acks", "all"),
+    ("max.in.flight.requests.per.connection", "5"),
+];
+
+/// Explains why `value` of property `name` does not go with idempotence, if it doesn't.
+pub(crate) fn idempotence_conflict(name: &str, value: &str) -> Option<String> {
+    let conflicts = match name {
+        "enable.idempotence" => value != "true",
+        "acks" | "request.required.acks" => value != "all" && value != "-1",
+        "max.in.flight.requests.per.connection" | "max.in.flight" => {
+            value.parse::<u32>().map_or(true, |max| max > 5)
+        }
+        _ => false,
+    };
+    if conflicts {
+        Some(format!(
+            "`{} = {}` conflicts with idempotent = true",
+            name, value
+        ))
+    } else {
+        None
+    }
+}
+
+/// Records sends and delivery reports. Clones share the same records.
+///
+/// Every send gets a sequence number, which travels to its delivery report in
+/// the [`DeliverySpan`](crate::DeliverySpan) opaque, so reports are matched to
+/// the exact record they are about, whatever its key.
+#[derive(Debug, Clone, Default)]
+pub struct DeliveryTracker {
+    inner: Arc<Mutex<Tracked>>,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    /// The sequence number of the next send.
+    next: u64,
+    cancelled: u64,
+    /// Topic and key of records not acknowledged yet, by send sequence number.
+    pending: BTreeMap<u64, (String, String)>,
+    /// Send sequence number and offset of every acknowledged record, by topic and partition.
+    acknowledged: BTreeMap<(String, i32), Vec<(u64, i64)>>,
+    failed: u64,
+}
+
+impl DeliveryTracker {
+    pub fn new() -> DeliveryTracker {
+        DeliveryTracker::default()
+    }
+
+    /// Records that a record with `key` is about to be enqueued, and returns
+    /// its sequence number for the [`DeliverySpan`](crate::DeliverySpan). Call
+    /// it before `send`, as the delivery report may arrive before `send` returns.
+    pub fn sent(&self, topic: &str, key: &str) -> u64 {
+        let mut tracked = self.inner.lock().unwrap();
+        let sequence = tracked.next;
+        tracked.next += 1;
+        tracked
+            .pending
+            .insert(sequence, (topic.to_string(), key.to_string()));
+        sequence
+    }
+
+    /// Takes back the [`sent`](Self::sent) record `sequence`, when enqueueing failed.
+    pub fn cancelled(&self, sequence: u64) {
+        let mut tracked = self.inner.lock().unwrap();
+        if tracked.pending.remove(&sequence).is_some() {
+            tracked.cancelled += 1;
+        }
+    }
+
+    /// Records the delivery report of record `sequence`.
+    pub fn delivered(&self, sequence: u64, result: &DeliveryResult<'_>) {
+        let mut tracked = self.inner.lock().unwrap();
+        let (topic, _) = match tracked.pending.remove(&sequence) {
+            Some(pending) => pending,
+            // not sent through this tracker, or cancelled
+            None => return,
+        };
+        match result {
+            Ok(msg) => tracked
+                .acknowledged
+                .entry((topic, msg.partition()))
+                .or_default()
+                .push((sequence, msg.offset())),
+            Err(_) => tracked.failed += 1,
+        }
+    }
+
+    /// The outcome so far. Call it after flushing the producer for the final report.
+    pub fn report(&self) -> DeliveryReport {
+        let tracked = self.inner.lock().unwrap();
+
+        let mut acknowledged = 0;
+        let mut out_of_order = 0;
+        for records in tracked.acknowledged.values() {
+            acknowledged += records.len() as u64;
+            let mut by_send_order = records.clone();
+            by_send_order.sort_unstable();
+            out_of_order += by_send_order
+                .windows(2)
+                .filter(|pair| pair[1].1 <= pair[0].1)
+                .count() as u64;
+        }
+
+        DeliveryReport {
+            sent: tracked.next - tracked.cancelled,
+            acknowledged,
+            failed: tracked.failed,
+            out_of_order,
+            // in send order
+            unacknowledged: tracked
+                .pending
+                .values()
+                .map(|(_, key)| key.clone())
+                .collect(),
+        }
+    }
+}
+
+/// A summary of the sends and delivery reports seen by a [`DeliveryTracker`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReport {
+    pub sent: u64,
+    pub acknowledged: u64,
+    /// Records whose delivery report was an error.
+    pub failed: u64,
+    /// Acknowledged records written before a record of the same partition that was sent earlier.
+    pub out_of_order: u64,
+    /// Keys of records without any delivery report, e.g. still queued at shutdown.
+    pub unacknowledged: Vec<String>,
+}
+
+impl DeliveryReport {
+    /// Every record acknowledged, at offsets in send order within each
+    /// partition. Delivery reports cannot show whether the broker also wrote a
+    /// duplicate of a retried record; that is what idempotence is for.
+    pub fn is_complete_in_order(&self) -> bool {
+        self.acknowledged == self.sent
+            && self.failed == 0
+            && self.out_of_order == 0
+            && self.unacknowledged.is_empty()
+    }
+}
+
+impl fmt::Display for DeliveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} sent, {} acknowledged, {} failed, {} out of order, {} unacknowledged",
+            self.sent,
+            self.acknowledged,
+            self.failed,
+            self.out_of_order,
+            self.unacknowledged.len()
+        )?;
+        if !self.unacknowledged.is_empty() {
+            write!(f, " ({})", self.unacknowledged.join(", "))?;
+        }
+        if self.is_complete_in_order() {
+            write!(f, " - every record acknowledged, in send order")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+//! Retrying records that fail processing, and parking the ones that keep failing.
+//!
+//! A [`RetryPolicy`] runs the processing step again with exponential backoff.
+//! When it gives up, [`DeadLetterQueue::send`] produces the original record to
+//! a dead letter topic with headers describing the failure, so that the
+//! consumer can commit and move on instead of stopping the whole group.
+//! [`RetryPolicy::run_async`] and [`DeadLetterQueue::send_async`] do the same
+//! without blocking the thread of an async runtime.
+//! [`send_in_transaction`] writes the same record as part of a Kafka transaction.
+//!
+//! Both are configured in an `[errors]` section, at the top level or per profile:
+//!
+//! ```toml
+//! [errors]
+//! max_retries = 3
+//! backoff_ms = 100
+//! max_backoff_ms = 5000
+//! dlq_topic = "rust-dlq"
+//! poison_pill = "log"
+//! require_key = false
+//! ```
+//!
+//! `dlq_topic` defaults to the topic name with a `-dlq` suffix. `KAFKA_DLQ_TOPIC`
+//! overrides it. `poison_pill` and `require_key` are described in
+//! [`poison`](crate::poison).
+
+use std::{fmt, future::Future, thread, time::Duration};
+
+use futures::channel::oneshot::Canceled;
+use rdkafka::{
+    error::{KafkaError, KafkaResult},
+    message::{Header, Headers, OwnedHeaders},
+    producer::{
+        future_producer::OwnedDeliveryResult, BaseRecord, FutureProducer, FutureRecord,
+        ProducerContext, ThreadedProducer,
+    },
+    ClientContext, Message,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{
+    config::Settings,
+    poison::PoisonStrategy,
+    producer::{self, DeliverySpan, ProduceCallbackLogger},
+};
+
+/// Why the record was dead-lettered.
+pub const HEADER_REASON: &str = "dlq.reason";
+pub const HEADER_ORIGINAL_TOPIC: &str = "dlq.original.topic";
+pub const HEADER_ORIGINAL_PARTITION: &str = "dlq.original.partition";
+pub const HEADER_ORIGINAL_OFFSET: &str = "dlq.original.offset";
+/// How many times processing was attempted, including the first one.
+pub const HEADER_ATTEMPTS: &str = "dlq.attempts";
+
+/// Appended to the topic name when no dead letter topic is configured.
+pub const DEFAULT_DLQ_SUFFIX: &str = "-dlq";
+
+/// How often, and how patiently, a failed step is retried.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt. Zero means no retries.
+    pub max_retries: u32,
+    /// Delay before the first retry. Each further retry waits twice as long.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry`, counting from 1.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+
+    /// Calls `op` with the attempt number until it succeeds or the retries are
+    /// used up, sleeping in between.
+    pub fn run<T, E, F>(&self, mut op: F) -> Result<T, Exhausted<E>>
+    where
+        E: fmt::Display,
+        F: FnMut(u32) -> Result<T, E>,
+    {
+        let mut attempt = 1;
+        loop {
+            let error = match op(attempt) {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            thread::sleep(self.retry_after(attempt, error)?);
+            attempt += 1;
+        }
+    }
+
+    /// Like [`run`](Self::run), for async code: awaits `op` and sleeps on the
+    /// tokio timer instead of blocking the thread.
+    pub async fn run_async<T, E, F, Fut>(&self, mut op: F) -> Result<T, Exhausted<E>>
+    where
+        E: fmt::Display,
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let error = match op(attempt).await {
+                Ok(value) => return Ok(value),
+                Err(error) => error,
+            };
+            tokio::time::sleep(self.retry_after(attempt, error)?).await;
+            attempt += 1;
+        }
+    }
+
+    /// The backoff before the next attempt, or the error once the retries are used up.
+    fn retry_after<E: fmt::Display>(
+        &self,
+        attempt: u32,
+        error: E,
+    ) -> Result<Duration, Exhausted<E>> {
+        if attempt > self.max_retries {
+            return Err(Exhausted {
+                error,
+                attempts: attempt,
+            });
+        }
+        let backoff = self.backoff(attempt);
+        warn!(
+            attempt,
+            error = %error,
+            backoff_ms = backoff.as_millis() as u64,
+            "attempt failed, retrying"
+        );
+        Ok(backoff)
+    }
+}
+
+/// The last error of a step that failed every attempt.
+#[derive(Debug)]
+pub struct Exhausted<E> {
+    pub error: E,
+    pub attempts: u32,
+}
+
+/// Produces records that could not be processed to a dead letter topic.
+pub struct DeadLetterQueue<C = ProduceCallbackLogger>
+where
+    C: ClientContext + 'static,
+{
+    producer: FutureProducer<C>,
+    topic: String,
+}
+
+impl<C> DeadLetterQueue<C>
+where
+    C: ClientContext + 'static,
+{
+    pub fn new<N: Into<String>>(producer: FutureProducer<C>, topic: N) -> Self {
+        DeadLetterQueue {
+            producer,
+            topic: topic.into(),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// Produces `msg` with its key, payload and headers, plus the `dlq.*` headers,
+    /// and blocks until the broker has acknowledged it. Only commit the original
+    /// offset once this returned `Ok`.
+    pub fn send<M: Message>(
+        &self,
+        msg: &M,
+        reason: &str,
+        attempts: u32,
+    ) -> KafkaResult<(i32, i64)> {
+        let delivery = self
+            .producer
+            .send_result(self.record(msg, reason, attempts))
+            .map_err(|(err, _)| err)?;
+        self.delivered(msg, futures::executor::block_on(delivery))
+    }
+
+    /// Like [`send`](Self::send), but awaits the acknowledgement instead of
+    /// blocking the thread, for use on an async runtime.
+    pub async fn send_async<M: Message>(
+        &self,
+        msg: &M,
+        reason: &str,
+        attempts: u32,
+    ) -> KafkaResult<(i32, i64)> {
+        let delivery = self
+            .producer
+            .send_result(self.record(msg, reason, attempts))
+            .map_err(|(err, _)| err)?;
+        self.delivered(msg, delivery.await)
+    }
+
+    fn record<'a, M: Message>(
+        &'a self,
+        msg: &'a M,
+        reason: &str,
+        attempts: u32,
+    ) -> FutureRecord<'a, [u8], [u8]> {
+        let headers = dead_letter_headers(msg, reason, attempts);
+        let mut record = FutureRecord::<[u8], [u8]>::to(&self.topic).headers(headers);
+        if let Some(key) = msg.key() {
+            record = record.key(key);
+        }
+        if let Some(payload) = msg.payload() {
+            record = record.payload(payload);
+        }
+        record
+    }
+
+    fn delivered<M: Message>(
+        &self,
+        msg: &M,
+        delivery: Result<OwnedDeliveryResult, Canceled>,
+    ) -> KafkaResult<(i32, i64)> {
+        let (dlq_partition, dlq_offset) = match delivery {
+            Ok(Ok(position)) => position,
+            Ok(Err((err, _))) => return Err(err),
+            Err(_) => return Err(KafkaError::Canceled),
+        };
+        info!(
+            topic = msg.topic(),
+            partition = msg.partition(),
+            offset = msg.offset(),
+            dlq_topic = %self.topic,
+            dlq_partition,
+            dlq_offset,
+            "sent to dead letter topic"
+        );
+        Ok((dlq_partition, dlq_offset))
+    }
+
+    /// The underlying producer, e.g. to `flush` it.
+    pub fn inner(&self) -> &FutureProducer<C> {
+        &self.producer
+    }
+}
+
+impl DeadLetterQueue {
+    /// A dead letter queue for the configured dead letter topic, with its own
+    /// producer created from `settings`.
+    pub fn from_settings(settings: &Settings) -> KafkaResult<DeadLetterQueue> {
+        let producer = settings
+            .producer_config()
+            .create_with_context(ProduceCallbackLogger::new().with_auth(&settings.auth))?;
+        Ok(DeadLetterQueue::new(producer, settings.dlq_topic()))
+    }
+}
+
+/// Produces `msg` to the dead letter `topic` like [`DeadLetterQueue::send`], but
+/// with the producer of a transaction and without waiting for the broker: the
+/// copy is only written if the transaction commits. Use it for messages of a
+/// batch that is committed in a transaction, so that an aborted batch that is
+/// consumed again does not leave copies behind.
+pub fn send_in_transaction<C, M>(
+    producer: &ThreadedProducer<C>,
+    topic: &str,
+    msg: &M,
+    reason: &str,
+    attempts: u32,
+) -> KafkaResult<()>
+where
+    C: ProducerContext<DeliveryOpaque = Box<DeliverySpan>>,
+    M: Message,
+{
+    let mut record = BaseRecord::<[u8], [u8], _>::with_opaque_to(
+        topic,
+        DeliverySpan::start(topic, producer::key_of(msg)),
+    )
+    .headers(dead_letter_headers(msg, reason, attempts));
+    if let Some(key) = msg.key() {
+        record = record.key(key);
+    }
+    if let Some(payload) = msg.payload() {
+        record = record.payload(payload);
+    }
+    producer.send(record).map_err(|(err, _)| err)
+}
+
+/// The headers of `msg`, plus the `dlq.*` headers describing the failure.
+fn dead_letter_headers<M: Message>(msg: &M, reason: &str, attempts: u32) -> OwnedHeaders {
+    let mut headers = OwnedHeaders::new();
+    if let Some(original) = msg.headers() {
+        for header in original.iter() {
+            headers = headers.insert(header);
+        }
+    }
+    let partition = msg.partition().to_string();
+    let offset = msg.offset().to_string();
+    let attempts = attempts.to_string();
+    let failure = [
+        (HEADER_REASON, reason),
+        (HEADER_ORIGINAL_TOPIC, msg.topic()),
+        (HEADER_ORIGINAL_PARTITION, &partition),
+        (HEADER_ORIGINAL_OFFSET, &offset),
+        (HEADER_ATTEMPTS, &attempts),
+    ];
+    for (key, value) in failure.iter() {
+        headers = headers.insert(Header {
+            key,
+            value: Some(*value),
+        });
+    }
+    headers
+}
+
+// not derived, which would needlessly require `C: Clone`
+impl<C> Clone for DeadLetterQueue<C>
+where
+    C: ClientContext + 'static,
+{
+    fn clone(&self) -> Self {
+        DeadLetterQueue {
+            producer: self.producer.clone(),
+            topic: self.topic.clone(),
+        }
+    }
+}
+
+/// The resolved `[errors]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorSettings {
+    pub retry: RetryPolicy,
+    /// See [`Settings::dlq_topic`](crate::Settings::dlq_topic) for the default.
+    pub dlq_topic: Option<String>,
+    pub poison_pill: PoisonStrategy,
+    /// Treat messages without a UTF-8 key as poison pills.
+    pub require_key: bool,
+}
+
+/// The `[errors]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ErrorsConfig {
+    max_retries: Option<u32>,
+    backoff_ms: Option<u64>,
+    max_backoff_ms: Option<u64>,
+    dlq_topic: Option<String>,
+    poison_pill: Option<PoisonStrategy>,
+    require_key: Option<bool>,
+}
+
+impl ErrorsConfig {
+    pub fn apply(self, settings: &mut ErrorSettings) {
+        if let Some(max_retries) = self.max_retries {
+            settings.retry.max_retries = max_retries;
+        }
+        if let Some(backoff_ms) = self.backoff_ms {
+            settings.retry.initial_backoff = Duration::from_millis(backoff_ms);
+        }
+        if let Some(max_backoff_ms) = self.max_backoff_ms {
+            settings.retry.max_backoff = Duration::from_millis(max_backoff_ms);
+        }
+        if let Some(dlq_topic) = self.dlq_topic {
+            settings.dlq_topic = Some(dlq_topic);
+        }
+        if let Some(poison_pill) = self.poison_pill {
+            settings.poison_pill = poison_pill;
+        }
+        if let Some(require_key) = self.require_key {
+            settings.require_key = require_key;
+        }
+    }
+}
@@ -0,0 +1,428 @@
+//! Record headers, and the ones the typed producers attach to every record.
+//!
+//! A producer given [`ProducerHeaders`] stamps each record with:
+//!
+//! - `content-type`, the media type of the payload, e.g. `application/json`;
+//! - `schema-id`, the schema registry id of Avro and Protobuf payloads;
+//! - `traceparent`, a W3C trace context, so a consumer can tie its logs to the
+//!   record's trace;
+//! - `app-id`, the application that produced the record;
+//! - `event-time`, when the event happened, in milliseconds since the epoch.
+//!   It is the time of the send unless the caller says otherwise, and unlike
+//!   the record timestamp it survives copies to other topics.
+//!
+//! Values are written as UTF-8 text, as the Java tooling and `kcat` print
+//! them. Headers passed to `send_with_headers` take precedence over the stamped
+//! ones with the same key. On the consumer side,
+//! [`Received::headers`](crate::consumer::Received::headers) reads them back
+//! into a [`RecordHeaders`], whose getters decode values with [`FromHeader`].
+
+use std::{
+    error::Error,
+    fmt,
+    num::ParseIntError,
+    str::{self, FromStr, Utf8Error},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use rand::Rng;
+use rdkafka::{
+    message::{Header, Headers, OwnedHeaders},
+    Message,
+};
+
+use crate::codec::Serializer;
+
+pub const CONTENT_TYPE: &str = "content-type";
+pub const SCHEMA_ID: &str = "schema-id";
+pub const TRACEPARENT: &str = "traceparent";
+pub const APP_ID: &str = "app-id";
+pub const EVENT_TIME: &str = "event-time";
+
+/// Encodes a value of a header.
+pub trait ToHeader {
+    fn to_header(&self) -> Vec<u8>;
+}
+
+/// Decodes the value of a header.
+pub trait FromHeader: Sized {
+    fn from_header(bytes: &[u8]) -> Result<Self, HeaderError>;
+}
+
+impl ToHeader for str {
+    fn to_header(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl ToHeader for String {
+    fn to_header(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl FromHeader for String {
+    fn from_header(bytes: &[u8]) -> Result<Self, HeaderError> {
+        Ok(str::from_utf8(bytes)?.to_string())
+    }
+}
+
+macro_rules! decimal_header {
+    ($($ty:ty),*) => {
+        $(
+            impl ToHeader for $ty {
+                fn to_header(&self) -> Vec<u8> {
+                    self.to_string().into_bytes()
+                }
+            }
+
+            impl FromHeader for $ty {
+                fn from_header(bytes: &[u8]) -> Result<Self, HeaderError> {
+                    Ok(str::from_utf8(bytes)?.parse()?)
+                }
+            }
+        )*
+    };
+}
+
+decimal_header!(i32, u32, i64, u64);
+
+/// Milliseconds since the epoch.
+impl ToHeader for SystemTime {
+    fn to_header(&self) -> Vec<u8> {
+        let millis = self
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_millis() as u64)
+            .unwrap_or(0);
+        millis.to_header()
+    }
+}
+
+impl FromHeader for SystemTime {
+    fn from_header(bytes: &[u8]) -> Result<Self, HeaderError> {
+        Ok(UNIX_EPOCH + Duration::from_millis(u64::from_header(bytes)?))
+    }
+}
+
+/// A W3C trace context, as carried by the `traceparent` header:
+/// `00-<trace id>-<parent id>-<flags>` in lowercase hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    /// The span that produced the record.
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// The start of a new, sampled trace.
+    pub fn new_root() -> Self {
+        let mut rng = rand::thread_rng();
+        TraceContext {
+            // all zeros is not a valid id
+            trace_id: rng.gen_range(1..=u128::MAX),
+            span_id: rng.gen_range(1..=u64::MAX),
+            sampled: true,
+        }
+    }
+
+    /// A new span of the same trace, e.g. for a record produced while
+    /// processing one that carried this context.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: rand::thread_rng().gen_range(1..=u64::MAX),
+            ..*self
+        }
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = HeaderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // lowercase hex of exactly `len` digits
+        fn hex(part: &str, len: usize) -> Option<u128> {
+            if part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+                u128::from_str_radix(part, 16).ok()
+            } else {
+                None
+            }
+        }
+
+        let parts: Vec<&str> = s.split('-').collect();
+        let parsed = match parts.as_slice() {
+            [version, trace_id, span_id, flags] if *version != "ff" => hex(version, 2)
+                .and(hex(trace_id, 32))
+                .zip(hex(span_id, 16))
+                .zip(hex(flags, 2)),
+            _ => None,
+        };
+        match parsed {
+            Some(((trace_id, span_id), flags)) if trace_id != 0 && span_id != 0 => {
+                Ok(TraceContext {
+                    trace_id,
+                    span_id: span_id as u64,
+                    sampled: flags & 1 == 1,
+                })
+            }
+            _ => Err(HeaderError::Invalid(format!(
+                "`{}` is not a W3C traceparent",
+                s
+            ))),
+        }
+    }
+}
+
+impl ToHeader for TraceContext {
+    fn to_header(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl FromHeader for TraceContext {
+    fn from_header(bytes: &[u8]) -> Result<Self, HeaderError> {
+        str::from_utf8(bytes)?.parse()
+    }
+}
+
+/// The headers of a record, in order. Kafka allows a key to appear more than
+/// once; the getters return the last value, like the Java client's `lastHeader`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordHeaders {
+    headers: Vec<(String, Option<Vec<u8>>)>,
+}
+
+impl RecordHeaders {
+    pub fn new() -> Self {
+        RecordHeaders::default()
+    }
+
+    /// A copy of the headers of `msg`.
+    pub fn from_message<M: Message>(msg: &M) -> Self {
+        let headers = msg
+            .headers()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .map(|header| (header.key.to_string(), header.value.map(<[u8]>::to_vec)))
+                    .collect()
+            })
+            .unwrap_or_default();
+        RecordHeaders { headers }
+    }
+
+    /// Appends `key` with `value` encoded.
+    pub fn insert<V: ToHeader + ?Sized>(self, key: &str, value: &V) -> Self {
+        self.insert_raw(key, Some(&value.to_header()))
+    }
+
+    /// Appends `key` with `value` as is, or without a value.
+    pub fn insert_raw(mut self, key: &str, value: Option<&[u8]>) -> Self {
+        self.headers
+            .push((key.to_string(), value.map(<[u8]>::to_vec)));
+        self
+    }
+
+    /// The last value of `key` decoded as a `V`, or `None` when the header is
+    /// missing. A header without a value is a [`HeaderError::Null`].
+    pub fn get<V: FromHeader>(&self, key: &str) -> Option<Result<V, HeaderError>> {
+        self.last(key).map(|value| match value {
+            Some(bytes) => V::from_header(bytes),
+            None => Err(HeaderError::Null),
+        })
+    }
+
+    /// The last value of `key` as is, `Some(None)` for a header without a value.
+    pub fn last(&self, key: &str) -> Option<Option<&[u8]>> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_deref())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.headers.iter().any(|(k, _)| k == key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&[u8]>)> {
+        self.headers
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_deref()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn content_type(&self) -> Option<Result<String, HeaderError>> {
+        self.get(CONTENT_TYPE)
+    }
+
+    pub fn schema_id(&self) -> Option<Result<u32, HeaderError>> {
+        self.get(SCHEMA_ID)
+    }
+
+    pub fn trace_context(&self) -> Option<Result<TraceContext, HeaderError>> {
+        self.get(TRACEPARENT)
+    }
+
+    pub fn app_id(&self) -> Option<Result<String, HeaderError>> {
+        self.get(APP_ID)
+    }
+
+    pub fn event_time(&self) -> Option<Result<SystemTime, HeaderError>> {
+        self.get(EVENT_TIME)
+    }
+
+    /// The headers of a record produced from this one, e.g. by a pipeline: a
+    /// child of its trace context and its event time, when it has them.
+    pub fn derived(&self) -> RecordHeaders {
+        let mut derived = RecordHeaders::new();
+        if let Some(Ok(trace)) = self.trace_context() {
+            derived = derived.insert(TRACEPARENT, &trace.child());
+        }
+        if let Some(Ok(event_time)) = self.event_time() {
+            derived = derived.insert(EVENT_TIME, &event_time);
+        }
+        derived
+    }
+
+    /// The headers in the form rdkafka's records take them.
+    pub fn to_owned_headers(&self) -> OwnedHeaders {
+        self.headers.iter().fold(
+            OwnedHeaders::new_with_capacity(self.len()),
+            |headers, (key, value)| {
+                headers.insert(Header {
+                    key,
+                    value: value.as_deref(),
+                })
+            },
+        )
+    }
+}
+
+/// `key=value, ...`, with values that are not UTF-8 shown by their length.
+impl fmt::Display for RecordHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            match value.map(str::from_utf8) {
+                Some(Ok(text)) => write!(f, "{}={}", key, text)?,
+                Some(Err(_)) => write!(f, "{}=<{} bytes>", key, value.unwrap_or_default().len())?,
+                None => write!(f, "{}=<null>", key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The headers a typed producer attaches to every record it sends.
+#[derive(Debug, Clone)]
+pub struct ProducerHeaders {
+    app_id: String,
+}
+
+impl ProducerHeaders {
+    /// Stamps records as produced by `app_id`.
+    pub fn new<N: Into<String>>(app_id: N) -> Self {
+        ProducerHeaders {
+            app_id: app_id.into(),
+        }
+    }
+
+    pub fn app_id(&self) -> &str {
+        &self.app_id
+    }
+
+    /// The headers of a record with `payload` written by `serializer`: the
+    /// stamped ones that `extra` does not set, followed by `extra`.
+    pub fn stamp<T, S>(
+        &self,
+        serializer: &S,
+        payload: &[u8],
+        extra: &RecordHeaders,
+    ) -> RecordHeaders
+    where
+        T: ?Sized,
+        S: Serializer<T>,
+    {
+        let mut headers = RecordHeaders::new();
+        let mut add = |key: &str, value: Vec<u8>| {
+            if !extra.contains(key) {
+                headers.headers.push((key.to_string(), Some(value)));
+            }
+        };
+        if let Some(content_type) = serializer.content_type() {
+            add(CONTENT_TYPE, content_type.to_header());
+        }
+        if let Some(schema_id) = serializer.schema_id(payload) {
+            add(SCHEMA_ID, schema_id.to_header());
+        }
+        add(TRACEPARENT, TraceContext::new_root().to_header());
+        add(APP_ID, self.app_id.to_header());
+        add(EVENT_TIME, SystemTime::now().to_header());
+
+        headers.headers.extend(extra.headers.iter().cloned());
+        headers
+    }
+}
+
+/// Why the value of a header could not be decoded.
+#[derive(Debug)]
+pub enum HeaderError {
+    /// The header is there but has no value.
+    Null,
+    NonUtf8(Utf8Error),
+    /// The text does not parse as the expected type.
+    Invalid(String),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Null => write!(f, "header has no value"),
+            HeaderError::NonUtf8(err) => write!(f, "header value is not UTF-8 - {}", err),
+            HeaderError::Invalid(err) => write!(f, "invalid header value - {}", err),
+        }
+    }
+}
+
+impl Error for HeaderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HeaderError::NonUtf8(err) => Some(err),
+            HeaderError::Null | HeaderError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<Utf8Error> for HeaderError {
+    fn from(err: Utf8Error) -> Self {
+        HeaderError::NonUtf8(err)
+    }
+}
+
+impl From<ParseIntError> for HeaderError {
+    fn from(err: ParseIntError) -> Self {
+        HeaderError::Invalid(err.to_string())
+    }
+}
@@ -0,0 +1,268 @@
+//! How far a consumer group is behind the end of its partitions.
+//!
+//! The lag of a partition is its high watermark minus the offset the group has
+//! committed. A partition without a committed offset counts everything still
+//! retained, from the low watermark, as it would be consumed with
+//! `auto.offset.reset = earliest`.
+//!
+//! A [`LagMonitor`] measures the partitions assigned to a consumer every
+//! `[lag] interval_ms`, from its poll loop:
+//!
+//! ```toml
+//! [lag]
+//! interval_ms = 30000
+//! ```
+//!
+//! Each measurement is logged and, given [`Metrics`], exported as
+//! `kafka101_consumer_lag` per partition and `kafka101_consumer_lag_total` per
+//! group and topic. [`measure`] works for any group and topic without joining
+//! the group, which is what the `9_lag_monitor` example does.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    consumer::{Consumer, ConsumerContext},
+    error::KafkaResult,
+    Offset, TopicPartitionList,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::{config::Settings, metrics::Metrics, replay};
+
+/// How long to wait for the committed offsets and watermarks of all partitions.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The lag of one partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartitionLag {
+    pub topic: String,
+    pub partition: i32,
+    /// The offset the group resumes from, if it has committed one.
+    pub committed: Option<i64>,
+    pub low: i64,
+    pub high: i64,
+    /// Records between the committed offset (or the low watermark) and the high watermark.
+    pub lag: i64,
+}
+
+/// The lag of a group on a set of partitions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LagReport {
+    pub group: String,
+    pub partitions: Vec<PartitionLag>,
+}
+
+impl LagReport {
+    /// The lag summed over all partitions.
+    pub fn total(&self) -> i64 {
+        self.partitions.iter().map(|p| p.lag).sum()
+    }
+
+    /// The lag summed per topic.
+    pub fn by_topic(&self) -> BTreeMap<&str, i64> {
+        let mut totals = BTreeMap::new();
+        for p in &self.partitions {
+            *totals.entry(p.topic.as_str()).or_default() += p.lag;
+        }
+        totals
+    }
+
+    /// Logs the lag of every partition, then the total.
+    pub fn log(&self) {
+        for p in &self.partitions {
+            info!(
+                group = self.group.as_str(),
+                topic = p.topic.as_str(),
+                partition = p.partition,
+                committed = ?p.committed,
+                low = p.low,
+                high = p.high,
+                lag = p.lag,
+                "partition lag"
+            );
+        }
+        info!(
+            group = self.group.as_str(),
+            partitions = self.partitions.len(),
+            lag = self.total(),
+            "consumer group lag"
+        );
+    }
+}
+
+impl fmt::Display for LagReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "group {} is {} records behind on {} partitions",
+            self.group,
+            self.total(),
+            self.partitions.len()
+        )
+    }
+}
+
+/// The lag of `consumer`'s group on the partitions in `tpl`. The committed
+/// offsets are those of the `group.id` the consumer was created with, whether
+/// or not it is subscribed, and `group` should be that id.
+pub fn measure<C, K>(
+    consumer: &K,
+    group: &str,
+    tpl: &TopicPartitionList,
+    timeout: Duration,
+) -> KafkaResult<LagReport>
+where
+    C: ConsumerContext,
+    K: Consumer<C>,
+{
+    let committed = consumer.committed_offsets(tpl.clone(), timeout)?;
+    let mut partitions = Vec::with_capacity(committed.count());
+    for e in committed.elements() {
+        let (low, high) = consumer.fetch_watermarks(e.topic(), e.partition(), timeout)?;
+        let committed = match e.offset() {
+            Offset::Offset(offset) => Some(offset),
+            _ => None,
+        };
+        // a committed offset below the low watermark was deleted by retention
+        let from = committed.unwrap_or(low).max(low);
+        partitions.push(PartitionLag {
+            topic: e.topic().to_string(),
+            partition: e.partition(),
+            committed,
+            low,
+            high,
+            lag: (high - from).max(0),
+        });
+    }
+    Ok(LagReport {
+        group: group.to_string(),
+        partitions,
+    })
+}
+
+/// The lag of `consumer`'s group on every partition of `topic`.
+pub fn measure_topic<C, K>(
+    consumer: &K,
+    group: &str,
+    topic: &str,
+    timeout: Duration,
+) -> KafkaResult<LagReport>
+where
+    C: ConsumerContext,
+    K: Consumer<C>,
+{
+    let mut tpl = TopicPartitionList::new();
+    for partition in replay::partitions(consumer, topic, timeout)? {
+        tpl.add_partition(topic, partition);
+    }
+    measure(consumer, group, &tpl, timeout)
+}
+
+/// Measures the lag of a consumer's assigned partitions every `interval`.
+pub struct LagMonitor {
+    group: String,
+    settings: LagSettings,
+    metrics: Option<Metrics>,
+    last: Mutex<Option<Instant>>,
+}
+
+impl LagMonitor {
+    /// A monitor for a consumer of `group`.
+    pub fn new<G: Into<String>>(group: G, settings: LagSettings) -> LagMonitor {
+        LagMonitor {
+            group: group.into(),
+            settings,
+            metrics: None,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Also exports every measurement through `metrics`.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Measures, logs and exports the lag of the assigned partitions if the
+    /// interval has passed since the last time. Call it on every turn of the
+    /// poll loop; it returns the report when one was taken.
+    pub fn tick<C, K>(&self, consumer: &K) -> Option<LagReport>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        {
+            let mut last = self.last.lock().unwrap();
+            if matches!(*last, Some(at) if at.elapsed() < self.settings.interval) {
+                return None;
+            }
+            *last = Some(Instant::now());
+        }
+
+        let assigned = match consumer.assignment() {
+            Ok(assigned) if assigned.count() > 0 => assigned,
+            // not measured until the first assignment
+            Ok(_) => return None,
+            Err(err) => {
+                warn!(error = %err, "failed to read assignment");
+                return None;
+            }
+        };
+        match measure(consumer, &self.group, &assigned, DEFAULT_FETCH_TIMEOUT) {
+            Ok(report) => {
+                report.log();
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_lag(&report);
+                }
+                Some(report)
+            }
+            Err(err) => {
+                warn!(error = %err, "failed to measure consumer lag");
+                None
+            }
+        }
+    }
+}
+
+impl LagMonitor {
+    /// A monitor for a consumer of the configured group, at the configured interval.
+    pub fn from_settings(settings: &Settings) -> LagMonitor {
+        LagMonitor::new(&settings.group_id, settings.lag)
+    }
+}
+
+/// The resolved `[lag]` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LagSettings {
+    /// How often the lag is measured.
+    pub interval: Duration,
+}
+
+impl Default for LagSettings {
+    fn default() -> Self {
+        LagSettings {
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The `[lag]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LagConfig {
+    interval_ms: Option<u64>,
+}
+
+impl LagConfig {
+    pub fn apply(self, settings: &mut LagSettings) {
+        if let Some(ms) = self.interval_ms {
+            settings.interval = Duration::from_millis(ms);
+        }
+    }
+}
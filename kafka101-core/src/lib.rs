@@ -0,0 +1,58 @@
+//! Shared types for the `part1` producer and `part2` consumer examples.
+//!
+//! The binaries in this repo used to carry their own copies of `User` and the
+//! callback loggers. They now depend on this crate instead, and so can any
+//! service that wants the same behaviour without forking the examples.
+
+pub mod auth;
+pub mod avro;
+pub mod codec;
+pub mod commit;
+pub mod config;
+pub mod consumer;
+pub mod delivery;
+pub mod dlq;
+pub mod headers;
+pub mod lag;
+pub mod logging;
+pub mod memory;
+pub mod metrics;
+pub mod parallel;
+pub mod partition;
+pub mod poison;
+pub mod producer;
+pub mod protobuf;
+pub mod replay;
+pub mod schema_registry;
+pub mod shutdown;
+pub mod simulation;
+pub mod transaction;
+pub mod user;
+
+pub use auth::{Auth, AuthContext};
+pub use avro::{AvroRecord, AvroSerde};
+pub use codec::{Deserializer, Format, FormatSerde, JsonSerde, Serializer};
+pub use commit::{CommitError, CommitManager, CommitStrategy, OffsetCommitter};
+pub use config::Settings;
+pub use consumer::{
+    ConsumerCallbackLogger, RebalanceListener, StreamTypedConsumer, TypedConsumer, ValueConsumer,
+};
+pub use delivery::{DeliveryReport, DeliveryTracker};
+pub use dlq::{DeadLetterQueue, RetryPolicy};
+pub use headers::{ProducerHeaders, RecordHeaders};
+pub use lag::{LagMonitor, LagReport};
+pub use memory::{InMemoryConsumer, InMemoryKafka, InMemoryProducer};
+pub use metrics::Metrics;
+pub use parallel::WorkerPool;
+pub use partition::{PartitionStrategy, ValuePartitioner};
+pub use poison::{PoisonPill, PoisonPillHandler, PoisonStrategy};
+pub use producer::{
+    DeliverySpan, FutureTypedProducer, ProduceCallbackLogger, TypedProducer, ValueProducer,
+};
+pub use protobuf::{ProtobufRecord, ProtobufSerde};
+pub use replay::{EndPosition, ReplayWindow, StartPosition};
+pub use schema_registry::{SchemaRegistryClient, SubjectNameStrategy};
+pub use shutdown::Shutdown;
+pub use simulation::FailureSimulator;
+pub use transaction::{BatchOffsets, TransactionError, TransactionalProducer};
+pub use user::User;
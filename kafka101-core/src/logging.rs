@@ -0,0 +1,141 @@
+//! Structured logging with `tracing`.
+//!
+//! Delivery reports, rebalances, commits and the other client events are
+//! `tracing` events with fields (`topic`, `partition`, `offset`, `key`,
+//! `latency_ms`, `error_code`) rather than formatted lines. Two kinds of span
+//! tie them together:
+//!
+//! - `produce`, from [`TypedProducer::send`](crate::TypedProducer::send) to the
+//!   delivery report of the record
+//! - `consume`, from the moment a typed consumer hands out a message until it is
+//!   dropped, which the examples do once it is committed. See
+//!   [`Received::span`](crate::consumer::Received::span).
+//!
+//! Both are logged when they close, with how long they were open.
+//!
+//! [`init`] installs the subscriber picked by the `[logging]` section (or
+//! `KAFKA_LOG_FORMAT` and `KAFKA_LOG_LEVEL`):
+//!
+//! ```toml
+//! [logging]
+//! format = "json"               # text (the default), pretty or json
+//! level = "info,rdkafka=warn"   # an env filter, like RUST_LOG
+//! ```
+//!
+//! `json` writes one JSON object per line, with the event fields at the top
+//! level and the fields of the enclosing span under `span`.
+
+use std::{error::Error, fmt, str::FromStr};
+
+use serde::Deserialize;
+use tracing::warn;
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+/// How events are written to stdout.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One line per event.
+    #[default]
+    Text,
+    /// Several lines per event, for reading in a terminal.
+    Pretty,
+    /// One JSON object per line, for log pipelines.
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!(
+                "unknown log format `{}`, expected text, pretty or json",
+                other
+            )),
+        }
+    }
+}
+
+/// Installs the global `tracing` subscriber. Fails if one is installed already.
+/// Warnings found while loading the configuration are logged right after.
+pub fn init(settings: &LoggingSettings) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let filter = EnvFilter::try_new(&settings.level)?;
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+    match settings.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Pretty => builder.pretty().try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .try_init(),
+    }?;
+    for warning in &settings.warnings {
+        warn!("{}", warning);
+    }
+    Ok(())
+}
+
+/// Whether `level` is a valid filter, and why not.
+pub(crate) fn check_level(level: &str) -> Result<(), String> {
+    EnvFilter::try_new(level)
+        .map(|_| ())
+        .map_err(|err| format!("invalid log level `{}` - {}", level, err))
+}
+
+/// The resolved `[logging]` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoggingSettings {
+    pub format: LogFormat,
+    /// Which events to write, in `RUST_LOG` syntax.
+    pub level: String,
+    /// Configuration problems that did not stop loading it, logged by [`init`]
+    /// because there is no logger yet while loading.
+    pub warnings: Vec<String>,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        LoggingSettings {
+            format: LogFormat::default(),
+            level: "info".to_string(),
+            warnings: Vec::new(),
+        }
+    }
+}
+
+/// The `[logging]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LoggingConfig {
+    format: Option<LogFormat>,
+    level: Option<String>,
+}
+
+impl LoggingConfig {
+    pub fn apply(self, settings: &mut LoggingSettings) {
+        if let Some(format) = self.format {
+            settings.format = format;
+        }
+        if let Some(level) = self.level {
+            settings.level = level;
+        }
+    }
+}
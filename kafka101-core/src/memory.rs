@@ -0,0 +1,530 @@
+//! A Kafka cluster that lives in memory, for testing the code around the
+//! clients without a broker.
+//!
+//! An [`InMemoryKafka`] keeps topics as partitioned logs of records, and
+//! consumer groups with the offsets they committed. [`InMemoryProducer`] and
+//! [`InMemoryConsumer`] implement [`ValueProducer`] and [`ValueConsumer`], like
+//! [`TypedProducer`](crate::TypedProducer) and
+//! [`TypedConsumer`](crate::TypedConsumer) do, so processing written against
+//! the traits runs the same against both.
+//!
+//! It only follows the parts of Kafka the examples depend on:
+//!
+//! - keyed records go to the partition librdkafka's default partitioner picks,
+//!   the CRC32 of the key modulo the partition count, unless the producer has
+//!   a [`ValuePartitioner`]; records without a key go to the partitions in turn;
+//! - the members of a group share the partitions of the topics they subscribed
+//!   to, each topic split into ranges in the order the members joined, like
+//!   the range assignor. Every join, leave or new topic starts a new
+//!   generation of the group;
+//! - a member picks up its assignment on its next poll, and resumes every
+//!   assigned partition from the offset the group committed, or from the
+//!   beginning, as with the eager assignors and `auto.offset.reset = earliest`;
+//! - a commit from a member that has not polled since the group changed is
+//!   refused with `REBALANCE_IN_PROGRESS`.
+//!
+//! Nothing runs in the background and polls never wait: a poll returns the
+//! next record that is already there, or `None`. What a test sees therefore
+//! only depends on what it did, in the order it did it.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use rdkafka::{
+    error::{KafkaError, KafkaResult},
+    message::{OwnedHeaders, OwnedMessage},
+    types::RDKafkaErrorCode,
+    Message, Timestamp,
+};
+
+use crate::{
+    codec::{Deserializer, Serializer},
+    consumer::{self, OwnedReceived, ReceiveError, Undecoded, ValueConsumer},
+    headers::{ProducerHeaders, RecordHeaders},
+    partition::{self, ValuePartitioner},
+    poison::PoisonPill,
+    producer::{SendError, ValueProducer},
+};
+
+/// The topics and consumer groups of a cluster. Clones share them.
+#[derive(Clone, Default)]
+pub struct InMemoryKafka {
+    cluster: Arc<Mutex<Cluster>>,
+}
+
+#[derive(Default)]
+struct Cluster {
+    topics: BTreeMap<String, Topic>,
+    groups: BTreeMap<String, Group>,
+    next_member: u64,
+}
+
+struct Topic {
+    partitions: Vec<Vec<OwnedMessage>>,
+    /// Where the next record without a key goes.
+    next_partition: usize,
+}
+
+#[derive(Default)]
+struct Group {
+    generation: i32,
+    /// In the order they joined.
+    members: Vec<Member>,
+    committed: BTreeMap<(String, i32), i64>,
+}
+
+struct Member {
+    id: u64,
+    topics: Vec<String>,
+}
+
+impl InMemoryKafka {
+    pub fn new() -> Self {
+        InMemoryKafka::default()
+    }
+
+    /// Creates `topic` with `partitions` empty partitions, unless it exists.
+    pub fn create_topic(&self, topic: &str, partitions: i32) {
+        assert!(partitions > 0, "topic {} needs a partition", topic);
+        let mut cluster = self.lock();
+        if cluster.topics.contains_key(topic) {
+            return;
+        }
+        cluster.topics.insert(
+            topic.to_string(),
+            Topic {
+                partitions: (0..partitions).map(|_| Vec::new()).collect(),
+                next_partition: 0,
+            },
+        );
+        // the groups subscribed to it rebalance to share its partitions
+        for group in cluster.groups.values_mut() {
+            if group.members.iter().any(|m| m.subscribes(topic)) {
+                group.generation += 1;
+            }
+        }
+    }
+
+    /// Appends a record to `topic`, as a producer would, and returns the
+    /// partition and offset it was written to. Raw payloads are how tests
+    /// plant poison pills.
+    pub fn append(
+        &self,
+        topic: &str,
+        key: Option<&[u8]>,
+        payload: Option<&[u8]>,
+    ) -> KafkaResult<(i32, i64)> {
+        self.append_to(topic, None, key, payload, None)
+    }
+
+    /// Appends a record to `partition` of `topic`, or to the one the default
+    /// partitioner picks.
+    fn append_to(
+        &self,
+        topic: &str,
+        partition: Option<i32>,
+        key: Option<&[u8]>,
+        payload: Option<&[u8]>,
+        headers: Option<OwnedHeaders>,
+    ) -> KafkaResult<(i32, i64)> {
+        let mut cluster = self.lock();
+        let log = cluster
+            .topics
+            .get_mut(topic)
+            .ok_or(KafkaError::MessageProduction(
+                RDKafkaErrorCode::UnknownTopicOrPartition,
+            ))?;
+        let count = log.partitions.len();
+        let partition = match (partition, key) {
+            (Some(partition), _) if (0..count as i32).contains(&partition) => partition as usize,
+            (Some(_), _) => {
+                return Err(KafkaError::MessageProduction(
+                    RDKafkaErrorCode::UnknownPartition,
+                ))
+            }
+            (None, Some(key)) => partition::crc32(key) as usize % count,
+            (None, None) => {
+                let partition = log.next_partition;
+                log.next_partition = (partition + 1) % count;
+                partition
+            }
+        };
+        let records = &mut log.partitions[partition];
+        let offset = records.len() as i64;
+        records.push(OwnedMessage::new(
+            payload.map(<[u8]>::to_vec),
+            key.map(<[u8]>::to_vec),
+            topic.to_string(),
+            Timestamp::NotAvailable,
+            partition as i32,
+            offset,
+            headers,
+        ));
+        Ok((partition as i32, offset))
+    }
+
+    /// A producer of values of `T` to `topic`.
+    pub fn producer<T, S>(&self, topic: &str, serializer: S) -> InMemoryProducer<T, S>
+    where
+        T: ?Sized,
+        S: Serializer<T>,
+    {
+        InMemoryProducer {
+            kafka: self.clone(),
+            topic: topic.to_string(),
+            serializer,
+            partitioner: None,
+            headers: None,
+            _value: PhantomData,
+        }
+    }
+
+    /// A consumer in `group`. It joins the group once it subscribes.
+    pub fn consumer<T, D>(&self, group: &str, deserializer: D) -> InMemoryConsumer<T, D>
+    where
+        D: Deserializer<T>,
+    {
+        let member = {
+            let mut cluster = self.lock();
+            cluster.next_member += 1;
+            cluster.next_member
+        };
+        InMemoryConsumer {
+            kafka: self.clone(),
+            group: group.to_string(),
+            member,
+            deserializer,
+            require_key: false,
+            state: Mutex::new(MemberState::default()),
+            _value: PhantomData,
+        }
+    }
+
+    /// Every record of `topic`, ordered by partition and offset.
+    pub fn records(&self, topic: &str) -> Vec<OwnedMessage> {
+        self.lock()
+            .topics
+            .get(topic)
+            .map(|log| log.partitions.iter().flatten().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The offset the next record of every partition of `topic` will get.
+    pub fn end_offsets(&self, topic: &str) -> Vec<i64> {
+        self.lock()
+            .topics
+            .get(topic)
+            .map(|log| {
+                log.partitions
+                    .iter()
+                    .map(|records| records.len() as i64)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The offsets `group` has committed on every partition of `topic`, `None`
+    /// where it has not committed any.
+    pub fn committed(&self, group: &str, topic: &str) -> Vec<Option<i64>> {
+        let cluster = self.lock();
+        let partitions = cluster
+            .topics
+            .get(topic)
+            .map_or(0, |log| log.partitions.len() as i32);
+        let committed = cluster.groups.get(group).map(|group| &group.committed);
+        (0..partitions)
+            .map(|partition| {
+                committed
+                    .and_then(|committed| committed.get(&(topic.to_string(), partition)))
+                    .copied()
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cluster> {
+        self.cluster.lock().unwrap()
+    }
+}
+
+impl fmt::Debug for InMemoryKafka {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cluster = self.lock();
+        f.debug_struct("InMemoryKafka")
+            .field("topics", &cluster.topics.keys().collect::<Vec<_>>())
+            .field("groups", &cluster.groups.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Member {
+    fn subscribes(&self, topic: &str) -> bool {
+        self.topics.iter().any(|t| t == topic)
+    }
+}
+
+impl Group {
+    /// The partitions of `member` in the current generation: a range of each
+    /// topic it subscribed to, by its rank among the members subscribed to it.
+    fn assignment(&self, member: u64, topics: &BTreeMap<String, Topic>) -> Vec<(String, i32)> {
+        let subscribed = match self.members.iter().find(|m| m.id == member) {
+            Some(m) => &m.topics,
+            None => return Vec::new(),
+        };
+        let mut assignment = Vec::new();
+        for topic in subscribed {
+            let partitions = match topics.get(topic) {
+                Some(log) => log.partitions.len(),
+                None => continue,
+            };
+            let members: Vec<u64> = self
+                .members
+                .iter()
+                .filter(|m| m.subscribes(topic))
+                .map(|m| m.id)
+                .collect();
+            let rank = members.iter().position(|&id| id == member).unwrap();
+            let (share, extra) = (partitions / members.len(), partitions % members.len());
+            let first = rank * share + rank.min(extra);
+            let count = share + usize::from(rank < extra);
+            assignment.extend((first..first + count).map(|p| (topic.clone(), p as i32)));
+        }
+        assignment
+    }
+}
+
+/// Sends values of `T` to a topic of an [`InMemoryKafka`].
+pub struct InMemoryProducer<T: ?Sized, S> {
+    kafka: InMemoryKafka,
+    topic: String,
+    serializer: S,
+    partitioner: Option<Arc<dyn ValuePartitioner<T>>>,
+    headers: Option<ProducerHeaders>,
+    _value: PhantomData<fn(&T)>,
+}
+
+impl<T: ?Sized, S> InMemoryProducer<T, S> {
+    /// Lets `partitioner` pick the partition of every record, see
+    /// [`crate::partition`].
+    pub fn with_partitioner(mut self, partitioner: Arc<dyn ValuePartitioner<T>>) -> Self {
+        self.partitioner = Some(partitioner);
+        self
+    }
+
+    /// Stamps every record with the headers of [`crate::headers`].
+    pub fn with_headers(mut self, headers: ProducerHeaders) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+}
+
+impl<T, S> ValueProducer<T> for InMemoryProducer<T, S>
+where
+    T: ?Sized,
+    S: Serializer<T>,
+{
+    /// Fails with `UnknownTopicOrPartition` when the topic was not created.
+    fn send_with_headers(
+        &self,
+        key: &str,
+        value: &T,
+        headers: &RecordHeaders,
+    ) -> Result<(), SendError> {
+        let payload = self
+            .serializer
+            .serialize(&self.topic, value)
+            .map_err(SendError::Serialization)?;
+        let partition = match &self.partitioner {
+            Some(partitioner) => {
+                let partitions = self.kafka.end_offsets(&self.topic).len() as i32;
+                if partitions == 0 {
+                    return Err(SendError::Kafka(KafkaError::MessageProduction(
+                        RDKafkaErrorCode::UnknownTopicOrPartition,
+                    )));
+                }
+                Some(partitioner.partition(key, value, partitions))
+            }
+            None => None,
+        };
+        let headers = match &self.headers {
+            Some(stamp) => stamp.stamp(&self.serializer, &payload, headers),
+            None => headers.clone(),
+        };
+        let headers = Some(headers)
+            .filter(|headers| !headers.is_empty())
+            .map(|headers| headers.to_owned_headers());
+        self.kafka
+            .append_to(
+                &self.topic,
+                partition,
+                Some(key.as_bytes()),
+                Some(&payload),
+                headers,
+            )
+            .map(|_| ())
+            .map_err(SendError::Kafka)
+    }
+
+    fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+/// A member of a consumer group of an [`InMemoryKafka`]. It leaves the group
+/// when dropped.
+pub struct InMemoryConsumer<T, D> {
+    kafka: InMemoryKafka,
+    group: String,
+    member: u64,
+    deserializer: D,
+    require_key: bool,
+    state: Mutex<MemberState>,
+    _value: PhantomData<fn() -> T>,
+}
+
+/// What a member knows of its group, as of its last poll.
+#[derive(Default)]
+struct MemberState {
+    /// Zero until the first poll after subscribing.
+    generation: i32,
+    assignment: Vec<(String, i32)>,
+    positions: BTreeMap<(String, i32), i64>,
+    /// The index in `assignment` to read from first, so that partitions take turns.
+    next: usize,
+}
+
+impl<T, D> InMemoryConsumer<T, D>
+where
+    D: Deserializer<T>,
+{
+    /// Report messages without a UTF-8 key as poison pills instead of decoding them.
+    pub fn require_key(mut self, require_key: bool) -> Self {
+        self.require_key = require_key;
+        self
+    }
+
+    /// Joins the group, or changes what this member subscribed to. Either
+    /// starts a new generation of the group.
+    pub fn subscribe(&self, topics: &[&str]) {
+        let mut cluster = self.kafka.lock();
+        let group = cluster.groups.entry(self.group.clone()).or_default();
+        let topics = topics.iter().map(|topic| topic.to_string()).collect();
+        match group.members.iter_mut().find(|m| m.id == self.member) {
+            Some(member) => member.topics = topics,
+            None => group.members.push(Member {
+                id: self.member,
+                topics,
+            }),
+        }
+        group.generation += 1;
+    }
+
+    /// The partitions assigned to this member, as of its last poll.
+    pub fn assignment(&self) -> Vec<(String, i32)> {
+        self.state.lock().unwrap().assignment.clone()
+    }
+
+    fn next_message(&self) -> Option<OwnedMessage> {
+        let cluster = self.kafka.lock();
+        let mut state = self.state.lock().unwrap();
+        let group = cluster.groups.get(&self.group)?;
+        if state.generation != group.generation {
+            let assignment = group.assignment(self.member, &cluster.topics);
+            state.positions = assignment
+                .iter()
+                .map(|tp| (tp.clone(), group.committed.get(tp).copied().unwrap_or(0)))
+                .collect();
+            state.assignment = assignment;
+            state.generation = group.generation;
+            state.next = 0;
+        }
+
+        let count = state.assignment.len();
+        for i in 0..count {
+            let index = (state.next + i) % count;
+            let (topic, partition) = state.assignment[index].clone();
+            let position = state.positions[&(topic.clone(), partition)];
+            let records = &cluster.topics[&topic].partitions[partition as usize];
+            if let Some(message) = records.get(position as usize) {
+                state.positions.insert((topic, partition), position + 1);
+                state.next = index + 1;
+                return Some(message.clone());
+            }
+        }
+        None
+    }
+
+    fn rewind(&self, msg: &OwnedMessage) {
+        let mut state = self.state.lock().unwrap();
+        let tp = (msg.topic().to_string(), msg.partition());
+        if state.positions.contains_key(&tp) {
+            state.positions.insert(tp, msg.offset());
+        }
+    }
+}
+
+impl<T, D> ValueConsumer<T> for InMemoryConsumer<T, D>
+where
+    D: Deserializer<T>,
+{
+    /// Returns the next record already in the assigned partitions, taking the
+    /// partitions in turn, without waiting for `timeout`.
+    fn poll(&self, _timeout: Duration) -> Option<Result<OwnedReceived<T>, ReceiveError>> {
+        let message = self.next_message()?;
+        Some(
+            match consumer::decode_value(&self.deserializer, self.require_key, &message) {
+                Ok(value) => Ok(OwnedReceived::new(message, value)),
+                Err(Undecoded::Poison(kind, error)) => {
+                    Err(ReceiveError::Poison(Box::new(PoisonPill {
+                        kind,
+                        message,
+                        error,
+                    })))
+                }
+                Err(Undecoded::Unavailable(error)) => {
+                    // received again by the next poll
+                    self.rewind(&message);
+                    Err(ReceiveError::Unavailable {
+                        error,
+                        rewound: true,
+                    })
+                }
+            },
+        )
+    }
+
+    /// Fails with `RebalanceInProgress` when the group changed since the last
+    /// poll, or this member never joined it.
+    fn commit(&self, msg: &OwnedMessage) -> KafkaResult<()> {
+        let mut cluster = self.kafka.lock();
+        let state = self.state.lock().unwrap();
+        match cluster.groups.get_mut(&self.group) {
+            Some(group) if state.generation != 0 && group.generation == state.generation => {
+                group
+                    .committed
+                    .insert((msg.topic().to_string(), msg.partition()), msg.offset() + 1);
+                Ok(())
+            }
+            _ => Err(KafkaError::ConsumerCommit(
+                RDKafkaErrorCode::RebalanceInProgress,
+            )),
+        }
+    }
+}
+
+impl<T, D> Drop for InMemoryConsumer<T, D> {
+    fn drop(&mut self) {
+        let mut cluster = self.kafka.lock();
+        if let Some(group) = cluster.groups.get_mut(&self.group) {
+            let before = group.members.len();
+            group.members.retain(|m| m.id != self.member);
+            if group.members.len() < before {
+                group.generation += 1;
+            }
+        }
+    }
+}
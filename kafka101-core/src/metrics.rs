@@ -0,0 +1,463 @@
+//! A Prometheus `/metrics` endpoint for the example clients.
+//!
+//! [`Metrics`] keeps application counters (records produced, failed
+//! deliveries, offset commits, processing errors) next to the latest
+//! statistics librdkafka reported for each client: queue depth, broker round
+//! trip times, requests waiting for a response, consumer lag per partition and
+//! rebalances. It also keeps the group lag measured by a
+//! [`LagMonitor`](crate::lag::LagMonitor). Hand a clone to the contexts with
+//! [`ProduceCallbackLogger::with_metrics`](crate::ProduceCallbackLogger::with_metrics)
+//! and [`ConsumerCallbackLogger::with_metrics`](crate::ConsumerCallbackLogger::with_metrics),
+//! and serve it with [`Metrics::serve`]:
+//!
+//! ```toml
+//! [metrics]
+//! listen = "127.0.0.1:9091"
+//! statistics_interval_ms = 5000
+//! ```
+//!
+//! With `listen` set (or `KAFKA_METRICS_LISTEN`) the client configs get
+//! `statistics.interval.ms`, so librdkafka calls the contexts' `stats`
+//! callback that often. Without it no statistics are collected.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write as _},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use rdkafka::statistics::{Statistics, Window};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::{config::Settings, lag::LagReport};
+
+/// How long the endpoint waits for a scraper to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Application counters and the latest librdkafka statistics of each client.
+/// Clones share the same values.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    produced: AtomicU64,
+    delivery_failures: AtomicU64,
+    commits: AtomicU64,
+    commit_failures: AtomicU64,
+    processing_errors: AtomicU64,
+    /// The last statistics reported by each client, by client name.
+    clients: Mutex<BTreeMap<String, Statistics>>,
+    /// The last lag measured for each consumer group.
+    lag: Mutex<BTreeMap<String, LagReport>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// New metrics, served on `[metrics] listen` when it is set.
+    pub fn from_settings(settings: &Settings) -> io::Result<Metrics> {
+        let metrics = Metrics::new();
+        if let Some(listen) = settings.metrics.listen {
+            metrics.serve(listen)?;
+        }
+        Ok(metrics)
+    }
+
+    /// Keeps `stats` as the current statistics of the client that reported them.
+    pub fn record_stats(&self, stats: Statistics) {
+        self.inner
+            .clients
+            .lock()
+            .unwrap()
+            .insert(stats.name.clone(), stats);
+    }
+
+    /// Keeps `report` as the current lag of its group.
+    pub fn record_lag(&self, report: &LagReport) {
+        self.inner
+            .lag
+            .lock()
+            .unwrap()
+            .insert(report.group.clone(), report.clone());
+    }
+
+    /// A record was acknowledged by the broker.
+    pub fn produced(&self) {
+        self.inner.produced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A record could not be delivered.
+    pub fn delivery_failed(&self) {
+        self.inner.delivery_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An offset commit succeeded.
+    pub fn committed(&self) {
+        self.inner.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// An offset commit failed.
+    pub fn commit_failed(&self) {
+        self.inner.commit_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The application failed to process a record.
+    pub fn processing_failed(&self) {
+        self.inner.processing_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Everything in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "kafka101_messages_produced_total",
+                "Records acknowledged by the broker.",
+                &self.inner.produced,
+            ),
+            (
+                "kafka101_delivery_failures_total",
+                "Records that could not be delivered.",
+                &self.inner.delivery_failures,
+            ),
+            (
+                "kafka101_commits_total",
+                "Successful offset commits.",
+                &self.inner.commits,
+            ),
+            (
+                "kafka101_commit_failures_total",
+                "Failed offset commits.",
+                &self.inner.commit_failures,
+            ),
+            (
+                "kafka101_processing_errors_total",
+                "Records the application failed to process.",
+                &self.inner.processing_errors,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            let sample = vec![(String::new(), value.load(Ordering::Relaxed) as f64)];
+            family(&mut out, name, help, "counter", &sample);
+        }
+
+        let clients = self.inner.clients.lock().unwrap();
+        for (name, help, kind, samples) in client_families(&clients) {
+            family(&mut out, name, help, kind, &samples);
+        }
+
+        let lag = self.inner.lag.lock().unwrap();
+        let mut partitions = Vec::new();
+        let mut totals = Vec::new();
+        for (group, report) in lag.iter() {
+            for p in &report.partitions {
+                let partition = p.partition.to_string();
+                let labels = labels(&[
+                    ("group", group),
+                    ("topic", &p.topic),
+                    ("partition", &partition),
+                ]);
+                partitions.push((labels, p.lag as f64));
+            }
+            for (topic, total) in report.by_topic() {
+                let labels = labels(&[("group", group), ("topic", topic)]);
+                totals.push((labels, total as f64));
+            }
+        }
+        family(
+            &mut out,
+            "kafka101_consumer_lag",
+            "Records between the committed offset of the group and the high watermark.",
+            "gauge",
+            &partitions,
+        );
+        family(
+            &mut out,
+            "kafka101_consumer_lag_total",
+            "Consumer lag summed over the partitions of a topic.",
+            "gauge",
+            &totals,
+        );
+        out
+    }
+
+    /// Serves [`render`](Self::render) on `http://<addr>/metrics` from a
+    /// background thread, for as long as the process runs. Returns the address
+    /// actually bound, which differs from `addr` when its port is 0.
+    pub fn serve(&self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        let bound = listener.local_addr()?;
+        let metrics = self.clone();
+        thread::Builder::new()
+            .name("metrics".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let result = stream.and_then(|stream| metrics.respond(stream));
+                    if let Err(err) = result {
+                        debug!(error = %err, "failed to answer metrics request");
+                    }
+                }
+            })?;
+        info!(addr = %bound, "serving metrics");
+        Ok(bound)
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut request_line = String::new();
+        BufReader::new(&stream).read_line(&mut request_line)?;
+
+        let mut parts = request_line.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), Some(_)) => ("404 Not Found", "try /metrics\n".to_string()),
+            _ => ("405 Method Not Allowed", String::new()),
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
+
+type Samples = Vec<(String, f64)>;
+
+/// The librdkafka statistics of every client, grouped by metric.
+fn client_families(
+    clients: &BTreeMap<String, Statistics>,
+) -> Vec<(&'static str, &'static str, &'static str, Samples)> {
+    let mut queue_messages = Vec::new();
+    let mut queue_bytes = Vec::new();
+    let mut tx_messages = Vec::new();
+    let mut rx_messages = Vec::new();
+    let mut outbuf = Vec::new();
+    let mut waitresp = Vec::new();
+    let mut rtt_avg = Vec::new();
+    let mut rtt_p99 = Vec::new();
+    let mut req_timeouts = Vec::new();
+    let mut partition_queue = Vec::new();
+    let mut lag = Vec::new();
+    let mut rebalances = Vec::new();
+    let mut assigned = Vec::new();
+
+    for (name, stats) in clients {
+        let client = labels(&[("client", name), ("type", &stats.client_type)]);
+        queue_messages.push((client.clone(), stats.msg_cnt as f64));
+        queue_bytes.push((client.clone(), stats.msg_size as f64));
+        tx_messages.push((client.clone(), stats.txmsgs as f64));
+        rx_messages.push((client.clone(), stats.rxmsgs as f64));
+
+        for broker in stats.brokers.values() {
+            // the bootstrap and coordinator entries duplicate the real brokers
+            if broker.nodeid < 0 {
+                continue;
+            }
+            let broker_labels = labels(&[("client", name), ("broker", &broker.nodename)]);
+            outbuf.push((broker_labels.clone(), broker.outbuf_msg_cnt as f64));
+            waitresp.push((broker_labels.clone(), broker.waitresp_cnt as f64));
+            req_timeouts.push((broker_labels.clone(), broker.req_timeouts as f64));
+            if let Some(rtt) = &broker.rtt {
+                rtt_avg.push((broker_labels.clone(), seconds(rtt, rtt.avg)));
+                rtt_p99.push((broker_labels, seconds(rtt, rtt.p99)));
+            }
+        }
+
+        for topic in stats.topics.values() {
+            for partition in topic.partitions.values() {
+                // -1 is librdkafka's internal partition for unassigned messages
+                if partition.partition < 0 {
+                    continue;
+                }
+                let partition_labels = labels(&[
+                    ("client", name),
+                    ("topic", &topic.topic),
+                    ("partition", &partition.partition.to_string()),
+                ]);
+                if stats.client_type == "producer" {
+                    let queued = partition.msgq_cnt + partition.xmit_msgq_cnt;
+                    partition_queue.push((partition_labels, queued as f64));
+                } else if partition.consumer_lag >= 0 {
+                    // -1 until the partition has been fetched from
+                    lag.push((partition_labels, partition.consumer_lag as f64));
+                }
+            }
+        }
+
+        if let Some(cgrp) = &stats.cgrp {
+            let group = labels(&[("client", name)]);
+            rebalances.push((group.clone(), cgrp.rebalance_cnt as f64));
+            assigned.push((group, cgrp.assignment_size as f64));
+        }
+    }
+
+    vec![
+        (
+            "rdkafka_queue_messages",
+            "Messages waiting in the client's queues.",
+            "gauge",
+            queue_messages,
+        ),
+        (
+            "rdkafka_queue_bytes",
+            "Size of the messages waiting in the client's queues.",
+            "gauge",
+            queue_bytes,
+        ),
+        (
+            "rdkafka_tx_messages_total",
+            "Messages sent to brokers.",
+            "counter",
+            tx_messages,
+        ),
+        (
+            "rdkafka_rx_messages_total",
+            "Messages received from brokers.",
+            "counter",
+            rx_messages,
+        ),
+        (
+            "rdkafka_broker_outbuf_messages",
+            "Messages waiting to be sent to the broker.",
+            "gauge",
+            outbuf,
+        ),
+        (
+            "rdkafka_broker_waitresp_requests",
+            "Requests sent to the broker and waiting for a response.",
+            "gauge",
+            waitresp,
+        ),
+        (
+            "rdkafka_broker_request_timeouts_total",
+            "Requests to the broker that timed out.",
+            "counter",
+            req_timeouts,
+        ),
+        (
+            "rdkafka_broker_rtt_avg_seconds",
+            "Average round trip time to the broker.",
+            "gauge",
+            rtt_avg,
+        ),
+        (
+            "rdkafka_broker_rtt_p99_seconds",
+            "99th percentile round trip time to the broker.",
+            "gauge",
+            rtt_p99,
+        ),
+        (
+            "rdkafka_partition_queue_messages",
+            "Messages waiting to be produced to the partition.",
+            "gauge",
+            partition_queue,
+        ),
+        (
+            "rdkafka_consumer_lag",
+            "Messages between the consumer's position and the end of the partition.",
+            "gauge",
+            lag,
+        ),
+        (
+            "rdkafka_consumer_group_rebalances_total",
+            "Rebalances of the consumer group.",
+            "counter",
+            rebalances,
+        ),
+        (
+            "rdkafka_consumer_group_assigned_partitions",
+            "Partitions assigned to the consumer.",
+            "gauge",
+            assigned,
+        ),
+    ]
+}
+
+/// Writes one metric family, skipping it when there is nothing to report.
+fn family(out: &mut String, name: &str, help: &str, kind: &str, samples: &[(String, f64)]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// `{name="value",...}` with the values escaped.
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<String> = pairs
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// A window value in seconds. librdkafka reports latencies in microseconds and
+/// an empty window as all zeros.
+fn seconds(window: &Window, micros: i64) -> f64 {
+    if window.cnt == 0 {
+        return 0.0;
+    }
+    micros as f64 / 1_000_000.0
+}
+
+/// The resolved `[metrics]` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSettings {
+    /// Where `/metrics` is served. Statistics are only collected when set.
+    pub listen: Option<SocketAddr>,
+    /// How often librdkafka reports statistics.
+    pub statistics_interval: Duration,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            listen: None,
+            statistics_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+/// The `[metrics]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct MetricsConfig {
+    listen: Option<SocketAddr>,
+    statistics_interval_ms: Option<u64>,
+}
+
+impl MetricsConfig {
+    pub fn apply(self, settings: &mut MetricsSettings) {
+        if let Some(listen) = self.listen {
+            settings.listen = Some(listen);
+        }
+        if let Some(ms) = self.statistics_interval_ms {
+            settings.statistics_interval = Duration::from_millis(ms);
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Processing records on a pool of worker threads without losing their order.
+//!
+//! A [`WorkerPool`] hands each record to one of its worker threads. Records of
+//! the same partition (`ordering = "partition"`), or with the same key
+//! (`ordering = "key"`), always go to the same worker, which processes them in
+//! the order they were submitted. Kafka only orders records within a
+//! partition, and most consumers only care about the order per key, so
+//! different partitions or keys can be processed in parallel. Records without
+//! a key are dispatched by partition.
+//!
+//! Workers finish records out of offset order. The consumer announces each
+//! record to an [`OffsetCommitter`](crate::OffsetCommitter) with `received`
+//! before submitting it, and marks it `processed` once it comes back from
+//! [`WorkerPool::completed`]. The committer's
+//! [`OffsetTracker`](crate::commit::OffsetTracker) never commits past the first
+//! record of a partition that is still in flight, so a crash only replays
+//! records that may not have been processed, like the Confluent parallel
+//! consumer.
+//!
+//! ```toml
+//! [parallel]
+//! workers = 4
+//! ordering = "key"      # partition or key
+//! max_in_flight = 256
+//! ```
+//!
+//! When partitions are revoked, a
+//! [`RebalanceListener`](crate::consumer::RebalanceListener) should
+//! [`drain`](WorkerPool::drain) them and mark what comes back as processed, so
+//! the commit on revoke covers it and the next owner does not process it again.
+//!
+//! Once `max_in_flight` records are submitted and not completed the consumer
+//! should wait for [`WorkerPool::completed`] instead of polling, so the
+//! workers are not buried under records that would only be replayed after a
+//! crash.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{mpsc, Arc},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use rdkafka::{message::OwnedMessage, Message, TopicPartitionList};
+use serde::Deserialize;
+use tracing::error;
+
+use crate::consumer::OwnedReceived;
+
+/// Which records must be processed in order, and so by the same worker.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Ordering {
+    /// Records of the same partition.
+    #[default]
+    Partition,
+    /// Records with the same key, for more parallelism than there are partitions.
+    Key,
+}
+
+/// A record that a worker is done with.
+pub struct Completed<T, E> {
+    pub received: OwnedReceived<T>,
+    pub result: Result<(), E>,
+}
+
+/// Worker threads that process records in order per partition or key.
+pub struct WorkerPool<T, E> {
+    ordering: Ordering,
+    max_in_flight: usize,
+    in_flight: usize,
+    /// Records in flight by topic and partition.
+    partitions: HashMap<(String, i32), usize>,
+    queues: Vec<mpsc::Sender<OwnedReceived<T>>>,
+    workers: Vec<JoinHandle<()>>,
+    done: mpsc::Receiver<Completed<T, E>>,
+}
+
+impl<T, E> WorkerPool<T, E>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    /// Starts `settings.workers` threads that run `process` on the records submitted to them.
+    pub fn new<F>(settings: &ParallelSettings, process: F) -> WorkerPool<T, E>
+    where
+        F: Fn(&OwnedReceived<T>) -> Result<(), E> + Send + Sync + 'static,
+    {
+        let process = Arc::new(process);
+        let (done_tx, done) = mpsc::channel();
+
+        let mut queues = Vec::with_capacity(settings.workers);
+        let mut workers = Vec::with_capacity(settings.workers);
+        for i in 0..settings.workers {
+            let (queue, records) = mpsc::channel::<OwnedReceived<T>>();
+            let process = process.clone();
+            let done_tx = done_tx.clone();
+            let worker = thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || {
+                    for received in records {
+                        let result = process(&received);
+                        // the pool is gone, nobody would commit it anyway
+                        if done_tx.send(Completed { received, result }).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .expect("failed to spawn worker thread");
+            queues.push(queue);
+            workers.push(worker);
+        }
+
+        WorkerPool {
+            ordering: settings.ordering,
+            max_in_flight: settings.max_in_flight,
+            in_flight: 0,
+            partitions: HashMap::new(),
+            queues,
+            workers,
+            done,
+        }
+    }
+
+    /// Records submitted and not returned by [`completed`](Self::completed) yet.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+    }
+
+    /// Whether `max_in_flight` records are in flight, and the consumer should stop polling.
+    pub fn is_full(&self) -> bool {
+        self.in_flight >= self.max_in_flight
+    }
+
+    /// Queues `received` behind the earlier records of its partition or key.
+    pub fn submit(&mut self, received: OwnedReceived<T>) {
+        let msg = received.message();
+        let worker = self.worker_for(msg);
+        let partition = (msg.topic().to_string(), msg.partition());
+        self.queues[worker]
+            .send(received)
+            .expect("worker thread panicked");
+        self.in_flight += 1;
+        *self.partitions.entry(partition).or_default() += 1;
+    }
+
+    /// The records finished since the last call, waiting up to `timeout` for
+    /// the first one. A zero timeout does not wait.
+    pub fn completed(&mut self, timeout: Duration) -> Vec<Completed<T, E>> {
+        let mut completed = Vec::new();
+        if let Ok(first) = self.done.recv_timeout(timeout) {
+            completed.push(first);
+            completed.extend(self.done.try_iter());
+        }
+        self.finished(&completed);
+        completed
+    }
+
+    /// Waits until no record of the partitions in `tpl` is in flight, e.g.
+    /// because they are being revoked. Returns every record finished meanwhile,
+    /// of these partitions or others.
+    pub fn drain(&mut self, tpl: &TopicPartitionList) -> Vec<Completed<T, E>> {
+        let mut completed = Vec::new();
+        while tpl.elements().iter().any(|e| {
+            self.partitions
+                .contains_key(&(e.topic().to_string(), e.partition()))
+        }) {
+            let done = self.done.recv().expect("worker thread panicked");
+            self.finished(std::slice::from_ref(&done));
+            completed.push(done);
+        }
+        completed
+    }
+
+    /// Waits for every record in flight and returns them. The pool keeps
+    /// taking records afterwards.
+    pub fn wait_idle(&mut self) -> Vec<Completed<T, E>> {
+        let mut completed = Vec::with_capacity(self.in_flight);
+        while self.in_flight > 0 {
+            let done = self.done.recv().expect("worker thread panicked");
+            self.finished(std::slice::from_ref(&done));
+            completed.push(done);
+        }
+        completed
+    }
+
+    /// Stops taking records, lets the workers finish the ones in flight, and
+    /// returns them.
+    pub fn shutdown(mut self) -> Vec<Completed<T, E>> {
+        self.queues.clear();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("worker thread panicked")
+            }
+        }
+        let completed: Vec<_> = self.done.try_iter().collect();
+        self.finished(&completed);
+        completed
+    }
+
+    fn finished(&mut self, completed: &[Completed<T, E>]) {
+        self.in_flight -= completed.len();
+        for done in completed {
+            let msg = done.received.message();
+            let partition = (msg.topic().to_string(), msg.partition());
+            if let Some(count) = self.partitions.get_mut(&partition) {
+                *count -= 1;
+                if *count == 0 {
+                    self.partitions.remove(&partition);
+                }
+            }
+        }
+    }
+
+    fn worker_for(&self, msg: &OwnedMessage) -> usize {
+        let mut hasher = DefaultHasher::new();
+        match (self.ordering, msg.key()) {
+            (Ordering::Key, Some(key)) => key.hash(&mut hasher),
+            _ => (msg.topic(), msg.partition()).hash(&mut hasher),
+        }
+        (hasher.finish() % self.queues.len() as u64) as usize
+    }
+}
+
+/// The resolved `[parallel]` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParallelSettings {
+    pub workers: usize,
+    pub ordering: Ordering,
+    /// Records submitted to the pool and not completed before the consumer stops polling.
+    pub max_in_flight: usize,
+}
+
+impl Default for ParallelSettings {
+    fn default() -> Self {
+        ParallelSettings {
+            workers: 4,
+            ordering: Ordering::default(),
+            max_in_flight: 256,
+        }
+    }
+}
+
+/// The `[parallel]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ParallelConfig {
+    workers: Option<usize>,
+    ordering: Option<Ordering>,
+    max_in_flight: Option<usize>,
+}
+
+impl ParallelConfig {
+    pub fn apply(self, settings: &mut ParallelSettings) {
+        if let Some(workers) = self.workers {
+            settings.workers = workers;
+        }
+        if let Some(ordering) = self.ordering {
+            settings.ordering = ordering;
+        }
+        if let Some(max_in_flight) = self.max_in_flight {
+            settings.max_in_flight = max_in_flight;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, BTreeSet},
+        sync::Mutex,
+    };
+
+    use rdkafka::Timestamp;
+
+    use super::*;
+
+    /// Record `offset` of `partition`, with `key`, whose value is its offset.
+    fn record(partition: i32, offset: i64, key: &str) -> OwnedReceived<i64> {
+        let message = OwnedMessage::new(
+            None,
+            Some(key.as_bytes().to_vec()),
+            "t".to_string(),
+            Timestamp::NotAvailable,
+            partition,
+            offset,
+            None,
+        );
+        OwnedReceived::new(message, offset)
+    }
+
+    /// A pool that records, for each key, the offsets processed and the worker
+    /// threads that processed them.
+    #[allow(clippy::type_complexity)]
+    fn recording_pool(
+        ordering: Ordering,
+    ) -> (
+        WorkerPool<i64, ()>,
+        Arc<Mutex<BTreeMap<String, Vec<(i64, String)>>>>,
+    ) {
+        let seen = Arc::new(Mutex::new(BTreeMap::new()));
+        let settings = ParallelSettings {
+            workers: 4,
+            ordering,
+            max_in_flight: 1000,
+        };
+        let recorder = seen.clone();
+        let pool = WorkerPool::new(&settings, move |received: &OwnedReceived<i64>| {
+            // uneven processing times, to shake out reordering
+            thread::sleep(Duration::from_micros((*received.value() as u64 * 37) % 200));
+            let worker = thread::current().name().unwrap().to_string();
+            recorder
+                .lock()
+                .unwrap()
+                .entry(received.key().unwrap().to_string())
+                .or_insert_with(Vec::new)
+                .push((*received.value(), worker));
+            Ok(())
+        });
+        (pool, seen)
+    }
+
+    #[test]
+    fn records_with_the_same_key_are_processed_in_order_by_one_worker() {
+        let (mut pool, seen) = recording_pool(Ordering::Key);
+        for offset in 0..400 {
+            let partition = (offset % 3) as i32;
+            pool.submit(record(partition, offset, &format!("key-{}", offset % 10)));
+        }
+        assert_eq!(pool.wait_idle().len(), 400);
+        assert_eq!(pool.in_flight(), 0);
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 10);
+        for (key, processed) in seen.iter() {
+            let offsets: Vec<i64> = processed.iter().map(|(offset, _)| *offset).collect();
+            let mut sorted = offsets.clone();
+            sorted.sort_unstable();
+            assert_eq!(offsets, sorted, "{}", key);
+            assert_eq!(offsets.len(), 40, "{}", key);
+            assert!(
+                processed
+                    .iter()
+                    .all(|(_, worker)| *worker == processed[0].1),
+                "{}",
+                key
+            );
+        }
+        let workers: BTreeSet<&String> = seen
+            .values()
+            .flat_map(|processed| processed.iter().map(|(_, worker)| worker))
+            .collect();
+        assert!(workers.len() > 1, "every key went to {:?}", workers);
+    }
+
+    #[test]
+    fn partition_ordering_keeps_a_partition_on_one_worker_whatever_the_key() {
+        let (mut pool, seen) = recording_pool(Ordering::Partition);
+        for offset in 0..100 {
+            pool.submit(record(7, offset, &format!("key-{}", offset % 10)));
+        }
+        pool.wait_idle();
+
+        let seen = seen.lock().unwrap();
+        let mut all: Vec<&(i64, String)> = seen.values().flatten().collect();
+        all.sort();
+        assert_eq!(all.len(), 100);
+        assert!(all.iter().all(|(_, worker)| *worker == all[0].1));
+        for processed in seen.values() {
+            assert!(processed.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        }
+    }
+
+    #[test]
+    fn drain_returns_once_the_partitions_are_done() {
+        let settings = ParallelSettings {
+            workers: 2,
+            ordering: Ordering::Partition,
+            max_in_flight: 3,
+        };
+        let mut pool = WorkerPool::new(&settings, |_: &OwnedReceived<i64>| Ok::<(), ()>(()));
+        pool.submit(record(0, 0, "a"));
+        pool.submit(record(0, 1, "a"));
+        assert!(!pool.is_full());
+        pool.submit(record(1, 0, "b"));
+        assert!(pool.is_full());
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("t", 0);
+        let drained = pool.drain(&revoked);
+        assert!(drained
+            .iter()
+            .filter(|done| done.received.message().partition() == 0)
+            .map(|done| done.received.message().offset())
+            .eq(vec![0, 1]));
+        assert_eq!(pool.in_flight(), 3 - drained.len());
+
+        let rest = pool.shutdown();
+        assert_eq!(drained.len() + rest.len(), 3);
+    }
+}
@@ -0,0 +1,329 @@
+//! Which partition the typed producers send a record to.
+//!
+//! By default librdkafka picks it: the CRC32 of the key modulo the partition
+//! count (`consistent_random`). A [`ValuePartitioner`] given to
+//! [`TypedProducer::with_partitioner`](crate::TypedProducer::with_partitioner)
+//! or [`FutureTypedProducer::with_partitioner`](crate::FutureTypedProducer::with_partitioner)
+//! picks it instead, from the key and the value of the record. The examples
+//! take it from a `[partitioner]` section, at the top level or per profile:
+//!
+//! ```toml
+//! [partitioner]
+//! strategy = "murmur2"     # consistent_random, murmur2, sticky or user_id
+//! sticky_linger_ms = 10
+//! ```
+//!
+//! - `murmur2` hashes keys like the Java client's default partitioner, so the
+//!   same key lands on the same partition as when a Java service produces it;
+//! - `sticky` ignores keys and sends every record to one partition until
+//!   `sticky_linger_ms` has passed, then moves to another one, like the Java
+//!   client's `UniformStickyPartitioner`, for bigger batches;
+//! - `user_id` sends a [`User`] to partition `id % partitions`.
+//!
+//! Any `Fn(&str, &T, i32) -> i32` taking the key, the value and the partition
+//! count is a partitioner too. The partition count of the topic is fetched from
+//! the cluster on the first send and kept for the life of the producer.
+//!
+//! Producers that send raw records can get the same placement from librdkafka
+//! with the `partitioner` property: `murmur2_random` matches `murmur2`.
+
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use rdkafka::{
+    client::Client,
+    error::{KafkaError, KafkaResult},
+    types::RDKafkaErrorCode,
+    ClientContext,
+};
+use serde::Deserialize;
+
+use crate::{config::Settings, user::User};
+
+/// How long the first send waits for the partition count of its topic.
+pub const METADATA_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Picks the partition of a record of `T` from its key and value.
+pub trait ValuePartitioner<T: ?Sized>: Send + Sync {
+    /// A partition from 0 to `partitions - 1`.
+    fn partition(&self, key: &str, value: &T, partitions: i32) -> i32;
+}
+
+impl<T, F> ValuePartitioner<T> for F
+where
+    T: ?Sized,
+    F: Fn(&str, &T, i32) -> i32 + Send + Sync,
+{
+    fn partition(&self, key: &str, value: &T, partitions: i32) -> i32 {
+        self(key, value, partitions)
+    }
+}
+
+/// The partitioner of the `[partitioner]` section.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PartitionStrategy {
+    /// librdkafka's default: the CRC32 of the key.
+    #[default]
+    ConsistentRandom,
+    /// The murmur2 hash of the key, like the Java client.
+    Murmur2,
+    /// One partition at a time, whatever the key.
+    Sticky,
+    /// The id of the [`User`] modulo the partition count.
+    UserId,
+}
+
+impl fmt::Display for PartitionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PartitionStrategy::ConsistentRandom => "consistent_random",
+            PartitionStrategy::Murmur2 => "murmur2",
+            PartitionStrategy::Sticky => "sticky",
+            PartitionStrategy::UserId => "user_id",
+        })
+    }
+}
+
+/// The partitioner of the typed `User` producers, `None` when librdkafka's
+/// default is configured.
+pub fn for_users(settings: &Settings) -> Option<Arc<dyn ValuePartitioner<User>>> {
+    let partitioner = &settings.partitioner;
+    match partitioner.strategy {
+        PartitionStrategy::ConsistentRandom => None,
+        PartitionStrategy::Murmur2 => Some(Arc::new(Murmur2Partitioner)),
+        PartitionStrategy::Sticky => {
+            Some(Arc::new(StickyPartitioner::new(partitioner.sticky_linger)))
+        }
+        PartitionStrategy::UserId => Some(Arc::new(FieldPartitioner::new(|user: &User| {
+            i64::from(user.id)
+        }))),
+    }
+}
+
+/// The Java client's partitioner for keyed records: the murmur2 hash of the
+/// key, made positive, modulo the partition count.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Murmur2Partitioner;
+
+impl<T: ?Sized> ValuePartitioner<T> for Murmur2Partitioner {
+    fn partition(&self, key: &str, _value: &T, partitions: i32) -> i32 {
+        ((murmur2(key.as_bytes()) & 0x7fff_ffff) % partitions as u32) as i32
+    }
+}
+
+/// librdkafka's default partitioner for keyed records: the CRC32 of the key
+/// modulo the partition count.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsistentPartitioner;
+
+impl<T: ?Sized> ValuePartitioner<T> for ConsistentPartitioner {
+    fn partition(&self, key: &str, _value: &T, partitions: i32) -> i32 {
+        (crc32(key.as_bytes()) % partitions as u32) as i32
+    }
+}
+
+/// Sends every record to the same partition until `linger` has passed, then
+/// picks another one at random.
+#[derive(Debug)]
+pub struct StickyPartitioner {
+    linger: Duration,
+    /// The partition in use and since when.
+    current: Mutex<Option<(i32, Instant)>>,
+}
+
+impl StickyPartitioner {
+    pub fn new(linger: Duration) -> Self {
+        StickyPartitioner {
+            linger,
+            current: Mutex::new(None),
+        }
+    }
+}
+
+impl<T: ?Sized> ValuePartitioner<T> for StickyPartitioner {
+    fn partition(&self, _key: &str, _value: &T, partitions: i32) -> i32 {
+        let mut current = self.current.lock().unwrap();
+        match *current {
+            Some((partition, since)) if since.elapsed() < self.linger && partition < partitions => {
+                partition
+            }
+            previous => {
+                let mut rng = rand::thread_rng();
+                let next = match previous {
+                    // another one than the last, so that the load moves on
+                    Some((last, _)) if partitions > 1 && last < partitions => {
+                        (last + rng.gen_range(1..partitions)) % partitions
+                    }
+                    _ => rng.gen_range(0..partitions),
+                };
+                *current = Some((next, Instant::now()));
+                next
+            }
+        }
+    }
+}
+
+/// Sends a record to the partition given by a field of its value, e.g.
+/// `id % partitions`. Negative fields count from the end.
+pub struct FieldPartitioner<F> {
+    field: F,
+}
+
+impl<F> FieldPartitioner<F> {
+    pub fn new(field: F) -> Self {
+        FieldPartitioner { field }
+    }
+}
+
+impl<T, F> ValuePartitioner<T> for FieldPartitioner<F>
+where
+    T: ?Sized,
+    F: Fn(&T) -> i64 + Send + Sync,
+{
+    fn partition(&self, _key: &str, value: &T, partitions: i32) -> i32 {
+        (self.field)(value).rem_euclid(i64::from(partitions)) as i32
+    }
+}
+
+impl<F> fmt::Debug for FieldPartitioner<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FieldPartitioner").finish_non_exhaustive()
+    }
+}
+
+/// A partitioner with the partition count of the one topic a typed producer
+/// sends to, fetched from the cluster on first use.
+pub(crate) struct Partitioning<T: ?Sized> {
+    partitioner: Arc<dyn ValuePartitioner<T>>,
+    partitions: Mutex<Option<i32>>,
+}
+
+impl<T: ?Sized> Partitioning<T> {
+    pub fn new(partitioner: Arc<dyn ValuePartitioner<T>>) -> Self {
+        Partitioning {
+            partitioner,
+            partitions: Mutex::new(None),
+        }
+    }
+
+    /// The partition of the record with `key` and `value` sent to `topic`.
+    pub fn partition<C: ClientContext>(
+        &self,
+        client: &Client<C>,
+        topic: &str,
+        key: &str,
+        value: &T,
+    ) -> KafkaResult<i32> {
+        let partitions = self.partitions(client, topic)?;
+        Ok(self.partitioner.partition(key, value, partitions))
+    }
+
+    fn partitions<C: ClientContext>(&self, client: &Client<C>, topic: &str) -> KafkaResult<i32> {
+        let mut partitions = self.partitions.lock().unwrap();
+        if let Some(count) = *partitions {
+            return Ok(count);
+        }
+        let metadata = client.fetch_metadata(Some(topic), METADATA_TIMEOUT)?;
+        let count = match metadata.topics().iter().find(|t| t.name() == topic) {
+            Some(t) => match t.error() {
+                Some(err) => return Err(KafkaError::MetadataFetch(err.into())),
+                None => t.partitions().len() as i32,
+            },
+            None => 0,
+        };
+        if count == 0 {
+            return Err(KafkaError::MetadataFetch(
+                RDKafkaErrorCode::UnknownTopicOrPartition,
+            ));
+        }
+        *partitions = Some(count);
+        Ok(count)
+    }
+}
+
+/// The murmur2 hash of Kafka's Java client, `Utils.murmur2`.
+pub fn murmur2(bytes: &[u8]) -> u32 {
+    const SEED: u32 = 0x9747_b28c;
+    const M: u32 = 0x5bd1_e995;
+    const R: u32 = 24;
+
+    let mut h = SEED ^ bytes.len() as u32;
+    let mut chunks = bytes.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h = h.wrapping_mul(M);
+        h ^= k;
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        for (i, &byte) in tail.iter().enumerate() {
+            h ^= u32::from(byte) << (8 * i);
+        }
+        h = h.wrapping_mul(M);
+    }
+
+    h ^= h >> 13;
+    h = h.wrapping_mul(M);
+    h ^= h >> 15;
+    h
+}
+
+/// CRC-32 (IEEE), as librdkafka's `consistent_random` partitioner hashes keys.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// The resolved `[partitioner]` section.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionerSettings {
+    pub strategy: PartitionStrategy,
+    /// How long [`PartitionStrategy::Sticky`] stays on a partition.
+    pub sticky_linger: Duration,
+}
+
+impl Default for PartitionerSettings {
+    fn default() -> Self {
+        PartitionerSettings {
+            strategy: PartitionStrategy::default(),
+            sticky_linger: Duration::from_millis(10),
+        }
+    }
+}
+
+/// The `[partitioner]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PartitionerConfig {
+    strategy: Option<PartitionStrategy>,
+    sticky_linger_ms: Option<u64>,
+}
+
+impl PartitionerConfig {
+    pub fn apply(self, settings: &mut PartitionerSettings) {
+        if let Some(strategy) = self.strategy {
+            settings.strategy = strategy;
+        }
+        if let Some(ms) = self.sticky_linger_ms {
+            settings.sticky_linger = Duration::from_millis(ms);
+        }
+    }
+}
@@ -0,0 +1,193 @@
+//! Messages that can never be turned into a record, however often they are retried.
+//!
+//! The typed consumers report such a message as a [`PoisonPill`] instead of
+//! failing the poll loop, and a [`PoisonPillHandler`] deals with it according
+//! to the `poison_pill` strategy in the `[errors]` section:
+//!
+//! - `skip` moves on silently
+//! - `log` logs what was wrong with the message, then moves on (the default)
+//! - `dlq` sends the raw message to the dead letter topic, then moves on
+//!
+//! Either way the caller may commit the message afterwards. Keys are only
+//! checked when `require_key = true`.
+//!
+//! A message that only fails to decode because the schema registry did not
+//! answer is not a poison pill, see
+//! [`ReceiveError::Unavailable`](crate::consumer::ReceiveError::Unavailable).
+
+use std::fmt;
+
+use rdkafka::{error::KafkaResult, message::OwnedMessage, Message};
+use serde::Deserialize;
+use tracing::{info_span, warn, Instrument, Span};
+
+use crate::{
+    codec::CodecError,
+    config::Settings,
+    dlq::DeadLetterQueue,
+    producer::{self, ProduceCallbackLogger},
+};
+
+/// What is wrong with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoisonKind {
+    MissingKey,
+    NonUtf8Key,
+    MissingPayload,
+    /// The payload does not decode, e.g. it is not JSON.
+    InvalidPayload,
+    /// The payload was written with a schema the consumer does not read.
+    SchemaMismatch,
+}
+
+impl PoisonKind {
+    /// Short name, used as the `dlq.reason` header.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PoisonKind::MissingKey => "missing-key",
+            PoisonKind::NonUtf8Key => "non-utf8-key",
+            PoisonKind::MissingPayload => "missing-payload",
+            PoisonKind::InvalidPayload => "invalid-payload",
+            PoisonKind::SchemaMismatch => "schema-mismatch",
+        }
+    }
+
+    /// What `error` says about the message, or `None` when it says nothing
+    /// about it, e.g. the schema registry could not be reached.
+    pub(crate) fn of(error: &CodecError) -> Option<PoisonKind> {
+        match error {
+            CodecError::Registry(err) if err.is_transient() => None,
+            CodecError::SchemaMismatch { .. } => Some(PoisonKind::SchemaMismatch),
+            _ => Some(PoisonKind::InvalidPayload),
+        }
+    }
+}
+
+impl fmt::Display for PoisonKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A message that could not be received as a record, copied out of the consumer.
+#[derive(Debug)]
+pub struct PoisonPill {
+    pub kind: PoisonKind,
+    pub message: OwnedMessage,
+    /// The decoding error, for [`PoisonKind::InvalidPayload`] and [`PoisonKind::SchemaMismatch`].
+    pub error: Option<CodecError>,
+}
+
+impl fmt::Display for PoisonPill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} in offset {} of partition {} of {} (key {})",
+            self.kind,
+            self.message.offset(),
+            self.message.partition(),
+            self.message.topic(),
+            producer::key_of(&self.message)
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, " - {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// How poison pills are dealt with.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PoisonStrategy {
+    Skip,
+    #[default]
+    Log,
+    Dlq,
+}
+
+/// Applies a [`PoisonStrategy`].
+pub struct PoisonPillHandler<C = ProduceCallbackLogger>
+where
+    C: rdkafka::ClientContext + 'static,
+{
+    strategy: PoisonStrategy,
+    dlq: Option<DeadLetterQueue<C>>,
+}
+
+impl<C> PoisonPillHandler<C>
+where
+    C: rdkafka::ClientContext + 'static,
+{
+    /// `dlq` is required for [`PoisonStrategy::Dlq`] and ignored otherwise.
+    pub fn new(strategy: PoisonStrategy, dlq: Option<DeadLetterQueue<C>>) -> Self {
+        assert!(
+            strategy != PoisonStrategy::Dlq || dlq.is_some(),
+            "the dlq poison pill strategy needs a dead letter queue"
+        );
+        PoisonPillHandler { strategy, dlq }
+    }
+
+    pub fn strategy(&self) -> PoisonStrategy {
+        self.strategy
+    }
+
+    /// Deals with `pill`. An error means it could not be sent to the dead
+    /// letter topic and must not be committed.
+    pub fn handle(&self, pill: &PoisonPill) -> KafkaResult<()> {
+        match (self.strategy, &self.dlq) {
+            (PoisonStrategy::Skip, _) => Ok(()),
+            (PoisonStrategy::Log, _) | (PoisonStrategy::Dlq, None) => {
+                let _span = pill_span(pill).entered();
+                warn!(error = %pill, "skipping poison pill");
+                Ok(())
+            }
+            (PoisonStrategy::Dlq, Some(dlq)) => {
+                let _span = pill_span(pill).entered();
+                warn!(error = %pill, dlq_topic = dlq.topic(), "sending poison pill to dead letter topic");
+                dlq.send(&pill.message, pill.kind.as_str(), 1).map(|_| ())
+            }
+        }
+    }
+
+    /// Like [`handle`](Self::handle), but awaits the dead letter send instead
+    /// of blocking the thread.
+    pub async fn handle_async(&self, pill: &PoisonPill) -> KafkaResult<()> {
+        match (self.strategy, &self.dlq) {
+            (PoisonStrategy::Dlq, Some(dlq)) => {
+                let span = pill_span(pill);
+                span.in_scope(|| {
+                    warn!(error = %pill, dlq_topic = dlq.topic(), "sending poison pill to dead letter topic")
+                });
+                dlq.send_async(&pill.message, pill.kind.as_str(), 1)
+                    .instrument(span)
+                    .await
+                    .map(|_| ())
+            }
+            _ => self.handle(pill),
+        }
+    }
+}
+
+impl PoisonPillHandler {
+    /// The handler for the configured strategy, with a dead letter producer
+    /// created from `settings` when the strategy needs one.
+    pub fn from_settings(settings: &Settings) -> KafkaResult<PoisonPillHandler> {
+        let dlq = match settings.errors.poison_pill {
+            PoisonStrategy::Dlq => Some(DeadLetterQueue::from_settings(settings)?),
+            _ => None,
+        };
+        Ok(PoisonPillHandler::new(settings.errors.poison_pill, dlq))
+    }
+}
+
+/// Where `pill` came from, for the events about it.
+fn pill_span(pill: &PoisonPill) -> Span {
+    info_span!(
+        "poison_pill",
+        kind = pill.kind.as_str(),
+        topic = pill.message.topic(),
+        partition = pill.message.partition(),
+        offset = pill.message.offset()
+    )
+}
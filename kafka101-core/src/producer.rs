@@ -0,0 +1,512 @@
+use std::{
+    error::Error,
+    fmt,
+    marker::PhantomData,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rdkafka::{
+    client::OAuthToken,
+    error::KafkaError,
+    producer::{
+        BaseRecord, DeliveryResult, FutureProducer, FutureRecord, Producer, ProducerContext,
+        ThreadedProducer,
+    },
+    statistics::Statistics,
+    types::RDKafkaErrorCode,
+    ClientContext, Message,
+};
+use tracing::{info, info_span, warn, Instrument, Span};
+
+use crate::{
+    auth::{self, Auth, TokenProvider},
+    codec::{CodecError, Serializer},
+    delivery::DeliveryTracker,
+    headers::{ProducerHeaders, RecordHeaders},
+    metrics::Metrics,
+    partition::{Partitioning, ValuePartitioner},
+};
+
+/// The `produce` span of a record, carried from `send` to its delivery report
+/// as the delivery opaque.
+#[derive(Debug)]
+pub struct DeliverySpan {
+    span: Span,
+    sent_at: Instant,
+    sequence: Option<u64>,
+}
+
+impl DeliverySpan {
+    /// Opens the span of a record with `key` sent to `topic`.
+    pub fn start(topic: &str, key: &str) -> Box<DeliverySpan> {
+        Box::new(DeliverySpan {
+            span: info_span!("produce", topic, key),
+            sent_at: Instant::now(),
+            sequence: None,
+        })
+    }
+
+    /// Carries the [`DeliveryTracker::sent`] sequence number of the record, so
+    /// the tracker of the producer context can match the delivery report to it.
+    pub fn with_sequence(mut self: Box<Self>, sequence: u64) -> Box<DeliverySpan> {
+        self.sequence = Some(sequence);
+        self
+    }
+}
+
+/// Logs the outcome of every delivery report.
+#[derive(Default)]
+#[non_exhaustive]
+pub struct ProduceCallbackLogger {
+    token_provider: Option<Arc<dyn TokenProvider>>,
+    tracker: Option<DeliveryTracker>,
+    metrics: Option<Metrics>,
+}
+
+impl ProduceCallbackLogger {
+    pub fn new() -> Self {
+        ProduceCallbackLogger::default()
+    }
+
+    /// Answers `OAUTHBEARER` token refreshes when `auth` is an OAuth profile.
+    pub fn with_auth(mut self, auth: &Auth) -> Self {
+        self.token_provider = auth.token_provider();
+        self
+    }
+
+    /// Also hands every delivery report to `tracker`.
+    pub fn with_tracker(mut self, tracker: DeliveryTracker) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Counts delivery reports in `metrics` and keeps the client's statistics there.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+impl fmt::Debug for ProduceCallbackLogger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProduceCallbackLogger")
+            .field("oauth", &self.token_provider.is_some())
+            .field("tracked", &self.tracker.is_some())
+            .field("metrics", &self.metrics.is_some())
+            .finish()
+    }
+}
+
+impl ClientContext for ProduceCallbackLogger {
+    const ENABLE_REFRESH_OAUTH_TOKEN: bool = true;
+
+    fn generate_oauth_token(
+        &self,
+        _oauthbearer_config: Option<&str>,
+    ) -> Result<OAuthToken, Box<dyn Error>> {
+        auth::generate_token(self.token_provider.as_ref())
+    }
+
+    fn stats(&self, statistics: Statistics) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_stats(statistics);
+        }
+    }
+}
+
+impl ProducerContext for ProduceCallbackLogger {
+    type DeliveryOpaque = Box<DeliverySpan>;
+
+    fn delivery(
+        &self,
+        delivery_result: &DeliveryResult<'_>,
+        delivery_opaque: Self::DeliveryOpaque,
+    ) {
+        if let (Some(tracker), Some(sequence)) = (&self.tracker, delivery_opaque.sequence) {
+            tracker.delivered(sequence, delivery_result);
+        }
+        if let Some(metrics) = &self.metrics {
+            match delivery_result {
+                Ok(_) => metrics.produced(),
+                Err(_) => metrics.delivery_failed(),
+            }
+        }
+        // closing the span logs how long the delivery took
+        let _entered = delivery_opaque.span.enter();
+        let latency_ms = delivery_opaque.sent_at.elapsed().as_millis() as u64;
+
+        match delivery_result {
+            Ok(msg) => info!(
+                topic = msg.topic(),
+                partition = msg.partition(),
+                offset = msg.offset(),
+                key = key_of(msg),
+                latency_ms,
+                "produced message"
+            ),
+            Err((err, msg)) => warn!(
+                topic = msg.topic(),
+                key = key_of(msg),
+                latency_ms,
+                error_code = ?err.rdkafka_error_code(),
+                error = %err,
+                "failed to produce message"
+            ),
+        }
+    }
+}
+
+/// A producer for one topic that takes values of `T` and serializes them itself.
+pub struct TypedProducer<T: ?Sized, S, C = ProduceCallbackLogger>
+where
+    C: ProducerContext + 'static,
+{
+    producer: ThreadedProducer<C>,
+    topic: String,
+    serializer: S,
+    tracker: Option<DeliveryTracker>,
+    partitioning: Option<Partitioning<T>>,
+    headers: Option<ProducerHeaders>,
+    _value: PhantomData<fn(&T)>,
+}
+
+impl<T, S, C> TypedProducer<T, S, C>
+where
+    T: ?Sized,
+    S: Serializer<T>,
+    C: ProducerContext<DeliveryOpaque = Box<DeliverySpan>> + 'static,
+{
+    pub fn new<N: Into<String>>(producer: ThreadedProducer<C>, topic: N, serializer: S) -> Self {
+        TypedProducer {
+            producer,
+            topic: topic.into(),
+            serializer,
+            tracker: None,
+            partitioning: None,
+            headers: None,
+            _value: PhantomData,
+        }
+    }
+
+    /// Records every send in `tracker`. Give the same tracker to the producer
+    /// context, see [`ProduceCallbackLogger::with_tracker`], so that it sees the
+    /// delivery reports too.
+    pub fn with_tracker(mut self, tracker: DeliveryTracker) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Lets `partitioner` pick the partition of every record instead of
+    /// librdkafka, see [`crate::partition`].
+    pub fn with_partitioner(mut self, partitioner: Arc<dyn ValuePartitioner<T>>) -> Self {
+        self.partitioning = Some(Partitioning::new(partitioner));
+        self
+    }
+
+    /// Stamps every record with the headers of [`crate::headers`].
+    pub fn with_headers(mut self, headers: ProducerHeaders) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+
+    /// Serializes `value` and enqueues it. Delivery is reported to the producer context.
+    pub fn send(&self, key: &str, value: &T) -> Result<(), SendError> {
+        self.send_with_headers(key, value, &RecordHeaders::new())
+    }
+
+    /// Like [`send`](Self::send), with `headers` on top of the ones the
+    /// producer attaches, and taking precedence over them.
+    pub fn send_with_headers(
+        &self,
+        key: &str,
+        value: &T,
+        headers: &RecordHeaders,
+    ) -> Result<(), SendError> {
+        let payload = self
+            .serializer
+            .serialize(&self.topic, value)
+            .map_err(SendError::Serialization)?;
+        let partition = match &self.partitioning {
+            Some(partitioning) => Some(
+                partitioning
+                    .partition(self.producer.client(), &self.topic, key, value)
+                    .map_err(SendError::Kafka)?,
+            ),
+            None => None,
+        };
+
+        let mut span = DeliverySpan::start(&self.topic, key);
+        let sequence = self
+            .tracker
+            .as_ref()
+            .map(|tracker| tracker.sent(&self.topic, key));
+        if let Some(sequence) = sequence {
+            span = span.with_sequence(sequence);
+        }
+        let headers = match &self.headers {
+            Some(stamp) => stamp.stamp(&self.serializer, &payload, headers),
+            None => headers.clone(),
+        };
+        let mut record = BaseRecord::with_opaque_to(&self.topic, span)
+            .key(key)
+            .payload(&payload);
+        record.partition = partition;
+        if !headers.is_empty() {
+            record = record.headers(headers.to_owned_headers());
+        }
+        self.producer.send(record).map_err(|(err, _)| {
+            if let (Some(tracker), Some(sequence)) = (&self.tracker, sequence) {
+                tracker.cancelled(sequence);
+            }
+            SendError::Kafka(err)
+        })
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// The underlying producer, e.g. to `flush` it.
+    pub fn inner(&self) -> &ThreadedProducer<C> {
+        &self.producer
+    }
+}
+
+/// Sends values of `T` to one topic, whether to Kafka through a
+/// [`TypedProducer`] or to an [`InMemoryKafka`](crate::memory::InMemoryKafka),
+/// so code that produces can be tested without a broker.
+pub trait ValueProducer<T: ?Sized> {
+    /// Serializes `value` and sends it with `key`.
+    fn send(&self, key: &str, value: &T) -> Result<(), SendError> {
+        self.send_with_headers(key, value, &RecordHeaders::new())
+    }
+
+    /// Like [`send`](Self::send), with `headers` on top of the ones the
+    /// producer attaches.
+    fn send_with_headers(
+        &self,
+        key: &str,
+        value: &T,
+        headers: &RecordHeaders,
+    ) -> Result<(), SendError>;
+
+    fn topic(&self) -> &str;
+}
+
+impl<T, S, C> ValueProducer<T> for TypedProducer<T, S, C>
+where
+    T: ?Sized,
+    S: Serializer<T>,
+    C: ProducerContext<DeliveryOpaque = Box<DeliverySpan>> + 'static,
+{
+    fn send_with_headers(
+        &self,
+        key: &str,
+        value: &T,
+        headers: &RecordHeaders,
+    ) -> Result<(), SendError> {
+        TypedProducer::send_with_headers(self, key, value, headers)
+    }
+
+    fn topic(&self) -> &str {
+        &self.topic
+    }
+}
+
+/// An async producer for one topic whose `send` resolves once the broker has
+/// acknowledged the record. `C` only needs to be a client context, e.g. for
+/// OAuth token refresh; delivery reports are logged by the producer itself.
+pub struct FutureTypedProducer<T: ?Sized, S, C = ProduceCallbackLogger>
+where
+    C: ClientContext + 'static,
+{
+    producer: FutureProducer<C>,
+    topic: String,
+    serializer: S,
+    queue_timeout: Duration,
+    metrics: Option<Metrics>,
+    partitioning: Option<Partitioning<T>>,
+    headers: Option<ProducerHeaders>,
+    _value: PhantomData<fn(&T)>,
+}
+
+impl<T, S, C> FutureTypedProducer<T, S, C>
+where
+    T: ?Sized,
+    S: Serializer<T>,
+    C: ClientContext + 'static,
+{
+    /// How long `send` waits for room when the local queue is full, by default.
+    pub const DEFAULT_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new<N: Into<String>>(producer: FutureProducer<C>, topic: N, serializer: S) -> Self {
+        FutureTypedProducer {
+            producer,
+            topic: topic.into(),
+            serializer,
+            queue_timeout: Self::DEFAULT_QUEUE_TIMEOUT,
+            metrics: None,
+            partitioning: None,
+            headers: None,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn with_queue_timeout(mut self, queue_timeout: Duration) -> Self {
+        self.queue_timeout = queue_timeout;
+        self
+    }
+
+    /// Counts delivery reports in `metrics`. The `FutureProducer` does not pass
+    /// them on to its context, so [`ProduceCallbackLogger::with_metrics`] only
+    /// gets its statistics.
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Lets `partitioner` pick the partition of every record instead of
+    /// librdkafka, see [`crate::partition`]. The first send blocks while the
+    /// partition count is fetched.
+    pub fn with_partitioner(mut self, partitioner: Arc<dyn ValuePartitioner<T>>) -> Self {
+        self.partitioning = Some(Partitioning::new(partitioner));
+        self
+    }
+
+    /// Stamps every record with the headers of [`crate::headers`].
+    pub fn with_headers(mut self, headers: ProducerHeaders) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+
+    /// Serializes `value`, sends it and waits for the delivery report.
+    /// Returns the partition and offset the record was written to. Needs the
+    /// multi-threaded tokio runtime, as serializing may block.
+    pub async fn send(&self, key: &str, value: &T) -> Result<(i32, i64), SendError> {
+        self.send_with_headers(key, value, &RecordHeaders::new())
+            .await
+    }
+
+    /// Like [`send`](Self::send), with `headers` on top of the ones the
+    /// producer attaches, and taking precedence over them.
+    pub async fn send_with_headers(
+        &self,
+        key: &str,
+        value: &T,
+        headers: &RecordHeaders,
+    ) -> Result<(i32, i64), SendError> {
+        // the serializer may call the schema registry, and the partitioner
+        // fetch metadata, both blocking
+        let (payload, partition, headers) = tokio::task::block_in_place(|| {
+            let payload = self
+                .serializer
+                .serialize(&self.topic, value)
+                .map_err(SendError::Serialization)?;
+            let partition = match &self.partitioning {
+                Some(partitioning) => Some(
+                    partitioning
+                        .partition(self.producer.client(), &self.topic, key, value)
+                        .map_err(SendError::Kafka)?,
+                ),
+                None => None,
+            };
+            let headers = match &self.headers {
+                Some(stamp) => stamp.stamp(&self.serializer, &payload, headers),
+                None => headers.clone(),
+            };
+            Ok::<_, SendError>((payload, partition, headers))
+        })?;
+        let mut record = FutureRecord::to(&self.topic).key(key).payload(&payload);
+        record.partition = partition;
+        if !headers.is_empty() {
+            record = record.headers(headers.to_owned_headers());
+        }
+        let span = info_span!("produce", topic = self.topic.as_str(), key);
+        let sent_at = Instant::now();
+        let delivered = self
+            .producer
+            .send(record, self.queue_timeout)
+            .instrument(span.clone())
+            .await;
+        let _entered = span.enter();
+        let latency_ms = sent_at.elapsed().as_millis() as u64;
+        if let Some(metrics) = &self.metrics {
+            match delivered {
+                Ok(_) => metrics.produced(),
+                Err(_) => metrics.delivery_failed(),
+            }
+        }
+        match delivered {
+            Ok((partition, offset)) => {
+                info!(
+                    topic = self.topic.as_str(),
+                    partition, offset, key, latency_ms, "produced message"
+                );
+                Ok((partition, offset))
+            }
+            Err((err, _)) => {
+                warn!(
+                    topic = self.topic.as_str(),
+                    key,
+                    latency_ms,
+                    error_code = ?err.rdkafka_error_code(),
+                    error = %err,
+                    "failed to produce message"
+                );
+                if err.rdkafka_error_code() == Some(RDKafkaErrorCode::QueueFull) {
+                    Err(SendError::Kafka(err))
+                } else {
+                    Err(SendError::Delivery(err))
+                }
+            }
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.topic
+    }
+
+    /// The underlying producer, e.g. to `flush` it.
+    pub fn inner(&self) -> &FutureProducer<C> {
+        &self.producer
+    }
+}
+
+/// Why [`TypedProducer::send`] or [`FutureTypedProducer::send`] failed.
+#[derive(Debug)]
+pub enum SendError {
+    Serialization(CodecError),
+    /// The record could not be enqueued.
+    Kafka(KafkaError),
+    /// The record was enqueued but the broker did not acknowledge it.
+    Delivery(KafkaError),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Serialization(err) => write!(f, "failed to serialize record - {}", err),
+            SendError::Kafka(err) => write!(f, "failed to enqueue record - {}", err),
+            SendError::Delivery(err) => write!(f, "failed to deliver record - {}", err),
+        }
+    }
+}
+
+impl Error for SendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SendError::Serialization(err) => Some(err),
+            SendError::Kafka(err) | SendError::Delivery(err) => Some(err),
+        }
+    }
+}
+
+/// Returns the message key as text, or a placeholder when it is missing or not UTF-8.
+pub(crate) fn key_of<M: Message>(msg: &M) -> &str {
+    match msg.key_view::<str>() {
+        Some(Ok(key)) => key,
+        Some(Err(_)) => "<non-utf8>",
+        None => "<none>",
+    }
+}
@@ -0,0 +1,354 @@
+//! Protobuf payloads in the Confluent wire format.
+//!
+//! The message types in [`pb`] are generated by prost-build from
+//! `proto/user.proto` and checked in, so building the crate does not need
+//! `protoc`. Regenerate them after changing the `.proto` file.
+//!
+//! A [`ProtobufRecord`] maps a plain record such as [`User`] onto its
+//! generated message. [`ProtobufSerde`] registers the `.proto` schema like
+//! [`AvroSerde`](crate::avro::AvroSerde) does, and frames every payload as the
+//! magic byte, the 4-byte schema id, the message index array that locates the
+//! message inside the schema, and finally the encoded message.
+//!
+//! When reading, the message index array is looked up in the writer schema
+//! fetched by id. Any writer schema whose indexes lead to the record's
+//! message, e.g. `io.kafka101.User`, is accepted: protobuf decoding skips
+//! fields the reader does not know and leaves missing ones at their default,
+//! and whether the two versions are compatible is for the registry to check
+//! when the writer registers its schema.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use prost::Message;
+
+use crate::{
+    codec::{CodecError, Deserializer, Serializer},
+    schema_registry::{self, SchemaRegistryClient, SchemaType, SubjectNameStrategy},
+    user::User,
+};
+
+/// Messages generated from the files in `proto/`.
+pub mod pb {
+    include!("io.kafka101.rs");
+}
+
+/// A record that travels as a protobuf message.
+pub trait ProtobufRecord: Sized {
+    type Message: Message + Default;
+
+    /// The `.proto` file that declares the message.
+    const SCHEMA: &'static str;
+    /// `package.Message`, used by the record name subject strategies.
+    const FULL_NAME: &'static str;
+    /// Where the message is declared in the schema: the index of the top level
+    /// message, followed by the index of each nested message on the way down.
+    const MESSAGE_INDEXES: &'static [i32] = &[0];
+
+    fn to_message(&self) -> Self::Message;
+
+    fn from_message(message: Self::Message) -> Result<Self, CodecError>;
+}
+
+impl ProtobufRecord for User {
+    type Message = pb::User;
+
+    const SCHEMA: &'static str = include_str!("../../proto/user.proto");
+    const FULL_NAME: &'static str = "io.kafka101.User";
+
+    fn to_message(&self) -> pb::User {
+        pb::User {
+            id: self.id,
+            email: self.email.clone(),
+        }
+    }
+
+    fn from_message(message: pb::User) -> Result<Self, CodecError> {
+        Ok(User {
+            id: message.id,
+            email: message.email,
+        })
+    }
+}
+
+/// A writer schema id and a message index array.
+type WriterMessage = (u32, Vec<i32>);
+
+/// Serializer and deserializer for [`ProtobufRecord`]s backed by a schema registry.
+pub struct ProtobufSerde {
+    registry: Arc<SchemaRegistryClient>,
+    strategy: SubjectNameStrategy,
+    auto_register: bool,
+    check_compatibility: bool,
+    is_key: bool,
+    /// The full name of the message that a writer schema id and message index
+    /// array point to, for those seen already. `None` when they point nowhere.
+    known_writers: Mutex<HashMap<WriterMessage, Option<String>>>,
+}
+
+impl ProtobufSerde {
+    /// A value serde with the topic name strategy that registers schemas on first use.
+    pub fn new(registry: Arc<SchemaRegistryClient>) -> Self {
+        ProtobufSerde {
+            registry,
+            strategy: SubjectNameStrategy::default(),
+            auto_register: true,
+            check_compatibility: true,
+            is_key: false,
+            known_writers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn subject_name_strategy(mut self, strategy: SubjectNameStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// When off, the schema must already be registered under the subject.
+    pub fn auto_register(mut self, auto_register: bool) -> Self {
+        self.auto_register = auto_register;
+        self
+    }
+
+    /// Ask the registry whether the schema is compatible before registering it.
+    pub fn check_compatibility(mut self, check: bool) -> Self {
+        self.check_compatibility = check;
+        self
+    }
+
+    /// Use `-key` subjects, for serializing message keys.
+    pub fn for_keys(mut self) -> Self {
+        self.is_key = true;
+        self
+    }
+
+    /// The full name of the message at `indexes` in the writer schema `schema_id`.
+    fn writer_message(
+        &self,
+        schema_id: u32,
+        indexes: &[i32],
+    ) -> Result<Option<String>, CodecError> {
+        let cache_key = (schema_id, indexes.to_vec());
+        if let Some(name) = self.known_writers.lock().unwrap().get(&cache_key) {
+            return Ok(name.clone());
+        }
+
+        let writer = self.registry.schema_by_id(schema_id)?;
+        let name = if writer.schema_type == SchemaType::Protobuf {
+            message_name(&writer.schema, indexes)
+        } else {
+            None
+        };
+        self.known_writers
+            .lock()
+            .unwrap()
+            .insert(cache_key, name.clone());
+        Ok(name)
+    }
+}
+
+impl<T: ProtobufRecord> Serializer<T> for ProtobufSerde {
+    fn serialize(&self, topic: &str, value: &T) -> Result<Vec<u8>, CodecError> {
+        let subject = self.strategy.subject(topic, T::FULL_NAME, self.is_key);
+        let schema_id = self.registry.schema_id(
+            &subject,
+            T::SCHEMA,
+            SchemaType::Protobuf,
+            self.auto_register,
+            self.check_compatibility,
+        )?;
+
+        let message = value.to_message();
+        let mut out = Vec::with_capacity(8 + message.encoded_len());
+        schema_registry::write_header(&mut out, schema_id);
+        write_message_indexes(&mut out, T::MESSAGE_INDEXES);
+        message
+            .encode(&mut out)
+            .expect("a Vec grows to fit the message");
+        Ok(out)
+    }
+
+    fn content_type(&self) -> Option<&'static str> {
+        Some("application/x-protobuf")
+    }
+
+    fn schema_id(&self, payload: &[u8]) -> Option<u32> {
+        schema_registry::read_header(payload)
+            .ok()
+            .map(|(schema_id, _)| schema_id)
+    }
+}
+
+impl<T: ProtobufRecord> Deserializer<T> for ProtobufSerde {
+    fn deserialize(&self, _topic: &str, payload: &[u8]) -> Result<T, CodecError> {
+        let (schema_id, mut body) = schema_registry::read_header(payload)?;
+        let indexes = read_message_indexes(&mut body)?;
+        if self.writer_message(schema_id, &indexes)?.as_deref() != Some(T::FULL_NAME) {
+            return Err(CodecError::SchemaMismatch {
+                schema_id,
+                expected: T::FULL_NAME.to_string(),
+            });
+        }
+
+        let message = T::Message::decode(body)?;
+        T::from_message(message)
+    }
+}
+
+/// Writes the message index array. The common case, the first message in the
+/// schema, is shortened to a single zero.
+pub(crate) fn write_message_indexes(out: &mut Vec<u8>, indexes: &[i32]) {
+    if indexes == [0] {
+        write_int(out, 0);
+        return;
+    }
+    write_int(out, indexes.len() as i32);
+    for index in indexes {
+        write_int(out, *index);
+    }
+}
+
+pub(crate) fn read_message_indexes(input: &mut &[u8]) -> Result<Vec<i32>, CodecError> {
+    let count = read_int(input)?;
+    if count == 0 {
+        return Ok(vec![0]);
+    }
+    if count < 0 || count as usize > input.len() {
+        return Err(CodecError::InvalidFraming(format!(
+            "invalid message index count {}",
+            count
+        )));
+    }
+    (0..count).map(|_| read_int(input)).collect()
+}
+
+// Confluent writes the message indexes as zigzag varints, the way Avro writes
+// an int.
+
+fn write_long(out: &mut Vec<u8>, value: i64) {
+    let mut n = ((value << 1) ^ (value >> 63)) as u64;
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_int(out: &mut Vec<u8>, value: i32) {
+    write_long(out, i64::from(value))
+}
+
+fn read_long(input: &mut &[u8]) -> Result<i64, CodecError> {
+    let invalid = |reason: &str| {
+        CodecError::InvalidFraming(format!("invalid message index array - {}", reason))
+    };
+
+    let mut n: u64 = 0;
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = input
+            .split_first()
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        *input = rest;
+        if shift >= 64 {
+            return Err(invalid("varint is longer than 10 bytes"));
+        }
+        n |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    Ok(((n >> 1) as i64) ^ -((n & 1) as i64))
+}
+
+fn read_int(input: &mut &[u8]) -> Result<i32, CodecError> {
+    let value = read_long(input)?;
+    if value < i64::from(i32::MIN) || value > i64::from(i32::MAX) {
+        return Err(CodecError::InvalidFraming(format!(
+            "invalid message index array - {} does not fit in an int",
+            value
+        )));
+    }
+    Ok(value as i32)
+}
+
+/// A message declared in a `.proto` file, with the messages nested in it, in
+/// declaration order like the message index array counts them.
+struct MessageDecl {
+    name: String,
+    nested: Vec<MessageDecl>,
+}
+
+/// The full name, `package.Outer.Inner`, of the message that `indexes` lead
+/// to in `schema`, or `None` if there is no such message.
+fn message_name(schema: &str, indexes: &[i32]) -> Option<String> {
+    let tokens = tokenize(schema);
+    let package = tokens
+        .iter()
+        .position(|token| token == "package")
+        .and_then(|at| tokens.get(at + 1))
+        .filter(|package| *package != ";");
+    let mut at = 0;
+    let mut messages = parse_block(&tokens, &mut at);
+
+    if indexes.is_empty() || indexes.iter().any(|index| *index < 0) {
+        return None;
+    }
+    let mut path: Vec<String> = package.into_iter().cloned().collect();
+    for index in indexes {
+        let message = messages.into_iter().nth(*index as usize)?;
+        path.push(message.name);
+        messages = message.nested;
+    }
+    Some(path.join("."))
+}
+
+/// The schema as identifiers, literals and punctuation, without comments.
+fn tokenize(schema: &str) -> Vec<String> {
+    let mut text = String::with_capacity(schema.len());
+    let mut rest = schema;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("//") {
+            rest = after.find('\n').map_or("", |end| &after[end..]);
+        } else if let Some(after) = rest.strip_prefix("/*") {
+            rest = after.find("*/").map_or("", |end| &after[end + 2..]);
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            if matches!(c, '{' | '}' | ';' | '=') {
+                text.push(' ');
+                text.push(c);
+                text.push(' ');
+            } else {
+                text.push(c);
+            }
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    text.split_whitespace().map(str::to_string).collect()
+}
+
+/// The messages declared up to the `}` that closes the current block.
+fn parse_block(tokens: &[String], at: &mut usize) -> Vec<MessageDecl> {
+    let mut messages = Vec::new();
+    while let Some(token) = tokens.get(*at) {
+        *at += 1;
+        match token.as_str() {
+            "}" => break,
+            "message" if tokens.get(*at + 1).map(String::as_str) == Some("{") => {
+                let name = tokens[*at].clone();
+                *at += 2;
+                let nested = parse_block(tokens, at);
+                messages.push(MessageDecl { name, nested });
+            }
+            // enums, oneofs, services and option values do not count
+            "{" => {
+                parse_block(tokens, at);
+            }
+            _ => {}
+        }
+    }
+    messages
+}
@@ -0,0 +1,500 @@
+//! Where consumers start reading, and replaying a bounded range of a topic.
+//!
+//! The `[replay]` section (or `--from` / `--until`, `KAFKA_FROM` /
+//! `KAFKA_UNTIL`) picks the start and, for a replay window, the end:
+//!
+//! ```toml
+//! [replay]
+//! from = "timestamp:1700000000000"   # committed, earliest, latest, timestamp:<ms> or offsets:<partition>=<offset>,...
+//! until = "now"                      # now, timestamp:<ms> or offsets:<partition>=<offset>,...
+//! ```
+//!
+//! A group consumer subscribed through [`subscribe`] starts each partition
+//! from `from` the first time it is assigned, and from the committed offset
+//! after that, so a rebalance does not rewind it again. `committed` (the
+//! default) keeps the usual behaviour. `offsets:` only moves the partitions it
+//! lists.
+//!
+//! A [`ReplayWindow`] assigns the partitions directly, without joining the
+//! group, reads from `from` up to `until` and then reports that it is done.
+//! `until` is exclusive: `now` stops at the end of each partition when the
+//! window was opened, `timestamp:` at the first record written at or after
+//! that time, and `offsets:` before the given offset. Partitions not listed
+//! in an `offsets:` start are not replayed.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    fmt,
+    str::FromStr,
+    time::Duration,
+};
+
+use rdkafka::{
+    consumer::{Consumer, ConsumerContext},
+    error::{KafkaError, KafkaResult},
+    Message, Offset, TopicPartitionList,
+};
+use serde::Deserialize;
+
+use crate::ConsumerCallbackLogger;
+
+/// How long to wait for metadata, watermarks and offset lookups.
+pub const DEFAULT_LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a consumer starts reading each partition.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(try_from = "String")]
+pub enum StartPosition {
+    /// The committed offset of the group, or `auto.offset.reset` without one.
+    #[default]
+    Committed,
+    Earliest,
+    Latest,
+    /// The first record written at or after this many milliseconds since the epoch.
+    Timestamp(i64),
+    /// These offsets, by partition.
+    Offsets(BTreeMap<i32, i64>),
+}
+
+/// Where a [`ReplayWindow`] stops reading each partition, exclusive.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub enum EndPosition {
+    /// The end of the partition when the window is opened.
+    Now,
+    /// The first record written at or after this many milliseconds since the epoch.
+    Timestamp(i64),
+    /// These offsets, by partition.
+    Offsets(BTreeMap<i32, i64>),
+}
+
+impl StartPosition {
+    /// The start offset of each partition of `topic`, or `None` to start from
+    /// the committed offsets.
+    pub fn resolve<C, K>(
+        &self,
+        consumer: &K,
+        topic: &str,
+        timeout: Duration,
+    ) -> KafkaResult<Option<TopicPartitionList>>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        let offset = |offset| -> KafkaResult<TopicPartitionList> {
+            let mut tpl = TopicPartitionList::new();
+            for partition in partitions(consumer, topic, timeout)? {
+                tpl.add_partition_offset(topic, partition, offset)?;
+            }
+            Ok(tpl)
+        };
+        match self {
+            StartPosition::Committed => Ok(None),
+            StartPosition::Earliest => offset(Offset::Beginning).map(Some),
+            StartPosition::Latest => offset(Offset::End).map(Some),
+            StartPosition::Timestamp(timestamp) => {
+                let tpl = offset(Offset::Offset(*timestamp))?;
+                consumer.offsets_for_times(tpl, timeout).map(Some)
+            }
+            StartPosition::Offsets(offsets) => {
+                let mut tpl = TopicPartitionList::new();
+                for (partition, offset) in offsets {
+                    tpl.add_partition_offset(topic, *partition, Offset::Offset(*offset))?;
+                }
+                Ok(Some(tpl))
+            }
+        }
+    }
+}
+
+impl EndPosition {
+    /// The offset before which each partition in `tpl` stops.
+    pub fn resolve<C, K>(
+        &self,
+        consumer: &K,
+        tpl: &TopicPartitionList,
+        timeout: Duration,
+    ) -> KafkaResult<BTreeMap<i32, i64>>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        let mut ends = BTreeMap::new();
+        match self {
+            EndPosition::Now => {
+                for e in tpl.elements() {
+                    let (_, high) = consumer.fetch_watermarks(e.topic(), e.partition(), timeout)?;
+                    ends.insert(e.partition(), high);
+                }
+            }
+            EndPosition::Timestamp(timestamp) => {
+                let mut times = TopicPartitionList::new();
+                for e in tpl.elements() {
+                    times.add_partition_offset(
+                        e.topic(),
+                        e.partition(),
+                        Offset::Offset(*timestamp),
+                    )?;
+                }
+                for e in consumer.offsets_for_times(times, timeout)?.elements() {
+                    let end = match e.offset() {
+                        Offset::Offset(offset) => offset,
+                        // nothing written since, so everything up to now
+                        _ => {
+                            consumer
+                                .fetch_watermarks(e.topic(), e.partition(), timeout)?
+                                .1
+                        }
+                    };
+                    ends.insert(e.partition(), end);
+                }
+            }
+            EndPosition::Offsets(offsets) => {
+                for e in tpl.elements() {
+                    let end = offsets.get(&e.partition()).copied().unwrap_or(0);
+                    ends.insert(e.partition(), end);
+                }
+            }
+        }
+        Ok(ends)
+    }
+}
+
+/// Subscribes `consumer` to `topic`. Each partition starts from `from` the
+/// first time it is assigned, see [`ConsumerCallbackLogger::start_from`].
+pub fn subscribe<K>(consumer: &K, topic: &str, from: &StartPosition) -> KafkaResult<()>
+where
+    K: Consumer<ConsumerCallbackLogger>,
+{
+    if let Some(start) = from.resolve(consumer, topic, DEFAULT_LOOKUP_TIMEOUT)? {
+        consumer.context().start_from(&start);
+    }
+    consumer.subscribe(&[topic])
+}
+
+/// A bounded range of a topic, read by a consumer that was assigned its
+/// partitions directly. The consumer needs `enable.partition.eof = true` to
+/// notice partitions that end before `until`.
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    /// The end offset of each partition still being read.
+    remaining: BTreeMap<i32, i64>,
+    finished: BTreeSet<i32>,
+}
+
+impl ReplayWindow {
+    /// Assigns the partitions of `topic` to `consumer` at `from`, and returns
+    /// the window that ends at `until`.
+    pub fn assign<C, K>(
+        consumer: &K,
+        topic: &str,
+        from: &StartPosition,
+        until: &EndPosition,
+    ) -> KafkaResult<ReplayWindow>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        let start = match from.resolve(consumer, topic, DEFAULT_LOOKUP_TIMEOUT)? {
+            Some(start) => start,
+            None => {
+                let mut committed = TopicPartitionList::new();
+                for partition in partitions(consumer, topic, DEFAULT_LOOKUP_TIMEOUT)? {
+                    committed.add_partition_offset(topic, partition, Offset::Stored)?;
+                }
+                committed
+            }
+        };
+        let remaining = until.resolve(consumer, &start, DEFAULT_LOOKUP_TIMEOUT)?;
+        consumer.assign(&start)?;
+        Ok(ReplayWindow {
+            remaining,
+            finished: BTreeSet::new(),
+        })
+    }
+
+    /// Whether `msg` is inside the window. The first message at or past the
+    /// end of its partition finishes that partition.
+    pub fn contains<M: Message>(&mut self, msg: &M) -> bool {
+        match self.remaining.get(&msg.partition()) {
+            Some(end) if msg.offset() < *end => {
+                if msg.offset() + 1 >= *end {
+                    self.finish(msg.partition());
+                }
+                true
+            }
+            Some(_) => {
+                self.finish(msg.partition());
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Tells the window about a consumer error. A partition EOF finishes its partition.
+    pub fn error(&mut self, err: &KafkaError) {
+        if let KafkaError::PartitionEOF(partition) = err {
+            self.finish(*partition);
+        }
+    }
+
+    /// Whether every partition has been read up to its end.
+    pub fn is_done(&self) -> bool {
+        self.remaining.is_empty()
+    }
+
+    /// Partitions read up to their end so far.
+    pub fn finished(&self) -> &BTreeSet<i32> {
+        &self.finished
+    }
+
+    fn finish(&mut self, partition: i32) {
+        if self.remaining.remove(&partition).is_some() {
+            self.finished.insert(partition);
+        }
+    }
+}
+
+/// The partitions of `topic`, from the cluster metadata.
+pub(crate) fn partitions<C, K>(
+    consumer: &K,
+    topic: &str,
+    timeout: Duration,
+) -> KafkaResult<Vec<i32>>
+where
+    C: ConsumerContext,
+    K: Consumer<C>,
+{
+    let metadata = consumer.fetch_metadata(Some(topic), timeout)?;
+    match metadata.topics().iter().find(|t| t.name() == topic) {
+        Some(t) => match t.error() {
+            Some(err) => Err(KafkaError::MetadataFetch(err.into())),
+            None => Ok(t.partitions().iter().map(|p| p.id()).collect()),
+        },
+        None => Ok(Vec::new()),
+    }
+}
+
+/// `offsets:<partition>=<offset>,...`
+fn parse_offsets(s: &str) -> Result<BTreeMap<i32, i64>, String> {
+    let mut offsets = BTreeMap::new();
+    for pair in s.split(',') {
+        let parsed = pair
+            .split_once('=')
+            .and_then(|(p, o)| Some((p.trim().parse().ok()?, o.trim().parse().ok()?)));
+        match parsed {
+            Some((partition, offset)) => {
+                offsets.insert(partition, offset);
+            }
+            None => {
+                return Err(format!(
+                    "expected <partition>=<offset> in `offsets:{}`, got `{}`",
+                    s, pair
+                ))
+            }
+        }
+    }
+    Ok(offsets)
+}
+
+fn parse_timestamp(s: &str) -> Result<i64, String> {
+    s.parse()
+        .map_err(|_| format!("expected milliseconds since the epoch in `timestamp:{}`", s))
+}
+
+impl FromStr for StartPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("timestamp", ms)) => parse_timestamp(ms).map(StartPosition::Timestamp),
+            Some(("offsets", offsets)) => parse_offsets(offsets).map(StartPosition::Offsets),
+            _ => match s {
+                "committed" => Ok(StartPosition::Committed),
+                "earliest" => Ok(StartPosition::Earliest),
+                "latest" => Ok(StartPosition::Latest),
+                other => Err(format!(
+                    "unknown start position `{}`, expected committed, earliest, latest, timestamp:<ms> or offsets:<partition>=<offset>,...",
+                    other
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for StartPosition {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl FromStr for EndPosition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("timestamp", ms)) => parse_timestamp(ms).map(EndPosition::Timestamp),
+            Some(("offsets", offsets)) => parse_offsets(offsets).map(EndPosition::Offsets),
+            _ => match s {
+                "now" => Ok(EndPosition::Now),
+                other => Err(format!(
+                    "unknown end position `{}`, expected now, timestamp:<ms> or offsets:<partition>=<offset>,...",
+                    other
+                )),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for EndPosition {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn fmt_offsets(f: &mut fmt::Formatter<'_>, offsets: &BTreeMap<i32, i64>) -> fmt::Result {
+    let pairs: Vec<String> = offsets
+        .iter()
+        .map(|(partition, offset)| format!("{}={}", partition, offset))
+        .collect();
+    write!(f, "offsets:{}", pairs.join(","))
+}
+
+impl fmt::Display for StartPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartPosition::Committed => f.write_str("committed"),
+            StartPosition::Earliest => f.write_str("earliest"),
+            StartPosition::Latest => f.write_str("latest"),
+            StartPosition::Timestamp(ms) => write!(f, "timestamp:{}", ms),
+            StartPosition::Offsets(offsets) => fmt_offsets(f, offsets),
+        }
+    }
+}
+
+impl fmt::Display for EndPosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EndPosition::Now => f.write_str("now"),
+            EndPosition::Timestamp(ms) => write!(f, "timestamp:{}", ms),
+            EndPosition::Offsets(offsets) => fmt_offsets(f, offsets),
+        }
+    }
+}
+
+/// The resolved `[replay]` section.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplaySettings {
+    pub from: StartPosition,
+    /// Where a replay window ends. Only used by replaying consumers.
+    pub until: Option<EndPosition>,
+}
+
+/// The `[replay]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ReplayConfig {
+    from: Option<StartPosition>,
+    until: Option<EndPosition>,
+}
+
+impl ReplayConfig {
+    pub fn apply(self, settings: &mut ReplaySettings) {
+        if let Some(from) = self.from {
+            settings.from = from;
+        }
+        if let Some(until) = self.until {
+            settings.until = Some(until);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn offsets(pairs: &[(i32, i64)]) -> BTreeMap<i32, i64> {
+        pairs.iter().copied().collect()
+    }
+
+    #[test]
+    fn start_positions_parse_and_print_back() {
+        let cases = [
+            ("committed", StartPosition::Committed),
+            ("earliest", StartPosition::Earliest),
+            ("latest", StartPosition::Latest),
+            (
+                "timestamp:1700000000000",
+                StartPosition::Timestamp(1_700_000_000_000),
+            ),
+            ("offsets:0=42", StartPosition::Offsets(offsets(&[(0, 42)]))),
+            (
+                "offsets:0=42,1=17",
+                StartPosition::Offsets(offsets(&[(0, 42), (1, 17)])),
+            ),
+        ];
+        for (text, position) in &cases {
+            assert_eq!(text.parse::<StartPosition>().as_ref(), Ok(position));
+            assert_eq!(position.to_string(), *text);
+        }
+        assert_eq!(
+            "offsets: 1 = 17 ,0=42".parse::<StartPosition>(),
+            Ok(StartPosition::Offsets(offsets(&[(0, 42), (1, 17)])))
+        );
+        assert_eq!(StartPosition::default(), StartPosition::Committed);
+    }
+
+    #[test]
+    fn end_positions_parse_and_print_back() {
+        let cases = [
+            ("now", EndPosition::Now),
+            ("timestamp:0", EndPosition::Timestamp(0)),
+            ("offsets:2=100", EndPosition::Offsets(offsets(&[(2, 100)]))),
+        ];
+        for (text, position) in &cases {
+            assert_eq!(text.parse::<EndPosition>().as_ref(), Ok(position));
+            assert_eq!(position.to_string(), *text);
+        }
+    }
+
+    #[test]
+    fn invalid_positions_are_explained() {
+        assert!("beginning"
+            .parse::<StartPosition>()
+            .unwrap_err()
+            .starts_with("unknown start position `beginning`"));
+        assert!("earliest"
+            .parse::<EndPosition>()
+            .unwrap_err()
+            .starts_with("unknown end position `earliest`"));
+        assert_eq!(
+            "timestamp:yesterday".parse::<StartPosition>(),
+            Err("expected milliseconds since the epoch in `timestamp:yesterday`".to_string())
+        );
+        assert_eq!(
+            "offsets:0=42,1".parse::<EndPosition>(),
+            Err("expected <partition>=<offset> in `offsets:0=42,1`, got `1`".to_string())
+        );
+        assert!("offsets:".parse::<StartPosition>().is_err());
+        assert!("offsets:x=1".parse::<StartPosition>().is_err());
+    }
+
+    #[test]
+    fn positions_deserialize_from_strings() {
+        #[derive(Deserialize)]
+        struct Replay {
+            from: StartPosition,
+            until: EndPosition,
+        }
+        let replay: Replay =
+            toml::from_str("from = \"timestamp:5\"\nuntil = \"offsets:0=9\"").unwrap();
+        assert_eq!(replay.from, StartPosition::Timestamp(5));
+        assert_eq!(replay.until, EndPosition::Offsets(offsets(&[(0, 9)])));
+        assert!(toml::from_str::<Replay>("from = \"soon\"\nuntil = \"now\"").is_err());
+    }
+}
@@ -0,0 +1,546 @@
+//! A client for the Confluent schema registry REST API.
+//!
+//! The client caches what it learns: schema ids by subject and schema text,
+//! and schemas by id. A producer therefore talks to the registry once per
+//! subject, and a consumer once per writer schema.
+//!
+//! The registry is configured in a `[schema_registry]` section, at the top
+//! level or per profile:
+//!
+//! ```toml
+//! [profiles.prod.schema_registry]
+//! url = "https://registry.prod:8081"
+//! username = "app"
+//! password = { env = "SCHEMA_REGISTRY_PASSWORD" }
+//! subject_name_strategy = "topic-record"
+//! auto_register = false
+//! ```
+//!
+//! `KAFKA_SCHEMA_REGISTRY_URL` overrides the url.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    auth::{Secret, SecretSource},
+    avro::AvroSerde,
+    codec::CodecError,
+    protobuf::ProtobufSerde,
+};
+
+/// First byte of every payload in the Confluent wire format.
+pub const MAGIC_BYTE: u8 = 0;
+
+const CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// error codes the registry uses in 404 responses
+const SUBJECT_NOT_FOUND: u32 = 40401;
+const VERSION_NOT_FOUND: u32 = 40402;
+
+/// How the subject a schema is registered under is derived.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubjectNameStrategy {
+    /// `<topic>-key` / `<topic>-value`, one schema per topic.
+    #[default]
+    #[serde(rename = "topic")]
+    TopicName,
+    /// The fully qualified record name, shared by every topic carrying the record.
+    #[serde(rename = "record")]
+    RecordName,
+    /// `<topic>-<record name>`, for topics carrying several record types.
+    #[serde(rename = "topic-record")]
+    TopicRecordName,
+}
+
+impl SubjectNameStrategy {
+    pub fn subject(self, topic: &str, record_name: &str, is_key: bool) -> String {
+        match self {
+            SubjectNameStrategy::TopicName => {
+                format!("{}-{}", topic, if is_key { "key" } else { "value" })
+            }
+            SubjectNameStrategy::RecordName => record_name.to_string(),
+            SubjectNameStrategy::TopicRecordName => format!("{}-{}", topic, record_name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SchemaType {
+    Avro,
+    Protobuf,
+    Json,
+}
+
+impl SchemaType {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SchemaType::Avro => "AVRO",
+            SchemaType::Protobuf => "PROTOBUF",
+            SchemaType::Json => "JSON",
+        }
+    }
+
+    fn parse(name: Option<&str>) -> Result<SchemaType, RegistryError> {
+        // the registry leaves schemaType out for Avro
+        match name {
+            None | Some("AVRO") => Ok(SchemaType::Avro),
+            Some("PROTOBUF") => Ok(SchemaType::Protobuf),
+            Some("JSON") => Ok(SchemaType::Json),
+            Some(other) => Err(RegistryError::InvalidResponse(format!(
+                "unknown schema type {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// A schema as stored in the registry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schema {
+    pub schema_type: SchemaType,
+    pub schema: String,
+}
+
+/// Why a registry request failed.
+#[derive(Debug)]
+pub enum RegistryError {
+    /// The registry could not be reached.
+    Transport(String),
+    /// The registry answered with an error status.
+    Api {
+        status: u16,
+        code: Option<u32>,
+        message: String,
+    },
+    /// The registry answered with something we could not make sense of.
+    InvalidResponse(String),
+    /// The schema is not compatible with the latest version registered under the subject.
+    Incompatible { subject: String },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistryError::Transport(err) => write!(f, "could not reach schema registry - {}", err),
+            RegistryError::Api {
+                status,
+                code: Some(code),
+                message,
+            } => write!(
+                f,
+                "schema registry returned {} ({}) - {}",
+                status, code, message
+            ),
+            RegistryError::Api {
+                status, message, ..
+            } => write!(f, "schema registry returned {} - {}", status, message),
+            RegistryError::InvalidResponse(err) => {
+                write!(f, "invalid schema registry response - {}", err)
+            }
+            RegistryError::Incompatible { subject } => write!(
+                f,
+                "schema is not compatible with the latest version of subject {}",
+                subject
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl RegistryError {
+    /// Whether asking again later may work: the registry could not be reached,
+    /// or answered with a server error.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            RegistryError::Transport(_) => true,
+            RegistryError::Api { status, .. } => *status >= 500 || *status == 408 || *status == 429,
+            _ => false,
+        }
+    }
+
+    fn is_not_found(&self, codes: &[u32]) -> bool {
+        matches!(self, RegistryError::Api { status: 404, code: Some(code), .. } if codes.contains(code))
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    ids: HashMap<(String, String), u32>,
+    schemas: HashMap<u32, Schema>,
+}
+
+/// Talks to one schema registry. Share it between serdes with an `Arc`.
+pub struct SchemaRegistryClient {
+    url: String,
+    agent: ureq::Agent,
+    authorization: Option<Secret>,
+    cache: Mutex<Cache>,
+}
+
+impl fmt::Debug for SchemaRegistryClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SchemaRegistryClient")
+            .field("url", &self.url)
+            .field("authorization", &self.authorization)
+            .finish()
+    }
+}
+
+impl SchemaRegistryClient {
+    pub fn new<S: Into<String>>(url: S) -> SchemaRegistryClient {
+        let url = url.into().trim_end_matches('/').to_string();
+        SchemaRegistryClient {
+            url,
+            agent: ureq::AgentBuilder::new().timeout(DEFAULT_TIMEOUT).build(),
+            authorization: None,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+
+    pub fn with_basic_auth(mut self, username: &str, password: &Secret) -> SchemaRegistryClient {
+        let credentials = format!("{}:{}", username, password.expose());
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        self.authorization = Some(Secret::new(format!("Basic {}", encoded)));
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> SchemaRegistryClient {
+        self.agent = ureq::AgentBuilder::new().timeout(timeout).build();
+        self
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Registers the schema under the subject, or returns its id if it is registered already.
+    pub fn register(
+        &self,
+        subject: &str,
+        schema: &str,
+        schema_type: SchemaType,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.cached_id(subject, schema) {
+            return Ok(id);
+        }
+        let response = self.post(
+            &format!("/subjects/{}/versions", encode(subject)),
+            schema_body(schema, schema_type),
+        )?;
+        let id = id_of(&response)?;
+        self.remember(subject, schema, schema_type, id);
+        Ok(id)
+    }
+
+    /// The id of a schema that must already be registered under the subject.
+    pub fn lookup(
+        &self,
+        subject: &str,
+        schema: &str,
+        schema_type: SchemaType,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.cached_id(subject, schema) {
+            return Ok(id);
+        }
+        let response = self.post(
+            &format!("/subjects/{}", encode(subject)),
+            schema_body(schema, schema_type),
+        )?;
+        let id = id_of(&response)?;
+        self.remember(subject, schema, schema_type, id);
+        Ok(id)
+    }
+
+    /// Whether the schema could be registered as the next version of the subject.
+    /// A subject without versions accepts anything.
+    pub fn is_compatible(
+        &self,
+        subject: &str,
+        schema: &str,
+        schema_type: SchemaType,
+    ) -> Result<bool, RegistryError> {
+        let response = self.post(
+            &format!(
+                "/compatibility/subjects/{}/versions/latest",
+                encode(subject)
+            ),
+            schema_body(schema, schema_type),
+        );
+        match response {
+            Ok(response) => response
+                .get("is_compatible")
+                .and_then(serde_json::Value::as_bool)
+                .ok_or_else(|| RegistryError::InvalidResponse("missing is_compatible".to_string())),
+            Err(err) if err.is_not_found(&[SUBJECT_NOT_FOUND, VERSION_NOT_FOUND]) => Ok(true),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The schema with the given id, as found in the header of a payload.
+    pub fn schema_by_id(&self, id: u32) -> Result<Schema, RegistryError> {
+        if let Some(schema) = self.cache.lock().unwrap().schemas.get(&id) {
+            return Ok(schema.clone());
+        }
+        let response = self.get(&format!("/schemas/ids/{}", id))?;
+        let schema = Schema {
+            schema_type: SchemaType::parse(
+                response
+                    .get("schemaType")
+                    .and_then(serde_json::Value::as_str),
+            )?,
+            schema: response
+                .get("schema")
+                .and_then(serde_json::Value::as_str)
+                .ok_or_else(|| RegistryError::InvalidResponse("missing schema".to_string()))?
+                .to_string(),
+        };
+        self.cache
+            .lock()
+            .unwrap()
+            .schemas
+            .insert(id, schema.clone());
+        Ok(schema)
+    }
+
+    /// The id used by serializers: registered on demand, or looked up when
+    /// auto registration is off.
+    pub(crate) fn schema_id(
+        &self,
+        subject: &str,
+        schema: &str,
+        schema_type: SchemaType,
+        auto_register: bool,
+        check_compatibility: bool,
+    ) -> Result<u32, RegistryError> {
+        if let Some(id) = self.cached_id(subject, schema) {
+            return Ok(id);
+        }
+        if !auto_register {
+            return self.lookup(subject, schema, schema_type);
+        }
+        if check_compatibility && !self.is_compatible(subject, schema, schema_type)? {
+            return Err(RegistryError::Incompatible {
+                subject: subject.to_string(),
+            });
+        }
+        self.register(subject, schema, schema_type)
+    }
+
+    fn cached_id(&self, subject: &str, schema: &str) -> Option<u32> {
+        self.cache
+            .lock()
+            .unwrap()
+            .ids
+            .get(&(subject.to_string(), schema.to_string()))
+            .copied()
+    }
+
+    fn remember(&self, subject: &str, schema: &str, schema_type: SchemaType, id: u32) {
+        let mut cache = self.cache.lock().unwrap();
+        cache
+            .ids
+            .insert((subject.to_string(), schema.to_string()), id);
+        cache.schemas.entry(id).or_insert_with(|| Schema {
+            schema_type,
+            schema: schema.to_string(),
+        });
+    }
+
+    fn get(&self, path: &str) -> Result<serde_json::Value, RegistryError> {
+        let request = self.request("GET", path);
+        read_response(request.call())
+    }
+
+    fn post(
+        &self,
+        path: &str,
+        body: serde_json::Value,
+    ) -> Result<serde_json::Value, RegistryError> {
+        let request = self.request("POST", path).set("Content-Type", CONTENT_TYPE);
+        read_response(request.send_string(&body.to_string()))
+    }
+
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self
+            .agent
+            .request(method, &format!("{}{}", self.url, path))
+            .set("Accept", CONTENT_TYPE);
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization.expose()),
+            None => request,
+        }
+    }
+}
+
+fn schema_body(schema: &str, schema_type: SchemaType) -> serde_json::Value {
+    match schema_type {
+        SchemaType::Avro => json!({ "schema": schema }),
+        other => json!({ "schema": schema, "schemaType": other.as_str() }),
+    }
+}
+
+fn id_of(response: &serde_json::Value) -> Result<u32, RegistryError> {
+    response
+        .get("id")
+        .and_then(serde_json::Value::as_u64)
+        .map(|id| id as u32)
+        .ok_or_else(|| RegistryError::InvalidResponse("missing schema id".to_string()))
+}
+
+fn read_response(
+    response: Result<ureq::Response, ureq::Error>,
+) -> Result<serde_json::Value, RegistryError> {
+    match response {
+        Ok(response) => {
+            let body = response
+                .into_string()
+                .map_err(|err| RegistryError::Transport(err.to_string()))?;
+            serde_json::from_str(&body)
+                .map_err(|err| RegistryError::InvalidResponse(err.to_string()))
+        }
+        Err(ureq::Error::Status(status, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            let parsed: Option<serde_json::Value> = serde_json::from_str(&body).ok();
+            let field = |name: &str| parsed.as_ref().and_then(|body| body.get(name).cloned());
+            Err(RegistryError::Api {
+                status,
+                code: field("error_code")
+                    .and_then(|code| code.as_u64())
+                    .map(|code| code as u32),
+                message: field("message")
+                    .and_then(|message| message.as_str().map(str::to_string))
+                    .unwrap_or(body),
+            })
+        }
+        Err(ureq::Error::Transport(err)) => Err(RegistryError::Transport(err.to_string())),
+    }
+}
+
+/// Percent-encodes a subject for use as a path segment.
+fn encode(subject: &str) -> String {
+    let mut encoded = String::with_capacity(subject.len());
+    for byte in subject.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            other => encoded.push_str(&format!("%{:02X}", other)),
+        }
+    }
+    encoded
+}
+
+pub(crate) fn write_header(out: &mut Vec<u8>, schema_id: u32) {
+    out.push(MAGIC_BYTE);
+    out.extend_from_slice(&schema_id.to_be_bytes());
+}
+
+/// Splits a payload into the schema id and the encoded record.
+pub(crate) fn read_header(payload: &[u8]) -> Result<(u32, &[u8]), CodecError> {
+    match payload {
+        [MAGIC_BYTE, a, b, c, d, body @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), body)),
+        [MAGIC_BYTE, ..] => Err(CodecError::InvalidFraming(format!(
+            "payload of {} bytes is too short for a schema id",
+            payload.len()
+        ))),
+        [other, ..] => Err(CodecError::InvalidFraming(format!(
+            "unknown magic byte {}",
+            other
+        ))),
+        [] => Err(CodecError::InvalidFraming("empty payload".to_string())),
+    }
+}
+
+/// The resolved `[schema_registry]` section.
+#[derive(Debug, Clone)]
+pub struct SchemaRegistrySettings {
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<Secret>,
+    pub subject_name_strategy: SubjectNameStrategy,
+    pub auto_register: bool,
+    pub check_compatibility: bool,
+}
+
+impl SchemaRegistrySettings {
+    pub fn client(&self) -> SchemaRegistryClient {
+        let client = SchemaRegistryClient::new(self.url.as_str());
+        match (&self.username, &self.password) {
+            (Some(username), Some(password)) => client.with_basic_auth(username, password),
+            _ => client,
+        }
+    }
+
+    /// An Avro serde using a new client and the configured registration behaviour.
+    pub fn avro_serde(&self) -> AvroSerde {
+        AvroSerde::new(Arc::new(self.client()))
+            .subject_name_strategy(self.subject_name_strategy)
+            .auto_register(self.auto_register)
+            .check_compatibility(self.check_compatibility)
+    }
+
+    /// A Protobuf serde using a new client and the configured registration behaviour.
+    pub fn protobuf_serde(&self) -> ProtobufSerde {
+        ProtobufSerde::new(Arc::new(self.client()))
+            .subject_name_strategy(self.subject_name_strategy)
+            .auto_register(self.auto_register)
+            .check_compatibility(self.check_compatibility)
+    }
+}
+
+/// The `[schema_registry]` section of a config file, before the password is read.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SchemaRegistryConfig {
+    url: String,
+    username: Option<String>,
+    password: Option<SecretSource>,
+    #[serde(default)]
+    subject_name_strategy: SubjectNameStrategy,
+    #[serde(default = "enabled")]
+    auto_register: bool,
+    #[serde(default = "enabled")]
+    check_compatibility: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl SchemaRegistryConfig {
+    pub fn resolve(self, problems: &mut Vec<String>) -> SchemaRegistrySettings {
+        let password = self
+            .password
+            .as_ref()
+            .and_then(|source| match source.resolve() {
+                Ok(secret) => Some(secret),
+                Err(problem) => {
+                    problems.push(format!("[schema_registry] {}", problem));
+                    None
+                }
+            });
+        if self.username.is_some() != self.password.is_some() {
+            problems
+                .push("[schema_registry] username and password must be set together".to_string());
+        }
+
+        SchemaRegistrySettings {
+            url: self.url,
+            username: self.username,
+            password,
+            subject_name_strategy: self.subject_name_strategy,
+            auto_register: self.auto_register,
+            check_compatibility: self.check_compatibility,
+        }
+    }
+}
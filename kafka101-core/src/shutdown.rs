@@ -0,0 +1,117 @@
+//! Stopping the examples cleanly on Ctrl-C or `SIGTERM`.
+//!
+//! A [`Shutdown`] is a flag shared by the send loop and the consumer thread.
+//! Once it is raised, the send loop stops, the producer is flushed with
+//! [`flush_producer`], and the consumer thread leaves its poll loop and calls
+//! [`close_consumer`] before it is joined. A second signal exits right away.
+
+use std::{
+    process,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use rdkafka::{
+    consumer::{CommitMode, Consumer, ConsumerContext},
+    error::{KafkaError, KafkaResult},
+    producer::{Producer, ProducerContext},
+    types::RDKafkaErrorCode,
+};
+use tracing::{info, warn};
+
+/// How long [`flush_producer`] waits for outstanding deliveries in the examples.
+pub const DEFAULT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a consumer poll blocks before the shutdown flag is checked again.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A shutdown request that can be raised once and waited on from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    /// A shutdown raised by `SIGINT` or `SIGTERM`. Only one handler can be
+    /// installed per process.
+    pub fn on_signal() -> Result<Shutdown, ctrlc::Error> {
+        let shutdown = Shutdown::new();
+        let handler = shutdown.clone();
+        ctrlc::set_handler(move || {
+            if handler.is_requested() {
+                warn!("received second signal, exiting without cleanup");
+                process::exit(130);
+            }
+            info!("received signal, shutting down");
+            handler.trigger();
+        })?;
+        Ok(shutdown)
+    }
+
+    pub fn trigger(&self) {
+        let (requested, wakeup) = &*self.inner;
+        *requested.lock().unwrap() = true;
+        wakeup.notify_all();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Sleeps for `duration` unless shutdown is requested first.
+    /// Returns whether shutdown has been requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let (requested, wakeup) = &*self.inner;
+        let guard = requested.lock().unwrap();
+        let (guard, _) = wakeup
+            .wait_timeout_while(guard, duration, |requested| !*requested)
+            .unwrap();
+        *guard
+    }
+
+    /// Blocks until shutdown is requested.
+    pub fn wait(&self) {
+        let (requested, wakeup) = &*self.inner;
+        let guard = requested.lock().unwrap();
+        let _guard = wakeup.wait_while(guard, |requested| !*requested).unwrap();
+    }
+}
+
+/// Flushes the producer for at most `timeout` and returns how many messages
+/// were still undelivered afterwards.
+pub fn flush_producer<C, P>(producer: &P, timeout: Duration) -> usize
+where
+    C: ProducerContext,
+    P: Producer<C>,
+{
+    info!("flushing producer");
+    if let Err(err) = producer.flush(timeout) {
+        warn!(error = %err, "producer flush did not complete")
+    }
+    producer.in_flight_count().max(0) as usize
+}
+
+/// Commits the offsets the consumer has stored, unless `commit` is false because
+/// the caller commits every message itself, and then leaves the group.
+pub fn close_consumer<C, K>(consumer: &K, commit: bool) -> KafkaResult<()>
+where
+    C: ConsumerContext,
+    K: Consumer<C>,
+{
+    let committed = if commit {
+        match consumer.commit_consumer_state(CommitMode::Sync) {
+            // nothing consumed since the last commit
+            Err(KafkaError::ConsumerCommit(RDKafkaErrorCode::NoOffset)) => Ok(()),
+            other => other,
+        }
+    } else {
+        Ok(())
+    };
+    consumer.unsubscribe();
+    info!("consumer closed");
+    committed
+}
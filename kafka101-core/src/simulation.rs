@@ -0,0 +1,80 @@
+//! The processing the consumer examples pretend to do.
+//!
+//! Every user fails to process at random, so that the retries, the dead letter
+//! topic and the commit strategies all get exercised. The binaries draw from a
+//! random seed. Tests use [`FailureSimulator::seeded`] instead, so that the
+//! same attempts fail on every run, and can check what the consumer did about
+//! them against an [`InMemoryKafka`](crate::memory::InMemoryKafka).
+
+use std::{ops::Range, sync::Mutex, time::Duration};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tracing::{info, warn};
+
+use crate::user::User;
+
+/// Decides which attempts fail, from a random or a fixed seed.
+#[derive(Debug)]
+pub struct FailureSimulator {
+    failure_rate: f64,
+    rng: Mutex<StdRng>,
+}
+
+impl FailureSimulator {
+    /// Half of the attempts fail, as in the examples.
+    pub const DEFAULT_FAILURE_RATE: f64 = 0.5;
+
+    /// Fails attempts with probability `failure_rate`, differently on every run.
+    pub fn random(failure_rate: f64) -> Self {
+        FailureSimulator::with_rng(failure_rate, StdRng::from_entropy())
+    }
+
+    /// Fails attempts with probability `failure_rate`, the same ones for the
+    /// same `seed`.
+    pub fn seeded(failure_rate: f64, seed: u64) -> Self {
+        FailureSimulator::with_rng(failure_rate, StdRng::seed_from_u64(seed))
+    }
+
+    fn with_rng(failure_rate: f64, rng: StdRng) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&failure_rate),
+            "failure rate {} is not a probability",
+            failure_rate
+        );
+        FailureSimulator {
+            failure_rate,
+            rng: Mutex::new(rng),
+        }
+    }
+
+    pub fn failure_rate(&self) -> f64 {
+        self.failure_rate
+    }
+
+    /// Whether the next attempt fails.
+    pub fn fails(&self) -> bool {
+        self.rng.lock().unwrap().gen_bool(self.failure_rate)
+    }
+
+    /// A processing time picked from `millis`, e.g. to make parallel workers overlap.
+    pub fn delay(&self, millis: Range<u64>) -> Duration {
+        Duration::from_millis(self.rng.lock().unwrap().gen_range(millis))
+    }
+}
+
+impl Default for FailureSimulator {
+    fn default() -> Self {
+        FailureSimulator::random(FailureSimulator::DEFAULT_FAILURE_RATE)
+    }
+}
+
+/// Processes `user`, failing when `failures` says so.
+pub fn process_user(user: &User, failures: &FailureSimulator) -> Result<(), String> {
+    if failures.fails() {
+        warn!(user = ?user, "failed to process user");
+        Err("random processing failure".to_string())
+    } else {
+        info!(user = ?user, "processed user");
+        Ok(())
+    }
+}
@@ -0,0 +1,536 @@
+//! Exactly-once consume-transform-produce with Kafka transactions.
+//!
+//! A [`TransactionalProducer`] writes the records derived from a batch of input
+//! messages and the consumer offsets of that batch in one transaction, so the
+//! output and the progress of the consumer group are committed together or not
+//! at all. Consumers of the output topic must read with
+//! `isolation.level = read_committed` to only see committed records.
+//!
+//! Failures are sorted the way librdkafka reports them:
+//!
+//! - retriable errors repeat the same step, with the backoff of the `[errors]` section
+//! - abortable errors abort the transaction and rewind the consumer to the start
+//!   of the batch, so it is consumed and transformed again. Should the rewind
+//!   fail, [`TransactionError::Rewind`] asks for the consumer to start over
+//!   from the committed offsets
+//! - a fenced producer means another instance with the same `transactional.id`
+//!   has started; this one must stop
+//! - any other fatal error leaves the producer unusable
+//!
+//! No transaction is open while the consumer polls, so a rebalance only takes
+//! away what was consumed since the last transaction. A
+//! [`RebalanceListener`](crate::consumer::RebalanceListener) drops that part of
+//! the batch with [`BatchOffsets::forget`]: this consumer can no longer commit
+//! its offsets, and the new owner consumes it again. Anything else a batch
+//! writes, such as [dead letters](crate::dlq::send_in_transaction), belongs in
+//! its transaction too, or it is written again every time the batch is.
+//!
+//! Configured in a `[transactions]` section:
+//!
+//! ```toml
+//! [transactions]
+//! output_topic = "rust-out"
+//! transactional_id = "rust-pipeline-1"
+//! timeout_ms = 10000
+//! ```
+//!
+//! `output_topic` defaults to the topic name with an `-out` suffix, and
+//! `transactional_id` to `<group_id>-<topic>`. Every running instance needs its
+//! own, stable `transactional_id`: `KAFKA_TRANSACTIONAL_ID` overrides it.
+
+use std::{collections::BTreeMap, error::Error, fmt, thread, time::Duration};
+
+use rdkafka::{
+    consumer::{Consumer, ConsumerContext},
+    error::{KafkaError, KafkaResult},
+    producer::{Producer, ProducerContext},
+    types::RDKafkaErrorCode,
+    Message, Offset, TopicPartitionList,
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    codec::Serializer,
+    dlq::RetryPolicy,
+    producer::{DeliverySpan, ProduceCallbackLogger, SendError, TypedProducer},
+};
+
+/// Appended to the topic name when no output topic is configured.
+pub const DEFAULT_OUTPUT_SUFFIX: &str = "-out";
+
+/// How long each transactional call may take by default.
+pub const DEFAULT_TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The first and last offset, and the number of messages, consumed from each
+/// partition since the last transaction.
+#[derive(Debug, Clone, Default)]
+pub struct BatchOffsets {
+    partitions: BTreeMap<(String, i32), (i64, i64, usize)>,
+    messages: usize,
+}
+
+impl BatchOffsets {
+    pub fn new() -> BatchOffsets {
+        BatchOffsets::default()
+    }
+
+    /// Adds `msg` to the batch. Messages of a partition must be tracked in order.
+    pub fn track<M: Message>(&mut self, msg: &M) {
+        let offset = msg.offset();
+        self.partitions
+            .entry((msg.topic().to_string(), msg.partition()))
+            .and_modify(|(_, last, count)| {
+                *last = offset;
+                *count += 1;
+            })
+            .or_insert((offset, offset, 1));
+        self.messages += 1;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages == 0
+    }
+
+    /// How many messages were tracked.
+    pub fn len(&self) -> usize {
+        self.messages
+    }
+
+    /// Drops the messages of the `revoked` partitions and returns how many
+    /// there were.
+    pub fn forget(&mut self, revoked: &TopicPartitionList) -> usize {
+        let mut forgotten = 0;
+        for e in revoked.elements() {
+            let key = (e.topic().to_string(), e.partition());
+            if let Some((_, _, count)) = self.partitions.remove(&key) {
+                forgotten += count;
+            }
+        }
+        self.messages -= forgotten;
+        forgotten
+    }
+
+    pub fn clear(&mut self) {
+        self.partitions.clear();
+        self.messages = 0;
+    }
+
+    /// The offsets to commit once the batch is done: one past the last message
+    /// of each partition.
+    pub fn to_commit(&self) -> KafkaResult<TopicPartitionList> {
+        let mut tpl = TopicPartitionList::new();
+        for ((topic, partition), (_, last, _)) in &self.partitions {
+            tpl.add_partition_offset(topic, *partition, Offset::Offset(last + 1))?;
+        }
+        Ok(tpl)
+    }
+
+    /// Seeks every partition back to the first message of the batch, so it is
+    /// consumed again. Partitions no longer assigned to `consumer` are skipped:
+    /// their new owner resumes from the committed offsets.
+    pub fn rewind<C, K>(&self, consumer: &K, timeout: Duration) -> KafkaResult<()>
+    where
+        C: ConsumerContext,
+        K: Consumer<C>,
+    {
+        let assignment = consumer.assignment()?;
+        for ((topic, partition), (first, _, _)) in &self.partitions {
+            if assignment.find_partition(topic, *partition).is_none() {
+                continue;
+            }
+            consumer.seek(topic, *partition, Offset::Offset(*first), timeout)?;
+        }
+        Ok(())
+    }
+}
+
+/// A [`TypedProducer`] whose records are only visible once the offsets of the
+/// input batch are committed with them.
+pub struct TransactionalProducer<T: ?Sized, S, C = ProduceCallbackLogger>
+where
+    C: ProducerContext + 'static,
+{
+    producer: TypedProducer<T, S, C>,
+    timeout: Duration,
+    retry: RetryPolicy,
+}
+
+impl<T, S, C> TransactionalProducer<T, S, C>
+where
+    T: ?Sized,
+    S: Serializer<T>,
+    C: ProducerContext<DeliveryOpaque = Box<DeliverySpan>> + 'static,
+{
+    /// `producer` must have been created with a `transactional.id`, see
+    /// [`Settings::transactional_producer_config`](crate::Settings::transactional_producer_config).
+    pub fn new(producer: TypedProducer<T, S, C>) -> Self {
+        TransactionalProducer {
+            producer,
+            timeout: DEFAULT_TRANSACTION_TIMEOUT,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// How long each transactional call may take.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How often retriable errors are retried.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Registers the `transactional.id` with the transaction coordinator. This
+    /// fences off older producers with the same id and completes or aborts
+    /// whatever transaction they left open. Call it once, before [`run`](Self::run).
+    pub fn init(&self) -> Result<(), TransactionError> {
+        self.retrying("init transactions", || {
+            self.producer.inner().init_transactions(self.timeout)
+        })
+        .map_err(TransactionError::unrecoverable)
+    }
+
+    /// Runs one transaction: `produce` sends the output of the batch, then the
+    /// offsets of `batch` are added and everything is committed.
+    ///
+    /// When the transaction has to be aborted, `consumer` is rewound to the
+    /// start of the batch and [`TransactionError::Aborted`] or
+    /// [`TransactionError::Send`] is returned; the batch can be processed again.
+    /// [`TransactionError::Rewind`] means the abort went through but the rewind
+    /// did not.
+    pub fn run<CC, K, F>(
+        &self,
+        consumer: &K,
+        batch: &BatchOffsets,
+        produce: F,
+    ) -> Result<(), TransactionError>
+    where
+        CC: ConsumerContext,
+        K: Consumer<CC>,
+        F: FnOnce(&TypedProducer<T, S, C>) -> Result<(), SendError>,
+    {
+        let producer = self.producer.inner();
+        producer
+            .begin_transaction()
+            .map_err(TransactionError::unrecoverable)?;
+
+        if let Err(err) = produce(&self.producer) {
+            return match err {
+                SendError::Kafka(err) | SendError::Delivery(err) => {
+                    self.abort(consumer, batch, err)
+                }
+                err => {
+                    self.abort_and_rewind(consumer, batch)?;
+                    Err(TransactionError::Send(err))
+                }
+            };
+        }
+
+        let group = match consumer.group_metadata() {
+            Some(group) => group,
+            None => {
+                self.abort_and_rewind(consumer, batch)?;
+                return Err(TransactionError::NoGroup);
+            }
+        };
+        let offsets = batch.to_commit().map_err(TransactionError::Fatal)?;
+        let committed = self
+            .retrying("send offsets to transaction", || {
+                producer.send_offsets_to_transaction(&offsets, &group, self.timeout)
+            })
+            .and_then(|_| {
+                self.retrying("commit transaction", || {
+                    producer.commit_transaction(self.timeout)
+                })
+            });
+
+        match committed {
+            Ok(()) => Ok(()),
+            Err(err) => self.abort(consumer, batch, err),
+        }
+    }
+
+    /// The underlying producer.
+    pub fn inner(&self) -> &TypedProducer<T, S, C> {
+        &self.producer
+    }
+
+    /// Aborts after `err` if it allows that, otherwise reports it as unrecoverable.
+    fn abort<CC, K>(
+        &self,
+        consumer: &K,
+        batch: &BatchOffsets,
+        err: KafkaError,
+    ) -> Result<(), TransactionError>
+    where
+        CC: ConsumerContext,
+        K: Consumer<CC>,
+    {
+        match TransactionError::unrecoverable(err) {
+            TransactionError::Aborted(err) => {
+                warn!(error = %err, "aborting transaction");
+                self.abort_and_rewind(consumer, batch)?;
+                Err(TransactionError::Aborted(err))
+            }
+            unrecoverable => Err(unrecoverable),
+        }
+    }
+
+    fn abort_and_rewind<CC, K>(
+        &self,
+        consumer: &K,
+        batch: &BatchOffsets,
+    ) -> Result<(), TransactionError>
+    where
+        CC: ConsumerContext,
+        K: Consumer<CC>,
+    {
+        self.retrying("abort transaction", || {
+            self.producer.inner().abort_transaction(self.timeout)
+        })
+        .map_err(|err| match TransactionError::unrecoverable(err) {
+            // an abort that cannot be aborted leaves nothing else to try
+            TransactionError::Aborted(err) => TransactionError::Fatal(err),
+            unrecoverable => unrecoverable,
+        })?;
+        // without the rewind the aborted batch would be skipped
+        batch
+            .rewind(consumer, self.timeout)
+            .map_err(TransactionError::Rewind)
+    }
+
+    /// Repeats `op` while it fails with a retriable error and retries are left.
+    fn retrying<F>(&self, what: &str, mut op: F) -> KafkaResult<()>
+    where
+        F: FnMut() -> KafkaResult<()>,
+    {
+        let mut retry = 0;
+        loop {
+            match op() {
+                Err(KafkaError::Transaction(err))
+                    if err.is_retriable() && retry < self.retry.max_retries =>
+                {
+                    retry += 1;
+                    let backoff = self.retry.backoff(retry);
+                    warn!(error = %err, backoff_ms = backoff.as_millis() as u64, "{} failed, retrying", what);
+                    thread::sleep(backoff);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Why a transaction did not commit.
+#[derive(Debug)]
+pub enum TransactionError {
+    /// The transaction was aborted and the consumer rewound. The batch can be
+    /// processed again.
+    Aborted(KafkaError),
+    /// A record of the batch could not be sent. The transaction was aborted and
+    /// the consumer rewound. A record that does not serialize fails again every
+    /// time the batch is processed, so only enqueue and delivery failures are
+    /// retriable.
+    Send(SendError),
+    /// The transaction was aborted, but the consumer could not be rewound to the
+    /// start of the batch. None of it was committed: resubscribe the consumer,
+    /// or restart it, to consume the batch again from the committed offsets.
+    Rewind(KafkaError),
+    /// Another producer with the same `transactional.id` took over. This one must stop.
+    Fenced(KafkaError),
+    /// The producer can no longer be used.
+    Fatal(KafkaError),
+    /// The consumer is not part of a group, so there is nothing to commit the offsets to.
+    NoGroup,
+}
+
+impl TransactionError {
+    /// Whether the batch can be processed again with the same producer.
+    pub fn is_retriable(&self) -> bool {
+        matches!(
+            self,
+            TransactionError::Aborted(_)
+                | TransactionError::Send(SendError::Kafka(_))
+                | TransactionError::Send(SendError::Delivery(_))
+        )
+    }
+
+    /// Sorts an error into fenced, fatal or abortable. Nothing has been aborted yet.
+    fn unrecoverable(err: KafkaError) -> TransactionError {
+        let fenced = matches!(
+            err.rdkafka_error_code(),
+            Some(RDKafkaErrorCode::Fenced)
+                | Some(RDKafkaErrorCode::ProducerFenced)
+                | Some(RDKafkaErrorCode::InvalidProducerEpoch)
+                | Some(RDKafkaErrorCode::TransactionCoordinatorFenced)
+        );
+        match &err {
+            _ if fenced => TransactionError::Fenced(err),
+            KafkaError::Transaction(txn_err) if txn_err.txn_requires_abort() => {
+                TransactionError::Aborted(err)
+            }
+            KafkaError::Transaction(_) => TransactionError::Fatal(err),
+            // e.g. a failed delivery inside the transaction
+            _ => TransactionError::Aborted(err),
+        }
+    }
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::Aborted(err) => write!(f, "transaction aborted - {}", err),
+            TransactionError::Send(err) => write!(f, "transaction aborted - {}", err),
+            TransactionError::Rewind(err) => write!(
+                f,
+                "transaction aborted, but the consumer could not be rewound - {}",
+                err
+            ),
+            TransactionError::Fenced(err) => write!(
+                f,
+                "producer fenced by a newer instance with the same transactional.id - {}",
+                err
+            ),
+            TransactionError::Fatal(err) => write!(f, "fatal transaction error - {}", err),
+            TransactionError::NoGroup => {
+                write!(f, "the consumer has no group to commit offsets to")
+            }
+        }
+    }
+}
+
+impl Error for TransactionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TransactionError::Aborted(err)
+            | TransactionError::Rewind(err)
+            | TransactionError::Fenced(err)
+            | TransactionError::Fatal(err) => Some(err),
+            TransactionError::Send(err) => Some(err),
+            TransactionError::NoGroup => None,
+        }
+    }
+}
+
+/// The resolved `[transactions]` section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionSettings {
+    /// See [`Settings::output_topic`](crate::Settings::output_topic) for the default.
+    pub output_topic: Option<String>,
+    /// See [`Settings::transactional_id`](crate::Settings::transactional_id) for the default.
+    pub transactional_id: Option<String>,
+    pub timeout: Duration,
+}
+
+impl Default for TransactionSettings {
+    fn default() -> Self {
+        TransactionSettings {
+            output_topic: None,
+            transactional_id: None,
+            timeout: DEFAULT_TRANSACTION_TIMEOUT,
+        }
+    }
+}
+
+/// The `[transactions]` section of a config file. Profiles override it field by field.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TransactionsConfig {
+    output_topic: Option<String>,
+    transactional_id: Option<String>,
+    timeout_ms: Option<u64>,
+}
+
+impl TransactionsConfig {
+    pub fn apply(self, settings: &mut TransactionSettings) {
+        if let Some(output_topic) = self.output_topic {
+            settings.output_topic = Some(output_topic);
+        }
+        if let Some(transactional_id) = self.transactional_id {
+            settings.transactional_id = Some(transactional_id);
+        }
+        if let Some(timeout_ms) = self.timeout_ms {
+            settings.timeout = Duration::from_millis(timeout_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rdkafka::{message::OwnedMessage, Timestamp};
+
+    use super::*;
+    use crate::codec::CodecError;
+
+    fn message(partition: i32, offset: i64) -> OwnedMessage {
+        OwnedMessage::new(
+            None,
+            None,
+            "rust".to_string(),
+            Timestamp::NotAvailable,
+            partition,
+            offset,
+            None,
+        )
+    }
+
+    fn offsets(tpl: &TopicPartitionList) -> Vec<(i32, Offset)> {
+        tpl.elements()
+            .iter()
+            .map(|e| (e.partition(), e.offset()))
+            .collect()
+    }
+
+    #[test]
+    fn offsets_past_the_last_message_of_each_partition_are_committed() {
+        let mut batch = BatchOffsets::new();
+        for (partition, offset) in &[(0, 5), (1, 2), (0, 6), (0, 7)] {
+            batch.track(&message(*partition, *offset));
+        }
+
+        assert_eq!(batch.len(), 4);
+        assert_eq!(
+            offsets(&batch.to_commit().unwrap()),
+            vec![(0, Offset::Offset(8)), (1, Offset::Offset(3))]
+        );
+    }
+
+    #[test]
+    fn revoked_partitions_are_forgotten() {
+        let mut batch = BatchOffsets::new();
+        for (partition, offset) in &[(0, 5), (1, 2), (0, 6), (2, 9)] {
+            batch.track(&message(*partition, *offset));
+        }
+
+        let mut revoked = TopicPartitionList::new();
+        revoked.add_partition("rust", 0);
+        revoked.add_partition("rust", 3);
+        assert_eq!(batch.forget(&revoked), 2);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            offsets(&batch.to_commit().unwrap()),
+            vec![(1, Offset::Offset(3)), (2, Offset::Offset(10))]
+        );
+
+        revoked.add_partition("rust", 1);
+        revoked.add_partition("rust", 2);
+        assert_eq!(batch.forget(&revoked), 2);
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn records_that_do_not_serialize_are_not_retried() {
+        let queue_full = || KafkaError::MessageProduction(RDKafkaErrorCode::QueueFull);
+        assert!(TransactionError::Aborted(queue_full()).is_retriable());
+        assert!(TransactionError::Send(SendError::Kafka(queue_full())).is_retriable());
+        assert!(TransactionError::Send(SendError::Delivery(queue_full())).is_retriable());
+
+        let unserializable = SendError::Serialization(CodecError::InvalidFraming("x".into()));
+        assert!(!TransactionError::Send(unserializable).is_retriable());
+        assert!(!TransactionError::Rewind(queue_full()).is_retriable());
+    }
+}
@@ -0,0 +1,8 @@
+use serde::{Deserialize, Serialize};
+
+/// The record exchanged by the JSON producer and the consumers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct User {
+    pub id: i32,
+    pub email: String,
+}
@@ -0,0 +1,238 @@
+mod common;
+
+use std::sync::Arc;
+
+use apache_avro::{to_avro_datum, types::Value, Schema};
+use kafka101_core::{
+    auth::Secret, avro::AvroRecord, codec::CodecError, schema_registry::RegistryError, AvroSerde,
+    Deserializer, SchemaRegistryClient, Serializer, SubjectNameStrategy, User,
+};
+
+use common::MockRegistry;
+
+fn user() -> User {
+    User {
+        id: 42,
+        email: "user-42@foobar.com".to_string(),
+    }
+}
+
+fn serde(registry: &MockRegistry) -> AvroSerde {
+    AvroSerde::new(Arc::new(SchemaRegistryClient::new(registry.url.as_str())))
+}
+
+/// A framed payload holding `fields` written with the writer schema `schema`.
+fn payload(id: u32, schema: &str, fields: Vec<(&str, Value)>) -> Vec<u8> {
+    let schema = Schema::parse_str(schema).unwrap();
+    let record = Value::Record(
+        fields
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value))
+            .collect(),
+    );
+    let mut payload = vec![0];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend(to_avro_datum(&schema, record).unwrap());
+    payload
+}
+
+#[test]
+fn user_is_encoded_as_int_then_string() {
+    let registry = MockRegistry::start();
+    let serde = serde(&registry);
+
+    let user = User {
+        id: 1,
+        email: "a@b".to_string(),
+    };
+    let payload = serde.serialize("rust", &user).unwrap();
+    assert_eq!(&payload[5..], &[0x02, 0x06, b'a', b'@', b'b']);
+
+    let err = Deserializer::<User>::deserialize(&serde, "rust", &payload[..8]).unwrap_err();
+    assert!(matches!(err, CodecError::Avro(_)), "{}", err);
+}
+
+#[test]
+fn round_trip_uses_the_confluent_wire_format() {
+    let registry = MockRegistry::start();
+    let serde = serde(&registry);
+
+    let payload = serde.serialize("rust", &user()).unwrap();
+    assert_eq!(&payload[..5], &[0, 0, 0, 0, 1]);
+
+    let decoded: User = serde.deserialize("rust", &payload).unwrap();
+    assert_eq!(decoded, user());
+    assert_eq!(registry.subjects(), vec!["rust-value"]);
+}
+
+#[test]
+fn schema_ids_are_cached() {
+    let registry = MockRegistry::start();
+    let producer_side = serde(&registry);
+    let consumer_side = serde(&registry);
+
+    for _ in 0..3 {
+        let payload = producer_side.serialize("rust", &user()).unwrap();
+        let _: User = consumer_side.deserialize("rust", &payload).unwrap();
+    }
+
+    assert_eq!(registry.count("POST", "/subjects/rust-value/versions"), 1);
+    assert_eq!(registry.count("GET", "/schemas/ids/1"), 1);
+}
+
+#[test]
+fn subject_name_strategies() {
+    let registry = MockRegistry::start();
+    for strategy in [
+        SubjectNameStrategy::TopicName,
+        SubjectNameStrategy::RecordName,
+        SubjectNameStrategy::TopicRecordName,
+    ] {
+        serde(&registry)
+            .subject_name_strategy(strategy)
+            .serialize("rust", &user())
+            .unwrap();
+    }
+    serde(&registry)
+        .for_keys()
+        .serialize("rust", &user())
+        .unwrap();
+
+    assert_eq!(
+        registry.subjects(),
+        vec![
+            "io.kafka101.User",
+            "rust-io.kafka101.User",
+            "rust-key",
+            "rust-value"
+        ]
+    );
+}
+
+#[test]
+fn incompatible_schema_is_not_registered() {
+    let registry = MockRegistry::start();
+    registry.register("rust-value", r#"{"type":"string"}"#, None);
+    registry.reject_incompatible();
+
+    let err = serde(&registry).serialize("rust", &user()).unwrap_err();
+    assert!(
+        matches!(err, CodecError::Registry(RegistryError::Incompatible { ref subject }) if subject == "rust-value"),
+        "{}",
+        err
+    );
+    assert_eq!(registry.count("POST", "/subjects/rust-value/versions"), 0);
+
+    // skipping the check leaves the decision to the registry
+    serde(&registry)
+        .check_compatibility(false)
+        .serialize("rust", &user())
+        .unwrap();
+}
+
+#[test]
+fn lookup_only_needs_a_registered_schema() {
+    let registry = MockRegistry::start();
+    let serde = serde(&registry).auto_register(false);
+
+    let err = serde.serialize("rust", &user()).unwrap_err();
+    assert!(
+        matches!(
+            err,
+            CodecError::Registry(RegistryError::Api {
+                status: 404,
+                code: Some(40401),
+                ..
+            })
+        ),
+        "{}",
+        err
+    );
+
+    let id = registry.register("rust-value", User::SCHEMA, None);
+    let payload = serde.serialize("rust", &user()).unwrap();
+    assert_eq!(&payload[1..5], &id.to_be_bytes());
+    assert_eq!(registry.count("POST", "/subjects/rust-value/versions"), 0);
+}
+
+#[test]
+fn formatting_differences_in_the_writer_schema_are_ignored() {
+    let registry = MockRegistry::start();
+    let pretty = serde_json::to_string_pretty(
+        &serde_json::from_str::<serde_json::Value>(User::SCHEMA).unwrap(),
+    )
+    .unwrap();
+    let id = registry.register("rust-value", &pretty, None);
+    let payload = payload(
+        id,
+        &pretty,
+        vec![
+            ("id", Value::Int(42)),
+            ("email", Value::String("user-42@foobar.com".to_string())),
+        ],
+    );
+
+    let decoded: User = serde(&registry).deserialize("rust", &payload).unwrap();
+    assert_eq!(decoded, user());
+}
+
+#[test]
+fn evolved_writer_schema_is_resolved_against_the_reader() {
+    let registry = MockRegistry::start();
+    let writer = r#"{"type":"record","name":"User","namespace":"io.kafka101","fields":[{"name":"id","type":"int"},{"name":"name","type":"string","default":""},{"name":"email","type":"string"}]}"#;
+    let id = registry.register("rust-value", writer, None);
+    let payload = payload(
+        id,
+        writer,
+        vec![
+            ("id", Value::Int(42)),
+            ("name", Value::String("Ada".to_string())),
+            ("email", Value::String("user-42@foobar.com".to_string())),
+        ],
+    );
+
+    let decoded: User = serde(&registry).deserialize("rust", &payload).unwrap();
+    assert_eq!(decoded, user());
+}
+
+#[test]
+fn unreadable_writer_schemas_are_rejected() {
+    let registry = MockRegistry::start();
+    let writer = r#"{"type":"record","name":"Order","fields":[{"name":"id","type":"int"}]}"#;
+    let id = registry.register("rust-value", writer, None);
+    let payload = payload(id, writer, vec![("id", Value::Int(7))]);
+
+    let err = Deserializer::<User>::deserialize(&serde(&registry), "rust", &payload).unwrap_err();
+    assert!(
+        matches!(err, CodecError::SchemaMismatch { schema_id, .. } if schema_id == id),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn payloads_without_framing_are_rejected() {
+    let registry = MockRegistry::start();
+    let serde = serde(&registry);
+
+    for payload in [&b""[..], &b"{\"id\":1}"[..], &[0, 0, 1][..]] {
+        let err = Deserializer::<User>::deserialize(&serde, "rust", payload).unwrap_err();
+        assert!(matches!(err, CodecError::InvalidFraming(_)), "{}", err);
+    }
+    assert!(registry.requests().is_empty());
+}
+
+#[test]
+fn basic_auth_is_sent() {
+    let registry = MockRegistry::start();
+    let client = SchemaRegistryClient::new(registry.url.as_str())
+        .with_basic_auth("app", &Secret::new("s3cret"));
+    client.schema_by_id(1).unwrap_err();
+
+    let requests = registry.requests();
+    assert_eq!(
+        requests[0].authorization.as_deref(),
+        Some("Basic YXBwOnMzY3JldA==")
+    );
+    assert!(!format!("{:?}", client).contains("YXBw"));
+}
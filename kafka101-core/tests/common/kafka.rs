@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+use kafka101_core::{config::Loader, Settings};
+use rdkafka::{
+    consumer::{BaseConsumer, Consumer},
+    message::OwnedMessage,
+    mocking::MockCluster,
+    producer::DefaultProducerContext,
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+
+/// How long a test waits for records, commits or a rebalance before failing.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// librdkafka's in-process mock cluster with one topic, so the clients can be
+/// run end to end without a broker.
+pub struct MockKafka {
+    pub cluster: MockCluster<'static, DefaultProducerContext>,
+    pub topic: String,
+    pub partitions: i32,
+}
+
+impl MockKafka {
+    /// A single broker cluster with `topic` split into `partitions`.
+    pub fn start(topic: &str, partitions: i32) -> MockKafka {
+        MockKafka::with_brokers(1, topic, partitions)
+    }
+
+    /// A cluster of `brokers`, numbered from 1, with `topic` split into
+    /// `partitions` and replicated to every broker.
+    pub fn with_brokers(brokers: i32, topic: &str, partitions: i32) -> MockKafka {
+        let cluster = MockCluster::new(brokers).unwrap();
+        cluster.create_topic(topic, partitions, brokers).unwrap();
+        MockKafka {
+            cluster,
+            topic: topic.to_string(),
+            partitions,
+        }
+    }
+
+    pub fn bootstrap_servers(&self) -> String {
+        self.cluster.bootstrap_servers()
+    }
+
+    /// Settings for the cluster and topic, as the binaries would load them,
+    /// followed by `args`. Consumers start from the earliest offset, as nothing
+    /// is committed yet. The mock coordinator waits up to a session timeout for
+    /// every member it knows, even one that left, before completing a
+    /// rebalance, so consumers get the shortest one it accepts.
+    pub fn settings(&self, args: &[&str]) -> Settings {
+        let mut all = vec![
+            "--bootstrap-servers".to_string(),
+            self.bootstrap_servers(),
+            "--topic".to_string(),
+            self.topic.clone(),
+            "--consumer-property".to_string(),
+            "auto.offset.reset=earliest".to_string(),
+            "--consumer-property".to_string(),
+            "session.timeout.ms=6000".to_string(),
+        ];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        Loader::new().args(all).load().unwrap()
+    }
+
+    /// Reads `count` records of the topic from the beginning, without a group,
+    /// ordered by partition and offset.
+    pub fn read(&self, count: usize) -> Vec<OwnedMessage> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", self.bootstrap_servers())
+            .set("group.id", "kafka101-tests-reader")
+            .set("enable.auto.commit", "false")
+            .create()
+            .unwrap();
+        let mut tpl = TopicPartitionList::new();
+        for partition in 0..self.partitions {
+            tpl.add_partition_offset(&self.topic, partition, Offset::Beginning)
+                .unwrap();
+        }
+        consumer.assign(&tpl).unwrap();
+
+        let deadline = Instant::now() + TIMEOUT;
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            assert!(
+                Instant::now() < deadline,
+                "read {} of {} records",
+                messages.len(),
+                count
+            );
+            if let Some(msg) = consumer.poll(Duration::from_millis(100)) {
+                messages.push(msg.unwrap().detach());
+            }
+        }
+        messages.sort_by_key(|msg| (msg.partition(), msg.offset()));
+        messages
+    }
+
+    /// The offsets `group` has committed on every partition of the topic, by
+    /// partition, `None` where it has not committed any.
+    pub fn committed(&self, group: &str) -> Vec<Option<i64>> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", self.bootstrap_servers())
+            .set("group.id", group)
+            .create()
+            .unwrap();
+        let mut tpl = TopicPartitionList::new();
+        for partition in 0..self.partitions {
+            tpl.add_partition(&self.topic, partition);
+        }
+        let committed = consumer.committed_offsets(tpl, TIMEOUT).unwrap();
+        committed
+            .elements()
+            .iter()
+            .map(|e| match e.offset() {
+                Offset::Offset(offset) => Some(offset),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// The high watermark of every partition of the topic.
+    pub fn high_watermarks(&self) -> Vec<i64> {
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", self.bootstrap_servers())
+            .create()
+            .unwrap();
+        (0..self.partitions)
+            .map(|partition| {
+                consumer
+                    .fetch_watermarks(&self.topic, partition, TIMEOUT)
+                    .unwrap()
+                    .1
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,247 @@
+#![allow(dead_code)]
+
+pub mod kafka;
+pub mod scenario;
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use kafka101_core::User;
+use serde_json::{json, Value};
+
+/// User `i`, keyed `user-<i>` by the tests.
+pub fn user(i: i32) -> User {
+    User {
+        id: i,
+        email: format!("user-{}@foobar.com", i),
+    }
+}
+
+/// A schema registry that keeps everything in memory and speaks just enough HTTP for ureq.
+pub struct MockRegistry {
+    pub url: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    schemas: Vec<(String, Option<String>)>,
+    subjects: HashMap<String, Vec<u32>>,
+    requests: Vec<Request>,
+    incompatible: bool,
+    failure: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub authorization: Option<String>,
+    pub body: String,
+}
+
+impl MockRegistry {
+    pub fn start() -> MockRegistry {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State::default()));
+
+        let server_state = state.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => handle(stream, &server_state),
+                    Err(_) => break,
+                }
+            }
+        });
+
+        MockRegistry { url, state }
+    }
+
+    /// Registers a schema directly, as another producer would have.
+    pub fn register(&self, subject: &str, schema: &str, schema_type: Option<&str>) -> u32 {
+        self.state
+            .lock()
+            .unwrap()
+            .register(subject, schema, schema_type.map(str::to_string))
+    }
+
+    /// Make every compatibility check fail from now on.
+    pub fn reject_incompatible(&self) {
+        self.state.lock().unwrap().incompatible = true;
+    }
+
+    /// Answer every request with `status` until called with `None`, like a
+    /// registry that is down.
+    pub fn fail_with(&self, status: Option<u16>) {
+        self.state.lock().unwrap().failure = status;
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    pub fn count(&self, method: &str, path: &str) -> usize {
+        self.requests()
+            .iter()
+            .filter(|request| request.method == method && request.path == path)
+            .count()
+    }
+
+    pub fn subjects(&self) -> Vec<String> {
+        let mut subjects: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .subjects
+            .keys()
+            .cloned()
+            .collect();
+        subjects.sort();
+        subjects
+    }
+}
+
+impl State {
+    fn register(&mut self, subject: &str, schema: &str, schema_type: Option<String>) -> u32 {
+        let id = match self.schemas.iter().position(|(s, _)| s == schema) {
+            Some(index) => index as u32 + 1,
+            None => {
+                self.schemas.push((schema.to_string(), schema_type));
+                self.schemas.len() as u32
+            }
+        };
+        let versions = self.subjects.entry(subject.to_string()).or_default();
+        if !versions.contains(&id) {
+            versions.push(id);
+        }
+        id
+    }
+
+    fn route(&mut self, request: &Request) -> (u16, Value) {
+        let segments: Vec<&str> = request.path.trim_start_matches('/').split('/').collect();
+        let body: Value = serde_json::from_str(&request.body).unwrap_or(Value::Null);
+        let schema = body["schema"].as_str().unwrap_or_default().to_string();
+        let schema_type = body["schemaType"].as_str().map(str::to_string);
+
+        if let Some(status) = self.failure {
+            return (
+                status,
+                json!({ "error_code": status, "message": "Service Unavailable" }),
+            );
+        }
+        match (request.method.as_str(), segments.as_slice()) {
+            ("POST", ["subjects", subject, "versions"]) => {
+                let id = self.register(subject, &schema, schema_type);
+                (200, json!({ "id": id }))
+            }
+            ("POST", ["subjects", subject]) => match self.subjects.get(*subject) {
+                None => not_found(40401, "Subject not found"),
+                Some(versions) => {
+                    let found = versions
+                        .iter()
+                        .enumerate()
+                        .find(|(_, id)| self.schemas[**id as usize - 1].0 == schema);
+                    match found {
+                        Some((version, id)) => (
+                            200,
+                            json!({
+                                "subject": subject,
+                                "id": id,
+                                "version": version + 1,
+                                "schema": schema,
+                            }),
+                        ),
+                        None => not_found(40403, "Schema not found"),
+                    }
+                }
+            },
+            ("GET", ["schemas", "ids", id]) => {
+                match id
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|id| self.schemas.get(id - 1))
+                {
+                    Some((schema, Some(schema_type))) => {
+                        (200, json!({ "schema": schema, "schemaType": schema_type }))
+                    }
+                    Some((schema, None)) => (200, json!({ "schema": schema })),
+                    None => not_found(40403, "Schema not found"),
+                }
+            }
+            ("POST", ["compatibility", "subjects", subject, "versions", "latest"]) => {
+                if self.subjects.contains_key(*subject) {
+                    (200, json!({ "is_compatible": !self.incompatible }))
+                } else {
+                    not_found(40401, "Subject not found")
+                }
+            }
+            _ => not_found(404, "HTTP 404 Not Found"),
+        }
+    }
+}
+
+fn not_found(code: u32, message: &str) -> (u16, Value) {
+    (404, json!({ "error_code": code, "message": message }))
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).is_err() {
+        return;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut content_length = 0;
+    let mut authorization = None;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap(),
+                "authorization" => authorization = Some(value.trim().to_string()),
+                _ => {}
+            }
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+
+    let request = Request {
+        method,
+        path,
+        authorization,
+        body: String::from_utf8(body).unwrap(),
+    };
+    let (status, response) = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        state.route(&request)
+    };
+
+    let response = response.to_string();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} Mock\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        response.len(),
+        response
+    )
+    .unwrap();
+}
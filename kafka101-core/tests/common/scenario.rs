@@ -0,0 +1,87 @@
+use std::fmt::{self, Debug};
+
+/// A fault injected into the mock cluster and what the clients were expected
+/// to do about it, checked one expectation at a time so that a failing test
+/// shows every expectation next to what actually happened:
+///
+/// ```text
+/// scenario: deliveries time out while the broker is down
+/// fault:    broker 1 down for 3 sends, message.timeout.ms = 2000
+///   ok    failed deliveries  expected 3  actual 3
+///   FAIL  acknowledged       expected 0  actual 1
+/// ```
+pub struct Scenario {
+    name: String,
+    fault: String,
+    checks: Vec<Check>,
+}
+
+struct Check {
+    what: String,
+    expected: String,
+    actual: String,
+    passed: bool,
+}
+
+impl Scenario {
+    pub fn new(name: &str, fault: &str) -> Scenario {
+        Scenario {
+            name: name.to_string(),
+            fault: fault.to_string(),
+            checks: Vec::new(),
+        }
+    }
+
+    /// Expects `actual` to equal `expected`.
+    pub fn expect_eq<T: Debug + PartialEq>(&mut self, what: &str, expected: T, actual: T) {
+        let passed = expected == actual;
+        self.expect(what, &format!("{:?}", expected), actual, passed);
+    }
+
+    /// Records `actual` against an `expected` outcome described in words, and
+    /// whether it `passed`.
+    pub fn expect<T: Debug>(&mut self, what: &str, expected: &str, actual: T, passed: bool) {
+        self.checks.push(Check {
+            what: what.to_string(),
+            expected: expected.to_string(),
+            actual: format!("{:?}", actual),
+            passed,
+        });
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    /// Fails the test with the report unless every expectation was met.
+    pub fn finish(self) {
+        assert!(self.passed(), "\n{}", self);
+    }
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "scenario: {}", self.name)?;
+        write!(f, "fault:    {}", self.fault)?;
+        let what = self.checks.iter().map(|c| c.what.len()).max().unwrap_or(0);
+        let expected = self
+            .checks
+            .iter()
+            .map(|c| c.expected.len())
+            .max()
+            .unwrap_or(0);
+        for check in &self.checks {
+            write!(
+                f,
+                "\n  {:<4}  {:<what$}  expected {:<expected$}  actual {}",
+                if check.passed { "ok" } else { "FAIL" },
+                check.what,
+                check.expected,
+                check.actual,
+                what = what,
+                expected = expected,
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,642 @@
+//! How the producer and consumer contexts behave when the broker misbehaves.
+//! Each scenario injects a fault through the mock cluster (request errors, a
+//! broker going down, a leader moving, an unavailable coordinator, slow round
+//! trips) and reports what was expected of delivery reports, commits and
+//! rebalances next to what happened.
+
+mod common;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use kafka101_core::{
+    commit::{CommitSettings, CommitStats},
+    dlq::RetryPolicy,
+    replay, shutdown, CommitError, CommitManager, CommitStrategy, ConsumerCallbackLogger,
+    DeliveryReport, DeliveryTracker, FormatSerde, Metrics, OffsetCommitter, ProduceCallbackLogger,
+    RebalanceListener, Settings, TypedConsumer, TypedProducer, User,
+};
+use rdkafka::{
+    types::{RDKafkaApiKey, RDKafkaRespErr},
+    Message, TopicPartitionList,
+};
+
+use common::{
+    kafka::{MockKafka, TIMEOUT},
+    scenario::Scenario,
+    user,
+};
+
+type UserConsumer = TypedConsumer<User, FormatSerde, ConsumerCallbackLogger>;
+
+/// A producer that reports its deliveries to `tracker` and `metrics`.
+fn producer(
+    settings: &Settings,
+    tracker: &DeliveryTracker,
+    metrics: &Metrics,
+) -> TypedProducer<User, FormatSerde> {
+    TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_metrics(metrics.clone())
+                    .with_tracker(tracker.clone()),
+            )
+            .unwrap(),
+        &settings.topic,
+        settings.serde().unwrap(),
+    )
+    .with_tracker(tracker.clone())
+}
+
+/// Sends users `ids` and waits for their delivery reports.
+fn produce(producer: &TypedProducer<User, FormatSerde>, ids: std::ops::RangeInclusive<i32>) {
+    for i in ids {
+        producer.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(producer.inner(), TIMEOUT), 0);
+}
+
+/// The value of an application counter, as served on `/metrics`.
+fn counter(metrics: &Metrics, name: &str) -> u64 {
+    let rendered = metrics.render();
+    rendered
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in\n{}", name, rendered))
+        .parse::<f64>()
+        .unwrap() as u64
+}
+
+/// Rebalance callbacks, in the order they came.
+#[derive(Default)]
+struct Rebalances {
+    events: Mutex<Vec<String>>,
+}
+
+impl Rebalances {
+    fn record(&self, kind: &str, tpl: &TopicPartitionList) {
+        let partitions: Vec<String> = tpl
+            .elements()
+            .iter()
+            .map(|e| e.partition().to_string())
+            .collect();
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", kind, partitions.join(",")));
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl RebalanceListener for Rebalances {
+    fn on_assign(&self, assigned: &TopicPartitionList) {
+        self.record("assign", assigned);
+    }
+
+    fn on_revoke(&self, revoked: &TopicPartitionList) {
+        self.record("revoke", revoked);
+    }
+
+    fn on_lost(&self, lost: &TopicPartitionList) {
+        self.record("lost", lost);
+    }
+}
+
+/// A manual commit consumer, built like `3_manual_commit`, subscribed to the topic.
+fn consumer(
+    settings: &Settings,
+    committer: &OffsetCommitter,
+    rebalances: &Arc<Rebalances>,
+    metrics: &Metrics,
+) -> UserConsumer {
+    let consumer = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .set("enable.auto.commit", "false")
+            .create_with_context(
+                ConsumerCallbackLogger::new()
+                    .with_metrics(metrics.clone())
+                    .with_committer(committer.clone())
+                    .with_listener(rebalances.clone()),
+            )
+            .unwrap(),
+        settings.serde().unwrap(),
+    );
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from).unwrap();
+    consumer
+}
+
+/// Polls until `count` users were received or the timeout passes, marking
+/// each processed, and returns the ids received with the result of their commit.
+fn consume(
+    consumer: &UserConsumer,
+    committer: &OffsetCommitter,
+    count: usize,
+) -> Vec<(i32, Result<(), CommitError>)> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut received = Vec::new();
+    while received.len() < count && Instant::now() < deadline {
+        if let Some(result) = consumer.poll(shutdown::POLL_INTERVAL) {
+            let user = result.unwrap();
+            let committed = committer.processed(consumer.inner(), user.message());
+            received.push((user.value().id, committed));
+        }
+    }
+    received
+}
+
+fn committer(strategy: CommitStrategy) -> OffsetCommitter {
+    let settings = CommitSettings {
+        strategy,
+        ..CommitSettings::default()
+    };
+    OffsetCommitter::new(settings, CommitManager::new(RetryPolicy::default()))
+}
+
+fn delivery(scenario: &mut Scenario, report: &DeliveryReport, acknowledged: u64, failed: u64) {
+    scenario.expect_eq("acknowledged", acknowledged, report.acknowledged);
+    scenario.expect_eq("failed deliveries", failed, report.failed);
+    scenario.expect_eq("out of order", 0, report.out_of_order);
+    scenario.expect_eq(
+        "without a delivery report",
+        Vec::<String>::new(),
+        report.unacknowledged.clone(),
+    );
+}
+
+fn commits(scenario: &mut Scenario, stats: &CommitStats, committed: u64, retries: u64) {
+    scenario.expect_eq("commits", committed, stats.committed);
+    scenario.expect_eq("commit retries", retries, stats.retries);
+}
+
+#[test]
+fn non_retriable_produce_error_fails_every_delivery() {
+    let kafka = MockKafka::start("produce-denied", 1);
+    let settings = kafka.settings(&[]);
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::Produce,
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_TOPIC_AUTHORIZATION_FAILED; 10],
+    );
+    let mut scenario = Scenario::new(
+        "a produce request refused for good fails its records",
+        "Produce answered with TOPIC_AUTHORIZATION_FAILED",
+    );
+
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=5);
+
+    delivery(&mut scenario, &tracker.report(), 0, 5);
+    scenario.expect_eq(
+        "kafka101_delivery_failures_total",
+        5,
+        counter(&metrics, "kafka101_delivery_failures_total"),
+    );
+    scenario.expect_eq("records written", vec![0], kafka.high_watermarks());
+    scenario.finish();
+}
+
+#[test]
+fn transient_produce_errors_are_retried_in_order() {
+    let kafka = MockKafka::start("produce-retried", 1);
+    let settings = kafka.settings(&[]);
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::Produce,
+        &[
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR_NOT_LEADER_FOR_PARTITION,
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR_REQUEST_TIMED_OUT,
+            RDKafkaRespErr::RD_KAFKA_RESP_ERR_NOT_ENOUGH_REPLICAS,
+        ],
+    );
+    let mut scenario = Scenario::new(
+        "transient produce errors are retried",
+        "the first three Produce requests fail with retriable errors",
+    );
+
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=10);
+
+    delivery(&mut scenario, &tracker.report(), 10, 0);
+    scenario.expect_eq(
+        "kafka101_messages_produced_total",
+        10,
+        counter(&metrics, "kafka101_messages_produced_total"),
+    );
+    scenario.expect_eq("records written", vec![10], kafka.high_watermarks());
+    scenario.finish();
+}
+
+#[test]
+fn deliveries_time_out_while_the_broker_is_down() {
+    let kafka = MockKafka::start("broker-down", 1);
+    let settings = kafka.settings(&["--producer-property", "message.timeout.ms=2000"]);
+    let mut scenario = Scenario::new(
+        "deliveries time out while the broker is down, and resume after",
+        "broker 1 down for the first 3 sends, message.timeout.ms = 2000",
+    );
+
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    let producer = producer(&settings, &tracker, &metrics);
+    kafka.cluster.broker_down(1).unwrap();
+    produce(&producer, 1..=3);
+    delivery(&mut scenario, &tracker.report(), 0, 3);
+
+    kafka.cluster.broker_up(1).unwrap();
+    produce(&producer, 4..=6);
+    delivery(&mut scenario, &tracker.report(), 3, 3);
+    scenario.expect_eq(
+        "kafka101_delivery_failures_total",
+        3,
+        counter(&metrics, "kafka101_delivery_failures_total"),
+    );
+    scenario.expect_eq("records written", vec![3], kafka.high_watermarks());
+    scenario.finish();
+}
+
+#[test]
+fn leader_change_keeps_every_record_in_order() {
+    let kafka = MockKafka::with_brokers(3, "leader-change", 1);
+    let settings = kafka.settings(&[]);
+    kafka
+        .cluster
+        .partition_leader(&kafka.topic, 0, Some(1))
+        .unwrap();
+    let mut scenario = Scenario::new(
+        "records follow the partition to its new leader",
+        "partition 0 moves from broker 1 to broker 2 after 5 records",
+    );
+
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    let producer = producer(&settings, &tracker, &metrics);
+    produce(&producer, 1..=5);
+    kafka
+        .cluster
+        .partition_leader(&kafka.topic, 0, Some(2))
+        .unwrap();
+    produce(&producer, 6..=10);
+
+    let report = tracker.report();
+    delivery(&mut scenario, &report, 10, 0);
+    scenario.expect(
+        "delivery report",
+        "every record acknowledged, in order",
+        report.to_string(),
+        report.is_complete_in_order(),
+    );
+    let ids: Vec<i32> = kafka
+        .read(10)
+        .iter()
+        .map(|msg| {
+            let key = std::str::from_utf8(msg.key().unwrap()).unwrap();
+            key["user-".len()..].parse().unwrap()
+        })
+        .collect();
+    scenario.expect_eq("keys in the partition", (1..=10).collect(), ids);
+    scenario.finish();
+}
+
+/// Commits after each of three records, the second answered with `errors` first.
+fn commit_with_errors(
+    topic: &str,
+    errors: &[RDKafkaRespErr],
+    mut scenario: Scenario,
+    retries: u64,
+) {
+    let kafka = MockKafka::start(topic, 1);
+    let settings = kafka.settings(&["--group", topic]);
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=3);
+
+    let committer = committer(CommitStrategy::EveryMessage);
+    let rebalances = Arc::new(Rebalances::default());
+    let consumer = consumer(&settings, &committer, &rebalances, &metrics);
+    let mut results = consume(&consumer, &committer, 1);
+    kafka
+        .cluster
+        .request_errors(RDKafkaApiKey::OffsetCommit, errors);
+    results.extend(consume(&consumer, &committer, 2));
+    // the results of the failed attempts reach the consumer context here
+    assert!(consumer.poll(Duration::from_millis(500)).is_none());
+
+    let results: Vec<String> = results
+        .iter()
+        .map(|(id, committed)| match committed {
+            Ok(()) => format!("{} committed", id),
+            Err(err) => format!("{} {}", id, err),
+        })
+        .collect();
+    scenario.expect_eq(
+        "commit results",
+        vec!["1 committed", "2 committed", "3 committed"],
+        results.iter().map(String::as_str).collect(),
+    );
+    let stats = committer.stats();
+    commits(&mut scenario, &stats, 3, retries);
+    scenario.expect_eq("failed commits", 0, stats.failed);
+    scenario.expect_eq(
+        "kafka101_commit_failures_total",
+        retries,
+        counter(&metrics, "kafka101_commit_failures_total"),
+    );
+    scenario.expect_eq("committed offsets", vec![Some(3)], kafka.committed(topic));
+    scenario.finish();
+}
+
+#[test]
+fn coordinator_errors_on_commit_are_retried_by_librdkafka() {
+    commit_with_errors(
+        "commit-coordinator-loading",
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_COORDINATOR_LOAD_IN_PROGRESS; 2],
+        Scenario::new(
+            "librdkafka retries a commit while the coordinator is loading",
+            "two OffsetCommit requests fail with COORDINATOR_LOAD_IN_PROGRESS",
+        ),
+        0,
+    );
+}
+
+#[test]
+fn network_errors_on_commit_are_retried_by_the_commit_manager() {
+    commit_with_errors(
+        "commit-network-error",
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_NETWORK_EXCEPTION; 2],
+        Scenario::new(
+            "the commit manager retries a commit that failed with a transient error",
+            "two OffsetCommit requests fail with NETWORK_EXCEPTION",
+        ),
+        2,
+    );
+}
+
+#[test]
+fn commit_refused_by_a_rebalance_is_not_retried() {
+    let kafka = MockKafka::start("commit-rebalance", 1);
+    let settings = kafka.settings(&["--group", "rebalanced"]);
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=2);
+    let mut scenario = Scenario::new(
+        "a commit refused because of a rebalance is given up, not retried",
+        "the second OffsetCommit request fails with REBALANCE_IN_PROGRESS",
+    );
+
+    let committer = committer(CommitStrategy::EveryMessage);
+    let rebalances = Arc::new(Rebalances::default());
+    let consumer = consumer(&settings, &committer, &rebalances, &metrics);
+    let first = consume(&consumer, &committer, 1);
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::OffsetCommit,
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_REBALANCE_IN_PROGRESS],
+    );
+    let second = consume(&consumer, &committer, 1);
+
+    scenario.expect(
+        "first commit",
+        "Ok",
+        &first[0].1,
+        matches!(first[0].1, Ok(())),
+    );
+    scenario.expect(
+        "second commit",
+        "CommitError::Rebalance, not fatal",
+        &second[0].1,
+        matches!(&second[0].1, Err(err @ CommitError::Rebalance(_)) if !err.is_fatal()),
+    );
+    let stats = committer.stats();
+    commits(&mut scenario, &stats, 1, 0);
+    scenario.expect_eq("rebalance failures", 1, stats.rebalance_failures);
+    scenario.expect_eq(
+        "committed offsets",
+        vec![Some(1)],
+        kafka.committed("rebalanced"),
+    );
+    scenario.finish();
+}
+
+#[test]
+fn failed_commit_is_attempted_again_with_the_next_record() {
+    let kafka = MockKafka::start("commit-every-n", 1);
+    let settings = kafka.settings(&["--group", "commit-every-n"]);
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=3);
+    let mut scenario = Scenario::new(
+        "a failed commit leaves the records uncommitted, so the next one commits them",
+        "committing every 2 records, the first OffsetCommit request fails with REBALANCE_IN_PROGRESS",
+    );
+
+    let committer = OffsetCommitter::new(
+        CommitSettings {
+            strategy: CommitStrategy::EveryN,
+            every: 2,
+            ..CommitSettings::default()
+        },
+        CommitManager::new(RetryPolicy::default()),
+    );
+    let rebalances = Arc::new(Rebalances::default());
+    let consumer = consumer(&settings, &committer, &rebalances, &metrics);
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::OffsetCommit,
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_REBALANCE_IN_PROGRESS],
+    );
+    let results = consume(&consumer, &committer, 3);
+
+    scenario.expect(
+        "commit of record 2",
+        "CommitError::Rebalance",
+        &results[1].1,
+        matches!(&results[1].1, Err(CommitError::Rebalance(_))),
+    );
+    let stats = committer.stats();
+    commits(&mut scenario, &stats, 1, 0);
+    scenario.expect_eq(
+        "committed offsets",
+        vec![Some(3)],
+        kafka.committed("commit-every-n"),
+    );
+    scenario.finish();
+}
+
+#[test]
+fn consumer_joins_once_the_coordinator_is_available() {
+    let kafka = MockKafka::start("coordinator-unavailable", 2);
+    let settings = kafka.settings(&["--group", "waiting"]);
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=6);
+    kafka.cluster.request_errors(
+        RDKafkaApiKey::FindCoordinator,
+        &[RDKafkaRespErr::RD_KAFKA_RESP_ERR_COORDINATOR_NOT_AVAILABLE; 5],
+    );
+    let mut scenario = Scenario::new(
+        "the consumer keeps looking for its coordinator and then consumes",
+        "the first five FindCoordinator requests fail with COORDINATOR_NOT_AVAILABLE",
+    );
+
+    let committer = committer(CommitStrategy::EveryMessage);
+    let rebalances = Arc::new(Rebalances::default());
+    let consumer = consumer(&settings, &committer, &rebalances, &metrics);
+    let mut ids: Vec<i32> = consume(&consumer, &committer, 6)
+        .into_iter()
+        .map(|(id, _)| id)
+        .collect();
+    ids.sort_unstable();
+
+    scenario.expect_eq("users received", (1..=6).collect(), ids);
+    scenario.expect_eq(
+        "rebalances",
+        vec!["assign 0,1".to_string()],
+        rebalances.events(),
+    );
+    scenario.expect_eq("failed commits", 0, committer.stats().failed);
+    scenario.finish();
+}
+
+#[test]
+fn records_of_revoked_partitions_are_not_lost() {
+    let kafka = MockKafka::start("revoke", 2);
+    let settings = kafka.settings(&["--group", "revoke"]);
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    produce(&producer(&settings, &tracker, &metrics), 1..=10);
+    let mut scenario = Scenario::new(
+        "records of revoked partitions are committed or consumed again, never lost",
+        "a second member joins the group after the first processed everything",
+    );
+
+    // commits once 100 records are processed, so only on revoke
+    let (first_committer, first_metrics) = (committer(CommitStrategy::EveryN), Metrics::new());
+    let first_rebalances = Arc::new(Rebalances::default());
+    let first = consumer(
+        &settings,
+        &first_committer,
+        &first_rebalances,
+        &first_metrics,
+    );
+    scenario.expect_eq(
+        "received by the first",
+        10,
+        consume(&first, &first_committer, 10).len(),
+    );
+
+    let second_committer = committer(CommitStrategy::EveryMessage);
+    let second_rebalances = Arc::new(Rebalances::default());
+    let second = consumer(&settings, &second_committer, &second_rebalances, &metrics);
+    // both members poll until the rebalance is done and nothing more arrives
+    let members = [(&first, &first_committer), (&second, &second_committer)];
+    let deadline = Instant::now() + TIMEOUT;
+    let mut last_received = Instant::now();
+    let mut received_again = Vec::new();
+    while Instant::now() < deadline {
+        for (consumer, committer) in members.iter() {
+            if let Some(result) = consumer.poll(Duration::from_millis(50)) {
+                let user = result.unwrap();
+                committer
+                    .processed(consumer.inner(), user.message())
+                    .unwrap();
+                received_again.push(user.value().id);
+                last_received = Instant::now();
+            }
+        }
+        let rebalanced =
+            first_rebalances.events().len() >= 3 && !second_rebalances.events().is_empty();
+        if rebalanced && last_received.elapsed() > Duration::from_secs(1) {
+            break;
+        }
+    }
+    received_again.sort_unstable();
+
+    let first_events = first_rebalances.events();
+    scenario.expect_eq(
+        "first member rebalances",
+        vec!["assign 0,1", "revoke 0,1"],
+        first_events.iter().take(2).map(String::as_str).collect(),
+    );
+    scenario.expect(
+        "then assigned",
+        "one partition",
+        first_events.get(2),
+        first_events.get(2).is_some_and(|e| !e.contains(',')),
+    );
+    scenario.expect(
+        "second member rebalances",
+        "one partition",
+        second_rebalances.events(),
+        matches!(&second_rebalances.events()[..], [e] if !e.contains(',')),
+    );
+
+    // the mock coordinator refuses commits while the group rebalances, where
+    // a broker may accept them, and may rebalance more than once
+    let revoke_commits = (
+        counter(&first_metrics, "kafka101_commits_total"),
+        counter(&first_metrics, "kafka101_commit_failures_total"),
+    );
+    scenario.expect(
+        "commits on revoke (ok, failed)",
+        "at least one",
+        revoke_commits,
+        revoke_commits.0 + revoke_commits.1 > 0,
+    );
+    let expected: Vec<i32> = if revoke_commits.1 == 0 {
+        Vec::new()
+    } else {
+        (1..=10).collect()
+    };
+    received_again.dedup();
+    scenario.expect_eq("received again", expected, received_again);
+
+    for (consumer, committer) in members.iter() {
+        committer.commit_sync(consumer.inner()).unwrap();
+    }
+    scenario.expect_eq(
+        "committed in the end",
+        kafka.high_watermarks().into_iter().map(Some).collect(),
+        kafka.committed("revoke"),
+    );
+    scenario.finish();
+}
+
+#[test]
+fn slow_broker_delays_commits_and_deliveries() {
+    let kafka = MockKafka::start("slow-broker", 1);
+    let settings = kafka.settings(&["--group", "slow"]);
+    let (tracker, metrics) = (DeliveryTracker::new(), Metrics::new());
+    let producer = producer(&settings, &tracker, &metrics);
+    produce(&producer, 1..=1);
+    let rtt = Duration::from_millis(300);
+    let mut scenario = Scenario::new(
+        "a slow broker makes commits and deliveries slower, not failed",
+        "300ms round trip time on broker 1",
+    );
+
+    let committer = committer(CommitStrategy::EveryMessage);
+    let rebalances = Arc::new(Rebalances::default());
+    let consumer = consumer(&settings, &committer, &rebalances, &metrics);
+    consume(&consumer, &committer, 1);
+    kafka.cluster.broker_round_trip_time(1, rtt).unwrap();
+
+    let started = Instant::now();
+    produce(&producer, 2..=2);
+    let delivered_in = started.elapsed();
+    consume(&consumer, &committer, 1);
+
+    delivery(&mut scenario, &tracker.report(), 2, 0);
+    scenario.expect(
+        "delivery latency",
+        ">= 300ms",
+        delivered_in,
+        delivered_in >= rtt,
+    );
+    let stats = committer.stats();
+    commits(&mut scenario, &stats, 2, 0);
+    scenario.expect(
+        "max commit latency",
+        ">= 300ms",
+        stats.max_latency,
+        stats.max_latency >= rtt,
+    );
+    scenario.expect_eq("committed offsets", vec![Some(2)], kafka.committed("slow"));
+    scenario.finish();
+}
@@ -0,0 +1,231 @@
+//! The headers the typed producers attach, and how consumers read them back.
+
+mod common;
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use kafka101_core::{
+    headers::{self, HeaderError, TraceContext},
+    shutdown, AvroSerde, InMemoryKafka, JsonSerde, ProduceCallbackLogger, ProducerHeaders,
+    RecordHeaders, SchemaRegistryClient, TypedProducer, User, ValueConsumer, ValueProducer,
+};
+
+use common::{
+    kafka::{MockKafka, TIMEOUT},
+    user, MockRegistry,
+};
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+#[test]
+fn header_values_round_trip_as_text() {
+    let event_time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_123);
+    let headers = RecordHeaders::new()
+        .insert("count", &42u32)
+        .insert("delta", &-7i64)
+        .insert("name", "kafka101")
+        .insert(headers::EVENT_TIME, &event_time)
+        .insert_raw("binary", Some(&[0xff, 0xfe]))
+        .insert_raw("empty", None)
+        .insert("count", &43u32);
+
+    assert_eq!(headers.last("delta"), Some(Some(&b"-7"[..])));
+    assert_eq!(
+        headers.last(headers::EVENT_TIME),
+        Some(Some(&b"1600000000123"[..]))
+    );
+    // the last value of a repeated key wins
+    assert_eq!(headers.get::<u32>("count").unwrap().unwrap(), 43);
+    assert_eq!(headers.get::<i64>("delta").unwrap().unwrap(), -7);
+    assert_eq!(headers.get::<String>("name").unwrap().unwrap(), "kafka101");
+    assert_eq!(headers.event_time().unwrap().unwrap(), event_time);
+    assert!(headers.get::<String>("missing").is_none());
+
+    assert!(matches!(
+        headers.get::<u32>("name"),
+        Some(Err(HeaderError::Invalid(_)))
+    ));
+    assert!(matches!(
+        headers.get::<String>("binary"),
+        Some(Err(HeaderError::NonUtf8(_)))
+    ));
+    assert!(matches!(
+        headers.get::<String>("empty"),
+        Some(Err(HeaderError::Null))
+    ));
+    assert_eq!(
+        headers.to_string(),
+        "count=42, delta=-7, name=kafka101, event-time=1600000000123, \
+         binary=<2 bytes>, empty=<null>, count=43"
+    );
+}
+
+#[test]
+fn trace_context_follows_w3c_traceparent() {
+    let trace: TraceContext = TRACEPARENT.parse().unwrap();
+    assert_eq!(trace.trace_id, 0x4bf9_2f35_77b3_4da6_a3ce_929d_0e0e_4736);
+    assert_eq!(trace.span_id, 0x00f0_67aa_0ba9_02b7);
+    assert!(trace.sampled);
+    assert_eq!(trace.to_string(), TRACEPARENT);
+
+    let child = trace.child();
+    assert_eq!(child.trace_id, trace.trace_id);
+    assert_ne!(child.span_id, trace.span_id);
+
+    let root = TraceContext::new_root();
+    assert_eq!(root.to_string().parse::<TraceContext>().unwrap(), root);
+
+    for invalid in &[
+        "",
+        "00-4BF92F3577B34DA6A3CE929D0E0E4736-00F067AA0BA902B7-01",
+        "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+        "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+    ] {
+        assert!(
+            matches!(
+                invalid.parse::<TraceContext>(),
+                Err(HeaderError::Invalid(_))
+            ),
+            "{:?}",
+            invalid
+        );
+    }
+}
+
+#[test]
+fn typed_producer_stamps_every_record() {
+    let kafka = MockKafka::start("stamped", 1);
+    let settings = kafka.settings(&[]);
+    let producer: TypedProducer<User, JsonSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(ProduceCallbackLogger::new())
+            .unwrap(),
+        &settings.topic,
+        JsonSerde::new(),
+    )
+    .with_headers(ProducerHeaders::new("headers-test"));
+
+    let event_time = UNIX_EPOCH + Duration::from_millis(1_600_000_000_000);
+    let before = SystemTime::now() - Duration::from_secs(1);
+    producer.send("user-1", &user(1)).unwrap();
+    producer
+        .send_with_headers(
+            "user-2",
+            &user(2),
+            &RecordHeaders::new()
+                .insert(headers::EVENT_TIME, &event_time)
+                .insert("tenant", "acme"),
+        )
+        .unwrap();
+    assert_eq!(shutdown::flush_producer(producer.inner(), TIMEOUT), 0);
+
+    let records: Vec<RecordHeaders> = kafka
+        .read(2)
+        .iter()
+        .map(RecordHeaders::from_message)
+        .collect();
+    for headers in &records {
+        assert_eq!(headers.content_type().unwrap().unwrap(), "application/json");
+        assert_eq!(headers.app_id().unwrap().unwrap(), "headers-test");
+        assert!(headers.trace_context().unwrap().is_ok());
+        // JSON payloads have no schema
+        assert!(headers.schema_id().is_none());
+    }
+    assert_ne!(
+        records[0].trace_context().unwrap().unwrap().trace_id,
+        records[1].trace_context().unwrap().unwrap().trace_id
+    );
+
+    let first = &records[0];
+    let keys: Vec<&str> = first.iter().map(|(key, _)| key).collect();
+    assert_eq!(
+        keys,
+        vec!["content-type", "traceparent", "app-id", "event-time"]
+    );
+    assert!(first.event_time().unwrap().unwrap() >= before);
+
+    // given headers are sent once, instead of the stamped ones
+    let second = &records[1];
+    assert_eq!(second.event_time().unwrap().unwrap(), event_time);
+    assert_eq!(second.get::<String>("tenant").unwrap().unwrap(), "acme");
+    assert_eq!(
+        second
+            .iter()
+            .filter(|(key, _)| *key == headers::EVENT_TIME)
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn records_have_no_headers_unless_asked_for() {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic("plain", 1);
+    let producer = kafka.producer("plain", JsonSerde::new());
+    producer.send("user-1", &user(1)).unwrap();
+    producer
+        .send_with_headers("user-2", &user(2), &RecordHeaders::new().insert("a", "b"))
+        .unwrap();
+
+    let consumer = kafka.consumer::<User, _>("plain", JsonSerde::new());
+    consumer.subscribe(&["plain"]);
+    let first = consumer.poll(Duration::ZERO).unwrap().unwrap();
+    assert!(first.headers().is_empty());
+    let second = consumer.poll(Duration::ZERO).unwrap().unwrap();
+    assert_eq!(second.headers(), RecordHeaders::new().insert("a", "b"));
+}
+
+#[test]
+fn consumers_see_the_schema_id_and_derive_headers_for_their_output() {
+    let registry = MockRegistry::start();
+    // so that the id of the user schema is not the first one
+    registry.register("other-value", r#""string""#, None);
+    let serde = AvroSerde::new(Arc::new(SchemaRegistryClient::new(registry.url.as_str())));
+
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic("users", 1);
+    let producer = kafka
+        .producer("users", serde)
+        .with_headers(ProducerHeaders::new("headers-test"));
+    let trace: TraceContext = TRACEPARENT.parse().unwrap();
+    producer
+        .send_with_headers(
+            "user-1",
+            &user(1),
+            &RecordHeaders::new().insert(headers::TRACEPARENT, &trace),
+        )
+        .unwrap();
+
+    let consumer = kafka.consumer::<User, _>(
+        "pipeline",
+        AvroSerde::new(Arc::new(SchemaRegistryClient::new(registry.url.as_str()))),
+    );
+    consumer.subscribe(&["users"]);
+    let received = consumer.poll(Duration::ZERO).unwrap().unwrap();
+    assert_eq!(received.value(), &user(1));
+
+    let headers = received.headers();
+    assert_eq!(
+        headers.content_type().unwrap().unwrap(),
+        "application/vnd.apache.avro+binary"
+    );
+    assert_eq!(headers.schema_id().unwrap().unwrap(), 2);
+    assert_eq!(headers.trace_context().unwrap().unwrap(), trace);
+
+    let derived = headers.derived();
+    let child = derived.trace_context().unwrap().unwrap();
+    assert_eq!(child.trace_id, trace.trace_id);
+    assert_ne!(child.span_id, trace.span_id);
+    assert_eq!(
+        derived.event_time().unwrap().unwrap(),
+        headers.event_time().unwrap().unwrap()
+    );
+    assert_eq!(derived.len(), 2);
+}
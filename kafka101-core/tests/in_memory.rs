@@ -0,0 +1,225 @@
+//! The processing of the consumer examples, against an `InMemoryKafka` and with
+//! seeded failures, so that every run fails the same attempts.
+
+mod common;
+
+use std::{ops::RangeInclusive, time::Duration};
+
+use kafka101_core::{
+    consumer::ReceiveError, poison::PoisonKind, producer::SendError, simulation, FailureSimulator,
+    InMemoryKafka, JsonSerde, RetryPolicy, User, ValueConsumer, ValueProducer,
+};
+use rdkafka::{error::KafkaError, types::RDKafkaErrorCode, Message};
+
+use common::user;
+
+const TOPIC: &str = "users";
+
+/// Retries twice, without sleeping in between.
+const RETRY: RetryPolicy = RetryPolicy {
+    max_retries: 2,
+    initial_backoff: Duration::ZERO,
+    max_backoff: Duration::ZERO,
+};
+
+fn produce_users<P: ValueProducer<User>>(producer: &P, ids: RangeInclusive<i32>) {
+    for i in ids {
+        producer.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+}
+
+/// What the loop of `3_manual_commit` did with the users it polled.
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    processed: Vec<i32>,
+    given_up: Vec<i32>,
+    attempts: u32,
+}
+
+/// The loop of `3_manual_commit`: every user is retried under `RETRY`, given
+/// up on once the retries are used up, and committed either way.
+fn process_all<C: ValueConsumer<User>>(consumer: &C, failures: &FailureSimulator) -> Outcome {
+    let mut outcome = Outcome::default();
+    while let Some(received) = consumer.poll(Duration::ZERO) {
+        let received = received.unwrap();
+        let processed = RETRY.run(|_| {
+            outcome.attempts += 1;
+            simulation::process_user(received.value(), failures)
+        });
+        match processed {
+            Ok(()) => outcome.processed.push(received.value().id),
+            Err(_) => outcome.given_up.push(received.value().id),
+        }
+        consumer.commit(received.message()).unwrap();
+    }
+    outcome
+}
+
+/// Runs `process_all` over 20 users in 3 partitions.
+fn process_users(failures: FailureSimulator) -> Outcome {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic(TOPIC, 3);
+    produce_users(&kafka.producer(TOPIC, JsonSerde::new()), 1..=20);
+
+    let consumer = kafka.consumer("manual", JsonSerde::new());
+    consumer.subscribe(&[TOPIC]);
+    let outcome = process_all(&consumer, &failures);
+
+    let end_offsets: Vec<Option<i64>> = kafka.end_offsets(TOPIC).into_iter().map(Some).collect();
+    assert_eq!(kafka.committed("manual", TOPIC), end_offsets);
+    let mut ids = [outcome.processed.clone(), outcome.given_up.clone()].concat();
+    ids.sort_unstable();
+    assert_eq!(ids, (1..=20).collect::<Vec<_>>());
+    outcome
+}
+
+#[test]
+fn seeded_failures_repeat_on_every_run() {
+    let draws = |seed| {
+        let failures = FailureSimulator::seeded(0.5, seed);
+        (0..64).map(|_| failures.fails()).collect::<Vec<_>>()
+    };
+    assert_eq!(draws(7), draws(7));
+    assert_ne!(draws(7), draws(8));
+    assert!(draws(7).contains(&true) && draws(7).contains(&false));
+
+    let never = FailureSimulator::seeded(0.0, 7);
+    let always = FailureSimulator::seeded(1.0, 7);
+    assert!((0..64).all(|_| !never.fails() && always.fails()));
+}
+
+#[test]
+fn manual_commit_loop_gives_up_on_the_same_users_for_a_seed() {
+    let outcome = process_users(FailureSimulator::seeded(0.5, 42));
+    assert_eq!(outcome, process_users(FailureSimulator::seeded(0.5, 42)));
+    // the first attempt of every user, and both retries of those given up on
+    assert!(outcome.attempts >= 20 + 2 * outcome.given_up.len() as u32);
+    assert!(outcome.attempts <= 20 * 3);
+
+    let never = process_users(FailureSimulator::seeded(0.0, 42));
+    assert_eq!((never.given_up.len(), never.attempts), (0, 20));
+    let always = process_users(FailureSimulator::seeded(1.0, 42));
+    assert_eq!((always.processed.len(), always.attempts), (0, 60));
+}
+
+#[test]
+fn keys_are_partitioned_like_librdkafka() {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic(TOPIC, 4);
+    let producer = kafka.producer(TOPIC, JsonSerde::new());
+    produce_users(&producer, 1..=10);
+    produce_users(&producer, 1..=10);
+
+    // the CRC32 of the key, as with the default consistent_random partitioner
+    let records = kafka.records(TOPIC);
+    for (i, expected) in (1..=10).zip(&[0, 2, 0, 3, 1, 3, 1, 0, 2, 2]) {
+        let key = format!("user-{}", i);
+        let partitions: Vec<i32> = records
+            .iter()
+            .filter(|msg| msg.key() == Some(key.as_bytes()))
+            .map(|msg| msg.partition())
+            .collect();
+        assert_eq!(partitions, vec![*expected; 2], "{}", key);
+    }
+    for partition in 0..4 {
+        let offsets: Vec<i64> = records
+            .iter()
+            .filter(|msg| msg.partition() == partition)
+            .map(|msg| msg.offset())
+            .collect();
+        assert_eq!(offsets, (0..offsets.len() as i64).collect::<Vec<_>>());
+    }
+
+    let missing = kafka.producer("missing", JsonSerde::new());
+    assert!(matches!(
+        missing.send("user-1", &user(1)),
+        Err(SendError::Kafka(KafkaError::MessageProduction(
+            RDKafkaErrorCode::UnknownTopicOrPartition
+        )))
+    ));
+}
+
+#[test]
+fn group_members_share_partitions_and_resume_from_commits() {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic(TOPIC, 4);
+    produce_users(&kafka.producer(TOPIC, JsonSerde::new()), 1..=20);
+    let all: Vec<(String, i32)> = (0..4).map(|p| (TOPIC.to_string(), p)).collect();
+
+    let first = kafka.consumer::<User, _>("group", JsonSerde::new());
+    first.subscribe(&[TOPIC]);
+    let committed = first.poll(Duration::ZERO).unwrap().unwrap();
+    assert_eq!(first.assignment(), all);
+    first.commit(committed.message()).unwrap();
+
+    let second = kafka.consumer::<User, _>("group", JsonSerde::new());
+    second.subscribe(&[TOPIC]);
+    // the first member has not polled since the second one joined
+    assert!(matches!(
+        first.commit(committed.message()),
+        Err(KafkaError::ConsumerCommit(
+            RDKafkaErrorCode::RebalanceInProgress
+        ))
+    ));
+
+    let drain = |consumer: &dyn ValueConsumer<User>| {
+        let mut ids = Vec::new();
+        while let Some(received) = consumer.poll(Duration::ZERO) {
+            ids.push(received.unwrap().value().id);
+        }
+        ids
+    };
+    let mut ids = [drain(&first), drain(&second)].concat();
+    assert_eq!(first.assignment(), all[..2]);
+    assert_eq!(second.assignment(), all[2..]);
+    ids.sort_unstable();
+    let rest: Vec<i32> = (1..=20).filter(|&id| id != committed.value().id).collect();
+    assert_eq!(ids, rest);
+
+    // nothing else was committed, so the first member gets it all again
+    drop(second);
+    let mut ids = drain(&first);
+    assert_eq!(first.assignment(), all);
+    ids.sort_unstable();
+    assert_eq!(ids, rest);
+}
+
+#[test]
+fn records_that_do_not_decode_are_poison_pills() {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic(TOPIC, 1);
+    kafka
+        .append(TOPIC, Some(b"user-1"), Some(b"not json"))
+        .unwrap();
+    kafka
+        .append(
+            TOPIC,
+            None,
+            Some(br#"{"id":2,"email":"user-2@foobar.com"}"#),
+        )
+        .unwrap();
+    kafka.append(TOPIC, Some(b"user-3"), None).unwrap();
+
+    let consumer = kafka
+        .consumer::<User, _>("poison", JsonSerde::new())
+        .require_key(true);
+    consumer.subscribe(&[TOPIC]);
+    let kinds: Vec<PoisonKind> = (0..3)
+        .map(|_| match consumer.poll(Duration::ZERO) {
+            Some(Err(ReceiveError::Poison(pill))) => pill.kind,
+            other => panic!(
+                "expected a poison pill, got {:?}",
+                other.map(|received| received.map(|received| received.into_value()))
+            ),
+        })
+        .collect();
+    assert_eq!(
+        kinds,
+        vec![
+            PoisonKind::InvalidPayload,
+            PoisonKind::MissingKey,
+            PoisonKind::MissingPayload
+        ]
+    );
+    assert!(consumer.poll(Duration::ZERO).is_none());
+}
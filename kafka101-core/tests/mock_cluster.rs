@@ -0,0 +1,391 @@
+//! The producer and consumer flows of the `part1` and `part2` examples, end to
+//! end against librdkafka's in-process mock cluster. Each test builds its
+//! clients the way the matching binary does, from `Settings`.
+
+mod common;
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use kafka101_core::{
+    consumer::ReceiveError, replay, shutdown, AuthContext, AvroSerde, CommitManager,
+    ConsumerCallbackLogger, DeliverySpan, DeliveryTracker, Deserializer, FormatSerde, JsonSerde,
+    Metrics, OffsetCommitter, ProduceCallbackLogger, SchemaRegistryClient, Settings, TypedConsumer,
+    TypedProducer, User,
+};
+use rdkafka::{
+    consumer::{Consumer, ConsumerContext},
+    producer::{BaseProducer, BaseRecord, ThreadedProducer},
+    Message,
+};
+
+use common::{
+    kafka::{MockKafka, TIMEOUT},
+    user, MockRegistry,
+};
+
+const RECORDS: i32 = 10;
+
+fn key(msg: &impl Message) -> &str {
+    std::str::from_utf8(msg.key().unwrap()).unwrap()
+}
+
+fn payload(msg: &impl Message) -> &str {
+    std::str::from_utf8(msg.payload().unwrap()).unwrap()
+}
+
+/// Sends `RECORDS` users keyed `user-<id>`, the way the consumer examples do,
+/// and waits for them to be delivered.
+fn produce_users(settings: &Settings) {
+    let producer: TypedProducer<User, FormatSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(ProduceCallbackLogger::new())
+            .unwrap(),
+        &settings.topic,
+        settings.serde().unwrap(),
+    );
+    for i in 1..=RECORDS {
+        producer.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(producer.inner(), TIMEOUT), 0);
+}
+
+/// Polls until `count` users were received, passing each to `handle` with the
+/// record it came from.
+fn receive<D, C, F>(consumer: &TypedConsumer<User, D, C>, count: usize, mut handle: F)
+where
+    D: Deserializer<User>,
+    C: ConsumerContext,
+    F: FnMut(&User, &rdkafka::message::BorrowedMessage<'_>),
+{
+    let deadline = Instant::now() + TIMEOUT;
+    let mut received = 0;
+    while received < count {
+        assert!(
+            Instant::now() < deadline,
+            "received {} of {} users",
+            received,
+            count
+        );
+        if let Some(result) = consumer.poll(shutdown::POLL_INTERVAL) {
+            let received_user = result.unwrap();
+            handle(received_user.value(), received_user.message());
+            received += 1;
+        }
+    }
+}
+
+/// Waits until `kafka` reports `expected` as the committed offsets of `group`.
+fn assert_committed(kafka: &MockKafka, group: &str, expected: &[Option<i64>]) {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let committed = kafka.committed(group);
+        if committed == expected {
+            return;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "committed {:?}, expected {:?}",
+            committed,
+            expected
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+#[test]
+fn simple_producer_writes_records_in_send_order() {
+    let kafka = MockKafka::start("simple-producer", 1);
+    let settings = kafka.settings(&[]);
+
+    let producer: BaseProducer<AuthContext> = settings
+        .producer_config()
+        .create_with_context(AuthContext::new(&settings.auth))
+        .unwrap();
+    for i in 1..=RECORDS {
+        producer
+            .send(
+                BaseRecord::to(&settings.topic)
+                    .key(&format!("key-{}", i))
+                    .payload(&format!("value-{}", i)),
+            )
+            .unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(&producer, TIMEOUT), 0);
+
+    assert_eq!(kafka.high_watermarks(), vec![i64::from(RECORDS)]);
+    let messages = kafka.read(RECORDS as usize);
+    for (offset, msg) in messages.iter().enumerate() {
+        let i = offset + 1;
+        assert_eq!(msg.offset(), offset as i64);
+        assert_eq!(key(msg), format!("key-{}", i));
+        assert_eq!(payload(msg), format!("value-{}", i));
+    }
+}
+
+#[test]
+fn threaded_producer_reports_every_delivery() {
+    let kafka = MockKafka::start("threaded-producer", 3);
+    let settings = kafka.settings(&[]);
+    let metrics = Metrics::new();
+
+    let producer: ThreadedProducer<ProduceCallbackLogger> = settings
+        .producer_config()
+        .create_with_context(ProduceCallbackLogger::new().with_metrics(metrics.clone()))
+        .unwrap();
+    for i in 1..=RECORDS {
+        let key = format!("key-{}", i);
+        producer
+            .send(
+                BaseRecord::with_opaque_to(
+                    &settings.topic,
+                    DeliverySpan::start(&settings.topic, &key),
+                )
+                .key(&key)
+                .payload(&format!("value-{}", i)),
+            )
+            .unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(&producer, TIMEOUT), 0);
+
+    let rendered = metrics.render();
+    assert!(
+        rendered.contains(&format!("kafka101_messages_produced_total {}", RECORDS)),
+        "{}",
+        rendered
+    );
+    assert!(
+        rendered.contains("kafka101_delivery_failures_total 0"),
+        "{}",
+        rendered
+    );
+    assert_eq!(
+        kafka.high_watermarks().iter().sum::<i64>(),
+        i64::from(RECORDS)
+    );
+
+    // every partition holds its records at consecutive offsets from zero
+    let messages = kafka.read(RECORDS as usize);
+    for partition in 0..kafka.partitions {
+        let offsets: Vec<i64> = messages
+            .iter()
+            .filter(|msg| msg.partition() == partition)
+            .map(|msg| msg.offset())
+            .collect();
+        assert_eq!(offsets, (0..offsets.len() as i64).collect::<Vec<_>>());
+    }
+    let mut keys: Vec<&str> = messages.iter().map(key).collect();
+    keys.sort_by_key(|key| key[4..].parse::<i64>().unwrap());
+    let expected: Vec<String> = (1..=RECORDS).map(|i| format!("key-{}", i)).collect();
+    assert_eq!(keys, expected);
+}
+
+#[test]
+fn json_producer_tracks_every_acknowledgement() {
+    let kafka = MockKafka::start("json-producer", 3);
+    let settings = kafka.settings(&[]);
+
+    let tracker = DeliveryTracker::new();
+    let producer: TypedProducer<User, FormatSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(ProduceCallbackLogger::new().with_tracker(tracker.clone()))
+            .unwrap(),
+        &settings.topic,
+        settings.serde().unwrap(),
+    )
+    .with_tracker(tracker.clone());
+    for i in 1..=RECORDS {
+        producer.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(producer.inner(), TIMEOUT), 0);
+
+    let report = tracker.report();
+    assert!(report.is_complete_in_order(), "{}", report);
+    assert_eq!(report.acknowledged, RECORDS as u64);
+
+    let serde = JsonSerde::pretty();
+    let mut users: Vec<User> = kafka
+        .read(RECORDS as usize)
+        .iter()
+        .map(|msg| {
+            let user: User = serde
+                .deserialize(&settings.topic, msg.payload().unwrap())
+                .unwrap();
+            assert_eq!(key(msg), format!("user-{}", user.id));
+            user
+        })
+        .collect();
+    users.sort_by_key(|user| user.id);
+    assert_eq!(users, (1..=RECORDS).map(user).collect::<Vec<_>>());
+}
+
+#[test]
+fn simple_consumer_receives_users_and_commits_on_close() {
+    let kafka = MockKafka::start("simple-consumer", 1);
+    let settings = kafka.settings(&["--group", "simple"]);
+    produce_users(&settings);
+
+    let consumer: TypedConsumer<User, FormatSerde, AuthContext> = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .create_with_context(AuthContext::new(&settings.auth))
+            .unwrap(),
+        settings.serde().unwrap(),
+    );
+    consumer.inner().subscribe(&[&settings.topic]).unwrap();
+
+    let mut users = Vec::new();
+    receive(&consumer, RECORDS as usize, |user, _| {
+        users.push(user.clone())
+    });
+    assert_eq!(users, (1..=RECORDS).map(user).collect::<Vec<_>>());
+
+    shutdown::close_consumer(consumer.inner(), true).unwrap();
+    assert_committed(&kafka, "simple", &[Some(i64::from(RECORDS))]);
+}
+
+#[test]
+fn callback_consumer_receives_users_from_every_partition() {
+    let kafka = MockKafka::start("callback-consumer", 3);
+    let settings = kafka.settings(&["--group", "callback", "--from", "earliest"]);
+    produce_users(&settings);
+
+    let consumer: TypedConsumer<User, FormatSerde, ConsumerCallbackLogger> = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .create_with_context(ConsumerCallbackLogger::new())
+            .unwrap(),
+        settings.serde().unwrap(),
+    );
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from).unwrap();
+
+    let mut users = Vec::new();
+    let mut next_offsets = vec![0; kafka.partitions as usize];
+    receive(&consumer, RECORDS as usize, |user, msg| {
+        assert_eq!(key(msg), format!("user-{}", user.id));
+        // each partition is read in offset order
+        let next = &mut next_offsets[msg.partition() as usize];
+        assert_eq!(msg.offset(), *next);
+        *next += 1;
+        users.push(user.clone());
+    });
+    users.sort_by_key(|user| user.id);
+    assert_eq!(users, (1..=RECORDS).map(user).collect::<Vec<_>>());
+    assert_eq!(consumer.inner().assignment().unwrap().count(), 3);
+
+    shutdown::close_consumer(consumer.inner(), true).unwrap();
+    let expected: Vec<Option<i64>> = next_offsets
+        .iter()
+        .map(|&next| if next > 0 { Some(next) } else { None })
+        .collect();
+    assert_committed(&kafka, "callback", &expected);
+}
+
+#[test]
+fn manual_commit_consumer_resumes_after_the_last_processed_record() {
+    let kafka = MockKafka::start("manual-commit", 1);
+    let settings = kafka.settings(&["--group", "manual"]);
+    produce_users(&settings);
+
+    let consume = |count: usize| -> Vec<User> {
+        let committer =
+            OffsetCommitter::new(settings.commit, CommitManager::new(settings.errors.retry));
+        let consumer: TypedConsumer<User, FormatSerde, ConsumerCallbackLogger> = TypedConsumer::new(
+            settings
+                .consumer_config()
+                .set("enable.auto.commit", "false")
+                .create_with_context(
+                    ConsumerCallbackLogger::new().with_committer(committer.clone()),
+                )
+                .unwrap(),
+            settings.serde().unwrap(),
+        );
+        replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from).unwrap();
+
+        let mut users = Vec::new();
+        receive(&consumer, count, |user, msg| {
+            users.push(user.clone());
+            committer.processed(consumer.inner(), msg).unwrap();
+        });
+        committer.commit_sync(consumer.inner()).unwrap();
+        shutdown::close_consumer(consumer.inner(), false).unwrap();
+
+        let stats = committer.stats();
+        assert_eq!(stats.failed, 0, "{}", stats);
+        users
+    };
+
+    // every processed record is committed before the next one is polled
+    let first = consume(6);
+    assert_eq!(first, (1..=6).map(user).collect::<Vec<_>>());
+    assert_committed(&kafka, "manual", &[Some(6)]);
+
+    let rest = consume(4);
+    assert_eq!(rest, (7..=RECORDS).map(user).collect::<Vec<_>>());
+    assert_committed(&kafka, "manual", &[Some(i64::from(RECORDS))]);
+}
+
+#[test]
+fn records_are_not_committed_while_the_schema_registry_is_down() {
+    let kafka = MockKafka::start("registry-down", 1);
+    let registry = MockRegistry::start();
+    let settings = kafka.settings(&["--group", "registry-down"]);
+    let serde = || AvroSerde::new(Arc::new(SchemaRegistryClient::new(registry.url.as_str())));
+
+    let producer: TypedProducer<User, AvroSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(ProduceCallbackLogger::new())
+            .unwrap(),
+        &settings.topic,
+        serde(),
+    );
+    producer.send("user-1", &user(1)).unwrap();
+    assert_eq!(shutdown::flush_producer(producer.inner(), TIMEOUT), 0);
+
+    registry.fail_with(Some(503));
+    let committer =
+        OffsetCommitter::new(settings.commit, CommitManager::new(settings.errors.retry));
+    let consumer: TypedConsumer<User, AvroSerde, ConsumerCallbackLogger> = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .set("enable.auto.commit", "false")
+            .create_with_context(ConsumerCallbackLogger::new().with_committer(committer.clone()))
+            .unwrap(),
+        serde(),
+    );
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from).unwrap();
+
+    // the record comes back after every failed attempt instead of being skipped
+    let deadline = Instant::now() + TIMEOUT;
+    let mut attempts = 0;
+    while attempts < 3 {
+        assert!(Instant::now() < deadline, "{} attempts", attempts);
+        match consumer.poll(shutdown::POLL_INTERVAL) {
+            Some(Err(err @ ReceiveError::Unavailable { .. })) => {
+                assert!(!err.is_fatal(), "{}", err);
+                attempts += 1;
+            }
+            Some(Err(err)) => panic!("{}", err),
+            Some(Ok(received)) => panic!("received {:?}", received.value()),
+            None => {}
+        }
+    }
+    committer.commit_sync(consumer.inner()).unwrap();
+    assert_eq!(kafka.committed("registry-down"), vec![None]);
+
+    registry.fail_with(None);
+    receive(&consumer, 1, |received_user, msg| {
+        assert_eq!(received_user, &user(1));
+        assert_eq!(msg.offset(), 0);
+        committer.processed(consumer.inner(), msg).unwrap();
+    });
+    committer.commit_sync(consumer.inner()).unwrap();
+    shutdown::close_consumer(consumer.inner(), false).unwrap();
+    assert_committed(&kafka, "registry-down", &[Some(1)]);
+}
@@ -0,0 +1,193 @@
+//! The partitioners of the typed producers, checked against the hashes of the
+//! Java client and against librdkafka's own partitioners on the mock cluster.
+
+mod common;
+
+use std::{collections::BTreeMap, sync::Arc, thread, time::Duration};
+
+use kafka101_core::{
+    partition::{self, ConsistentPartitioner, Murmur2Partitioner, StickyPartitioner},
+    shutdown, InMemoryKafka, JsonSerde, PartitionStrategy, ProduceCallbackLogger, Settings,
+    TypedProducer, User, ValuePartitioner, ValueProducer,
+};
+use rdkafka::{
+    producer::{BaseProducer, BaseRecord},
+    Message,
+};
+
+use common::{
+    kafka::{MockKafka, TIMEOUT},
+    user,
+};
+
+const PARTITIONS: i32 = 6;
+
+/// The partition of every record of the topic, by key.
+fn partitions_by_key(kafka: &MockKafka, count: usize) -> BTreeMap<String, i32> {
+    kafka
+        .read(count)
+        .iter()
+        .map(|msg| {
+            let key = std::str::from_utf8(msg.key().unwrap()).unwrap();
+            (key.to_string(), msg.partition())
+        })
+        .collect()
+}
+
+/// Sends users 1 to 20 with a typed producer built from `settings`.
+fn send_users(settings: &Settings) {
+    let producer: TypedProducer<User, JsonSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(ProduceCallbackLogger::new())
+            .unwrap(),
+        &settings.topic,
+        JsonSerde::new(),
+    );
+    let producer = match partition::for_users(settings) {
+        Some(partitioner) => producer.with_partitioner(partitioner),
+        None => producer,
+    };
+    for i in 1..=20 {
+        producer.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(producer.inner(), TIMEOUT), 0);
+}
+
+/// Sends keys `user-1` to `user-20` with librdkafka's `partitioner`.
+fn send_keys_with(kafka: &MockKafka, partitioner: &str) {
+    let producer: BaseProducer = kafka
+        .settings(&[
+            "--producer-property",
+            &format!("partitioner={}", partitioner),
+        ])
+        .producer_config()
+        .create()
+        .unwrap();
+    for i in 1..=20 {
+        producer
+            .send(
+                BaseRecord::to(&kafka.topic)
+                    .key(&format!("user-{}", i))
+                    .payload("{}"),
+            )
+            .unwrap();
+    }
+    assert_eq!(shutdown::flush_producer(&producer, TIMEOUT), 0);
+}
+
+#[test]
+fn murmur2_matches_the_java_client() {
+    // Utils.murmur2 of the Java client, as listed in librdkafka's unit test
+    let cases: &[(&str, u32)] = &[
+        ("kafka", 0xd067_cf64),
+        ("giberish123456789", 0x8f55_2b0c),
+        ("1234", 0x9fc9_7b14),
+        ("234", 0xe7c0_09ca),
+        ("34", 0x8739_30da),
+        ("4", 0x5a4b_5ca1),
+        ("PreAmbleWillBeRemoved,ThePrePartThatIs", 0x7842_4f1c),
+        ("reAmbleWillBeRemoved,ThePrePartThatIs", 0x4a62_b377),
+        ("eAmbleWillBeRemoved,ThePrePartThatIs", 0xe0e4_e09e),
+        ("AmbleWillBeRemoved,ThePrePartThatIs", 0x62b8_b43f),
+        ("", 0x106e_08d9),
+    ];
+    for (key, hash) in cases {
+        assert_eq!(partition::murmur2(key.as_bytes()), *hash, "{:?}", key);
+    }
+}
+
+#[test]
+fn murmur2_places_keys_like_librdkafka_murmur2_random() {
+    let java = MockKafka::start("murmur2-java", PARTITIONS);
+    send_keys_with(&java, "murmur2_random");
+
+    let typed = MockKafka::start("murmur2-typed", PARTITIONS);
+    let mut settings = typed.settings(&[]);
+    settings.partitioner.strategy = PartitionStrategy::Murmur2;
+    send_users(&settings);
+
+    let expected = partitions_by_key(&java, 20);
+    assert_eq!(partitions_by_key(&typed, 20), expected);
+    for (key, partition) in &expected {
+        assert_eq!(
+            ValuePartitioner::partition(&Murmur2Partitioner, key, &(), PARTITIONS),
+            *partition
+        );
+    }
+}
+
+#[test]
+fn consistent_partitioner_places_keys_like_librdkafka_default() {
+    let kafka = MockKafka::start("consistent", PARTITIONS);
+    send_keys_with(&kafka, "consistent_random");
+    for (key, partition) in partitions_by_key(&kafka, 20) {
+        assert_eq!(
+            ValuePartitioner::partition(&ConsistentPartitioner, &key, &(), PARTITIONS),
+            partition
+        );
+    }
+}
+
+#[test]
+fn user_id_strategy_sends_users_to_id_modulo_partitions() {
+    let kafka = MockKafka::start("user-id", PARTITIONS);
+    let mut settings = kafka.settings(&[]);
+    settings.partitioner.strategy = PartitionStrategy::UserId;
+    send_users(&settings);
+
+    for (key, partition) in partitions_by_key(&kafka, 20) {
+        let id: i32 = key["user-".len()..].parse().unwrap();
+        assert_eq!(partition, id % PARTITIONS, "{}", key);
+    }
+}
+
+#[test]
+fn sticky_partitioner_moves_on_once_the_linger_has_passed() {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic("sticky", PARTITIONS);
+
+    let lingering = kafka
+        .producer("sticky", JsonSerde::new())
+        .with_partitioner(Arc::new(StickyPartitioner::new(Duration::from_secs(60))));
+    for i in 1..=20 {
+        lingering.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+    let used: Vec<i64> = kafka
+        .end_offsets("sticky")
+        .into_iter()
+        .filter(|&end| end > 0)
+        .collect();
+    assert_eq!(used, vec![20]);
+
+    let sticky = StickyPartitioner::new(Duration::from_millis(1));
+    let mut last = ValuePartitioner::partition(&sticky, "user-1", &(), PARTITIONS);
+    for _ in 0..10 {
+        thread::sleep(Duration::from_millis(2));
+        let next = ValuePartitioner::partition(&sticky, "user-1", &(), PARTITIONS);
+        assert_ne!(next, last);
+        last = next;
+    }
+}
+
+#[test]
+fn custom_partitioner_functions_pick_the_partition() {
+    let kafka = InMemoryKafka::new();
+    kafka.create_topic("custom", PARTITIONS);
+    // even ids to the first partition, odd ids to the last one
+    let producer = kafka
+        .producer("custom", JsonSerde::new())
+        .with_partitioner(Arc::new(
+            |_key: &str, user: &User, partitions: i32| {
+                if user.id % 2 == 0 {
+                    0
+                } else {
+                    partitions - 1
+                }
+            },
+        ));
+    for i in 1..=10 {
+        producer.send(&format!("user-{}", i), &user(i)).unwrap();
+    }
+    assert_eq!(kafka.end_offsets("custom"), vec![5, 0, 0, 0, 0, 5]);
+}
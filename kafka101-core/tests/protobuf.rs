@@ -0,0 +1,200 @@
+mod common;
+
+use std::sync::Arc;
+
+use kafka101_core::{
+    codec::CodecError, config::Loader, protobuf::pb, Deserializer, Format, FormatSerde,
+    ProtobufRecord, ProtobufSerde, SchemaRegistryClient, Serializer, User,
+};
+use prost::Message;
+
+use common::MockRegistry;
+
+fn user() -> User {
+    User {
+        id: 42,
+        email: "user-42@foobar.com".to_string(),
+    }
+}
+
+fn serde(registry: &MockRegistry) -> ProtobufSerde {
+    ProtobufSerde::new(Arc::new(SchemaRegistryClient::new(registry.url.as_str())))
+}
+
+/// A payload framed with writer schema `id` and the already encoded `indexes`.
+fn framed(id: u32, indexes: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut payload = vec![0];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(indexes);
+    payload.extend_from_slice(message);
+    payload
+}
+
+#[test]
+fn first_message_is_framed_with_a_single_zero_index() {
+    let registry = MockRegistry::start();
+    let serde = serde(&registry);
+
+    let payload = serde.serialize("rust", &user()).unwrap();
+    assert_eq!(&payload[..6], &[0, 0, 0, 0, 1, 0]);
+    assert_eq!(
+        pb::User::decode(&payload[6..]).unwrap(),
+        user().to_message()
+    );
+
+    let decoded: User = serde.deserialize("rust", &payload).unwrap();
+    assert_eq!(decoded, user());
+
+    let registered = &registry.requests()[1];
+    assert_eq!(registered.path, "/subjects/rust-value/versions");
+    assert!(registered.body.contains(r#""schemaType":"PROTOBUF""#));
+}
+
+#[test]
+fn reformatted_writer_schema_is_accepted() {
+    let registry = MockRegistry::start();
+    let id = registry.register(
+        "rust-value",
+        "syntax = \"proto3\";\npackage io.kafka101;\n\nmessage User {\n    int32 id = 1; // primary key\n    string email = 2;\n}\n",
+        Some("PROTOBUF"),
+    );
+
+    let mut payload = vec![0];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.push(0);
+    user().to_message().encode(&mut payload).unwrap();
+
+    let decoded: User = serde(&registry).deserialize("rust", &payload).unwrap();
+    assert_eq!(decoded, user());
+}
+
+#[test]
+fn writer_schema_with_an_extra_field_is_accepted() {
+    let registry = MockRegistry::start();
+    let id = registry.register(
+        "rust-value",
+        "syntax = \"proto3\";\npackage io.kafka101;\n\nmessage User {\n  int32 id = 1;\n  string email = 2;\n  /* added later */\n  string name = 3;\n}\n",
+        Some("PROTOBUF"),
+    );
+
+    let mut message = user().to_message().encode_to_vec();
+    // field 3, length delimited: "Ada"
+    message.extend_from_slice(&[0x1a, 0x03, b'A', b'd', b'a']);
+
+    let decoded: User = serde(&registry)
+        .deserialize("rust", &framed(id, &[0], &message))
+        .unwrap();
+    assert_eq!(decoded, user());
+}
+
+#[test]
+fn user_is_found_by_its_indexes_in_the_writer_schema() {
+    let registry = MockRegistry::start();
+    let id = registry.register(
+        "rust-value",
+        "syntax = \"proto3\";\npackage io.kafka101;\n\nmessage Account {\n  enum Kind { PERSONAL = 0; }\n  message Owner { string name = 1; }\n  Kind kind = 1;\n}\n\nmessage User {\n  int32 id = 1;\n  string email = 2;\n}\n",
+        Some("PROTOBUF"),
+    );
+    let message = user().to_message().encode_to_vec();
+
+    // indexes [1]: count 1, then 1, zigzag encoded
+    let decoded: User = serde(&registry)
+        .deserialize("rust", &framed(id, &[0x02, 0x02], &message))
+        .unwrap();
+    assert_eq!(decoded, user());
+
+    // the first message is Account, and [0, 0] is Account.Owner
+    for indexes in &[&[0x00][..], &[0x04, 0x00, 0x00]] {
+        let err = Deserializer::<User>::deserialize(
+            &serde(&registry),
+            "rust",
+            &framed(id, indexes, &message),
+        )
+        .unwrap_err();
+        assert!(matches!(err, CodecError::SchemaMismatch { .. }), "{}", err);
+    }
+}
+
+#[test]
+fn nested_message_indexes_are_not_the_user() {
+    let registry = MockRegistry::start();
+    let id = registry.register("rust-value", User::SCHEMA, Some("PROTOBUF"));
+
+    // indexes [1, 0]: count 2, then 1 and 0, all zigzag encoded
+    let mut payload = vec![0];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.extend_from_slice(&[0x04, 0x02, 0x00]);
+    user().to_message().encode(&mut payload).unwrap();
+
+    let err = Deserializer::<User>::deserialize(&serde(&registry), "rust", &payload).unwrap_err();
+    assert!(matches!(err, CodecError::SchemaMismatch { .. }), "{}", err);
+}
+
+#[test]
+fn avro_writer_schema_is_rejected() {
+    let registry = MockRegistry::start();
+    let id = registry.register(
+        "rust-value",
+        <User as kafka101_core::AvroRecord>::SCHEMA,
+        None,
+    );
+
+    let mut payload = vec![0];
+    payload.extend_from_slice(&id.to_be_bytes());
+    payload.push(0);
+    user().to_message().encode(&mut payload).unwrap();
+
+    let err = Deserializer::<User>::deserialize(&serde(&registry), "rust", &payload).unwrap_err();
+    assert!(matches!(err, CodecError::SchemaMismatch { .. }), "{}", err);
+}
+
+#[test]
+fn truncated_message_is_a_protobuf_error() {
+    let registry = MockRegistry::start();
+    let serde = serde(&registry);
+
+    let payload = serde.serialize("rust", &user()).unwrap();
+    let err = Deserializer::<User>::deserialize(&serde, "rust", &payload[..payload.len() - 3])
+        .unwrap_err();
+    assert!(matches!(err, CodecError::Protobuf(_)), "{}", err);
+}
+
+#[test]
+fn format_is_selected_by_configuration() {
+    let registry = MockRegistry::start();
+    let settings = Loader::new()
+        .env(vec![(
+            "KAFKA_SCHEMA_REGISTRY_URL".to_string(),
+            registry.url.clone(),
+        )])
+        .args(vec!["--format".to_string(), "protobuf".to_string()])
+        .load()
+        .unwrap();
+    assert_eq!(settings.format, Format::Protobuf);
+
+    let serde = settings.serde().unwrap();
+    assert!(matches!(serde, FormatSerde::Protobuf(_)));
+    let payload = serde.serialize(&settings.topic, &user()).unwrap();
+    let decoded: User = serde.deserialize(&settings.topic, &payload).unwrap();
+    assert_eq!(decoded, user());
+}
+
+#[test]
+fn schema_formats_need_a_registry() {
+    let err = Loader::new()
+        .env(vec![("KAFKA_FORMAT".to_string(), "avro".to_string())])
+        .args(vec!["--format=xml".to_string()])
+        .load()
+        .unwrap_err();
+    assert_eq!(err.problems().len(), 2, "{}", err);
+    assert!(
+        err.problems()[0].contains("unknown format `xml`"),
+        "{}",
+        err
+    );
+    assert!(
+        err.problems()[1].contains("needs a [schema_registry]"),
+        "{}",
+        err
+    );
+}
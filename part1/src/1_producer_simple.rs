@@ -1,32 +1,43 @@
-use std::{thread, time::Duration};
+use std::{process, time::Duration};
 
-use rdkafka::{
-    producer::{BaseProducer, BaseRecord},
-    ClientConfig,
+use kafka101_core::{
+    logging,
+    shutdown::{self, Shutdown},
+    AuthContext, Settings,
 };
+use rdkafka::producer::{BaseProducer, BaseRecord};
+use tracing::info;
 
 fn main() {
-    let producer: BaseProducer = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        //for auth
-        /*.set("security.protocol", "SASL_SSL")
-        .set("sasl.mechanisms", "PLAIN")
-        .set("sasl.username", "<update>")
-        .set("sasl.password", "<update>")*/
-        .create()
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+
+    let producer: BaseProducer<AuthContext> = settings
+        .producer_config()
+        .create_with_context(AuthContext::new(&settings.auth))
         .expect("invalid producer config");
 
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
     for i in 1..100 {
-        println!("sending message");
+        info!("sending message");
 
         producer
             .send(
-                BaseRecord::to("rust")
+                BaseRecord::to(&settings.topic)
                     .key(&format!("key-{}", i))
                     .payload(&format!("value-{}", i)),
             )
             .expect("failed to send message");
 
-        thread::sleep(Duration::from_secs(3));
+        if shutdown.sleep(Duration::from_secs(3)) {
+            break;
+        }
     }
+
+    let undelivered = shutdown::flush_producer(&producer, shutdown::DEFAULT_FLUSH_TIMEOUT);
+    info!(undelivered, "shutdown complete");
 }
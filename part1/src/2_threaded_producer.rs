@@ -1,71 +1,53 @@
-use std::{thread, time::Duration};
+use std::{process, time::Duration};
 
-use rdkafka::{
-    producer::{BaseProducer, BaseRecord, Producer, ProducerContext, ThreadedProducer},
-    ClientConfig, ClientContext, Message,
+use kafka101_core::{
+    logging,
+    shutdown::{self, Shutdown},
+    DeliverySpan, Metrics, ProduceCallbackLogger, Settings,
 };
+use rdkafka::producer::{BaseRecord, ThreadedProducer};
+use tracing::info;
 
 fn main() {
-    let producer: ThreadedProducer<ProduceCallbackLogger> = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        //for auth
-        /*.set("security.protocol", "SASL_SSL")
-        .set("sasl.mechanisms", "PLAIN")
-        .set("sasl.username", "<update>")
-        .set("sasl.password", "<update>")*/
-        .create_with_context(ProduceCallbackLogger {})
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let producer: ThreadedProducer<ProduceCallbackLogger> = settings
+        .producer_config()
+        .create_with_context(
+            ProduceCallbackLogger::new()
+                .with_auth(&settings.auth)
+                .with_metrics(metrics.clone()),
+        )
         .expect("invalid producer config");
 
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
     for i in 1..100 {
-        println!("sending message");
+        info!("sending message");
 
+        let key = format!("key-{}", i);
         producer
             .send(
-                BaseRecord::to("rust")
-                    .key(&format!("key-{}", i))
-                    .payload(&format!("value-{}", i)),
+                // the delivery report is logged in the span this opens
+                BaseRecord::with_opaque_to(
+                    &settings.topic,
+                    DeliverySpan::start(&settings.topic, &key),
+                )
+                .key(&key)
+                .payload(&format!("value-{}", i)),
             )
             .expect("failed to send message");
 
-        //producer.flush(Duration::from_secs(3));
-        //println!("flushed message");
-        thread::sleep(Duration::from_secs(3));
-    }
-}
-
-struct ProduceCallbackLogger;
-
-impl ClientContext for ProduceCallbackLogger {}
-
-impl ProducerContext for ProduceCallbackLogger {
-    type DeliveryOpaque = ();
-
-    fn delivery(
-        &self,
-        delivery_result: &rdkafka::producer::DeliveryResult<'_>,
-        _delivery_opaque: Self::DeliveryOpaque,
-    ) {
-        let dr = delivery_result.as_ref();
-        //let msg = dr.unwrap();
-
-        match dr {
-            Ok(msg) => {
-                let key: &str = msg.key_view().unwrap().unwrap();
-                println!(
-                    "produced message with key {} in offset {} of partition {}",
-                    key,
-                    msg.offset(),
-                    msg.partition()
-                )
-            }
-            Err(producer_err) => {
-                let key: &str = producer_err.1.key_view().unwrap().unwrap();
-
-                println!(
-                    "failed to produce message with key {} - {}",
-                    key, producer_err.0,
-                )
-            }
+        if shutdown.sleep(Duration::from_secs(3)) {
+            break;
         }
     }
+
+    let undelivered = shutdown::flush_producer(&producer, shutdown::DEFAULT_FLUSH_TIMEOUT);
+    info!(undelivered, "shutdown complete");
 }
@@ -1,94 +1,67 @@
-use std::{thread, time::Duration};
+use std::{process, time::Duration};
 
-use bytes::Bytes;
-use rdkafka::{
-    message::ToBytes,
-    producer::{BaseProducer, BaseRecord, Producer, ProducerContext, ThreadedProducer},
-    ClientConfig, ClientContext, Message,
+use kafka101_core::{
+    logging, partition,
+    shutdown::{self, Shutdown},
+    DeliveryTracker, FormatSerde, Metrics, ProduceCallbackLogger, ProducerHeaders, Settings,
+    TypedProducer, User,
 };
+use tracing::info;
 
 fn main() {
-    let producer: ThreadedProducer<ProduceCallbackLogger> = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        //for auth
-        /*.set("security.protocol", "SASL_SSL")
-        .set("sasl.mechanisms", "PLAIN")
-        .set("sasl.username", "<update>")
-        .set("sasl.password", "<update>")*/
-        .create_with_context(ProduceCallbackLogger {})
-        .expect("invalid producer config");
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let tracker = DeliveryTracker::new();
+    let producer: TypedProducer<User, FormatSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone())
+                    .with_tracker(tracker.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")))
+    .with_tracker(tracker.clone());
+    let producer = match partition::for_users(&settings) {
+        Some(partitioner) => producer.with_partitioner(partitioner),
+        None => producer,
+    };
+    info!(partitioner = %settings.partitioner.strategy, "sending users");
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
 
     for i in 1..100 {
-        println!("sending message");
+        info!("sending message");
 
         let user = User {
             id: i,
             email: format!("user-{}@foobar.com", i),
         };
 
-        let user_json = serde_json::to_string_pretty(&user).expect("json serialization failed");
-        //let user_json = serde_json::to_vec_pretty(&user).expect("json serialization failed");
-
         producer
-            .send(
-                BaseRecord::to("rust")
-                    .key(&format!("user-{}", i))
-                    .payload(&user_json),
-            )
+            .send(&format!("user-{}", i), &user)
             .expect("failed to send message");
 
-        thread::sleep(Duration::from_secs(3));
-    }
-}
-
-//will not compile
-/*impl ToBytes for User {
-    fn to_bytes(&self) -> &[u8] {
-        let b = serde_json::to_vec_pretty(&self).expect("json serialization failed");
-        b.as_slice()
-    }
-}*/
-
-use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, Debug)]
-struct User {
-    id: i32,
-    email: String,
-}
-
-struct ProduceCallbackLogger;
-
-impl ClientContext for ProduceCallbackLogger {}
-
-impl ProducerContext for ProduceCallbackLogger {
-    type DeliveryOpaque = ();
-
-    fn delivery(
-        &self,
-        delivery_result: &rdkafka::producer::DeliveryResult<'_>,
-        _delivery_opaque: Self::DeliveryOpaque,
-    ) {
-        let dr = delivery_result.as_ref();
-        //let msg = dr.unwrap();
-
-        match dr {
-            Ok(msg) => {
-                let key: &str = msg.key_view().unwrap().unwrap();
-                println!(
-                    "produced message with key {} in offset {} of partition {}",
-                    key,
-                    msg.offset(),
-                    msg.partition()
-                )
-            }
-            Err(producer_err) => {
-                let key: &str = producer_err.1.key_view().unwrap().unwrap();
-
-                println!(
-                    "failed to produce message with key {} - {}",
-                    key, producer_err.0,
-                )
-            }
+        if shutdown.sleep(Duration::from_secs(3)) {
+            break;
         }
     }
+
+    let undelivered = shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT);
+    info!(undelivered, "shutdown complete");
+    info!(
+        idempotent = settings.idempotent,
+        report = %tracker.report(),
+        "delivery report"
+    );
 }
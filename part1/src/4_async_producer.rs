@@ -0,0 +1,53 @@
+use std::{process, time::Duration};
+
+use kafka101_core::{
+    logging,
+    shutdown::{self, Shutdown},
+    AuthContext, Settings,
+};
+use rdkafka::producer::{FutureProducer, FutureRecord};
+use tracing::{info, warn};
+
+#[tokio::main]
+async fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+
+    let producer: FutureProducer<AuthContext> = settings
+        .producer_config()
+        .create_with_context(AuthContext::new(&settings.auth))
+        .expect("invalid producer config");
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    for i in 1..100 {
+        info!("sending message");
+
+        let key = format!("key-{}", i);
+        let delivery = producer
+            .send(
+                FutureRecord::to(&settings.topic)
+                    .key(&key)
+                    .payload(&format!("value-{}", i)),
+                Duration::from_secs(5),
+            )
+            .await;
+
+        match delivery {
+            Ok((partition, offset)) => info!(%key, partition, offset, "produced message"),
+            Err((err, _)) => warn!(%key, error = %err, "failed to produce message"),
+        }
+
+        if tokio::task::block_in_place(|| shutdown.sleep(Duration::from_secs(3))) {
+            break;
+        }
+    }
+
+    let undelivered = tokio::task::block_in_place(|| {
+        shutdown::flush_producer(&producer, shutdown::DEFAULT_FLUSH_TIMEOUT)
+    });
+    info!(undelivered, "shutdown complete");
+}
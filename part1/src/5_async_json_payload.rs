@@ -0,0 +1,64 @@
+use std::{process, time::Duration};
+
+use kafka101_core::{
+    logging, partition,
+    shutdown::{self, Shutdown},
+    FormatSerde, FutureTypedProducer, Metrics, ProduceCallbackLogger, ProducerHeaders, Settings,
+    User,
+};
+use tracing::{info, warn};
+
+#[tokio::main]
+async fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let producer: FutureTypedProducer<User, FormatSerde> = FutureTypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")))
+    .with_metrics(metrics.clone());
+    let producer = match partition::for_users(&settings) {
+        Some(partitioner) => producer.with_partitioner(partitioner),
+        None => producer,
+    };
+    info!(partitioner = %settings.partitioner.strategy, "sending users");
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    for i in 1..100 {
+        info!("sending message");
+
+        let user = User {
+            id: i,
+            email: format!("user-{}@foobar.com", i),
+        };
+
+        // the delivery report has been logged by the time send returns
+        if let Err(err) = producer.send(&format!("user-{}", i), &user).await {
+            warn!(error = %err, "failed to send message")
+        }
+
+        if tokio::task::block_in_place(|| shutdown.sleep(Duration::from_secs(3))) {
+            break;
+        }
+    }
+
+    let undelivered = tokio::task::block_in_place(|| {
+        shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT)
+    });
+    info!(undelivered, "shutdown complete");
+}
@@ -1,170 +1,118 @@
-use std::{thread, time::Duration};
-
-use rdkafka::{
-    consumer::{BaseConsumer, Consumer, ConsumerContext, Rebalance},
-    message::ToBytes,
-    producer::{BaseProducer, BaseRecord, Producer, ProducerContext, ThreadedProducer},
-    ClientConfig, ClientContext, Message, Offset,
+use std::{process, thread, time::Duration};
+
+use kafka101_core::{
+    consumer::ReceiveError,
+    logging, replay,
+    shutdown::{self, Shutdown},
+    ConsumerCallbackLogger, FormatSerde, Metrics, PoisonPillHandler, ProduceCallbackLogger,
+    ProducerHeaders, Settings, TypedConsumer, TypedProducer, User,
 };
+use tracing::{error, info, warn};
 
 fn main() {
-    let consumer: BaseConsumer<ConsumerCallbackLogger> = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        //for auth
-        /*.set("security.protocol", "SASL_SSL")
-        .set("sasl.mechanisms", "PLAIN")
-        .set("sasl.username", "<update>")
-        .set("sasl.password", "<update>")*/
-        .set("group.id", "my_consumer_group")
-        .create_with_context(ConsumerCallbackLogger {})
-        .expect("invalid consumer config");
-
-    consumer
-        .subscribe(&["rust"])
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let consumer: TypedConsumer<User, FormatSerde, ConsumerCallbackLogger> = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .create_with_context(
+                ConsumerCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid consumer config"),
+        settings.serde().expect("invalid payload format"),
+    )
+    .require_key(settings.errors.require_key);
+
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from)
         .expect("topic subscribe failed");
 
-    thread::spawn(move || loop {
-        for msg_result in consumer.iter() {
-            let msg = msg_result.unwrap();
-            let key: &str = msg.key_view().unwrap().unwrap();
-            let value = msg.payload().unwrap();
-            let user: User =
-                serde_json::from_slice(value).expect("failed to deserialize JSON to User");
-            println!(
-                "received key {} with value {:?} in offset {:?} from partition {}",
-                key,
-                user,
-                msg.offset(),
-                msg.partition()
-            )
+    let poison =
+        PoisonPillHandler::from_settings(&settings).expect("invalid dead letter producer config");
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    let consumer_shutdown = shutdown.clone();
+    let consumer_thread = thread::spawn(move || {
+        while !consumer_shutdown.is_requested() {
+            let received = match consumer.poll(shutdown::POLL_INTERVAL) {
+                Some(Ok(received)) => received,
+                Some(Err(ReceiveError::Poison(pill))) => {
+                    // auto commit moves past it either way
+                    if let Err(err) = poison.handle(&pill) {
+                        error!(error = %err, "failed to handle poison pill")
+                    }
+                    continue;
+                }
+                Some(Err(err)) => {
+                    warn!(error = %err, "failed to consume message");
+                    if err.is_fatal() {
+                        error!("loop encountered receive error, closing consumer");
+                        break;
+                    }
+                    // e.g. the schema registry is down, and the message comes back
+                    if let Some(backoff) = err.backoff() {
+                        consumer_shutdown.sleep(backoff);
+                    }
+                    continue;
+                }
+                None => continue,
+            };
+            // commits and failures below are logged in the span of the message
+            let _span = received.span().clone().entered();
+            info!(
+                value = ?received.value(),
+                headers = %received.headers(),
+                "received message"
+            );
+        }
+
+        if let Err(err) = shutdown::close_consumer(consumer.inner(), true) {
+            warn!(error = %err, "failed to commit final offsets")
         }
     });
 
-    let producer: ThreadedProducer<ProduceCallbackLogger> = ClientConfig::new()
-        .set("bootstrap.servers", "localhost:9092")
-        //for auth
-        /*.set("security.protocol", "SASL_SSL")
-        .set("sasl.mechanisms", "PLAIN")
-        .set("sasl.username", "<update>")
-        .set("sasl.password", "<update>")*/
-        .create_with_context(ProduceCallbackLogger {})
-        .expect("invalid producer config");
+    let producer: TypedProducer<User, FormatSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")));
 
     for i in 1..100 {
-        println!("sending message");
+        info!("sending message");
 
         let user = User {
             id: i,
             email: format!("user-{}@foobar.com", i),
         };
 
-        let user_json = serde_json::to_string_pretty(&user).expect("json serialization failed");
-
         producer
-            .send(
-                BaseRecord::to("rust")
-                    .key(&format!("user-{}", i))
-                    .payload(&user_json),
-            )
+            .send(&format!("user-{}", i), &user)
             .expect("failed to send message");
 
-        thread::sleep(Duration::from_secs(3));
-    }
-}
-
-use serde::{Deserialize, Serialize};
-#[derive(Serialize, Deserialize, Debug)]
-struct User {
-    id: i32,
-    email: String,
-}
-
-struct ConsumerCallbackLogger;
-
-impl ClientContext for ConsumerCallbackLogger {}
-
-impl ConsumerContext for ConsumerCallbackLogger {
-    fn pre_rebalance<'a>(&self, _rebalance: &rdkafka::consumer::Rebalance<'a>) {}
-
-    fn post_rebalance<'a>(&self, rebalance: &rdkafka::consumer::Rebalance<'a>) {
-        println!("post_rebalance callback");
-
-        match rebalance {
-            Rebalance::Assign(tpl) => {
-                for e in tpl.elements() {
-                    println!("rebalanced partition {}", e.partition())
-                }
-            }
-            Rebalance::Revoke => {
-                println!("ALL partitions have been REVOKED")
-            }
-            Rebalance::Error(err_info) => {
-                println!("Post Rebalance error {}", err_info)
-            }
+        if shutdown.sleep(Duration::from_secs(3)) {
+            break;
         }
     }
 
-    fn commit_callback(
-        &self,
-        result: rdkafka::error::KafkaResult<()>,
-        offsets: &rdkafka::TopicPartitionList,
-    ) {
-        match result {
-            Ok(_) => {
-                for e in offsets.elements() {
-                    match e.offset() {
-                        //skip Invalid offset
-                        Offset::Invalid => {}
-                        _ => {
-                            println!(
-                                "committed offset {:?} in partition {}",
-                                e.offset(),
-                                e.partition()
-                            )
-                        }
-                    }
-                }
-            }
-            Err(err) => {
-                println!("error committing offset - {}", err)
-            }
-        }
-    }
-}
+    let undelivered = shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT);
 
-struct ProduceCallbackLogger;
-
-impl ClientContext for ProduceCallbackLogger {}
-
-impl ProducerContext for ProduceCallbackLogger {
-    type DeliveryOpaque = ();
-
-    fn delivery(
-        &self,
-        delivery_result: &rdkafka::producer::DeliveryResult<'_>,
-        _delivery_opaque: Self::DeliveryOpaque,
-    ) {
-        let dr = delivery_result.as_ref();
-        //let msg = dr.unwrap();
-
-        match dr {
-            Ok(msg) => {
-                let key: &str = msg.key_view().unwrap().unwrap();
-                println!(
-                    "produced message with key {} in offset {} of partition {}",
-                    key,
-                    msg.offset(),
-                    msg.partition()
-                )
-            }
-            Err(producer_err) => {
-                let key: &str = producer_err.1.key_view().unwrap().unwrap();
-
-                println!(
-                    "failed to produce message with key {} - {}",
-                    key, producer_err.0,
-                )
-            }
-        }
-    }
+    shutdown.trigger();
+    consumer_thread.join().expect("consumer thread panicked");
+
+    info!(undelivered, "shutdown complete");
 }
@@ -0,0 +1,130 @@
+use std::{process, time::Duration};
+
+use futures::StreamExt;
+use kafka101_core::{
+    consumer::ReceiveError,
+    logging, replay,
+    shutdown::{self, Shutdown},
+    ConsumerCallbackLogger, FormatSerde, FutureTypedProducer, Metrics, PoisonPillHandler,
+    ProduceCallbackLogger, ProducerHeaders, Settings, StreamTypedConsumer, User,
+};
+use tracing::{error, info, warn};
+
+#[tokio::main]
+async fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let consumer: StreamTypedConsumer<User, FormatSerde, ConsumerCallbackLogger> =
+        StreamTypedConsumer::new(
+            settings
+                .consumer_config()
+                .create_with_context(
+                    ConsumerCallbackLogger::new()
+                        .with_auth(&settings.auth)
+                        .with_metrics(metrics.clone()),
+                )
+                .expect("invalid consumer config"),
+            settings.serde().expect("invalid payload format"),
+        )
+        .require_key(settings.errors.require_key);
+
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from)
+        .expect("topic subscribe failed");
+
+    let poison =
+        PoisonPillHandler::from_settings(&settings).expect("invalid dead letter producer config");
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    let consumer_shutdown = shutdown.clone();
+    let consumer_task = tokio::spawn(async move {
+        let mut stream = consumer.stream();
+        while !consumer_shutdown.is_requested() {
+            let received = match tokio::time::timeout(shutdown::POLL_INTERVAL, stream.next()).await
+            {
+                Ok(Some(Ok(received))) => received,
+                Ok(Some(Err(ReceiveError::Poison(pill)))) => {
+                    // auto commit moves past it either way
+                    if let Err(err) = poison.handle_async(&pill).await {
+                        error!(error = %err, "failed to handle poison pill")
+                    }
+                    continue;
+                }
+                Ok(Some(Err(err))) => {
+                    warn!(error = %err, "failed to consume message");
+                    if err.is_fatal() {
+                        error!("loop encountered receive error, closing consumer");
+                        break;
+                    }
+                    // e.g. the schema registry is down, and the message comes back
+                    if let Some(backoff) = err.backoff() {
+                        tokio::time::sleep(backoff).await;
+                    }
+                    continue;
+                }
+                Ok(None) => break,
+                // nothing within the poll interval, check for shutdown again
+                Err(_) => continue,
+            };
+            info!(
+                parent: received.span(),
+                value = ?received.value(),
+                headers = %received.headers(),
+                "received message"
+            );
+        }
+        drop(stream);
+
+        if let Err(err) =
+            tokio::task::block_in_place(|| shutdown::close_consumer(consumer.inner(), true))
+        {
+            warn!(error = %err, "failed to commit final offsets")
+        }
+    });
+
+    let producer: FutureTypedProducer<User, FormatSerde> = FutureTypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")))
+    .with_metrics(metrics.clone());
+
+    for i in 1..100 {
+        info!("sending message");
+
+        let user = User {
+            id: i,
+            email: format!("user-{}@foobar.com", i),
+        };
+
+        if let Err(err) = producer.send(&format!("user-{}", i), &user).await {
+            warn!(error = %err, "failed to send message")
+        }
+
+        if tokio::task::block_in_place(|| shutdown.sleep(Duration::from_secs(3))) {
+            break;
+        }
+    }
+
+    let undelivered = tokio::task::block_in_place(|| {
+        shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT)
+    });
+
+    shutdown.trigger();
+    consumer_task.await.expect("consumer task panicked");
+
+    info!(undelivered, "shutdown complete");
+}
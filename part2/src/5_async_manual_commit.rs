@@ -0,0 +1,238 @@
+use std::{process, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use kafka101_core::{
+    consumer::{OwnedReceived, ReceiveError},
+    logging, replay,
+    shutdown::{self, Shutdown},
+    simulation, CommitError, CommitManager, ConsumerCallbackLogger, DeadLetterQueue,
+    FailureSimulator, FormatSerde, FutureTypedProducer, Metrics, OffsetCommitter, PoisonPill,
+    PoisonPillHandler, ProduceCallbackLogger, ProducerHeaders, Settings, StreamTypedConsumer, User,
+};
+use rdkafka::Message;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn, Instrument};
+
+/// Messages read ahead of processing. When the buffer is full the reader stops
+/// polling the consumer until the processor catches up.
+const PIPELINE_CAPACITY: usize = 16;
+
+type UserConsumer = StreamTypedConsumer<User, FormatSerde, ConsumerCallbackLogger>;
+
+#[tokio::main]
+async fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let retry = settings.errors.retry;
+    let committer = OffsetCommitter::new(settings.commit, CommitManager::new(retry));
+    info!(strategy = %committer.strategy(), "committing offsets");
+
+    let consumer: Arc<UserConsumer> = Arc::new(
+        StreamTypedConsumer::new(
+            settings
+                .consumer_config()
+                .set("enable.auto.commit", "false")
+                .create_with_context(
+                    ConsumerCallbackLogger::new()
+                        .with_auth(&settings.auth)
+                        .with_metrics(metrics.clone())
+                        .with_committer(committer.clone()),
+                )
+                .expect("invalid consumer config"),
+            settings.serde().expect("invalid payload format"),
+        )
+        .require_key(settings.errors.require_key),
+    );
+
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from)
+        .expect("topic subscribe failed");
+
+    let dlq =
+        DeadLetterQueue::from_settings(&settings).expect("invalid dead letter producer config");
+    let poison = PoisonPillHandler::new(settings.errors.poison_pill, Some(dlq.clone()));
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    // poison pills go through the pipeline too, so offsets are committed in order
+    let (tx, mut rx) =
+        mpsc::channel::<Result<OwnedReceived<User>, Box<PoisonPill>>>(PIPELINE_CAPACITY);
+
+    let reader = consumer.clone();
+    let reading = committer.clone();
+    let reader_shutdown = shutdown.clone();
+    // dropping `tx` when it stops lets the processor finish what was read
+    let reader_task = tokio::spawn(async move {
+        let mut stream = reader.stream();
+        while !reader_shutdown.is_requested() {
+            let received = match tokio::time::timeout(shutdown::POLL_INTERVAL, stream.next()).await
+            {
+                Ok(Some(Ok(received))) => received,
+                Ok(Some(Err(ReceiveError::Poison(pill)))) => {
+                    reading.received(&pill.message);
+                    if tx.send(Err(pill)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                Ok(Some(Err(err))) => {
+                    warn!(error = %err, "failed to consume message");
+                    if err.is_fatal() {
+                        error!("loop encountered receive error, closing consumer");
+                        break;
+                    }
+                    // e.g. the schema registry is down, and the message comes back
+                    if let Some(backoff) = err.backoff() {
+                        tokio::time::sleep(backoff).await;
+                    }
+                    continue;
+                }
+                Ok(None) => break,
+                // nothing within the poll interval, check for shutdown again
+                Err(_) => continue,
+            };
+            let msg = received.message();
+            // in flight until the processor is done with it
+            reading.received(msg);
+
+            info!(
+                parent: received.span(),
+                value = ?received.value(),
+                headers = %received.headers(),
+                "received message"
+            );
+
+            // fails once the processor has stopped
+            if tx.send(Ok(received.detach())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let processing = metrics.clone();
+    let failures = FailureSimulator::default();
+    let processor_task = tokio::spawn(async move {
+        while let Some(received) = rx.recv().await {
+            let received = match received {
+                Ok(received) => received,
+                Err(pill) => {
+                    // only committed once handled, like a processed message
+                    if let Err(err) = poison.handle_async(&pill).await {
+                        error!(error = %err, "failed to handle poison pill");
+                        error!("loop encountered processing error, closing consumer");
+                        break;
+                    }
+                    if let Err(err) = commit(&committer, &consumer, &pill.message) {
+                        warn!(error = %err, "commit failed");
+                        if err.is_fatal() {
+                            break;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            let msg = received.message();
+            let processed = retry
+                .run_async(|_| async { simulation::process_user(received.value(), &failures) })
+                .instrument(received.span().clone())
+                .await;
+            let given_up = match processed {
+                Ok(_) => Ok(()),
+                Err(failed) => {
+                    processing.processing_failed();
+                    warn!(
+                        parent: received.span(),
+                        user = ?received.value(),
+                        attempts = failed.attempts,
+                        "giving up on user"
+                    );
+                    dlq.send_async(msg, &failed.error, failed.attempts)
+                        .instrument(received.span().clone())
+                        .await
+                        .map(|_| ())
+                }
+            };
+            // without a copy in the dead letter topic the offset must not be committed
+            if let Err(err) = given_up {
+                error!(dlq_topic = dlq.topic(), error = %err, "failed to send to dead letter topic");
+                error!("loop encountered processing error, closing consumer");
+                break;
+            }
+
+            if let Err(err) = commit(&committer, &consumer, msg) {
+                warn!(error = %err, "commit failed");
+                if err.is_fatal() {
+                    error!("loop encountered commit error, closing consumer");
+                    break;
+                }
+            }
+        }
+        // whatever the strategy left uncommitted
+        tokio::task::block_in_place(|| {
+            if let Err(err) = committer.commit_sync(consumer.inner()) {
+                warn!(error = %err, "commit failed")
+            }
+            if let Err(err) = shutdown::close_consumer(consumer.inner(), false) {
+                warn!(error = %err, "failed to close consumer")
+            }
+        });
+        info!(stats = %committer.stats(), "commit stats");
+    });
+
+    let producer: FutureTypedProducer<User, FormatSerde> = FutureTypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")))
+    .with_metrics(metrics.clone());
+
+    for i in 1..100 {
+        let user = User {
+            id: i,
+            email: format!("user-{}@foobar.com", i),
+        };
+
+        info!("sending message");
+
+        if let Err(err) = producer.send(&format!("user-{}", i), &user).await {
+            warn!(error = %err, "failed to send message")
+        }
+
+        if tokio::task::block_in_place(|| shutdown.sleep(Duration::from_secs(3))) {
+            break;
+        }
+    }
+
+    let undelivered = tokio::task::block_in_place(|| {
+        shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT)
+    });
+
+    shutdown.trigger();
+    reader_task.await.expect("reader task panicked");
+    processor_task.await.expect("processor task panicked");
+
+    info!(undelivered, "shutdown complete");
+}
+
+/// Marks `msg` as processed, committing if the strategy says so.
+fn commit<M: Message>(
+    committer: &OffsetCommitter,
+    consumer: &UserConsumer,
+    msg: &M,
+) -> Result<(), CommitError> {
+    // a synchronous commit, and its retries, block until the broker answers
+    tokio::task::block_in_place(|| committer.processed(consumer.inner(), msg))
+}
@@ -0,0 +1,325 @@
+use std::{
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use kafka101_core::{
+    consumer::ReceiveError,
+    dlq, logging,
+    producer::SendError,
+    shutdown::{self, Shutdown},
+    BatchOffsets, ConsumerCallbackLogger, FormatSerde, Metrics, PoisonPill, PoisonPillHandler,
+    PoisonStrategy, ProduceCallbackLogger, ProducerHeaders, RebalanceListener, RecordHeaders,
+    Settings, TransactionError, TransactionalProducer, TypedConsumer, TypedProducer, User,
+};
+use rdkafka::{consumer::Consumer, Message, TopicPartitionList};
+use tracing::{error, info, warn};
+
+/// Messages transformed in one transaction. A shorter batch is committed when
+/// no message arrives within a poll interval.
+const BATCH_SIZE: usize = 100;
+
+/// A transformed record, waiting for the transaction of its batch.
+struct Transformed {
+    topic: String,
+    partition: i32,
+    key: String,
+    user: User,
+    headers: RecordHeaders,
+}
+
+/// What was consumed since the last transaction.
+#[derive(Default)]
+struct Pending {
+    batch: BatchOffsets,
+    transformed: Vec<Transformed>,
+    /// Poison pills to send to the dead letter topic with the batch.
+    dead_letters: Vec<PoisonPill>,
+}
+
+impl Pending {
+    fn forget(&mut self, revoked: &TopicPartitionList) -> usize {
+        let revoked_partition =
+            |topic: &str, partition| revoked.find_partition(topic, partition).is_some();
+        self.transformed
+            .retain(|t| !revoked_partition(&t.topic, t.partition));
+        self.dead_letters
+            .retain(|pill| !revoked_partition(pill.message.topic(), pill.message.partition()));
+        self.batch.forget(revoked)
+    }
+
+    fn clear(&mut self) {
+        self.batch.clear();
+        self.transformed.clear();
+        self.dead_letters.clear();
+    }
+}
+
+/// Aborts the part of the pending batch that came from revoked partitions.
+/// Nothing of it was written yet, since no transaction is open while the
+/// consumer polls, and its offsets can no longer be committed from here: the
+/// new owner of the partitions transforms it again.
+struct PendingListener {
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl PendingListener {
+    fn drop_messages(&self, partitions: &TopicPartitionList, why: &str) {
+        let dropped = self.pending.lock().unwrap().forget(partitions);
+        if dropped > 0 {
+            info!(
+                messages = dropped,
+                "dropped pending messages of {} partitions", why
+            );
+        }
+    }
+}
+
+impl RebalanceListener for PendingListener {
+    fn on_revoke(&self, revoked: &TopicPartitionList) {
+        self.drop_messages(revoked, "revoked")
+    }
+
+    fn on_lost(&self, lost: &TopicPartitionList) {
+        self.drop_messages(lost, "lost")
+    }
+}
+
+fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let pending = Arc::new(Mutex::new(Pending::default()));
+
+    let consumer: TypedConsumer<User, FormatSerde, ConsumerCallbackLogger> = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .set("enable.auto.commit", "false")
+            // skip the output of aborted transactions when reading a pipeline's output
+            .set("isolation.level", "read_committed")
+            .create_with_context(
+                ConsumerCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone())
+                    .with_listener(Arc::new(PendingListener {
+                        pending: pending.clone(),
+                    })),
+            )
+            .expect("invalid consumer config"),
+        settings.serde().expect("invalid payload format"),
+    )
+    .require_key(settings.errors.require_key);
+
+    consumer
+        .inner()
+        .subscribe(&[&settings.topic])
+        .expect("topic subscribe failed");
+
+    let output: TransactionalProducer<User, FormatSerde> = TransactionalProducer::new(
+        TypedProducer::new(
+            settings
+                .transactional_producer_config()
+                .create_with_context(
+                    ProduceCallbackLogger::new()
+                        .with_auth(&settings.auth)
+                        .with_metrics(metrics.clone()),
+                )
+                .expect("invalid transactional producer config"),
+            settings.output_topic(),
+            settings.serde().expect("invalid payload format"),
+        )
+        .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME"))),
+    )
+    .with_timeout(settings.transactions.timeout)
+    .with_retry_policy(settings.errors.retry);
+
+    output.init().expect("failed to initialize transactions");
+    info!(
+        topic = %settings.topic,
+        output_topic = settings.output_topic(),
+        transactional_id = %settings.transactional_id(),
+        "transforming"
+    );
+
+    // dead letters are written in the transaction of their batch, so that an
+    // aborted batch that is consumed again does not send them twice
+    let strategy = settings.errors.poison_pill;
+    let poison: Option<PoisonPillHandler> =
+        (strategy != PoisonStrategy::Dlq).then(|| PoisonPillHandler::new(strategy, None));
+    let dlq_topic = settings.dlq_topic();
+    let topic = settings.topic.clone();
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    let consumer_shutdown = shutdown.clone();
+    let consumer_thread = thread::spawn(move || {
+        loop {
+            let stopping = consumer_shutdown.is_requested();
+            let polled = if stopping {
+                None
+            } else {
+                consumer.poll(shutdown::POLL_INTERVAL)
+            };
+            // only locked after the poll: the rebalance listener takes the lock inside it
+            let mut pending = pending.lock().unwrap();
+            let idle = match polled {
+                Some(Ok(received)) => {
+                    let msg = received.message();
+                    // commits and failures below are logged in the span of the message
+                    let _span = received.span().clone().entered();
+                    let headers = received.headers();
+                    info!(
+                        value = ?received.value(),
+                        headers = %headers,
+                        "received message"
+                    );
+                    pending.batch.track(msg);
+                    pending.transformed.push(Transformed {
+                        topic: msg.topic().to_string(),
+                        partition: msg.partition(),
+                        key: received.key().unwrap_or_default().to_string(),
+                        user: transform(received.value()),
+                        // the output continues the trace of its input
+                        headers: headers.derived(),
+                    });
+                    false
+                }
+                Some(Err(ReceiveError::Poison(pill))) => {
+                    // committed with the batch, without output
+                    pending.batch.track(&pill.message);
+                    match &poison {
+                        Some(poison) => {
+                            if let Err(err) = poison.handle(&pill) {
+                                error!(error = %err, "failed to handle poison pill");
+                                error!("loop encountered processing error, closing consumer");
+                                break;
+                            }
+                        }
+                        None => {
+                            warn!(error = %pill, dlq_topic = %dlq_topic, "dead-lettering poison pill with the batch");
+                            pending.dead_letters.push(*pill);
+                        }
+                    }
+                    false
+                }
+                Some(Err(err)) => {
+                    warn!(error = %err, "failed to consume message");
+                    if err.is_fatal() {
+                        error!("loop encountered receive error, closing consumer");
+                        break;
+                    }
+                    // e.g. the schema registry is down, and the message comes back
+                    if let Some(backoff) = err.backoff() {
+                        consumer_shutdown.sleep(backoff);
+                    }
+                    true
+                }
+                None => true,
+            };
+
+            if !pending.batch.is_empty() && (idle || pending.batch.len() >= BATCH_SIZE) {
+                let committed = output.run(consumer.inner(), &pending.batch, |producer| {
+                    for t in &pending.transformed {
+                        producer.send_with_headers(&t.key, &t.user, &t.headers)?;
+                    }
+                    for pill in &pending.dead_letters {
+                        dlq::send_in_transaction(
+                            producer.inner(),
+                            &dlq_topic,
+                            &pill.message,
+                            pill.kind.as_str(),
+                            1,
+                        )
+                        .map_err(SendError::Kafka)?;
+                    }
+                    Ok(())
+                });
+                match committed {
+                    Ok(()) => info!(
+                        records = pending.transformed.len(),
+                        dead_letters = pending.dead_letters.len(),
+                        messages = pending.batch.len(),
+                        "committed transaction"
+                    ),
+                    // the consumer was rewound, the batch is consumed again
+                    Err(err) if err.is_retriable() => warn!(error = %err, "transaction aborted"),
+                    Err(TransactionError::Rewind(err)) => {
+                        warn!(error = %err, "failed to rewind, consuming again from the committed offsets");
+                        // partitions assigned again start from their committed offsets
+                        consumer.inner().unsubscribe();
+                        if let Err(err) = consumer.inner().subscribe(&[&topic]) {
+                            error!(error = %err, "topic subscribe failed");
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        error!(error = %err, "transaction failed");
+                        error!("loop encountered transaction error, closing consumer");
+                        break;
+                    }
+                }
+                pending.clear();
+            }
+
+            if stopping {
+                break;
+            }
+        }
+
+        // every transformed message has been committed in its transaction
+        if let Err(err) = shutdown::close_consumer(consumer.inner(), false) {
+            warn!(error = %err, "failed to close consumer")
+        }
+    });
+
+    let producer: TypedProducer<User, FormatSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")));
+
+    for i in 1..100 {
+        let user = User {
+            id: i,
+            email: format!("User-{}@FooBar.com", i),
+        };
+
+        info!("sending message");
+
+        producer
+            .send(&format!("user-{}", i), &user)
+            .expect("failed to send message");
+
+        if shutdown.sleep(Duration::from_secs(3)) {
+            break;
+        }
+    }
+
+    let undelivered = shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT);
+
+    shutdown.trigger();
+    consumer_thread.join().expect("consumer thread panicked");
+
+    info!(undelivered, "shutdown complete");
+}
+
+fn transform(u: &User) -> User {
+    User {
+        id: u.id,
+        email: u.email.to_lowercase(),
+    }
+}
@@ -0,0 +1,331 @@
+use std::{
+    collections::BTreeMap,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use kafka101_core::{
+    consumer::{OwnedReceived, ReceiveError},
+    logging,
+    parallel::Completed,
+    replay,
+    shutdown::{self, Shutdown},
+    simulation, CommitManager, ConsumerCallbackLogger, DeadLetterQueue, FailureSimulator,
+    FormatSerde, Metrics, OffsetCommitter, PoisonPillHandler, ProduceCallbackLogger,
+    ProducerHeaders, RebalanceListener, Settings, TypedConsumer, TypedProducer, User, WorkerPool,
+};
+use rdkafka::{Message, TopicPartitionList};
+use tracing::{error, info, warn};
+
+type UserConsumer = TypedConsumer<User, FormatSerde, ConsumerCallbackLogger>;
+type UserPool = WorkerPool<User, String>;
+
+/// Finishes the records of revoked partitions before their offsets are
+/// committed, and counts the records processed per partition.
+struct PartitionListener {
+    pool: Mutex<UserPool>,
+    committer: OffsetCommitter,
+    /// Records processed per partition since it was assigned.
+    processed: Mutex<BTreeMap<(String, i32), u64>>,
+    /// A record drained on revoke failed, so the loop stops like for any other failure.
+    failed: AtomicBool,
+}
+
+impl PartitionListener {
+    fn count<M: Message>(&self, msg: &M) {
+        let mut processed = self.processed.lock().unwrap();
+        if let Some(count) = processed.get_mut(&(msg.topic().to_string(), msg.partition())) {
+            *count += 1;
+        }
+    }
+
+    fn forget(&self, tpl: &TopicPartitionList, why: &str) {
+        let mut processed = self.processed.lock().unwrap();
+        for e in tpl.elements() {
+            if let Some(count) = processed.remove(&(e.topic().to_string(), e.partition())) {
+                info!(
+                    topic = e.topic(),
+                    partition = e.partition(),
+                    processed = count,
+                    "{} partition",
+                    why
+                );
+            }
+        }
+    }
+}
+
+impl RebalanceListener for PartitionListener {
+    fn on_assign(&self, assigned: &TopicPartitionList) {
+        let mut processed = self.processed.lock().unwrap();
+        for e in assigned.elements() {
+            processed.insert((e.topic().to_string(), e.partition()), 0);
+        }
+    }
+
+    fn on_revoke(&self, revoked: &TopicPartitionList) {
+        let completed = self.pool.lock().unwrap().drain(revoked);
+        info!(records = completed.len(), "finished records before revoke");
+        for done in completed {
+            match done.result {
+                // committed by the consumer context once this returns
+                Ok(()) => {
+                    self.committer.mark_processed(done.received.message());
+                    self.count(done.received.message());
+                }
+                Err(ref err) => {
+                    error!(parent: done.received.span(), error = %err, "failed to process record");
+                    self.failed.store(true, Ordering::SeqCst);
+                }
+            }
+        }
+        self.forget(revoked, "revoked");
+    }
+
+    fn on_lost(&self, lost: &TopicPartitionList) {
+        // records in flight are done again by the new owner
+        self.forget(lost, "lost");
+    }
+}
+
+fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    let retry = settings.errors.retry;
+    let committer = OffsetCommitter::new(settings.commit, CommitManager::new(retry));
+    info!(
+        workers = settings.parallel.workers,
+        ordering = ?settings.parallel.ordering,
+        strategy = %committer.strategy(),
+        "processing on a worker pool"
+    );
+
+    let dlq =
+        DeadLetterQueue::from_settings(&settings).expect("invalid dead letter producer config");
+    let poison = PoisonPillHandler::new(settings.errors.poison_pill, Some(dlq.clone()));
+
+    let processing = metrics.clone();
+    let failures = FailureSimulator::default();
+    let pool = WorkerPool::new(
+        &settings.parallel,
+        move |received: &OwnedReceived<User>| -> Result<(), String> {
+            let failed = match retry.run(|_| process(received.value(), &failures)) {
+                Ok(()) => return Ok(()),
+                Err(failed) => failed,
+            };
+            processing.processing_failed();
+            warn!(
+                user = ?received.value(),
+                attempts = failed.attempts,
+                "giving up on user"
+            );
+            // without a copy in the dead letter topic the offset must not be committed
+            match dlq.send(received.message(), &failed.error, failed.attempts) {
+                Ok(_) => Ok(()),
+                Err(err) => Err(format!("failed to send to {} - {}", dlq.topic(), err)),
+            }
+        },
+    );
+    let listener = Arc::new(PartitionListener {
+        pool: Mutex::new(pool),
+        committer: committer.clone(),
+        processed: Mutex::new(BTreeMap::new()),
+        failed: AtomicBool::new(false),
+    });
+
+    let consumer: UserConsumer = TypedConsumer::new(
+        settings
+            .consumer_config()
+            .set("enable.auto.commit", "false")
+            .create_with_context(
+                ConsumerCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone())
+                    .with_committer(committer.clone())
+                    .with_listener(listener.clone()),
+            )
+            .expect("invalid consumer config"),
+        settings.serde().expect("invalid payload format"),
+    )
+    .require_key(settings.errors.require_key);
+
+    replay::subscribe(consumer.inner(), &settings.topic, &settings.replay.from)
+        .expect("topic subscribe failed");
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    let consumer_shutdown = shutdown.clone();
+    let consumer_thread = thread::spawn(move || {
+        while !consumer_shutdown.is_requested() && !listener.failed.load(Ordering::SeqCst) {
+            if listener.pool.lock().unwrap().is_full() {
+                // stop fetching until the workers catch up
+                let completed = listener
+                    .pool
+                    .lock()
+                    .unwrap()
+                    .completed(shutdown::POLL_INTERVAL);
+                if !complete(&listener, &consumer, completed) {
+                    break;
+                }
+                continue;
+            }
+
+            match consumer.poll(shutdown::POLL_INTERVAL) {
+                Some(Ok(received)) => {
+                    let msg = received.message();
+                    // commits and failures below are logged in the span of the message
+                    let _span = received.span().clone().entered();
+                    info!(
+                        value = ?received.value(),
+                        headers = %received.headers(),
+                        "received message"
+                    );
+                    // not committable until the worker is done with it
+                    committer.received(msg);
+                    listener.pool.lock().unwrap().submit(received.detach());
+                }
+                Some(Err(ReceiveError::Poison(pill))) => {
+                    // later records of its partition wait for the earlier ones to be committed
+                    if let Err(err) = poison.handle(&pill) {
+                        error!(error = %err, "failed to handle poison pill");
+                        error!("loop encountered processing error, closing consumer");
+                        break;
+                    }
+                    if let Err(err) = committer.processed(consumer.inner(), &pill.message) {
+                        warn!(error = %err, "commit failed");
+                        if err.is_fatal() {
+                            break;
+                        }
+                    }
+                }
+                Some(Err(err)) => {
+                    warn!(error = %err, "failed to consume message");
+                    if err.is_fatal() {
+                        error!("loop encountered receive error, closing consumer");
+                        break;
+                    }
+                    // e.g. the schema registry is down, and the message comes back
+                    if let Some(backoff) = err.backoff() {
+                        consumer_shutdown.sleep(backoff);
+                    }
+                }
+                None => {
+                    if let Err(err) = committer.tick(consumer.inner()) {
+                        warn!(error = %err, "commit failed");
+                        if err.is_fatal() {
+                            break;
+                        }
+                    }
+                }
+            }
+
+            let completed = listener.pool.lock().unwrap().completed(Duration::ZERO);
+            if !complete(&listener, &consumer, completed) {
+                break;
+            }
+        }
+
+        // finish what the workers have started before the final commit
+        let completed = {
+            let mut pool = listener.pool.lock().unwrap();
+            info!(
+                in_flight = pool.in_flight(),
+                "waiting for records in flight"
+            );
+            pool.wait_idle()
+        };
+        complete(&listener, &consumer, completed);
+        if let Err(err) = committer.commit_sync(consumer.inner()) {
+            warn!(error = %err, "commit failed")
+        }
+        if let Err(err) = shutdown::close_consumer(consumer.inner(), false) {
+            warn!(error = %err, "failed to close consumer")
+        }
+        info!(stats = %committer.stats(), "commit stats");
+    });
+
+    let producer: TypedProducer<User, FormatSerde> = TypedProducer::new(
+        settings
+            .producer_config()
+            .create_with_context(
+                ProduceCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid producer config"),
+        &settings.topic,
+        settings.serde().expect("invalid payload format"),
+    )
+    .with_headers(ProducerHeaders::new(env!("CARGO_BIN_NAME")));
+
+    for i in 1..100 {
+        let user = User {
+            id: i,
+            email: format!("user-{}@foobar.com", i),
+        };
+
+        info!("sending message");
+
+        producer
+            .send(&format!("user-{}", i % 10), &user)
+            .expect("failed to send message");
+
+        if shutdown.sleep(Duration::from_millis(300)) {
+            break;
+        }
+    }
+
+    let undelivered = shutdown::flush_producer(producer.inner(), shutdown::DEFAULT_FLUSH_TIMEOUT);
+
+    shutdown.trigger();
+    consumer_thread.join().expect("consumer thread panicked");
+
+    info!(undelivered, "shutdown complete");
+}
+
+/// Marks finished records as processed. Returns false when the loop should stop.
+fn complete(
+    listener: &PartitionListener,
+    consumer: &UserConsumer,
+    completed: Vec<Completed<User, String>>,
+) -> bool {
+    let mut keep_going = true;
+    for done in completed {
+        let _span = done.received.span().clone().entered();
+        if let Err(err) = done.result {
+            // left uncommitted, so it is consumed again after a restart
+            error!(error = %err, "failed to process record");
+            error!("loop encountered processing error, closing consumer");
+            keep_going = false;
+            continue;
+        }
+        listener.count(done.received.message());
+        if let Err(err) = listener
+            .committer
+            .processed(consumer.inner(), done.received.message())
+        {
+            warn!(error = %err, "commit failed");
+            if err.is_fatal() {
+                error!("loop encountered commit error, closing consumer");
+                keep_going = false;
+            }
+        }
+    }
+    keep_going
+}
+
+fn process(u: &User, failures: &FailureSimulator) -> Result<(), String> {
+    // slow enough for the workers to overlap
+    thread::sleep(failures.delay(100..1000));
+    simulation::process_user(u, failures)
+}
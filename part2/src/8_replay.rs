@@ -0,0 +1,107 @@
+use std::process;
+
+use kafka101_core::{
+    consumer::ReceiveError,
+    logging,
+    replay::EndPosition,
+    shutdown::{self, Shutdown},
+    ConsumerCallbackLogger, FormatSerde, Metrics, ReplayWindow, Settings, TypedConsumer, User,
+};
+use rdkafka::error::KafkaError;
+use tracing::{error, info, warn};
+
+fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+    let until = settings.replay.until.clone().unwrap_or(EndPosition::Now);
+
+    let consumer: TypedConsumer<User, FormatSerde, ConsumerCallbackLogger> = TypedConsumer::new(
+        settings
+            .consumer_config()
+            // replaying must not move the offsets of the group
+            .set("enable.auto.commit", "false")
+            // notices partitions that end before `until`
+            .set("enable.partition.eof", "true")
+            .create_with_context(
+                ConsumerCallbackLogger::new()
+                    .with_auth(&settings.auth)
+                    .with_metrics(metrics.clone()),
+            )
+            .expect("invalid consumer config"),
+        settings.serde().expect("invalid payload format"),
+    )
+    .require_key(settings.errors.require_key);
+
+    let mut window = ReplayWindow::assign(
+        consumer.inner(),
+        &settings.topic,
+        &settings.replay.from,
+        &until,
+    )
+    .expect("failed to open replay window");
+    info!(
+        topic = %settings.topic,
+        from = %settings.replay.from,
+        until = %until,
+        "replaying"
+    );
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    let mut replayed = 0;
+    while !window.is_done() && !shutdown.is_requested() {
+        match consumer.poll(shutdown::POLL_INTERVAL) {
+            Some(Ok(received)) => {
+                let msg = received.message();
+                if !window.contains(msg) {
+                    continue;
+                }
+                info!(
+                    parent: received.span(),
+                    value = ?received.value(),
+                    headers = %received.headers(),
+                    "replayed message"
+                );
+                replayed += 1;
+            }
+            Some(Err(ReceiveError::Poison(pill))) => {
+                // still moves the window along
+                let inside = window.contains(&pill.message);
+                if inside {
+                    warn!(error = %pill, "skipped poison pill");
+                }
+            }
+            Some(Err(err @ ReceiveError::Unavailable { .. })) => {
+                warn!(error = %err, "failed to consume message");
+                if err.is_fatal() {
+                    error!("replay encountered receive error, stopping");
+                    break;
+                }
+                // e.g. the schema registry is down, and the message comes back
+                if let Some(backoff) = err.backoff() {
+                    shutdown.sleep(backoff);
+                }
+            }
+            Some(Err(ReceiveError::Kafka(err))) => {
+                window.error(&err);
+                if !matches!(err, KafkaError::PartitionEOF(_)) {
+                    warn!(error = %err, "failed to consume message");
+                }
+            }
+            None => {}
+        }
+    }
+
+    if let Err(err) = shutdown::close_consumer(consumer.inner(), false) {
+        warn!(error = %err, "failed to close consumer")
+    }
+    info!(
+        replayed,
+        finished = window.finished().len(),
+        "replay complete"
+    );
+}
@@ -0,0 +1,58 @@
+use std::process;
+
+use kafka101_core::{
+    lag::{self, DEFAULT_FETCH_TIMEOUT},
+    logging,
+    shutdown::Shutdown,
+    ConsumerCallbackLogger, Metrics, Settings,
+};
+use rdkafka::consumer::BaseConsumer;
+use tracing::{info, warn};
+
+fn main() {
+    let settings = Settings::load().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        process::exit(1)
+    });
+    logging::init(&settings.logging).expect("failed to install logger");
+    let metrics = Metrics::from_settings(&settings).expect("failed to serve metrics");
+
+    // never subscribes, so the group is only read, not joined
+    let consumer: BaseConsumer<ConsumerCallbackLogger> = settings
+        .consumer_config()
+        .set("enable.auto.commit", "false")
+        .create_with_context(
+            ConsumerCallbackLogger::new()
+                .with_auth(&settings.auth)
+                .with_metrics(metrics.clone()),
+        )
+        .expect("invalid consumer config");
+
+    info!(
+        group = %settings.group_id,
+        topic = %settings.topic,
+        interval_ms = settings.lag.interval.as_millis() as u64,
+        "monitoring consumer lag"
+    );
+
+    let shutdown = Shutdown::on_signal().expect("failed to install signal handler");
+
+    loop {
+        match lag::measure_topic(
+            &consumer,
+            &settings.group_id,
+            &settings.topic,
+            DEFAULT_FETCH_TIMEOUT,
+        ) {
+            Ok(report) => {
+                report.log();
+                metrics.record_lag(&report);
+            }
+            Err(err) => warn!(error = %err, "failed to measure consumer lag"),
+        }
+
+        if shutdown.sleep(settings.lag.interval) {
+            break;
+        }
+    }
+}